@@ -0,0 +1,178 @@
+//! Adaptive concurrency control for bulk requests.
+//!
+//! Rather than sending a fixed number of requests concurrently, a `Limiter`
+//! adjusts the number of requests in flight based on how the cluster copes
+//! with them. This follows an AIMD (additive increase, multiplicative
+//! decrease) scheme; the limit is halved whenever the cluster rejects work
+//! (or slows down noticeably), and grown slowly while the cluster keeps up.
+use tokio::sync::Notify;
+use tracing::{debug, info};
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The minimum delay between two decreases of the limit.
+///
+/// Requests already in flight when the limit is decreased are likely to be
+/// rejected (or slow) too, so this avoids collapsing the limit on a single
+/// burst of failures.
+const COOLDOWN: Duration = Duration::from_secs(1);
+
+/// The weight given to each new sample of the average request time.
+const SMOOTHING: f64 = 0.2;
+
+/// The factor of the baseline request time considered to be congestion.
+const CONGESTION: f64 = 2.0;
+
+/// Limiter controlling the number of requests in flight.
+///
+/// The limit starts at a single request and grows by one for each request
+/// completed successfully, until the cluster first pushes back. From then
+/// on it only grows by one after a full limit of requests has completed,
+/// and is never allowed to grow beyond the configured maximum.
+#[derive(Debug)]
+pub(crate) struct Limiter {
+    max: usize,
+    state: Mutex<State>,
+    notify: Notify,
+}
+
+/// Internal state of a limiter, guarded by a lock.
+#[derive(Debug)]
+struct State {
+    /// The current number of requests allowed in flight.
+    limit: usize,
+    /// The number of requests currently in flight.
+    in_flight: usize,
+    /// Whether the limit is still growing on every success.
+    probing: bool,
+    /// The number of successes since the limit last changed.
+    successes: usize,
+    /// The smoothed average time taken by each request, in milliseconds.
+    average: Option<f64>,
+    /// The lowest smoothed average seen, in milliseconds.
+    baseline: Option<f64>,
+    /// The last time the limit was decreased.
+    decreased: Option<Instant>,
+}
+
+impl Limiter {
+    /// Constructs a new limiter, allowing up to a maximum number of requests.
+    pub(crate) fn new(max: usize) -> Self {
+        Self {
+            max: max.max(1),
+            state: Mutex::new(State {
+                limit: 1,
+                in_flight: 0,
+                probing: true,
+                successes: 0,
+                average: None,
+                baseline: None,
+                decreased: None,
+            }),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Waits for room to send a request, returning a permit to hold while sending.
+    pub(crate) async fn acquire(&self) -> Permit<'_> {
+        loop {
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+
+            // register interest before checking, to avoid missing a release
+            notified.as_mut().enable();
+            {
+                let mut state = self.state.lock().expect("poisoned lock");
+                if state.in_flight < state.limit {
+                    state.in_flight += 1;
+                    return Permit { limiter: self };
+                }
+            }
+
+            notified.await
+        }
+    }
+
+    /// Records that the cluster rejected work due to load.
+    pub(crate) fn reject(&self) {
+        let mut state = self.state.lock().expect("poisoned lock");
+        if self.decrease(&mut state) {
+            info!(
+                limit = state.limit,
+                "Cluster rejected requests, reducing concurrency"
+            );
+        }
+    }
+
+    /// Records the time taken by the cluster to handle a successful request.
+    pub(crate) fn observe(&self, took: Duration) {
+        let mut state = self.state.lock().expect("poisoned lock");
+        let took = took.as_secs_f64() * 1000.0;
+
+        // smooth the time taken to avoid reacting to any one slow request
+        let average = match state.average {
+            Some(average) => average + SMOOTHING * (took - average),
+            None => took,
+        };
+        let baseline = state
+            .baseline
+            .map_or(average, |baseline| baseline.min(average));
+        state.average = Some(average);
+        state.baseline = Some(baseline);
+
+        // requests slowing down well beyond the baseline signal congestion
+        if average > baseline * CONGESTION && average - baseline >= 1.0 {
+            if self.decrease(&mut state) {
+                info!(
+                    limit = state.limit,
+                    "Cluster slowing down, reducing concurrency"
+                );
+            }
+            return;
+        }
+
+        // grow the limit on every success while probing, or each full window after
+        state.successes += 1;
+        if (state.probing || state.successes >= state.limit) && state.limit < self.max {
+            state.limit += 1;
+            state.successes = 0;
+            debug!(limit = state.limit, "Increasing concurrency");
+            self.notify.notify_waiters();
+        }
+    }
+
+    /// Halves the limit, unless it was decreased very recently.
+    ///
+    /// Returns whether the limit was actually decreased, as it never drops
+    /// below a single request.
+    fn decrease(&self, state: &mut State) -> bool {
+        // skip decreases caused by requests sent before the last one
+        if state.decreased.is_some_and(|at| at.elapsed() < COOLDOWN) {
+            return false;
+        }
+
+        // halve the limit and stop probing for a higher one
+        let previous = state.limit;
+        state.limit = (state.limit / 2).max(1);
+        state.probing = false;
+        state.successes = 0;
+        state.decreased = Some(Instant::now());
+
+        // reset the average, so the new limit is judged on its own
+        state.average = None;
+        state.limit < previous
+    }
+}
+
+/// Permit to send a request, released on drop.
+pub(crate) struct Permit<'a> {
+    limiter: &'a Limiter,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.limiter.state.lock().expect("poisoned lock").in_flight -= 1;
+        self.limiter.notify.notify_waiters();
+    }
+}
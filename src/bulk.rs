@@ -0,0 +1,178 @@
+//! Bulk operation structures used to construct bulk requests.
+//!
+//! The operations offered by the `elasticsearch` crate intentionally omit
+//! some metadata (such as `_type`) which is still required when working with
+//! older clusters. This module offers a minimal operation type which allows
+//! arbitrary metadata, whilst still being usable as a bulk request body.
+use bytes::{BufMut, Bytes, BytesMut};
+use elasticsearch::http::request::Body;
+use elasticsearch::Error;
+use serde_json::{Map, Value};
+
+/// Bulk actions supported by an `Operation`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Index,
+    Create,
+    Delete,
+}
+
+impl Action {
+    /// Returns the name of this action within a bulk request.
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Index => "index",
+            Action::Create => "create",
+            Action::Delete => "delete",
+        }
+    }
+}
+
+/// Bulk operation containing an action, metadata and optional source.
+///
+/// The source is serialized eagerly on construction, which allows for both
+/// cheap cloning of operations and sizing of a request ahead of time.
+#[derive(Clone, Debug)]
+pub struct Operation {
+    action: Action,
+    metadata: Map<String, Value>,
+    source: Option<Bytes>,
+    input: Option<Bytes>,
+    offset: Option<u64>,
+}
+
+impl Operation {
+    /// Constructs a new operation for an action and optional source.
+    pub fn new(action: Action, source: Option<Value>) -> Self {
+        Self {
+            action,
+            metadata: Map::new(),
+            source: source.map(|source| {
+                // serializing a `Value` can never fail
+                Bytes::from(serde_json::to_vec(&source).expect("valid JSON"))
+            }),
+            input: None,
+            offset: None,
+        }
+    }
+
+    /// Constructs a new operation for an action and pre-serialized source.
+    ///
+    /// The source is not validated, so it must be a valid JSON document
+    /// on a single line, otherwise the bulk request will be rejected.
+    pub fn raw<B: Into<Bytes>>(action: Action, source: B) -> Self {
+        Self {
+            action,
+            metadata: Map::new(),
+            source: Some(source.into()),
+            input: None,
+            offset: None,
+        }
+    }
+
+    /// Attaches the raw input this operation was constructed from.
+    ///
+    /// This is not sent as part of the request, but allows the caller to
+    /// recover the original input in case of failure.
+    pub fn with_input<B: Into<Bytes>>(mut self, input: B) -> Self {
+        self.input = Some(input.into());
+        self
+    }
+
+    /// Returns the raw input this operation was constructed from, if any.
+    pub fn input(&self) -> Option<&[u8]> {
+        self.input.as_deref()
+    }
+
+    /// Attaches the offset of the end of the input within its source.
+    ///
+    /// Much like the input, this is not sent as part of the request, but
+    /// allows the caller to track progress through an input source.
+    pub fn with_offset(mut self, offset: u64) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+
+    /// Returns the offset of the end of the input within its source, if any.
+    pub fn offset(&self) -> Option<u64> {
+        self.offset
+    }
+
+    /// Attaches a metadata field to this operation.
+    ///
+    /// Any value previously stored under the same key is replaced.
+    pub fn metadata<K, V>(mut self, key: K, value: V) -> Self
+    where
+        K: Into<String>,
+        V: Into<Value>,
+    {
+        self.metadata.insert(key.into(), value.into());
+        self
+    }
+
+    /// Returns the action of this operation.
+    pub fn action(&self) -> Action {
+        self.action
+    }
+
+    /// Returns the identifier of the document this operation targets, if any.
+    pub fn id(&self) -> Option<&str> {
+        self.metadata.get("_id").and_then(Value::as_str)
+    }
+
+    /// Returns the index this operation targets, if any.
+    pub fn index(&self) -> Option<&str> {
+        self.metadata.get("_index").and_then(Value::as_str)
+    }
+
+    /// Returns the size of this operation within a bulk request body.
+    pub fn size(&self) -> usize {
+        let header = serde_json::to_vec(&self.metadata).map_or(0, |m| m.len());
+        let source = self.source.as_ref().map_or(0, |s| s.len() + 1);
+        header + self.action.name().len() + 6 + source
+    }
+}
+
+impl Body for Operation {
+    fn write(&self, bytes: &mut BytesMut) -> Result<(), Error> {
+        // write the action header, containing all metadata
+        bytes.put_slice(b"{\"");
+        bytes.put_slice(self.action.name().as_bytes());
+        bytes.put_slice(b"\":");
+        serde_json::to_writer(bytes.writer(), &self.metadata)?;
+        bytes.put_slice(b"}\n");
+
+        // write the source when provided (i.e. not for deletes)
+        if let Some(source) = &self.source {
+            bytes.put_slice(source);
+            bytes.put_u8(b'\n');
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn size_matches_the_written_body() {
+        let operations = [
+            Operation::new(Action::Index, Some(json!({ "a": [1, "two"] })))
+                .metadata("_index", "idx")
+                .metadata("_id", "1"),
+            Operation::raw(Action::Create, &b"{\"b\":true}"[..]).metadata("routing", "r"),
+            Operation::new(Action::Delete, None)
+                .metadata("_index", "idx")
+                .metadata("_id", "2"),
+            Operation::new(Action::Delete, None),
+        ];
+        for operation in operations {
+            let mut bytes = BytesMut::new();
+            operation.write(&mut bytes).unwrap();
+            assert_eq!(operation.size(), bytes.len());
+        }
+    }
+}
@@ -0,0 +1,201 @@
+//! Checkpoint tracking used to resume an interrupted import.
+//!
+//! Batches are acknowledged as they complete, which may be out of order due
+//! to concurrency. The checkpoint only ever advances past batches which are
+//! contiguous from the start of the input, so the persisted offset is always
+//! safe to resume from (although some documents after it may be replayed).
+//!
+//! Each checkpoint is guarded by a `Lock` for the duration of an import, so
+//! two imports can never write to the same checkpoint at the same time.
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The minimum interval between writes of a checkpoint to disk.
+const INTERVAL: Duration = Duration::from_secs(1);
+
+/// Checkpoint of the byte offset of the last acknowledged batch.
+///
+/// Checkpoints can also be kept in memory only, to track the offset of an
+/// input which has some other way to persist its position.
+pub struct Checkpoint {
+    path: Option<String>,
+    state: Mutex<State>,
+}
+
+/// Internal state used to track acknowledged batches.
+struct State {
+    /// The sequence number of the next batch expected to advance.
+    next: usize,
+    /// Batches acknowledged ahead of the next expected batch.
+    pending: BTreeMap<usize, u64>,
+    /// The offset of the last contiguous acknowledged batch.
+    offset: u64,
+    /// The offset written to disk most recently, if any.
+    written: Option<u64>,
+    /// The time of the most recent write to disk.
+    timestamp: Instant,
+}
+
+impl Checkpoint {
+    /// Creates a new checkpoint at a path, starting from an offset.
+    ///
+    /// The starting offset is written immediately, to replace any existing
+    /// checkpoint which may have been left behind by a previous import.
+    pub fn create(path: &str, offset: u64) -> Result<Self> {
+        let mut checkpoint = Self::memory(offset);
+        checkpoint.path = Some(path.to_owned());
+        checkpoint.flush()?;
+        Ok(checkpoint)
+    }
+
+    /// Creates a new checkpoint which is never written to disk.
+    pub fn memory(offset: u64) -> Self {
+        Self {
+            path: None,
+            state: Mutex::new(State {
+                next: 0,
+                pending: BTreeMap::new(),
+                offset,
+                written: None,
+                timestamp: Instant::now(),
+            }),
+        }
+    }
+
+    /// Returns the offset of the last contiguous acknowledged batch.
+    pub fn offset(&self) -> u64 {
+        self.state.lock().expect("poisoned lock").offset
+    }
+
+    /// Reads the offset stored in a checkpoint file.
+    ///
+    /// A missing checkpoint file is treated as an offset of zero, as there's
+    /// no progress to resume from.
+    pub fn read(path: &str) -> Result<u64> {
+        // no checkpoint means starting at the beginning
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err.into()),
+        };
+
+        // pull the offset back out of the checkpoint
+        serde_json::from_slice::<Value>(&contents)?
+            .get("offset")
+            .and_then(Value::as_u64)
+            .ok_or_else(|| anyhow!("Invalid checkpoint file: {}", path))
+    }
+
+    /// Acknowledges a batch by sequence number, ending at an offset.
+    ///
+    /// The checkpoint is written to disk periodically as it advances.
+    pub fn acknowledge(&self, sequence: usize, offset: u64) -> Result<()> {
+        let mut guard = self.state.lock().expect("poisoned lock");
+        let state = &mut *guard;
+
+        // store the batch until all previous batches are done
+        state.pending.insert(sequence, offset);
+
+        // advance through all contiguous batches
+        while let Some(offset) = state.pending.remove(&state.next) {
+            state.offset = state.offset.max(offset);
+            state.next += 1;
+        }
+
+        // only write to disk after an interval
+        if state.timestamp.elapsed() < INTERVAL {
+            return Ok(());
+        }
+
+        self.persist(state)
+    }
+
+    /// Writes the current checkpoint to disk, regardless of interval.
+    pub fn flush(&self) -> Result<()> {
+        self.persist(&mut self.state.lock().expect("poisoned lock"))
+    }
+
+    /// Writes the checkpoint to disk if it has advanced since last written.
+    ///
+    /// The checkpoint is written to a temporary file before being moved into
+    /// place, to avoid a corrupted checkpoint if interrupted mid-write.
+    fn persist(&self, state: &mut State) -> Result<()> {
+        state.timestamp = Instant::now();
+
+        // nothing to do if we haven't moved (or have nowhere to write)
+        let path = match self.path {
+            Some(ref path) if state.written != Some(state.offset) => path,
+            _ => return Ok(()),
+        };
+
+        // write and swap the checkpoint file
+        let temporary = format!("{}.tmp", path);
+        let contents = serde_json::to_vec(&json!({ "offset": state.offset }))?;
+
+        fs::write(&temporary, contents)?;
+        fs::rename(&temporary, path)?;
+
+        state.written = Some(state.offset);
+
+        Ok(())
+    }
+}
+
+/// Exclusive lock held on a checkpoint for the duration of an import.
+///
+/// The lock is held on a file alongside the checkpoint, containing the id
+/// of the process holding it. The operating system releases the lock when
+/// the process exits (even if it crashes), so a lock is never left stale.
+/// The file itself is left behind, as removing it would allow another run
+/// to lock a file which is no longer in place.
+#[derive(Debug)]
+pub struct Lock {
+    _file: File,
+}
+
+impl Lock {
+    /// Acquires the lock of the checkpoint at a path.
+    ///
+    /// This fails immediately if another process already holds the lock,
+    /// rather than waiting for it to be released.
+    pub fn acquire(path: &str) -> Result<Self> {
+        // open the lock file without truncating, as it may be held
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(format!("{}.lock", path))?;
+
+        // take the lock, reporting the holder if it's already held
+        match file.try_lock() {
+            Ok(()) => (),
+            Err(TryLockError::WouldBlock) => {
+                let mut holder = String::new();
+                file.read_to_string(&mut holder)?;
+                return Err(anyhow!(
+                    "Another limber run is active for {} (pid {})",
+                    path,
+                    holder
+                        .trim()
+                        .parse::<u32>()
+                        .map_or("unknown".to_owned(), |pid| pid.to_string())
+                ));
+            }
+            Err(TryLockError::Error(err)) => return Err(err.into()),
+        }
+
+        // record ourselves as the holder of the lock
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", std::process::id())?;
+
+        Ok(Self { _file: file })
+    }
+}
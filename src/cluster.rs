@@ -0,0 +1,210 @@
+//! Detection of the version and distribution of a cluster.
+//!
+//! The APIs used by Limber have shifted over time (such as the removal of
+//! document types), so the version of a cluster is detected on startup to
+//! allow requests to be shaped to suit it. Clusters which are too old to be
+//! supported at all are rejected up front, rather than failing later with a
+//! cryptic error from the cluster.
+use anyhow::{anyhow, Result};
+use elasticsearch::Elasticsearch;
+use serde_json::Value;
+use tracing::{info, warn};
+
+use std::fmt::{self, Display, Formatter};
+
+/// The oldest version of Elasticsearch supported, due to sliced scrolls.
+const MIN_VERSION: Version = Version {
+    major: 5,
+    minor: 0,
+    patch: 0,
+};
+
+/// Distributions of a cluster which can be detected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Distribution {
+    Elasticsearch,
+    OpenSearch,
+}
+
+/// Version of a cluster, in the form `major.minor.patch`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+}
+
+impl Version {
+    /// Parses a version from a string, ignoring any suffix (such as `-SNAPSHOT`).
+    pub fn parse(input: &str) -> Result<Self> {
+        let invalid = || anyhow!("Invalid cluster version provided: {}", input);
+
+        // trim any qualifier from the numeric components
+        let numeric = input.split('-').next().unwrap_or_default();
+        let mut components = numeric.split('.').map(str::parse::<u64>);
+
+        // missing components default to zero, as in `8.0`
+        let mut next = || components.next().unwrap_or(Ok(0)).map_err(|_| invalid());
+
+        Ok(Self {
+            major: next()?,
+            minor: next()?,
+            patch: next()?,
+        })
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// How document types are handled by a cluster.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Types {
+    /// Every document requires a type, using the default when none is known.
+    Required(&'static str),
+    /// Types are deprecated, and only sent when explicitly known.
+    Optional,
+    /// Types have been removed, and are never sent.
+    Removed,
+}
+
+/// The version and distribution of a cluster.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cluster {
+    pub distribution: Distribution,
+    pub version: Version,
+}
+
+impl Cluster {
+    /// Detects the version and distribution of a cluster.
+    ///
+    /// Clusters which don't allow access to their root info (such as when
+    /// using restricted credentials) return `None`, in which case it's up to
+    /// the caller to assume sensible defaults. Clusters which are too old to
+    /// be supported return an error.
+    pub async fn detect(client: &Elasticsearch) -> Result<Option<Self>> {
+        // fetch the root info of the cluster
+        let response = client.info().send().await?;
+
+        // rejected credentials are always an error
+        let response = match response.status_code().as_u16() {
+            401 => response.error_for_status_code()?,
+            _ => response,
+        };
+
+        // anything else unsuccessful leaves the cluster unknown
+        if !response.status_code().is_success() {
+            let status = response.status_code().as_u16();
+            warn!(status, "Unable to detect cluster version");
+            return Ok(None);
+        }
+
+        // parse the cluster out of the response
+        let cluster = Self::parse(&response.json::<Value>().await?)?;
+        info!(
+            distribution = %cluster.distribution,
+            version = %cluster.version,
+            "Connected to cluster"
+        );
+
+        // reject anything too old to work with
+        cluster.check()?;
+        Ok(Some(cluster))
+    }
+
+    /// Parses a cluster from the body of a root info response.
+    pub fn parse(body: &Value) -> Result<Self> {
+        // fetch the version block from the response
+        let version = body
+            .get("version")
+            .ok_or_else(|| anyhow!("Unable to locate version of cluster"))?;
+
+        // OpenSearch marks itself via the distribution (or tagline), Elasticsearch doesn't
+        let tagline = body
+            .get("tagline")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let distribution = match version.get("distribution").and_then(Value::as_str) {
+            Some("opensearch") => Distribution::OpenSearch,
+            _ if tagline.contains("OpenSearch") => Distribution::OpenSearch,
+            _ => Distribution::Elasticsearch,
+        };
+
+        // parse the version number itself
+        let number = version
+            .get("number")
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("Unable to locate version of cluster"))?;
+
+        Ok(Self {
+            distribution,
+            version: Version::parse(number)?,
+        })
+    }
+
+    /// Checks whether this cluster is supported.
+    pub fn check(&self) -> Result<()> {
+        if self.distribution == Distribution::Elasticsearch && self.version < MIN_VERSION {
+            return Err(anyhow!(
+                "Elasticsearch {} is not supported, version {} or newer is required",
+                self.version,
+                MIN_VERSION
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns how this cluster handles document types.
+    ///
+    /// Types were deprecated in Elasticsearch 7.x (and OpenSearch 1.x, which
+    /// is derived from it) and removed entirely in the following releases.
+    /// Older clusters require a type, where `_doc` is only allowed from 6.2.
+    ///
+    /// OpenSearch can be configured to report itself as Elasticsearch 7.10.2
+    /// for the sake of older clients, which hides the real version. Types are
+    /// never sent in this case, as that works for every OpenSearch release.
+    pub fn types(&self) -> Types {
+        match (self.distribution, self.version.major) {
+            (Distribution::OpenSearch, 0..=1) => Types::Optional,
+            (Distribution::OpenSearch, _) => Types::Removed,
+            (Distribution::Elasticsearch, 0..=5) => Types::Required("doc"),
+            (Distribution::Elasticsearch, 6) if self.version.minor < 2 => Types::Required("doc"),
+            (Distribution::Elasticsearch, 6) => Types::Required("_doc"),
+            (Distribution::Elasticsearch, 7) => Types::Optional,
+            (Distribution::Elasticsearch, _) => Types::Removed,
+        }
+    }
+}
+
+impl Cluster {
+    /// Returns the REST API version to request compatibility with, if any.
+    ///
+    /// Requests are shaped for the 7.x REST API, so newer versions of
+    /// Elasticsearch are asked to treat them as if sent to the previous
+    /// major version (which is the furthest back compatibility reaches).
+    /// OpenSearch has no such mechanism, so this is always `None`.
+    pub fn compatibility(&self) -> Option<u64> {
+        match self.distribution {
+            Distribution::Elasticsearch if self.version.major >= 8 => Some(self.version.major - 1),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Distribution {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Distribution::Elasticsearch => f.write_str("Elasticsearch"),
+            Distribution::OpenSearch => f.write_str("OpenSearch"),
+        }
+    }
+}
+
+impl Display for Cluster {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.distribution, self.version)
+    }
+}
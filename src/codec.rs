@@ -0,0 +1,458 @@
+//! Codecs used to write (and read back) documents in the layouts of other tools.
+//!
+//! Documents are always handled in the export format, as a line of JSON
+//! holding the `_index`, `_id` and `_source` of each document (alongside any
+//! other metadata). Codecs convert documents between this format and those
+//! expected elsewhere: the lines written by `elasticdump` (which also carry
+//! a `_type` and `_score`), and rows of a table written as CSV or Parquet.
+//!
+//! Tables hold the `index`, `id` and `source` (as JSON) of each document,
+//! with any other metadata (such as `_routing`) kept as a JSON object in a
+//! `metadata` column, so that a dump converted to a table and back again
+//! is left intact.
+use anyhow::{anyhow, Result};
+use serde_json::value::{self, RawValue};
+use tokio::io::{self, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::io::{BufReader, DuplexStream};
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+
+use std::collections::BTreeMap;
+
+use crate::format::RECORD_SEPARATOR;
+use crate::input::Reader;
+
+/// The size of the buffer between a codec and its reader (or writer).
+const PIPE_SIZE: usize = 1024 * 1024;
+
+/// The type written for documents without one, as expected by `elasticdump`.
+const DEFAULT_TYPE: &str = "_doc";
+
+/// Fields which only describe the query a document was found by.
+const DROPPED: [&str; 2] = ["_score", "sort"];
+
+/// The columns of every table documents are written to, in order.
+pub const COLUMNS: [&str; 4] = ["index", "id", "source", "metadata"];
+
+/// Codecs available to write (and read) documents with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    /// Lines in the layout written by `elasticdump`.
+    Elasticdump,
+    /// Rows of a CSV file, beneath a header of `COLUMNS`.
+    Csv,
+    /// Rows of a set of Parquet files, within a directory.
+    Parquet,
+}
+
+/// Document as a row of a table, with a field for each of `COLUMNS`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Row {
+    /// The index of the document, if any.
+    pub index: Option<String>,
+    /// The identifier of the document, if any.
+    pub id: Option<String>,
+    /// The source of the document as JSON, if any.
+    pub source: Option<String>,
+    /// Any other fields of the document as a JSON object, if any.
+    pub metadata: Option<String>,
+}
+
+impl Codec {
+    /// Parses a codec from its name, if it names one.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "elasticdump" => Some(Codec::Elasticdump),
+            "csv" => Some(Codec::Csv),
+            "parquet" => Some(Codec::Parquet),
+            _ => None,
+        }
+    }
+
+    /// Spawns a task encoding every document written to the returned writer.
+    ///
+    /// Documents are written to the returned writer in the export format (as
+    /// lines, or records of a sequence) and written out to the provided writer
+    /// once encoded. Parquet can't be written as a stream, so has to be written
+    /// to a directory via `parquet::Sink` instead.
+    pub fn encode<W>(self, writer: W) -> Result<(DuplexStream, JoinHandle<Result<()>>)>
+    where
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        let (input, output) = io::duplex(PIPE_SIZE);
+        let task = match self {
+            Codec::Elasticdump => tokio::spawn(encode_elasticdump(output, writer)),
+            Codec::Csv => tokio::spawn(encode_csv(output, writer)),
+            Codec::Parquet => {
+                return Err(anyhow!("Parquet can only be written to a directory"));
+            }
+        };
+        Ok((input, task))
+    }
+
+    /// Spawns a task decoding a reader into documents in the export format.
+    ///
+    /// The returned reader provides a line for each document. Parquet can't be
+    /// read as a stream, so has to be read from a path via `parquet::read`.
+    pub fn decode<R>(self, reader: R) -> Result<(Reader, JoinHandle<Result<()>>)>
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+    {
+        let (input, output) = io::duplex(PIPE_SIZE);
+        let task = match self {
+            Codec::Elasticdump => tokio::spawn(decode_elasticdump(reader, output)),
+            Codec::Csv => {
+                let handle = Handle::current();
+                tokio::task::spawn_blocking(move || decode_csv(handle, reader, output))
+            }
+            Codec::Parquet => {
+                return Err(anyhow!("Parquet can only be read from a file or directory"));
+            }
+        };
+        Ok((Box::new(input), task))
+    }
+}
+
+impl Row {
+    /// Parses a row from a document in the export format.
+    pub fn parse(document: &[u8]) -> Result<Self> {
+        let mut fields = fields(document)?;
+        let index = fields.remove("_index").map(text);
+        let id = fields.remove("_id").map(text);
+        let source = fields
+            .remove("_source")
+            .map(|source| source.get().to_owned());
+
+        // anything else is metadata, besides anything describing the query
+        for field in DROPPED {
+            fields.remove(field);
+        }
+        let metadata = match fields.is_empty() {
+            true => None,
+            false => Some(serde_json::to_string(&fields)?),
+        };
+
+        Ok(Self {
+            index,
+            id,
+            source,
+            metadata,
+        })
+    }
+
+    /// Converts a row back into a document in the export format.
+    pub fn document(&self) -> Result<Vec<u8>> {
+        let mut fields = match self.metadata.as_deref() {
+            Some(metadata) => serde_json::from_str::<BTreeMap<String, Box<RawValue>>>(metadata)
+                .map_err(|err| anyhow!("Invalid metadata provided in row: {}", err))?,
+            None => BTreeMap::new(),
+        };
+        if let Some(ref index) = self.index {
+            fields.insert("_index".to_owned(), value::to_raw_value(index)?);
+        }
+        if let Some(ref id) = self.id {
+            fields.insert("_id".to_owned(), value::to_raw_value(id)?);
+        }
+        if let Some(ref source) = self.source {
+            let source = serde_json::from_str::<Box<RawValue>>(source)
+                .map_err(|err| anyhow!("Invalid source provided in row: {}", err))?;
+            fields.insert("_source".to_owned(), source);
+        }
+        Ok(object(fields, &["_index", "_id"]))
+    }
+}
+
+/// Reads each document written in the export format, skipping empty records.
+async fn next_document<R>(lines: &mut io::Split<BufReader<R>>) -> Result<Option<Vec<u8>>>
+where
+    R: AsyncRead + Unpin,
+{
+    while let Some(line) = lines.next_segment().await? {
+        // records of a sequence are also written with a trailing line break
+        let document = line.strip_prefix(&[RECORD_SEPARATOR]).unwrap_or(&line);
+        if !document.iter().all(u8::is_ascii_whitespace) {
+            return Ok(Some(document.to_vec()));
+        }
+    }
+    Ok(None)
+}
+
+/// Encodes documents as lines in the layout written by `elasticdump`.
+///
+/// The layout is the same as the export format, except that every document
+/// has a `_type` (as required by `elasticdump` when importing).
+async fn encode_elasticdump<W>(reader: DuplexStream, mut writer: W) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut lines = BufReader::new(reader).split(b'\n');
+    while let Some(document) = next_document(&mut lines).await? {
+        let mut fields = fields(&document)?;
+        if !fields.contains_key("_type") {
+            fields.insert("_type".to_owned(), value::to_raw_value(DEFAULT_TYPE)?);
+        }
+        let mut line = object(fields, &["_index", "_type", "_id"]);
+        line.push(b'\n');
+        writer.write_all(&line).await?;
+    }
+    writer.shutdown().await?;
+    Ok(())
+}
+
+/// Decodes lines written by `elasticdump` into the export format.
+///
+/// The fields describing the query the document was found by (such as the
+/// `_score`) are dropped, and anything else is kept as it is.
+async fn decode_elasticdump<R>(reader: R, mut output: DuplexStream) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut lines = BufReader::new(reader).split(b'\n');
+    while let Some(document) = next_document(&mut lines).await? {
+        let mut fields = fields(&document)?;
+        for field in DROPPED {
+            fields.remove(field);
+        }
+        let mut line = object(fields, &["_index", "_id"]);
+        line.push(b'\n');
+        output.write_all(&line).await?;
+    }
+    Ok(())
+}
+
+/// Encodes documents as rows of a CSV file, beneath a header of `COLUMNS`.
+async fn encode_csv<W>(reader: DuplexStream, mut writer: W) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut csv = csv::Writer::from_writer(Vec::new());
+    csv.write_record(COLUMNS)?;
+
+    let mut lines = BufReader::new(reader).split(b'\n');
+    loop {
+        // pass each chunk of rows through to the writer as it fills
+        let document = next_document(&mut lines).await?;
+        if csv.get_ref().len() >= PIPE_SIZE || document.is_none() {
+            let mut buffer = csv.into_inner().map_err(|err| err.into_error())?;
+            writer.write_all(&buffer).await?;
+            buffer.clear();
+            csv = csv::Writer::from_writer(buffer);
+        }
+
+        let row = match document {
+            Some(document) => Row::parse(&document)?,
+            None => break,
+        };
+        csv.write_record([
+            row.index.unwrap_or_default(),
+            row.id.unwrap_or_default(),
+            row.source.unwrap_or_default(),
+            row.metadata.unwrap_or_default(),
+        ])?;
+    }
+    writer.shutdown().await?;
+    Ok(())
+}
+
+/// Decodes the rows of a CSV file into the export format.
+///
+/// Columns are located via the header, so may appear in any order (and any
+/// other columns are ignored). Empty columns are treated as missing.
+fn decode_csv<R>(handle: Handle, reader: R, mut output: DuplexStream) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut csv = csv::Reader::from_reader(Blocking::new(handle.clone(), reader));
+
+    // locate each column within the header
+    let headers = csv.headers()?.clone();
+    let position = |column: &str| headers.iter().position(|header| header == column);
+    let columns = COLUMNS.map(position);
+    if columns[2].is_none() {
+        return Err(anyhow!("CSV input must contain a source column"));
+    }
+
+    for record in csv.records() {
+        let record = record?;
+        let column = |index: usize| {
+            columns[index]
+                .and_then(|position| record.get(position))
+                .filter(|value| !value.is_empty())
+                .map(str::to_owned)
+        };
+        let row = Row {
+            index: column(0),
+            id: column(1),
+            source: column(2),
+            metadata: column(3),
+        };
+
+        let mut line = row.document()?;
+        line.push(b'\n');
+        handle.block_on(output.write_all(&line))?;
+    }
+    Ok(())
+}
+
+/// Parses the fields of a document in the export format, keeping them raw.
+fn fields(document: &[u8]) -> Result<BTreeMap<String, Box<RawValue>>> {
+    serde_json::from_slice(document).map_err(|err| anyhow!("Invalid document provided: {}", err))
+}
+
+/// Converts a field into text, unquoting it when it's a string.
+fn text(value: Box<RawValue>) -> String {
+    serde_json::from_str(value.get()).unwrap_or_else(|_| value.get().to_owned())
+}
+
+/// Writes the fields of a document as a JSON object.
+///
+/// The leading fields are written first (when present), followed by any
+/// others, with the `_source` always written last to keep it out of the way.
+fn object(mut fields: BTreeMap<String, Box<RawValue>>, leading: &[&str]) -> Vec<u8> {
+    let source = fields.remove("_source");
+    let leading = leading
+        .iter()
+        .filter_map(|name| fields.remove_entry(*name))
+        .collect::<Vec<_>>();
+
+    let mut object = vec![b'{'];
+    let fields = leading.into_iter().chain(fields).chain(
+        source
+            .into_iter()
+            .map(|source| ("_source".to_owned(), source)),
+    );
+    for (position, (name, value)) in fields.enumerate() {
+        if position > 0 {
+            object.push(b',');
+        }
+        serde_json::to_writer(&mut object, &name).expect("strings can be written");
+        object.push(b':');
+        object.extend_from_slice(value.get().as_bytes());
+    }
+    object.push(b'}');
+    object
+}
+
+/// Reader blocking on an asynchronous reader, for use on a blocking thread.
+pub(crate) struct Blocking<R> {
+    handle: Handle,
+    reader: R,
+}
+
+impl<R> Blocking<R> {
+    /// Constructs a blocking reader, driven by a runtime handle.
+    pub(crate) fn new(handle: Handle, reader: R) -> Self {
+        Self { handle, reader }
+    }
+}
+
+impl<R: AsyncRead + Unpin> std::io::Read for Blocking<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.handle.block_on(self.reader.read(buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs documents through an encoder, and back through the decoder.
+    async fn round_trip(codec: Codec, input: &str) -> (String, String) {
+        let (output, mut encoded) = io::duplex(PIPE_SIZE);
+        let (mut writer, task) = codec.encode(output).unwrap();
+        writer.write_all(input.as_bytes()).await.unwrap();
+        drop(writer);
+        task.await.unwrap().unwrap();
+        let mut buffer = String::new();
+        encoded.read_to_string(&mut buffer).await.unwrap();
+        let encoded = buffer;
+
+        let (mut reader, task) = codec.decode(std::io::Cursor::new(encoded.clone())).unwrap();
+        let mut decoded = String::new();
+        reader.read_to_string(&mut decoded).await.unwrap();
+        task.await.unwrap().unwrap();
+        (encoded, decoded)
+    }
+
+    #[test]
+    fn codecs_parse_names() {
+        assert_eq!(Codec::parse("csv"), Some(Codec::Csv));
+        assert_eq!(Codec::parse("elasticdump"), Some(Codec::Elasticdump));
+        assert_eq!(Codec::parse("parquet"), Some(Codec::Parquet));
+        assert_eq!(Codec::parse("ndjson"), None);
+    }
+
+    #[test]
+    fn rows_keep_metadata() {
+        let document =
+            br#"{"_index":"idx","_id":"1","_score":1.0,"_routing":"a","_source":{"b":[1,2.50]}}"#;
+        let row = Row::parse(document).unwrap();
+        assert_eq!(
+            row,
+            Row {
+                index: Some("idx".to_owned()),
+                id: Some("1".to_owned()),
+                source: Some(r#"{"b":[1,2.50]}"#.to_owned()),
+                metadata: Some(r#"{"_routing":"a"}"#.to_owned()),
+            }
+        );
+        assert_eq!(
+            row.document().unwrap(),
+            br#"{"_index":"idx","_id":"1","_routing":"a","_source":{"b":[1,2.50]}}"#
+        );
+    }
+
+    #[test]
+    fn rows_reject_invalid_sources() {
+        let row = Row {
+            source: Some("{".to_owned()),
+            ..Row::default()
+        };
+        assert!(row.document().is_err());
+    }
+
+    #[tokio::test]
+    async fn elasticdump_round_trips() {
+        let input = "{\"_index\":\"idx\",\"_id\":\"1\",\"_source\":{\"a\":1}}\n\
+                     \x1e{\"_id\":\"2\",\"_type\":\"doc\",\"_index\":\"idx\",\"_source\":{}}\n";
+        let (encoded, decoded) = round_trip(Codec::Elasticdump, input).await;
+        assert_eq!(
+            encoded,
+            "{\"_index\":\"idx\",\"_type\":\"_doc\",\"_id\":\"1\",\"_source\":{\"a\":1}}\n\
+             {\"_index\":\"idx\",\"_type\":\"doc\",\"_id\":\"2\",\"_source\":{}}\n"
+        );
+        assert_eq!(
+            decoded,
+            "{\"_index\":\"idx\",\"_id\":\"1\",\"_type\":\"_doc\",\"_source\":{\"a\":1}}\n\
+             {\"_index\":\"idx\",\"_id\":\"2\",\"_type\":\"doc\",\"_source\":{}}\n"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn csv_round_trips() {
+        let input = "{\"_index\":\"idx\",\"_id\":\"1\",\"_source\":{\"a\":\"x,\\\"y\\\"\"}}\n\
+                     {\"_id\":\"2\",\"_routing\":\"r\",\"_source\":{}}\n";
+        let (encoded, decoded) = round_trip(Codec::Csv, input).await;
+        assert_eq!(
+            encoded,
+            "index,id,source,metadata\n\
+             idx,1,\"{\"\"a\"\":\"\"x,\\\"\"y\\\"\"\"\"}\",\n\
+             ,2,{},\"{\"\"_routing\"\":\"\"r\"\"}\"\n"
+        );
+        assert_eq!(
+            decoded,
+            "{\"_index\":\"idx\",\"_id\":\"1\",\"_source\":{\"a\":\"x,\\\"y\\\"\"}}\n\
+             {\"_id\":\"2\",\"_routing\":\"r\",\"_source\":{}}\n"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn csv_requires_a_source_column() {
+        let (reader, task) = Codec::Csv
+            .decode(std::io::Cursor::new("index,id\nidx,1\n"))
+            .unwrap();
+        drop(reader);
+        let err = task.await.unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "CSV input must contain a source column");
+    }
+}
@@ -1,3 +1,397 @@
 //! Exported command bindings invoked by the CLI.
+use anyhow::{anyhow, Result};
+use clap::ArgMatches;
+use limber::codec::Codec;
+use limber::compress::{self, Compression};
+use limber::format::Format;
+use limber::input::{self, Reader};
+#[cfg(feature = "kafka")]
+use limber::kafka;
+#[cfg(feature = "parquet")]
+use limber::parquet;
+#[cfg(feature = "sqlite")]
+use limber::sqlite;
+use limber::state::{self, Job};
+use limber::tee::Tee;
+use limber::transform::Transform;
+use limber::{units, ClientOptions, Credentials};
+use tokio::fs::File;
+use tokio::io::{AsyncWrite, BufWriter};
+use tokio::task::JoinHandle;
+
+use std::io::{self, IsTerminal};
+use std::path::PathBuf;
+
+pub mod anonymize;
+pub mod convert;
 pub mod export;
+pub mod filter;
 pub mod import;
+pub mod infer_mapping;
+pub mod inspect;
+pub mod jobs;
+pub mod mapping_diff;
+pub mod merge;
+pub mod schedule;
+pub mod split;
+
+/// Parses the options used to connect to a cluster.
+///
+/// Credentials are provided for basic authentication in the form of
+/// `user:password`, alongside an optional CA certificate and any timeouts
+/// (or bandwidth limits) to apply to requests sent to the cluster.
+fn parse_client(args: &ArgMatches) -> Result<ClientOptions> {
+    // parse the credentials into the user and password
+    let auth = parse_auth(args, "auth")?;
+
+    // parse any timeouts to apply to the transport
+    let connect_timeout = args
+        .value_of("connect-timeout")
+        .map(units::parse_duration)
+        .transpose()?;
+    let request_timeout = args
+        .value_of("request-timeout")
+        .map(units::parse_duration)
+        .transpose()?;
+
+    // parse any limit on the rate of transfer
+    let max_bandwidth = args
+        .value_of("max-bandwidth")
+        .map(units::parse_bytes)
+        .transpose()?;
+
+    Ok(ClientOptions {
+        auth,
+        ca_cert: args.value_of("ca-cert").map(str::to_owned),
+        connect_timeout,
+        debug_http: args.is_present("debug-http"),
+        max_bandwidth,
+        record: args.value_of("record").map(PathBuf::from),
+        replay: args.value_of("replay").map(PathBuf::from),
+        request_timeout,
+        sniff: args.is_present("sniff"),
+        ..ClientOptions::default()
+    })
+}
+
+/// Parses the credentials used for basic authentication, if any.
+///
+/// Credentials are provided via a flag (such as `--auth`) in the form of
+/// `user:password`.
+fn parse_auth(args: &ArgMatches, flag: &str) -> Result<Option<Credentials>> {
+    match args.value_of(flag).map(|auth| auth.split_once(':')) {
+        Some(Some((user, password))) if !user.is_empty() => Ok(Some(Credentials::Basic(
+            user.to_owned(),
+            password.to_owned(),
+        ))),
+        Some(_) => Err(anyhow!(
+            "Invalid credentials provided, expected user:password"
+        )),
+        None => Ok(None),
+    }
+}
+
+/// Locates the state directory, based on the `--state-dir` flag.
+///
+/// Without the flag, the default state directory of the platform is used.
+fn state_dir(args: &ArgMatches) -> Result<PathBuf> {
+    match args.value_of("state-dir") {
+        Some(dir) => Ok(PathBuf::from(dir)),
+        None => state::default_dir().ok_or_else(|| anyhow!("Unable to locate state directory")),
+    }
+}
+
+/// Opens the job named by the `--job` flag, if any.
+fn open_job(args: &ArgMatches) -> Result<Option<Job>> {
+    match args.value_of("job") {
+        Some(name) => Ok(Some(Job::open(&state_dir(args)?, name)?)),
+        None => Ok(None),
+    }
+}
+
+/// The size of the buffer used when writing documents to each output.
+const OUTPUT_BUFFER: usize = 256 * 1024;
+
+/// Writer of documents to every output of a command.
+type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Opens every output documents are written to, alongside the tasks feeding them.
+///
+/// Outputs are files (with `-` referring to `stdout`), Kafka topics or SQLite
+/// databases, with any compression set via `--compress` applied to the files.
+/// The tasks must be passed to `finish_tasks` once everything is written.
+async fn open_outputs(
+    args: &ArgMatches,
+    outputs: &[&str],
+) -> Result<(Writer, Vec<JoinHandle<Result<()>>>)> {
+    // refuse to flood an interactive terminal with documents
+    if outputs.contains(&"-") && io::stdout().is_terminal() && !args.is_present("force") {
+        return Err(anyhow!(
+            "Refusing to write documents to a terminal, redirect stdout or use --force"
+        ));
+    }
+
+    // parse any compression to apply to the output
+    let compression = args
+        .value_of("compress")
+        .map(Compression::parse)
+        .transpose()?;
+
+    // Kafka topics receive each document as a message, so can't be compressed
+    let topics = outputs.iter().any(|output| output.starts_with("kafka://"));
+    if topics && compression.is_some() {
+        return Err(anyhow!(
+            "Output can't be compressed when publishing to a Kafka topic"
+        ));
+    }
+    #[cfg(not(feature = "kafka"))]
+    if topics {
+        return Err(anyhow!(
+            "Kafka outputs require limber to be built with the kafka feature"
+        ));
+    }
+
+    // SQLite databases receive each document as a row, so can't be compressed either
+    let databases = outputs.iter().any(|output| output.starts_with("sqlite://"));
+    if databases && compression.is_some() {
+        return Err(anyhow!(
+            "Output can't be compressed when writing to a SQLite database"
+        ));
+    }
+    #[cfg(not(feature = "sqlite"))]
+    if databases {
+        return Err(anyhow!(
+            "SQLite outputs require limber to be built with the sqlite feature"
+        ));
+    }
+
+    // buffer every output, as each write is otherwise handed to a blocking thread
+    let mut writers = Vec::with_capacity(outputs.len());
+    let mut publishers = Vec::new();
+    for output in outputs.iter().copied() {
+        let (writer, publisher): (Writer, Option<JoinHandle<_>>) = match output {
+            "-" => (Box::new(tokio::io::stdout()), None),
+            #[cfg(feature = "kafka")]
+            url if url.starts_with("kafka://") => {
+                let (writer, publisher) = kafka::Sink::connect(url)?.spawn();
+                (Box::new(writer), Some(publisher))
+            }
+            #[cfg(feature = "sqlite")]
+            url if url.starts_with("sqlite://") => {
+                let (writer, publisher) = sqlite::Sink::open(url)?.spawn();
+                (Box::new(writer), Some(publisher))
+            }
+            path => (
+                Box::new(
+                    File::create(path)
+                        .await
+                        .map_err(|err| anyhow!("Unable to create output {}: {}", path, err))?,
+                ),
+                None,
+            ),
+        };
+        writers.push(BufWriter::with_capacity(OUTPUT_BUFFER, writer));
+        publishers.extend(publisher);
+    }
+
+    // compress the output across several threads, when asked to
+    let (writer, compressor): (Writer, _) = match compression {
+        Some(compression) => {
+            let (writer, compressor) = compress::spawn(Tee::new(writers), compression);
+            (Box::new(writer), Some(compressor))
+        }
+        None => (Box::new(Tee::new(writers)), None),
+    };
+
+    // the compressor finishes writing before anything else can finish
+    let tasks = compressor.into_iter().chain(publishers).collect();
+    Ok((writer, tasks))
+}
+
+/// Opens a dump to read documents from, decompressing it when compressed.
+///
+/// A path of `-` refers to `stdin`, which is read as is. Any task returned
+/// must be passed to `finish_tasks` once the input has been read.
+async fn open_input(path: &str) -> Result<(Reader, Option<JoinHandle<Result<()>>>)> {
+    match path {
+        "-" => Ok((input::open(None, 0).await?, None)),
+        path => compress::decompress(path)
+            .map_err(|err| anyhow!("Unable to open input {}: {}", path, err)),
+    }
+}
+
+/// Opens every output documents are written to, encoded via a codec (if any).
+///
+/// Documents are still written in the export format, and are encoded before
+/// any compression is applied. Parquet is written to a directory for each
+/// output instead, so can't be compressed (or written anywhere else).
+async fn open_encoded(
+    args: &ArgMatches,
+    outputs: &[&str],
+    codec: Option<Codec>,
+) -> Result<(Writer, Vec<JoinHandle<Result<()>>>)> {
+    let codec = match codec {
+        None => return open_outputs(args, outputs).await,
+        Some(Codec::Parquet) => return open_parquet(args, outputs),
+        Some(codec) => codec,
+    };
+
+    // topics and databases receive documents as they are, so can't be encoded
+    if outputs
+        .iter()
+        .any(|output| output.starts_with("kafka://") || output.starts_with("sqlite://"))
+    {
+        return Err(anyhow!(
+            "Kafka and SQLite outputs can only be written in the export format"
+        ));
+    }
+
+    // the encoder finishes writing before anything else can finish
+    let (writer, tasks) = open_outputs(args, outputs).await?;
+    let (writer, encoder) = codec.encode(writer)?;
+    Ok((
+        Box::new(writer),
+        std::iter::once(encoder).chain(tasks).collect(),
+    ))
+}
+
+/// Opens a directory of Parquet files for every output.
+#[cfg(feature = "parquet")]
+fn open_parquet(
+    args: &ArgMatches,
+    outputs: &[&str],
+) -> Result<(Writer, Vec<JoinHandle<Result<()>>>)> {
+    // Parquet files compress each column themselves
+    if args.is_present("compress") {
+        return Err(anyhow!("Parquet output can't be compressed via --compress"));
+    }
+
+    let mut writers = Vec::with_capacity(outputs.len());
+    let mut tasks = Vec::with_capacity(outputs.len());
+    for output in outputs.iter().copied() {
+        if output == "-" || output.contains("://") {
+            return Err(anyhow!(
+                "Parquet can only be written to a directory, not {}",
+                output
+            ));
+        }
+        let (writer, task) = parquet::Sink::create(output)?.spawn();
+        writers.push(writer);
+        tasks.push(task);
+    }
+    Ok((Box::new(Tee::new(writers)), tasks))
+}
+
+/// Opens a directory of Parquet files for every output.
+#[cfg(not(feature = "parquet"))]
+fn open_parquet(
+    _args: &ArgMatches,
+    _outputs: &[&str],
+) -> Result<(Writer, Vec<JoinHandle<Result<()>>>)> {
+    Err(anyhow!(
+        "Parquet outputs require limber to be built with the parquet feature"
+    ))
+}
+
+/// Opens a dump to read documents from, decoding it via a codec (if any).
+///
+/// The returned reader provides documents in the export format. Parquet is
+/// read from a file (or a directory of files) as is, whereas anything else is
+/// decompressed when compressed. Every task returned must be passed to
+/// `finish_tasks` once the input has been read.
+async fn open_decoded(
+    path: &str,
+    codec: Option<Codec>,
+) -> Result<(Reader, Vec<JoinHandle<Result<()>>>)> {
+    // Parquet has to be read from files, rather than as a stream
+    if codec == Some(Codec::Parquet) {
+        #[cfg(feature = "parquet")]
+        return match path {
+            "-" => Err(anyhow!(
+                "Parquet input must be read from a file or directory"
+            )),
+            path => parquet::read(path).map(|(reader, task)| (reader, vec![task])),
+        };
+        #[cfg(not(feature = "parquet"))]
+        return Err(anyhow!(
+            "Parquet inputs require limber to be built with the parquet feature"
+        ));
+    }
+
+    // anything else is decoded once decompressed
+    let (reader, decompressor) = open_input(path).await?;
+    match codec {
+        Some(codec) => {
+            let (reader, decoder) = codec.decode(reader)?;
+            Ok((
+                reader,
+                std::iter::once(decoder).chain(decompressor).collect(),
+            ))
+        }
+        None => Ok((reader, decompressor.into_iter().collect())),
+    }
+}
+
+/// Waits for every task feeding the input or outputs, keeping the first error.
+///
+/// Any error from the command itself takes precedence over that of a task.
+async fn finish_tasks(tasks: Vec<JoinHandle<Result<()>>>, mut result: Result<()>) -> Result<()> {
+    for task in tasks {
+        let finished = task.await?;
+        if result.is_ok() {
+            result = finished;
+        }
+    }
+    result
+}
+
+/// Parses the format of documents named by a flag (such as `--format`).
+fn parse_format(args: &ArgMatches, name: &str) -> Format {
+    match args.value_of(name) {
+        Some("json-seq") => Format::JsonSeq,
+        _ => Format::Ndjson,
+    }
+}
+
+/// Parses the codec of documents named by a flag (such as `--format`), if any.
+///
+/// Formats which aren't codecs (such as `json-seq`) are parsed by `parse_format`.
+fn parse_codec(args: &ArgMatches, name: &str) -> Option<Codec> {
+    args.value_of(name).and_then(Codec::parse)
+}
+
+/// Parses all transforms provided to a command, in the order they were provided.
+///
+/// As each kind of transform is provided via a separate argument, the
+/// position of each argument is used to restore the original order.
+fn parse_transforms(args: &ArgMatches, names: &[&str]) -> Result<Vec<Transform>> {
+    let mut transforms = Vec::new();
+
+    // parse every transform, tracking the position it was provided in
+    for name in names {
+        let positions = args.indices_of(name).into_iter().flatten();
+        let values = args.values_of(name).into_iter().flatten();
+        for (position, value) in positions.zip(values) {
+            // rules files can list many transforms, so they share a position
+            let parsed = match *name {
+                "set" => vec![Transform::set(value)?],
+                "remove" => vec![Transform::remove(value)?],
+                "rename" => vec![Transform::rename(value)?],
+                "shift-time" => vec![Transform::shift(value)?],
+                "coerce" => vec![Transform::coerce(value)?],
+                "jq" => vec![Transform::jq(value)?],
+                "rules" => Transform::rules(value)?,
+                _ => vec![Transform::plugin(value)?],
+            };
+            transforms.extend(parsed.into_iter().map(|transform| (position, transform)));
+        }
+    }
+
+    // restore the original ordering
+    transforms.sort_by_key(|(position, _)| *position);
+
+    Ok(transforms
+        .into_iter()
+        .map(|(_, transform)| transform)
+        .collect())
+}
@@ -0,0 +1,169 @@
+//! Anonymize command module for Limber.
+//!
+//! This module exposes functions to sanitize an existing dump without touching
+//! any cluster, applying the same transforms as an export so that backups taken
+//! before any rules existed can still be redacted before being shared.
+use anyhow::{anyhow, Result};
+use clap::{Arg, ArgMatches, Command};
+use futures::TryStreamExt;
+use limber::format::Format;
+use limber::input::{self, Reader};
+use limber::transform::Transform;
+use serde_json::Value;
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufReader};
+use tracing::{info, warn};
+
+use super::{finish_tasks, open_input, open_outputs, parse_format, parse_transforms};
+
+/// Returns the definition for this command in the CLI.
+///
+/// This function dictates options available to this command and what
+/// can be asserted to exist, as well as the other optional arguments.
+pub fn cmd<'a>() -> Command<'a> {
+    Command::new("anonymize")
+        .about("Sanitize a dump of documents using export transforms")
+        .args(&[
+            // compress: --compress
+            Arg::new("compress")
+                .help("Compress output using gzip or zstd, with an optional level (e.g. zstd:9)")
+                .long("compress")
+                .env("LIMBER_COMPRESS")
+                .takes_value(true),
+            // force: --force
+            Arg::new("force")
+                .help("Write documents to stdout even when it's a terminal")
+                .long("force")
+                .env("LIMBER_FORCE"),
+            // format: --format [ndjson]
+            Arg::new("format")
+                .help("The format documents are read (and written) in")
+                .long("format")
+                .env("LIMBER_FORMAT")
+                .takes_value(true)
+                .possible_values(["ndjson", "json-seq"])
+                .default_value("ndjson")
+                .hide_default_value(true),
+            // jq: --jq
+            Arg::new("jq")
+                .help("A jq expression to transform each document with")
+                .long("jq")
+                .env("LIMBER_JQ")
+                .takes_value(true)
+                .multiple_occurrences(true),
+            // out: --out
+            Arg::new("out")
+                .help("A file (or kafka:// topic, sqlite:// database) to write documents to")
+                .long("out")
+                .env("LIMBER_OUT")
+                .takes_value(true),
+            // rules: --rules
+            Arg::new("rules")
+                .help("A YAML file of fields to remove, redact or hash in each document")
+                .long("rules")
+                .env("LIMBER_RULES")
+                .takes_value(true)
+                .multiple_occurrences(true),
+            // transform: --transform
+            Arg::new("transform")
+                .help("A WebAssembly plugin (.wasm) to transform each document with")
+                .long("transform")
+                .env("LIMBER_TRANSFORM")
+                .takes_value(true)
+                .multiple_occurrences(true),
+            // input: +required
+            Arg::new("input")
+                .help("A dump to read documents from, compressed or not (or - for stdin)")
+                .required(true),
+        ])
+}
+
+/// Executes the `anonymize` command, reading the input through to the end.
+pub async fn run(args: &ArgMatches) -> Result<()> {
+    // parse all transforms applied to documents, requiring at least one
+    let transforms = parse_transforms(args, &["jq", "rules", "transform"])?;
+    if transforms.is_empty() {
+        return Err(anyhow!(
+            "No transforms provided, via --rules, --jq or --transform"
+        ));
+    }
+
+    // open the input, decompressing it when compressed
+    let path = args.value_of("input").expect("guaranteed by CLI");
+    let (reader, decompressor) = open_input(path).await?;
+
+    // open the output (defaulting to stdout), alongside any tasks feeding it
+    let output = args.value_of("out").unwrap_or("-");
+    let (writer, mut tasks) = open_outputs(args, &[output]).await?;
+
+    // write out every document once transformed
+    let format = parse_format(args, "format");
+    let (documents, dropped, result) = match anonymize(reader, writer, &transforms, format).await {
+        Ok((documents, dropped)) => (documents, dropped, Ok(())),
+        Err(err) => (0, 0, Err(err)),
+    };
+
+    // wait for the output to be written, and the input to be decompressed
+    tasks.extend(decompressor);
+    finish_tasks(tasks, result).await?;
+
+    info!(documents, dropped, "Anonymized documents");
+    Ok(())
+}
+
+/// Writes every document of a reader into a writer, once transformed.
+///
+/// Transforms are applied to the source of each document, exactly as they
+/// are during an export. Records which can't be parsed as documents can't be
+/// sanitized, so they're dropped (rather than copied) with a warning. The
+/// number of documents written and dropped are returned.
+async fn anonymize<W>(
+    reader: Reader,
+    mut writer: W,
+    transforms: &[Transform],
+    format: Format,
+) -> Result<(u64, u64)>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut records = Box::pin(input::records(BufReader::new(reader), 0, format));
+    let mut documents = 0;
+    let mut dropped = 0;
+
+    while let Some((record, offset)) = records.try_next().await? {
+        // nothing to sanitize within empty records
+        if record.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+
+        // parse each document, dropping anything which can't be sanitized
+        let mut document = match serde_json::from_slice::<Value>(&record) {
+            Ok(document @ Value::Object(_)) => document,
+            Ok(_) => {
+                warn!(offset, "Dropping record which isn't a document");
+                dropped += 1;
+                continue;
+            }
+            Err(err) => {
+                warn!(offset, error = %err, "Dropping invalid document");
+                dropped += 1;
+                continue;
+            }
+        };
+
+        // apply every transform to the source (if any), in order
+        if let Some(source) = document.get_mut("_source") {
+            for transform in transforms {
+                transform.apply(source)?;
+            }
+        }
+
+        // write each document with the framing of the format
+        writer.write_all(format.prefix()).await?;
+        writer.write_all(&serde_json::to_vec(&document)?).await?;
+        writer.write_all(b"\n").await?;
+        documents += 1;
+    }
+
+    writer.shutdown().await?;
+    Ok((documents, dropped))
+}
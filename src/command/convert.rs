@@ -0,0 +1,118 @@
+//! Convert command module for Limber.
+//!
+//! This module exposes functions to re-encode an existing dump without
+//! touching any cluster, such as to switch between formats, compress (or
+//! decompress) a dump, or load a dump into a database. Dumps can also be
+//! converted to (and from) CSV, Parquet and elasticdump files, and output is
+//! written via the same destinations available to an export.
+use anyhow::Result;
+use clap::{Arg, ArgMatches, Command};
+use futures::TryStreamExt;
+use limber::format::Format;
+use limber::input::{self, Reader};
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufReader};
+use tracing::info;
+
+use super::{finish_tasks, open_decoded, open_encoded, parse_codec, parse_format};
+
+/// Returns the definition for this command in the CLI.
+///
+/// This function dictates options available to this command and what
+/// can be asserted to exist, as well as the other optional arguments.
+pub fn cmd<'a>() -> Command<'a> {
+    Command::new("convert")
+        .about("Convert a dump of documents into another format")
+        .args(&[
+            // compress: --compress
+            Arg::new("compress")
+                .help("Compress output using gzip or zstd, with an optional level (e.g. zstd:9)")
+                .long("compress")
+                .env("LIMBER_COMPRESS")
+                .takes_value(true),
+            // force: --force
+            Arg::new("force")
+                .help("Write documents to stdout even when it's a terminal")
+                .long("force")
+                .env("LIMBER_FORCE"),
+            // from: --from [ndjson]
+            Arg::new("from")
+                .help("The format to read documents in")
+                .long("from")
+                .env("LIMBER_FROM")
+                .takes_value(true)
+                .possible_values(["ndjson", "json-seq", "csv", "elasticdump", "parquet"])
+                .default_value("ndjson")
+                .hide_default_value(true),
+            // to: --to [ndjson]
+            Arg::new("to")
+                .help("The format to write documents in")
+                .long("to")
+                .env("LIMBER_TO")
+                .takes_value(true)
+                .possible_values(["ndjson", "json-seq", "csv", "elasticdump", "parquet"])
+                .default_value("ndjson")
+                .hide_default_value(true),
+            // input: +required
+            Arg::new("input")
+                .help("A dump to read documents from, compressed or not (or - for stdin)")
+                .required(true),
+            // output: +required
+            Arg::new("output")
+                .help("A file (or kafka:// topic, sqlite:// database, Parquet directory) to write documents to")
+                .required(true),
+        ])
+}
+
+/// Executes the `convert` command, reading the input through to the end.
+pub async fn run(args: &ArgMatches) -> Result<()> {
+    // open the input, decompressing and decoding it when needed
+    let path = args.value_of("input").expect("guaranteed by CLI");
+    let (reader, decoders) = open_decoded(path, parse_codec(args, "from")).await?;
+
+    // open the output, alongside any tasks feeding it
+    let output = args.value_of("output").expect("guaranteed by CLI");
+    let (writer, mut tasks) = open_encoded(args, &[output], parse_codec(args, "to")).await?;
+
+    // re-encode every document into the output format
+    let from = parse_format(args, "from");
+    let to = parse_format(args, "to");
+    let (documents, result) = match convert(reader, writer, from, to).await {
+        Ok(documents) => (documents, Ok(())),
+        Err(err) => (0, Err(err)),
+    };
+
+    // wait for the output to be written, and the input to be decoded
+    tasks.extend(decoders);
+    finish_tasks(tasks, result).await?;
+
+    info!(documents, "Converted documents");
+    Ok(())
+}
+
+/// Writes every document of a reader into a writer, in another format.
+///
+/// Empty records (such as blank lines) are dropped, and the number of
+/// documents written is returned once the writer has been flushed.
+async fn convert<W>(reader: Reader, mut writer: W, from: Format, to: Format) -> Result<u64>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut records = Box::pin(input::records(BufReader::new(reader), 0, from));
+    let mut documents = 0;
+
+    while let Some((record, _)) = records.try_next().await? {
+        // nothing to write for empty records
+        if record.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+
+        // write each record with the framing of the output format
+        writer.write_all(to.prefix()).await?;
+        writer.write_all(&record).await?;
+        writer.write_all(b"\n").await?;
+        documents += 1;
+    }
+
+    writer.shutdown().await?;
+    Ok(documents)
+}
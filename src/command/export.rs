@@ -4,18 +4,20 @@
 //! `stdio`. This allows the caller to pipe into any compression algorithms
 //! they may wish to use, and store in any container they might wish to use.
 //!
-//! This interface also allows chaining into another instance of Limber, to
-//! enable piping from one cluster/index to another in a streaming fashion.
+//! Arguments are converted into `ExportOptions`, with the export itself
+//! being carried out by the library.
 use anyhow::Result;
-use clap::{Arg, ArgMatches, Command};
-use elasticsearch::{Elasticsearch, ScrollParts, SearchParts};
-use futures::prelude::*;
-use serde_json::{json, Value};
-
-use std::sync::Arc;
-
-use crate::remote;
-use crate::stats::Counter;
+use clap::{Arg, ArgMatches, Command, ValueHint};
+use limber::encoding::UnicodePolicy;
+use limber::{units, ExportOptions};
+
+use super::{
+    finish_tasks, open_encoded, open_job, parse_client, parse_codec, parse_format, parse_transforms,
+};
+use crate::metrics::Publisher;
+use crate::progress::Reporter;
+use crate::signals;
+use crate::summary;
 
 /// Returns the definition for this command in the CLI.
 ///
@@ -25,33 +27,198 @@ pub fn cmd<'a>() -> Command<'a> {
     Command::new("export")
         .about("Export documents from an Elasticsearch cluster")
         .args(&[
+            // ascii-only: --ascii-only
+            Arg::new("ascii-only")
+                .help("Escape every non-ASCII character in the output as \\uXXXX")
+                .long("ascii-only")
+                .env("LIMBER_ASCII_ONLY")
+                .conflicts_with("exact"),
+            // auth: --auth
+            Arg::new("auth")
+                .help("Credentials to authenticate with, in the form user:password")
+                .long("auth")
+                .env("LIMBER_AUTH")
+                .hide_env_values(true)
+                .takes_value(true),
+            // ca-cert: --ca-cert
+            Arg::new("ca-cert")
+                .help("A CA certificate (PEM) used to verify the cluster")
+                .long("ca-cert")
+                .env("LIMBER_CA_CERT")
+                .takes_value(true),
+            // compress: --compress
+            Arg::new("compress")
+                .help("Compress output using gzip or zstd, with an optional level (e.g. zstd:9)")
+                .long("compress")
+                .env("LIMBER_COMPRESS")
+                .takes_value(true),
             // concurrency: -c [1]
             Arg::new("concurrency")
                 .help("A concurrency weighting to tune throughput")
                 .short('c')
                 .long("concurrency")
+                .env("LIMBER_CONCURRENCY")
                 .takes_value(true)
                 .default_value("1")
                 .hide_default_value(true),
+            // connect-timeout: --connect-timeout
+            Arg::new("connect-timeout")
+                .help("The maximum time to wait for the cluster to accept a connection at startup")
+                .long("connect-timeout")
+                .env("LIMBER_CONNECT_TIMEOUT")
+                .takes_value(true),
+            // debug-http: --debug-http
+            Arg::new("debug-http")
+                .help("Print each request sent to the cluster as a curl command")
+                .long("debug-http")
+                .env("LIMBER_DEBUG_HTTP"),
+            // exact: --exact
+            Arg::new("exact")
+                .help("Write the source of each document exactly as returned by the cluster")
+                .long("exact")
+                .env("LIMBER_EXACT")
+                .conflicts_with_all(&["jq", "rules", "transform"]),
+            // force: --force
+            Arg::new("force")
+                .help("Write documents to stdout even when it's a terminal")
+                .long("force")
+                .env("LIMBER_FORCE"),
+            // format: --format [ndjson]
+            Arg::new("format")
+                .help("The format to write documents in")
+                .long("format")
+                .env("LIMBER_FORMAT")
+                .takes_value(true)
+                .possible_values(["ndjson", "json-seq", "csv", "elasticdump", "parquet"])
+                .default_value("ndjson")
+                .hide_default_value(true),
+            // invalid-unicode: --invalid-unicode
+            Arg::new("invalid-unicode")
+                .help("How to handle invalid UTF-8 and lone surrogates in documents")
+                .long("invalid-unicode")
+                .env("LIMBER_INVALID_UNICODE")
+                .takes_value(true)
+                .possible_values(["keep", "reject", "replace"]),
+            // job: --job
+            Arg::new("job")
+                .help("A named job to keep run history under")
+                .long("job")
+                .env("LIMBER_JOB")
+                .takes_value(true),
+            // jq: --jq
+            Arg::new("jq")
+                .help("A jq expression to transform each document with")
+                .long("jq")
+                .env("LIMBER_JQ")
+                .takes_value(true)
+                .multiple_occurrences(true),
+            // max-bandwidth: --max-bandwidth
+            Arg::new("max-bandwidth")
+                .help("The maximum rate of transfer from the cluster per second (e.g. 50mb)")
+                .long("max-bandwidth")
+                .env("LIMBER_MAX_BANDWIDTH")
+                .takes_value(true),
+            // no-preflight: --no-preflight
+            Arg::new("no-preflight")
+                .help("Skip checking the health of the cluster before starting")
+                .long("no-preflight")
+                .env("LIMBER_NO_PREFLIGHT"),
+            // output: -o, --output
+            Arg::new("output")
+                .help("A file (or kafka:// topic, sqlite:// database) to write documents to instead of stdout (repeatable)")
+                .short('o')
+                .long("output")
+                .env("LIMBER_OUTPUT")
+                .takes_value(true)
+                .multiple_occurrences(true),
+            // parents-first: --parents-first
+            Arg::new("parents-first")
+                .help("Export the parents of any join field before their children")
+                .long("parents-first")
+                .env("LIMBER_PARENTS_FIRST"),
+            // per-index: --per-index
+            Arg::new("per-index")
+                .help("Export each index separately, scheduling indices by size")
+                .long("per-index")
+                .env("LIMBER_PER_INDEX"),
             // size: -q, --query [{}]
             Arg::new("query")
                 .help("A query to use to filter exported documents")
                 .short('q')
                 .long("query")
+                .env("LIMBER_QUERY")
                 .takes_value(true)
                 .default_value("{\"match_all\":{}}")
                 .hide_default_value(true),
+            // record: --record
+            Arg::new("record")
+                .help("A directory to record every request sent to the cluster into")
+                .long("record")
+                .env("LIMBER_RECORD")
+                .takes_value(true)
+                .conflicts_with("replay"),
+            // replay: --replay
+            Arg::new("replay")
+                .help("A directory of recorded requests to replay instead of the cluster")
+                .long("replay")
+                .env("LIMBER_REPLAY")
+                .takes_value(true),
+            // request-timeout: --request-timeout
+            Arg::new("request-timeout")
+                .help("The maximum time to wait for each request to complete")
+                .long("request-timeout")
+                .env("LIMBER_REQUEST_TIMEOUT")
+                .takes_value(true),
+            // retries: --retries [3]
+            Arg::new("retries")
+                .help("The number of times to retry a failed request")
+                .long("retries")
+                .env("LIMBER_RETRIES")
+                .takes_value(true)
+                .default_value("3")
+                .hide_default_value(true),
+            // retry-backoff: --retry-backoff [1s]
+            Arg::new("retry-backoff")
+                .help("The initial delay between retries of a failed request")
+                .long("retry-backoff")
+                .env("LIMBER_RETRY_BACKOFF")
+                .takes_value(true)
+                .default_value("1s")
+                .hide_default_value(true),
+            // rules: --rules
+            Arg::new("rules")
+                .help("A YAML file of fields to remove, redact or hash in each document")
+                .long("rules")
+                .env("LIMBER_RULES")
+                .takes_value(true)
+                .multiple_occurrences(true),
             // size: -s, --size [100]
             Arg::new("size")
                 .help("The amount of documents to pull per request")
                 .short('s')
                 .long("size")
+                .env("LIMBER_SIZE")
                 .takes_value(true)
                 .default_value("100")
                 .hide_default_value(true),
+            // sniff: --sniff
+            Arg::new("sniff")
+                .help("Discover and spread requests across the nodes of the cluster")
+                .long("sniff")
+                .env("LIMBER_SNIFF")
+                .conflicts_with_all(&["debug-http", "record", "replay"]),
+            // transform: --transform
+            Arg::new("transform")
+                .help("A WebAssembly plugin (.wasm) to transform each document with")
+                .long("transform")
+                .env("LIMBER_TRANSFORM")
+                .takes_value(true)
+                .multiple_occurrences(true),
             // source: +required
             Arg::new("source")
                 .help("Source host to export documents from")
+                .env("LIMBER_SOURCE")
+                .value_hint(ValueHint::Url)
                 .required(true),
         ])
 }
@@ -62,167 +229,96 @@ pub fn cmd<'a>() -> Command<'a> {
 /// process. The returned future will be a combination of several futures
 /// to represent the concurrency flags provided via the CLI arguments.
 pub async fn run(args: &ArgMatches) -> Result<()> {
+    // fetch every destination to write to, defaulting to stdout
+    let outputs = match args.values_of("output") {
+        Some(outputs) => outputs.collect::<Vec<_>>(),
+        None => vec!["-"],
+    };
+
     // fetch the source from the arguments, should always be possible
     let source = args.value_of("source").expect("guaranteed by CLI");
 
+    // construct the default options for the source
+    let mut options = ExportOptions::new(source);
+
+    // parse the options used to connect to the cluster
+    options.client = parse_client(args)?;
+
     // fetch the concurrency factor to use for export, default to single handle
-    let concurrency = args.value_of_t::<usize>("concurrency").unwrap_or(1);
-
-    // parse arguments into a host/index pairing for later
-    let (host, index) = remote::parse_cluster(source)?;
-
-    // shim the index value when needed by defaulting to all
-    let index = index.unwrap_or_else(|| "_all".to_string());
-
-    // construct a single client instance for all tasks
-    let client = Arc::new(remote::create_client(&host)?);
-
-    // create iterable state
-    let counter = Counter::shared(0);
-    let mut tasks = Vec::with_capacity(concurrency);
-
-    // create all worker tasks
-    for idx in 0..concurrency {
-        // take ownership of stuff
-        let index = index.to_owned();
-        let client = client.to_owned();
-        let counter = counter.to_owned();
-
-        // spawn a new worker task for idx
-        let handle = tokio::spawn(scroll(
-            client,
-            counter,
-            index,
-            construct_query(args, idx, concurrency)?,
-        ));
-
-        // push the handle
-        tasks.push(handle);
-    }
+    options.concurrency = args.value_of_t::<usize>("concurrency").unwrap_or(1);
 
-    // attempt to join all task handles
-    future::try_join_all(tasks).await?;
+    // fetch the configured batch size, or default to 100
+    options.size = args.value_of_t::<usize>("size").unwrap_or(100);
 
-    // complete!
-    Ok(())
-}
+    // fetch the retry policy for failed requests
+    options.retries = args.value_of_t::<usize>("retries").unwrap_or(3);
+    options.retry_backoff = units::parse_duration(args.value_of("retry-backoff").unwrap_or("1s"))?;
 
-/// Executes an async scroll against a given index set using a provided query.
-///
-/// This is separated out from the main loop so it can be spawned multiple times on a Tokio
-/// worker pool to allow for easy concurrency control, instead of the (previous) single thread.
-async fn scroll(client: Arc<Elasticsearch>, counter: Arc<Counter>, index: String, query: Value) {
-    // scroll params
-    let scroll = "1m";
-
-    // initialize the search request
-    let mut response = client
-        .search(SearchParts::Index(&[&index]))
-        .scroll(scroll)
-        .body(query.clone())
-        .send()
-        .await
-        .expect("unable to initialize search");
-
-    loop {
-        // parse the response body
-        let mut body = response
-            .json::<Value>()
-            .await
-            .expect("unable to parse scroll page");
-
-        // fetch the value
-        let value = body
-            .pointer_mut("/hits/hits")
-            .expect("unable to locate hits");
-
-        // turn the hits back into an array
-        let hits = value.as_array_mut().unwrap();
-
-        // empty hits means we're done
-        if hits.is_empty() {
-            break;
-        }
-
-        // store hit length
-        let length = hits.len();
-
-        // iterate docs
-        for hit in hits {
-            // grab a mutable reference to the document
-            let container = hit.as_object_mut().unwrap();
-
-            // drop some query based fields
-            container.remove("sort");
-            container.remove("_score");
-
-            // drop it to stdout
-            println!("{}", hit);
-        }
-
-        // increment the counter and print the state to stderr
-        eprintln!(
-            "Fetched another batch, have now processed {}",
-            counter.increment(length)
-        );
-
-        // fetch the new scroll_id
-        let scroll_id = body
-            .get("_scroll_id")
-            .expect("unable to locate scroll_id")
-            .as_str()
-            .expect("scroll_id is of wrong type")
-            .to_owned();
-
-        // fetch next page
-        response = client
-            .scroll(ScrollParts::None)
-            .body(json!({
-                "scroll": scroll,
-                "scroll_id": scroll_id
-            }))
-            .send()
-            .await
-            .expect("unable to continue search");
-    }
-}
+    // check the health of the cluster first, unless skipped
+    options.preflight = !args.is_present("no-preflight");
 
-/// Constructs a query instance based on the handle count and identifier.
-///
-/// This can technically fail if the query provided is invalid, which is why
-/// the return type is a `Result`. This is the safest option, as the user will
-/// expect their results to be correctly filtered.
-///
-/// An error in this case should halt all progress by the main export process.
-fn construct_query(args: &ArgMatches, id: usize, max: usize) -> Result<Value> {
-    // fetch the configured batch size, or default to 100
-    let size = args.value_of_t::<usize>("size").unwrap_or(100);
-
-    // fetch the query filter to use to limit matches (defaults to all docs)
-    let filter = args.value_of("query").unwrap();
-    let filter = serde_json::from_str::<Value>(filter)?;
-
-    // construct query
-    let mut query = json!({
-        "query": filter,
-        "size": size,
-        "sort": [
-            "_doc"
-        ]
-    });
-
-    // handle multiple handles...
-    if max > 1 {
-        // ... by adding the slice identifier
-        query.as_object_mut().unwrap().insert(
-            "slice".to_owned(),
-            json!({
-                "id": id,
-                "max": max
-            }),
-        );
+    // export each index separately, when asked to
+    options.per_index = args.is_present("per-index");
+
+    // order parents before their children, when asked to
+    options.parents_first = args.is_present("parents-first");
+
+    // parse all transforms applied to documents, if any
+    options.transforms = parse_transforms(args, &["jq", "rules", "transform"])?;
+
+    // keep the source of each document byte for byte, when asked to
+    options.exact = args.is_present("exact");
+
+    // fetch the format to write documents in
+    options.format = parse_format(args, "format");
+
+    // escape and validate the characters of each document, when asked to
+    options.ascii_only = args.is_present("ascii-only");
+    options.unicode = match args.value_of("invalid-unicode") {
+        Some("reject") => UnicodePolicy::Reject,
+        Some("replace") => UnicodePolicy::Replace,
+        _ => UnicodePolicy::Keep,
+    };
+
+    // parse the query filter to use to limit matches (defaults to all docs)
+    if let Some(query) = args.value_of("query") {
+        options.query = serde_json::from_str(query)?;
     }
 
-    // pass back!
-    Ok(query)
+    // report progress while the export runs
+    let tracker = options.tracker.clone();
+    let reporter = Reporter::start(tracker.clone(), args)?;
+    let metrics = Publisher::start("export", tracker.clone(), args).await?;
+
+    // shut down gracefully when interrupted
+    let signals = signals::listen(options.shutdown.clone());
+
+    // open every output (encoded when needed), before anything is written
+    let (writer, tasks) = open_encoded(args, &outputs, parse_codec(args, "format")).await?;
+
+    // record the run against the job, if any
+    let run = open_job(args)?
+        .map(|job| job.create_run("export"))
+        .transpose()?;
+
+    // hand off to the library to write documents to every output
+    let result = limber::export(options, writer).await;
+
+    // wait for the last of the output to reach every destination
+    let result = finish_tasks(tasks, result).await;
+
+    signals.abort();
+    reporter.stop();
+    metrics.stop().await;
+
+    // write out statistics (and notify) before reporting any errors
+    summary::write("export", &tracker, args, &result)?;
+    summary::record(run, "export", &tracker, &result)?;
+    summary::notify("export", &tracker, args, &result).await;
+
+    // summarize the export once complete, even if interrupted
+    if result.is_ok() || summary::interrupted(&result) {
+        summary::report("exported", &tracker, args);
+    }
+    result
 }
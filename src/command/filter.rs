@@ -0,0 +1,145 @@
+//! Filter command module for Limber.
+//!
+//! This module exposes functions to extract the documents of an existing dump
+//! which match a set of filters (or a query) without touching any cluster, so
+//! a subset of a backup can be pulled out without restoring it first.
+use anyhow::Result;
+use clap::{Arg, ArgMatches, Command};
+use futures::TryStreamExt;
+use limber::filter::{Filter, Query};
+use limber::format::Format;
+use limber::input::{self, Reader};
+use serde_json::Value;
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufReader};
+use tracing::{info, warn};
+
+use super::{finish_tasks, open_input, open_outputs, parse_format};
+
+/// Returns the definition for this command in the CLI.
+///
+/// This function dictates options available to this command and what
+/// can be asserted to exist, as well as the other optional arguments.
+pub fn cmd<'a>() -> Command<'a> {
+    Command::new("filter")
+        .about("Filter a dump of documents down to those matching")
+        .args(&[
+            // compress: --compress
+            Arg::new("compress")
+                .help("Compress output using gzip or zstd, with an optional level (e.g. zstd:9)")
+                .long("compress")
+                .env("LIMBER_COMPRESS")
+                .takes_value(true),
+            // force: --force
+            Arg::new("force")
+                .help("Write documents to stdout even when it's a terminal")
+                .long("force")
+                .env("LIMBER_FORCE"),
+            // format: --format [ndjson]
+            Arg::new("format")
+                .help("The format documents are read (and written) in")
+                .long("format")
+                .env("LIMBER_FORMAT")
+                .takes_value(true)
+                .possible_values(["ndjson", "json-seq"])
+                .default_value("ndjson")
+                .hide_default_value(true),
+            // out: --out
+            Arg::new("out")
+                .help("A file (or kafka:// topic, sqlite:// database) to write documents to")
+                .long("out")
+                .env("LIMBER_OUT")
+                .takes_value(true),
+            // query: --query
+            Arg::new("query")
+                .help("A query documents must match, in a subset of the query DSL")
+                .long("query")
+                .env("LIMBER_QUERY")
+                .takes_value(true),
+            // where: --where
+            Arg::new("where")
+                .help("An expression documents must match (e.g. status==\"failed\")")
+                .long("where")
+                .env("LIMBER_WHERE")
+                .takes_value(true)
+                .multiple_occurrences(true),
+            // input: +required
+            Arg::new("input")
+                .help("A dump to read documents from, compressed or not (or - for stdin)")
+                .required(true),
+        ])
+}
+
+/// Executes the `filter` command, reading the input through to the end.
+pub async fn run(args: &ArgMatches) -> Result<()> {
+    // parse every filter and the query (if any), which must all match
+    let mut conditions = args
+        .values_of("where")
+        .into_iter()
+        .flatten()
+        .map(|filter| Filter::parse(filter).map(Query::Filter))
+        .collect::<Result<Vec<_>>>()?;
+    if let Some(query) = args.value_of("query") {
+        conditions.push(Query::parse(&serde_json::from_str(query)?)?);
+    }
+    let query = Query::All(conditions);
+
+    // open the input, decompressing it when compressed
+    let path = args.value_of("input").expect("guaranteed by CLI");
+    let (reader, decompressor) = open_input(path).await?;
+
+    // open the output (defaulting to stdout), alongside any tasks feeding it
+    let output = args.value_of("out").unwrap_or("-");
+    let (writer, mut tasks) = open_outputs(args, &[output]).await?;
+
+    // write out only the documents which match
+    let format = parse_format(args, "format");
+    let (documents, result) = match filter(reader, writer, &query, format).await {
+        Ok(documents) => (documents, Ok(())),
+        Err(err) => (0, Err(err)),
+    };
+
+    // wait for the output to be written, and the input to be decompressed
+    tasks.extend(decompressor);
+    finish_tasks(tasks, result).await?;
+
+    info!(documents, "Filtered documents");
+    Ok(())
+}
+
+/// Writes every document of a reader which matches a query into a writer.
+///
+/// Records which can't be parsed as documents are skipped with a warning,
+/// and the number of documents written is returned.
+async fn filter<W>(reader: Reader, mut writer: W, query: &Query, format: Format) -> Result<u64>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut records = Box::pin(input::records(BufReader::new(reader), 0, format));
+    let mut documents = 0;
+
+    while let Some((record, offset)) = records.try_next().await? {
+        // nothing to check for empty records
+        if record.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+
+        // skip anything which doesn't match
+        match serde_json::from_slice::<Value>(&record) {
+            Ok(document) if query.matches(&document) => (),
+            Ok(_) => continue,
+            Err(err) => {
+                warn!(offset, error = %err, "Skipping invalid document");
+                continue;
+            }
+        }
+
+        // write each record with the framing of the format
+        writer.write_all(format.prefix()).await?;
+        writer.write_all(&record).await?;
+        writer.write_all(b"\n").await?;
+        documents += 1;
+    }
+
+    writer.shutdown().await?;
+    Ok(documents)
+}
@@ -4,21 +4,38 @@
 //! Elasticsearch cluster/index. Input is received from `stdin` as it enables
 //! easy chaining against other tools (such as those for compression).
 //!
-//! This interface also allows chaining from another instance of Limber, to
-//! enable piping from one cluster/index to another in a streaming fashion.
-use anyhow::Result;
-use bytelines::*;
-use clap::{Arg, ArgMatches, Command};
-use elasticsearch::indices::IndicesRefreshParts;
-use elasticsearch::{BulkOperation, BulkParts};
-use futures::stream::StreamExt;
-use serde_json::Value;
-use tokio::io::{self, BufReader};
+//! Arguments are converted into `ImportOptions`, with the import itself
+//! being carried out by the library.
+use anyhow::{anyhow, Result};
+use clap::{Arg, ArgMatches, Command, ValueHint, ValueSource};
+use limber::bulk::Action;
+use limber::checkpoint::Checkpoint;
+use limber::dedupe::Dedupe;
+use limber::error::{Error, ErrorKind};
+use limber::filter::Filter;
+use limber::identity::IdTemplate;
+use limber::import::{self, Budget, ErrorPolicy, ImportOptions, RefreshPolicy};
+use limber::input;
+#[cfg(feature = "kafka")]
+use limber::kafka::Source;
+use limber::naming::{Renamer, Template};
+use limber::script::Script;
+use limber::units;
+use serde_json::{json, Map, Value};
 
+use std::num::NonZeroUsize;
+#[cfg(feature = "kafka")]
 use std::sync::Arc;
+#[cfg(feature = "kafka")]
+use std::time::Duration;
 
-use crate::remote;
-use crate::stats::Counter;
+use super::{
+    finish_tasks, open_decoded, open_job, parse_client, parse_codec, parse_format, parse_transforms,
+};
+use crate::metrics::Publisher;
+use crate::progress::Reporter;
+use crate::signals;
+use crate::summary;
 
 /// Returns the definition for this command in the CLI.
 ///
@@ -28,25 +45,444 @@ pub fn cmd<'a>() -> Command<'a> {
     Command::new("import")
         .about("Import documents to an Elasticsearch cluster")
         .args(&[
+            // adaptive: --adaptive
+            Arg::new("adaptive")
+                .help("Adapt concurrency to cluster load, up to the concurrency provided")
+                .long("adaptive")
+                .env("LIMBER_ADAPTIVE"),
+            // auth: --auth
+            Arg::new("auth")
+                .help("Credentials to authenticate with, in the form user:password")
+                .long("auth")
+                .env("LIMBER_AUTH")
+                .hide_env_values(true)
+                .takes_value(true),
+            // batch-bytes: --batch-bytes
+            Arg::new("batch-bytes")
+                .help("The maximum payload size of each request (e.g. 8mb)")
+                .long("batch-bytes")
+                .env("LIMBER_BATCH_BYTES")
+                .takes_value(true),
+            // batch-timeout: --batch-timeout
+            Arg::new("batch-timeout")
+                .help("The longest time to wait to fill a request before sending it (e.g. 1s)")
+                .long("batch-timeout")
+                .env("LIMBER_BATCH_TIMEOUT")
+                .takes_value(true),
+            // ca-cert: --ca-cert
+            Arg::new("ca-cert")
+                .help("A CA certificate (PEM) used to verify the cluster")
+                .long("ca-cert")
+                .env("LIMBER_CA_CERT")
+                .takes_value(true),
+            // checkpoint: --checkpoint
+            Arg::new("checkpoint")
+                .help("A file to track progress in (defaults alongside input)")
+                .long("checkpoint")
+                .env("LIMBER_CHECKPOINT")
+                .takes_value(true)
+                .requires("input"),
+            // coerce: --coerce
+            Arg::new("coerce")
+                .help("A field:type pair to coerce a field to (e.g. status:long->string)")
+                .long("coerce")
+                .env("LIMBER_COERCE")
+                .takes_value(true)
+                .multiple_occurrences(true),
+            // coerce-to-mapping: --coerce-to-mapping
+            Arg::new("coerce-to-mapping")
+                .help("Coerce every field to its type within the mapping of the target index")
+                .long("coerce-to-mapping")
+                .env("LIMBER_COERCE_TO_MAPPING"),
             // concurrency: c [1]
             Arg::new("concurrency")
                 .help("A concurrency weighting to tune throughput")
                 .short('c')
                 .long("concurrency")
+                .env("LIMBER_CONCURRENCY")
                 .takes_value(true)
                 .default_value("1")
                 .hide_default_value(true),
+            // connect-timeout: --connect-timeout
+            Arg::new("connect-timeout")
+                .help("The maximum time to wait for the cluster to accept a connection at startup")
+                .long("connect-timeout")
+                .env("LIMBER_CONNECT_TIMEOUT")
+                .takes_value(true),
+            // create-with: --create-with
+            Arg::new("create-with")
+                .help("Settings (or @file) used to create missing indices")
+                .long("create-with")
+                .env("LIMBER_CREATE_WITH")
+                .takes_value(true),
+            // dead-letter: --dead-letter
+            Arg::new("dead-letter")
+                .help("A file to write documents which failed to import")
+                .long("dead-letter")
+                .env("LIMBER_DEAD_LETTER")
+                .takes_value(true),
+            // debug-http: --debug-http
+            Arg::new("debug-http")
+                .help("Print each request sent to the cluster as a curl command")
+                .long("debug-http")
+                .env("LIMBER_DEBUG_HTTP"),
+            // dedupe: --dedupe
+            Arg::new("dedupe")
+                .help("Drop repeated documents, keeping the first or last")
+                .long("dedupe")
+                .env("LIMBER_DEDUPE")
+                .takes_value(true)
+                .possible_values(["first", "last"])
+                .requires_if("last", "input"),
+            // dedupe-memory: --dedupe-memory [1gb]
+            Arg::new("dedupe-memory")
+                .help("The maximum memory used to track documents when dropping repeats")
+                .long("dedupe-memory")
+                .env("LIMBER_DEDUPE_MEMORY")
+                .takes_value(true)
+                .default_value("1gb")
+                .hide_default_value(true),
+            // date-index-field: --date-index-field
+            Arg::new("date-index-field")
+                .help("A date field used to partition documents into indices")
+                .long("date-index-field")
+                .env("LIMBER_DATE_INDEX_FIELD")
+                .takes_value(true)
+                .requires("date-index-format"),
+            // date-index-format: --date-index-format
+            Arg::new("date-index-format")
+                .help("A date format used to name partitioned indices")
+                .long("date-index-format")
+                .env("LIMBER_DATE_INDEX_FORMAT")
+                .takes_value(true)
+                .requires("date-index-field"),
+            // dry-run: --dry-run
+            Arg::new("dry-run")
+                .help("Report what would be imported without sending anything")
+                .long("dry-run")
+                .env("LIMBER_DRY_RUN"),
+            // error-policy: --error-policy
+            Arg::new("error-policy")
+                .help("How to handle documents which failed to import")
+                .long("error-policy")
+                .env("LIMBER_ERROR_POLICY")
+                .takes_value(true)
+                .possible_values(["abort", "skip", "dead-letter"]),
+            // exact: --exact
+            Arg::new("exact")
+                .help("Send the source of each document exactly as it appears in the input")
+                .long("exact")
+                .env("LIMBER_EXACT")
+                .conflicts_with_all(&[
+                    "set",
+                    "remove",
+                    "rename",
+                    "shift-time",
+                    "coerce",
+                    "coerce-to-mapping",
+                    "jq",
+                    "transform",
+                    "script",
+                ]),
+            // filter: --filter
+            Arg::new("filter")
+                .help("An expression documents must match to be imported")
+                .long("filter")
+                .env("LIMBER_FILTER")
+                .takes_value(true)
+                .multiple_occurrences(true),
+            // force-merge: --force-merge[=<segments>]
+            Arg::new("force-merge")
+                .help("Force merge target indices once the import completes, down to a number of segments (e.g. --force-merge=1)")
+                .long("force-merge")
+                .env("LIMBER_FORCE_MERGE")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .value_name("segments"),
+            // format: --format [ndjson]
+            Arg::new("format")
+                .help("The format to read documents in")
+                .long("format")
+                .env("LIMBER_FORMAT")
+                .takes_value(true)
+                .possible_values(["ndjson", "json-seq", "csv", "elasticdump", "parquet"])
+                .default_value("ndjson")
+                .hide_default_value(true),
+            // http-compression: --http-compression
+            Arg::new("http-compression")
+                .help("Compress bulk requests sent to the cluster using gzip")
+                .long("http-compression")
+                .env("LIMBER_HTTP_COMPRESSION"),
+            // id-from: --id-from
+            Arg::new("id-from")
+                .help("A template to generate the _id of each document from (e.g. sha1({a}:{b}))")
+                .long("id-from")
+                .env("LIMBER_ID_FROM")
+                .takes_value(true)
+                .conflicts_with("regenerate-ids"),
+            // id-prefix: --id-prefix
+            Arg::new("id-prefix")
+                .help("A prefix to attach to the _id of each document")
+                .long("id-prefix")
+                .env("LIMBER_ID_PREFIX")
+                .takes_value(true)
+                .conflicts_with("regenerate-ids"),
+            // id-suffix: --id-suffix
+            Arg::new("id-suffix")
+                .help("A suffix to attach to the _id of each document")
+                .long("id-suffix")
+                .env("LIMBER_ID_SUFFIX")
+                .takes_value(true)
+                .conflicts_with("regenerate-ids"),
+            // index-prefix: --index-prefix
+            Arg::new("index-prefix")
+                .help("A prefix to attach to the index of each document")
+                .long("index-prefix")
+                .env("LIMBER_INDEX_PREFIX")
+                .takes_value(true),
+            // index-suffix: --index-suffix
+            Arg::new("index-suffix")
+                .help("A suffix to attach to the index of each document")
+                .long("index-suffix")
+                .env("LIMBER_INDEX_SUFFIX")
+                .takes_value(true),
+            // index-template: --index-template
+            Arg::new("index-template")
+                .help("A template used to render the index of each document")
+                .long("index-template")
+                .env("LIMBER_INDEX_TEMPLATE")
+                .takes_value(true)
+                .conflicts_with("date-index-field"),
+            // input: -i, --input
+            Arg::new("input")
+                .help("A file (or pattern, kafka:// topic) to read documents from instead of stdin (repeatable)")
+                .short('i')
+                .long("input")
+                .env("LIMBER_INPUT")
+                .takes_value(true)
+                .multiple_occurrences(true),
+            // job: --job
+            Arg::new("job")
+                .help("A named job to keep checkpoints and run history under")
+                .long("job")
+                .env("LIMBER_JOB")
+                .takes_value(true),
+            // jq: --jq
+            Arg::new("jq")
+                .help("A jq expression to transform each document with")
+                .long("jq")
+                .env("LIMBER_JQ")
+                .takes_value(true)
+                .multiple_occurrences(true),
+            // max-bandwidth: --max-bandwidth
+            Arg::new("max-bandwidth")
+                .help("The maximum rate of transfer to the cluster per second (e.g. 50mb)")
+                .long("max-bandwidth")
+                .env("LIMBER_MAX_BANDWIDTH")
+                .takes_value(true),
+            // max-errors: --max-errors
+            Arg::new("max-errors")
+                .help("A number (or percentage) of failures to halt import at")
+                .long("max-errors")
+                .env("LIMBER_MAX_ERRORS")
+                .takes_value(true),
+            // max-memory: --max-memory
+            Arg::new("max-memory")
+                .help("The maximum size of batches to buffer in memory (e.g. 512mb)")
+                .long("max-memory")
+                .env("LIMBER_MAX_MEMORY")
+                .takes_value(true),
+            // mode: -m, --mode [index]
+            Arg::new("mode")
+                .help("The bulk operation to apply to each document")
+                .short('m')
+                .long("mode")
+                .env("LIMBER_MODE")
+                .takes_value(true)
+                .possible_values(["index", "create", "delete"])
+                .default_value("index")
+                .hide_default_value(true),
+            // no-preflight: --no-preflight
+            Arg::new("no-preflight")
+                .help("Skip checking the health of the cluster before starting")
+                .long("no-preflight")
+                .env("LIMBER_NO_PREFLIGHT"),
+            // pipeline: -p, --pipeline
+            Arg::new("pipeline")
+                .help("An ingest pipeline to process documents with")
+                .short('p')
+                .long("pipeline")
+                .env("LIMBER_PIPELINE")
+                .takes_value(true),
+            // preserve-version: --preserve-version
+            Arg::new("preserve-version")
+                .help("Index documents using their exported version")
+                .long("preserve-version")
+                .env("LIMBER_PRESERVE_VERSION"),
+            // record: --record
+            Arg::new("record")
+                .help("A directory to record every request sent to the cluster into")
+                .long("record")
+                .env("LIMBER_RECORD")
+                .takes_value(true)
+                .conflicts_with("replay"),
+            // replay: --replay
+            Arg::new("replay")
+                .help("A directory of recorded requests to replay instead of the cluster")
+                .long("replay")
+                .env("LIMBER_REPLAY")
+                .takes_value(true),
+            // refresh: -r, --refresh [all]
+            Arg::new("refresh")
+                .help("The indices to refresh once the import completes")
+                .short('r')
+                .long("refresh")
+                .env("LIMBER_REFRESH")
+                .takes_value(true)
+                .possible_values(["all", "none", "target", "wait_for"])
+                .default_value("all")
+                .hide_default_value(true),
+            // regenerate-ids: --regenerate-ids
+            Arg::new("regenerate-ids")
+                .help("Drop the _id of each document, letting the cluster generate new ones")
+                .long("regenerate-ids")
+                .env("LIMBER_REGENERATE_IDS")
+                .conflicts_with_all(&["dedupe", "preserve-version"]),
+            // remove: --remove
+            Arg::new("remove")
+                .help("A field to remove from the source of each document")
+                .long("remove")
+                .env("LIMBER_REMOVE")
+                .takes_value(true)
+                .multiple_occurrences(true),
+            // rename: --rename
+            Arg::new("rename")
+                .help("An old=new rule to rename a field in each document")
+                .long("rename")
+                .env("LIMBER_RENAME")
+                .takes_value(true)
+                .multiple_occurrences(true),
+            // rename-index: --rename-index
+            Arg::new("rename-index")
+                .help("A pattern=replacement rule to rename document indices (--rename renames fields)")
+                .long("rename-index")
+                .env("LIMBER_RENAME_INDEX")
+                .takes_value(true)
+                .multiple_occurrences(true),
+            // request-timeout: --request-timeout
+            Arg::new("request-timeout")
+                .help("The maximum time to wait for each request to complete")
+                .long("request-timeout")
+                .env("LIMBER_REQUEST_TIMEOUT")
+                .takes_value(true),
+            // resume: --resume
+            Arg::new("resume")
+                .help("Resume an import from the last checkpoint of the input")
+                .long("resume")
+                .env("LIMBER_RESUME")
+                .requires("input"),
+            // retries: --retries [3]
+            Arg::new("retries")
+                .help("The number of times to retry a failed request")
+                .long("retries")
+                .env("LIMBER_RETRIES")
+                .takes_value(true)
+                .default_value("3")
+                .hide_default_value(true),
+            // retry-backoff: --retry-backoff [1s]
+            Arg::new("retry-backoff")
+                .help("The initial delay between retries of a failed request")
+                .long("retry-backoff")
+                .env("LIMBER_RETRY_BACKOFF")
+                .takes_value(true)
+                .default_value("1s")
+                .hide_default_value(true),
+            // rollover-docs: --rollover-docs
+            Arg::new("rollover-docs")
+                .help("Roll over a target alias after this many documents (greater than zero)")
+                .long("rollover-docs")
+                .env("LIMBER_ROLLOVER_DOCS")
+                .takes_value(true),
+            // script: --script
+            Arg::new("script")
+                .help("A Rhai script (.rhai) to run against each document")
+                .long("script")
+                .env("LIMBER_SCRIPT")
+                .takes_value(true),
+            // set: --set
+            Arg::new("set")
+                .help("A field=value pair to set in the source of each document")
+                .long("set")
+                .env("LIMBER_SET")
+                .takes_value(true)
+                .multiple_occurrences(true),
+            // shift-time: --shift-time
+            Arg::new("shift-time")
+                .help("A field=offset pair to shift a date field by (e.g. +30d)")
+                .long("shift-time")
+                .env("LIMBER_SHIFT_TIME")
+                .takes_value(true)
+                .multiple_occurrences(true),
             // size: s, size [100]
             Arg::new("size")
                 .help("The amount of documents to index per request")
                 .short('s')
                 .long("size")
+                .env("LIMBER_SIZE")
                 .takes_value(true)
                 .default_value("100")
                 .hide_default_value(true),
+            // sniff: --sniff
+            Arg::new("sniff")
+                .help("Discover and spread requests across the nodes of the cluster")
+                .long("sniff")
+                .env("LIMBER_SNIFF")
+                .conflicts_with_all(&["debug-http", "record", "replay"]),
+            // strict: --strict
+            Arg::new("strict")
+                .help("Fail if document counts don't match once imported")
+                .long("strict")
+                .env("LIMBER_STRICT"),
+            // target-hosts: --target-hosts
+            Arg::new("target-hosts")
+                .help("Additional hosts of the target cluster to spread load across")
+                .long("target-hosts")
+                .env("LIMBER_TARGET_HOSTS")
+                .takes_value(true)
+                .use_value_delimiter(true)
+                .multiple_occurrences(true)
+                .conflicts_with_all(&["debug-http", "record", "replay"]),
+            // transform: --transform
+            Arg::new("transform")
+                .help("A WebAssembly plugin (.wasm) to transform each document with")
+                .long("transform")
+                .env("LIMBER_TRANSFORM")
+                .takes_value(true)
+                .multiple_occurrences(true),
+            // turbo: --turbo
+            Arg::new("turbo")
+                .help("Disable replicas and refreshes on indices during import")
+                .long("turbo")
+                .env("LIMBER_TURBO"),
+            // type: -t, --type
+            Arg::new("type")
+                .help("A document type to use for pre-7.x clusters")
+                .short('t')
+                .long("type")
+                .env("LIMBER_TYPE")
+                .takes_value(true),
+            // wait-for-active-shards: --wait-for-active-shards
+            Arg::new("wait-for-active-shards")
+                .help("The number of active shard copies required to write")
+                .long("wait-for-active-shards")
+                .env("LIMBER_WAIT_FOR_ACTIVE_SHARDS")
+                .takes_value(true),
             // target: +required
             Arg::new("target")
                 .help("Target host to import documents to")
+                .env("LIMBER_TARGET")
+                .value_hint(ValueHint::Url)
                 .required(true),
         ])
 }
@@ -55,103 +491,436 @@ pub fn cmd<'a>() -> Command<'a> {
 ///
 /// This future should be spawned on a Runtime to carry out the importing process.
 pub async fn run(args: &ArgMatches) -> Result<()> {
-    // fetch the configured batch size, or default to 100
-    let size = args.value_of_t::<usize>("size").unwrap_or(100);
-
     // fetch the target from the arguments, should always be possible
     let target = args.value_of("target").expect("guaranteed by CLI");
 
-    // fetch the concurrency factor to use for export, default to single worker
-    let concurrency = args.value_of_t::<usize>("concurrency").unwrap_or(1);
+    // construct the default options for the target
+    let mut options = ImportOptions::new(target);
+
+    // fetch the configured batch size, or default to 100
+    options.size = args.value_of_t::<usize>("size").unwrap_or(100);
 
-    // parse arguments into a host/index pairing for later
-    let (host, index) = remote::parse_cluster(target)?;
-    let client = Arc::new(remote::create_client(&host)?);
+    // fetch the configured batch payload limit, if any
+    options.batch_bytes = args
+        .value_of("batch-bytes")
+        .map(units::parse_bytes)
+        .transpose()?;
 
-    // create a counter to track docs
-    let counter = Counter::shared(0);
+    // payload limits replace the default document limit
+    if options.batch_bytes.is_some() && args.value_source("size") == Some(ValueSource::DefaultValue)
+    {
+        options.size = usize::MAX;
+    }
 
-    // fetch stdin as lines
-    let stdin = BufReader::new(io::stdin());
-    let lines = AsyncByteLines::new(stdin);
+    // fetch the longest time to wait to fill a batch, if any
+    options.batch_timeout = args
+        .value_of("batch-timeout")
+        .map(units::parse_duration)
+        .transpose()?;
 
-    // start streaming the lines and map into bulk operations
-    let filter = lines.into_stream().filter_map(|input| async {
-        // parsed the bytes into a `Value` so we can fetch JSON data back from it
-        let mut parsed = serde_json::from_slice::<Value>(&input.ok()?).ok()?;
+    // fetch the concurrency factor to use for import, default to single worker
+    options.concurrency = args.value_of_t::<usize>("concurrency").unwrap_or(1);
+    options.adaptive = args.is_present("adaptive");
 
-        // shim the index to the doc index
-        let index = match index {
-            Some(ref index) => index.to_owned(),
-            None => parsed.get("_index")?.as_str()?.to_owned(),
-        };
+    // attach any additional hosts of the target cluster
+    options.target_hosts = args
+        .values_of("target-hosts")
+        .into_iter()
+        .flatten()
+        .map(str::to_owned)
+        .collect();
 
-        Some(
-            // create our bulk request using the source
-            BulkOperation::index(parsed["_source"].take())
-                .id(parsed.get("_id")?.as_str()?.to_owned())
-                .index(index)
-                .into(),
-        )
+    // parse the options used to connect to the cluster
+    options.client = parse_client(args)?;
+    options.client.compression = args.is_present("http-compression");
+
+    // fetch the bulk action to use for each document
+    options.action = match args.value_of("mode") {
+        Some("create") => Action::Create,
+        Some("delete") => Action::Delete,
+        _ => Action::Index,
+    };
+
+    // fetch whether to resume from a previous checkpoint
+    options.resume = args.is_present("resume");
+
+    // fetch the document threshold to roll over the target alias, if any
+    options.rollover_docs = args
+        .value_of("rollover-docs")
+        .map(|docs| {
+            docs.parse::<NonZeroUsize>()
+                .map_err(|_| anyhow!("Invalid rollover document count provided: {}", docs))
+        })
+        .transpose()?;
+
+    // parse the body used to create any missing indices, if any
+    options.create_with = args
+        .value_of("create-with")
+        .map(construct_settings)
+        .transpose()?;
+
+    // fetch whether to speed up indexing by disabling replicas and refreshes
+    options.turbo = args.is_present("turbo");
+
+    // fetch the refresh behaviour to use, defaulting to refreshing all indices
+    options.refresh = match args.value_of("refresh") {
+        Some("none") => RefreshPolicy::None,
+        Some("target") => RefreshPolicy::Target,
+        Some("wait_for") => RefreshPolicy::WaitFor,
+        _ => RefreshPolicy::All,
+    };
+
+    // fetch the parameters used for every bulk request
+    options.pipeline = args.value_of("pipeline").map(str::to_owned);
+    options.wait_for_active_shards = args.value_of("wait-for-active-shards").map(str::to_owned);
+    options.retries = args.value_of_t::<usize>("retries").unwrap_or(3);
+    options.retry_backoff = units::parse_duration(args.value_of("retry-backoff").unwrap_or("1s"))?;
+
+    // fetch the policy for failures, dead lettering whenever we have a file
+    options.error_policy = match args.value_of("error-policy") {
+        Some("abort") => ErrorPolicy::Abort,
+        Some("skip") => ErrorPolicy::Skip,
+        Some(_) => ErrorPolicy::DeadLetter,
+        None if args.is_present("dead-letter") => ErrorPolicy::DeadLetter,
+        None => ErrorPolicy::Skip,
+    };
+
+    // open the job to keep state under, if any
+    let job = open_job(args)?;
+
+    // fetch the file used to store failed documents, defaulting within the job
+    options.dead_letter = args.value_of("dead-letter").map(str::to_owned).or_else(|| {
+        job.as_ref()
+            .filter(|_| options.error_policy == ErrorPolicy::DeadLetter)
+            .map(|job| job.dead_letter().to_string_lossy().into_owned())
     });
 
-    // chunk the stream into batches
-    let chunk = filter.chunks(size);
-
-    // handle each batch concurrently and send each buffer to Elasticsearch directly
-    let worker = chunk.for_each_concurrent(concurrency, |batch: Vec<BulkOperation<_>>| {
-        async {
-            // grab counter for later
-            let total = batch.len();
-
-            // index the batch
-            let response = client
-                .bulk(BulkParts::None)
-                .body(batch)
-                .send()
-                .await
-                .expect("unable to import batch")
-                .error_for_status_code()
-                .expect("unable to import batch");
-
-            // increment the counter and print the state to stderr
-            eprintln!(
-                "Indexed another batch, have now processed {}",
-                counter.increment(total)
-            );
-
-            // turn the body back into an array of items to work with
-            let body = response.json::<Value>().await.unwrap();
-
-            // skip out if none of the requests returned an error
-            if !body.get("errors").unwrap().as_bool().unwrap_or(false) {
-                return;
-            }
+    // parse the threshold of failed documents, if any
+    options.max_errors = args.value_of("max-errors").map(Budget::parse).transpose()?;
+
+    // parse the limit of memory used to buffer batches, if any
+    options.max_memory = args
+        .value_of("max-memory")
+        .map(units::parse_bytes)
+        .transpose()?;
+
+    // Kafka topics are consumed rather than read, so must be the only input
+    let inputs = args
+        .values_of("input")
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    let topic = inputs
+        .iter()
+        .copied()
+        .find(|input| input.starts_with("kafka://"));
+    if topic.is_some() && inputs.len() > 1 {
+        return Err(anyhow!("Kafka topics must be the only input of an import"));
+    }
+    if topic.is_some() && (args.is_present("checkpoint") || options.resume) {
+        return Err(anyhow!(
+            "Kafka topics track progress via consumer offsets, not checkpoints"
+        ));
+    }
+    if topic.is_some() && args.value_of("dedupe") == Some("last") {
+        return Err(anyhow!(
+            "Keeping the last duplicate is not supported with a Kafka topic"
+        ));
+    }
+
+    // connect to the topic, when the feature is available
+    #[cfg(feature = "kafka")]
+    let source = topic.map(Source::connect).transpose()?;
+    #[cfg(not(feature = "kafka"))]
+    if topic.is_some() {
+        return Err(anyhow!(
+            "Kafka inputs require limber to be built with the kafka feature"
+        ));
+    }
+
+    // fetch the input files to read from, expanding any patterns
+    let paths = input::expand(inputs.into_iter().filter(|_| topic.is_none()))?;
+    let path = paths.first().map(String::as_str);
+
+    // several inputs are read concurrently, so can't share a checkpoint
+    let several = paths.len() > 1;
+    if several && (args.is_present("checkpoint") || options.resume) {
+        return Err(anyhow!(
+            "Checkpoints are only supported when importing from a single input"
+        ));
+    }
+    if several && args.value_of("dedupe") == Some("last") {
+        return Err(anyhow!(
+            "Keeping the last duplicate is only supported with a single input"
+        ));
+    }
+
+    // decoded inputs have no offsets within the file, so can't be resumed
+    let codec = parse_codec(args, "format");
+    if codec.is_some() && (args.is_present("checkpoint") || options.resume) {
+        return Err(anyhow!(
+            "Checkpoints are only supported when importing NDJSON or JSON sequences"
+        ));
+    }
+    if codec.is_some() && topic.is_some() {
+        return Err(anyhow!(
+            "Kafka topics can only be imported as NDJSON or JSON sequences"
+        ));
+    }
+
+    // fetch the file used to track progress, defaulting within the job or alongside the input
+    options.checkpoint = args
+        .value_of("checkpoint")
+        .map(str::to_owned)
+        .or_else(|| {
+            job.as_ref()
+                .map(|job| job.checkpoint().to_string_lossy().into_owned())
+        })
+        .or_else(|| path.map(|path| format!("{}.checkpoint", path)))
+        .filter(|_| !several && topic.is_none() && codec.is_none());
+
+    // fetch the offset to start from, when resuming
+    options.offset = match options.checkpoint {
+        Some(ref checkpoint) if options.resume => Checkpoint::read(checkpoint)?,
+        _ => 0,
+    };
+
+    // fetch whether to only report what would be imported
+    options.dry_run = args.is_present("dry-run");
+
+    // track progress through the inputs, when they're all regular files
+    let mut length = Some(0);
+    for path in &paths {
+        let metadata = tokio::fs::metadata(path).await?;
+        length = length
+            .filter(|_| metadata.is_file())
+            .map(|length| length + metadata.len());
+    }
+    options.length = length.filter(|_| !paths.is_empty() && codec.is_none());
+
+    // construct the rules used to rename document indices
+    options.renamer = Renamer::new(
+        args.values_of("rename-index").into_iter().flatten(),
+        args.value_of("index-prefix"),
+        args.value_of("index-suffix"),
+    )?;
 
-            // iterate through all items which came back in the response
-            for item in body.get("items").unwrap().as_array().unwrap() {
-                // fetch the failed shard counter to check errors
-                let failed = item.pointer("/index/_shards/failed");
+    // parse the template used to render document indices, if any
+    options.template = match args.value_of("date-index-field") {
+        Some(field) => Some(Template::date(
+            field,
+            args.value_of("date-index-format")
+                .expect("guaranteed by CLI"),
+        )?),
+        None => args
+            .value_of("index-template")
+            .map(Template::parse)
+            .transpose()?,
+    };
 
-                // log errors if any happened (based on shards)
-                if failed.unwrap().as_u64().unwrap_or(1) > 0 {
-                    eprintln!("err: {:?}", item);
-                }
+    // parse all filters documents must match, if any
+    options.filters = args
+        .values_of("filter")
+        .into_iter()
+        .flatten()
+        .map(Filter::parse)
+        .collect::<Result<Vec<_>>>()?;
+
+    // parse all transforms applied to documents, if any
+    options.transforms = parse_transforms(
+        args,
+        &[
+            "set",
+            "remove",
+            "rename",
+            "shift-time",
+            "coerce",
+            "jq",
+            "transform",
+        ],
+    )?;
+
+    // coerce fields to the target mapping, when asked to
+    options.coerce_mapping = args.is_present("coerce-to-mapping");
+
+    // load any script run against each document
+    options.script = args.value_of("script").map(Script::load).transpose()?;
+
+    // keep the source of each document byte for byte, when asked to
+    options.exact = args.is_present("exact");
+
+    // fetch the format to read documents in
+    options.format = parse_format(args, "format");
+
+    // fetch the document metadata overrides
+    options.preserve_version = args.is_present("preserve-version");
+    options.regenerate_ids = args.is_present("regenerate-ids");
+
+    // messages without a key have no id, so the cluster generates one instead
+    options.generate_missing_ids = topic.is_some();
+    options.id_from = args
+        .value_of("id-from")
+        .map(IdTemplate::parse)
+        .transpose()?;
+    options.id_prefix = args.value_of("id-prefix").map(str::to_owned);
+    options.id_suffix = args.value_of("id-suffix").map(str::to_owned);
+    options.ty = args.value_of("type").map(str::to_owned);
+
+    // fetch whether to force merge, and the maximum number of segments
+    options.force_merge = args.is_present("force-merge");
+    options.max_segments = args
+        .value_of("force-merge")
+        .map(|segments| match segments.parse::<i64>() {
+            Ok(segments) if segments > 0 => Ok(segments),
+            _ => Err(anyhow!("Invalid segment count provided: {}", segments)),
+        })
+        .transpose()?;
+
+    // fetch whether to fail on mismatched document counts
+    options.strict = args.is_present("strict");
+
+    // check the health of the cluster first, unless skipped
+    options.preflight = !args.is_present("no-preflight");
+
+    // construct the tracker used to drop duplicate documents, if any
+    let limit = units::parse_bytes(args.value_of("dedupe-memory").expect("guaranteed by CLI"))?;
+    options.dedupe = match args.value_of("dedupe") {
+        Some("first") => Some(Dedupe::first().with_limit(limit)),
+        Some(_) if codec.is_some() => {
+            let (reader, tasks) = open_decoded(path.unwrap_or("-"), codec).await?;
+            let (dedupe, result) = match import::scan_last(&options, reader, Some(limit)).await {
+                Ok(dedupe) => (Some(dedupe), Ok(())),
+                Err(err) => (None, Err(err)),
+            };
+            finish_tasks(tasks, result).await?;
+            dedupe
+        }
+        Some(_) => {
+            let reader = input::open(path, options.offset).await?;
+            Some(import::scan_last(&options, reader, Some(limit)).await?)
+        }
+        None => None,
+    };
+
+    // open every input, each from the starting offset
+    let mut readers = Vec::with_capacity(paths.len().max(1));
+
+    // consume from the topic, committing offsets as batches are acknowledged
+    #[cfg(feature = "kafka")]
+    if let Some(ref source) = source {
+        readers.push(source.reader());
+        options.commit = Some(Arc::new(source.clone()));
+        options.batch_timeout.get_or_insert(Duration::from_secs(1));
+    }
+    let mut decoders = Vec::new();
+    for path in &paths {
+        match codec {
+            Some(codec) => {
+                let (reader, tasks) = open_decoded(path, Some(codec)).await?;
+                readers.push(reader);
+                decoders.extend(tasks);
             }
+            None => readers.push(input::open(Some(path), options.offset).await?),
         }
-    });
+    }
+    if readers.is_empty() {
+        match codec {
+            Some(codec) => {
+                let (reader, tasks) = open_decoded("-", Some(codec)).await?;
+                readers.push(reader);
+                decoders.extend(tasks);
+            }
+            None => readers.push(input::open(None, options.offset).await?),
+        }
+    }
+
+    // report progress while the import runs
+    let tracker = options.tracker.clone();
+    let reporter = Reporter::start(tracker.clone(), args)?;
+    let metrics = Publisher::start("import", tracker.clone(), args).await?;
+
+    // shut down gracefully when interrupted
+    let signals = signals::listen(options.shutdown.clone());
+
+    // dry runs report on what would have happened instead
+    let dry_run = options.dry_run;
+
+    // record the run against the job, if any
+    let run = job.map(|job| job.create_run("import")).transpose()?;
+
+    // hand off to the library to carry out the import
+    let result = limber::import_all(options, readers).await;
+
+    // wait for every input to be decoded, when decoded at all
+    let result = finish_tasks(decoders, result).await;
 
-    // await all!
-    worker.await;
+    // commit the final offsets of the topic, even if interrupted
+    #[cfg(feature = "kafka")]
+    let result = match source.as_ref().map_or(Ok(()), Source::finish) {
+        Ok(()) => result,
+        Err(err) => result.and(Err(err)),
+    };
+    signals.abort();
+    reporter.stop();
+    metrics.stop().await;
+
+    // write out statistics (and notify) before reporting any errors
+    summary::write("import", &tracker, args, &result)?;
+    summary::record(run, "import", &tracker, &result)?;
+    summary::notify("import", &tracker, args, &result).await;
+
+    // summarize the import once complete, even if interrupted
+    if (result.is_ok() || summary::interrupted(&result)) && !dry_run {
+        summary::report("imported", &tracker, args);
+    }
+    result?;
+
+    // any failed documents make this only a partial success
+    match tracker.failed() {
+        0 => Ok(()),
+        failed => Err(Error::new(
+            ErrorKind::Documents,
+            format!("{} documents failed to import", failed),
+        )
+        .into()),
+    }
+}
+
+/// Constructs the body used to create missing indices.
+///
+/// Settings are provided in the form `key=value` separated by commas, with
+/// `shards` and `replicas` available as shorthands for the shard counts.
+/// Alternatively a path prefixed with `@` can be provided to read a full
+/// index creation body (including mappings) from a JSON file.
+fn construct_settings(input: &str) -> Result<Value> {
+    // read the body from a file if requested
+    if let Some(path) = input.strip_prefix('@') {
+        let body = std::fs::read(path)?;
+        return Ok(serde_json::from_slice(&body)?);
+    }
+
+    // parse all settings into a map
+    let mut settings = Map::new();
+    for setting in input.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (key, value) = setting
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Invalid index setting provided: {}", setting))?;
+
+        // expand any shorthand setting names
+        let key = match key.trim() {
+            "shards" => "number_of_shards",
+            "replicas" => "number_of_replicas",
+            key => key,
+        };
+
+        // treat numeric values as numbers, otherwise strings
+        let value = match value.trim().parse::<u64>() {
+            Ok(value) => Value::from(value),
+            Err(_) => Value::from(value.trim()),
+        };
 
-    // execute a refresh against the cluster
-    client
-        .indices()
-        .refresh(IndicesRefreshParts::Index(&["_all"]))
-        .send()
-        .await?
-        .error_for_status_code()?;
+        settings.insert(key.to_owned(), value);
+    }
 
-    // done!
-    Ok(())
+    Ok(json!({ "settings": { "index": settings } }))
 }
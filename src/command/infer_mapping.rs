@@ -0,0 +1,114 @@
+//! Infer mapping command module for Limber.
+//!
+//! This module exposes functions to propose a mapping from the documents of
+//! an existing dump, for when a backup has to be restored into a fresh cluster
+//! but the mapping it was taken with has been lost.
+use anyhow::Result;
+use clap::{Arg, ArgMatches, Command};
+use futures::TryStreamExt;
+use limber::input;
+use limber::mapping::Inference;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::BufReader;
+use tracing::{info, warn};
+
+use super::{finish_tasks, open_input, parse_format};
+
+/// The number of documents sampled by default.
+const DEFAULT_SAMPLES: &str = "10000";
+
+/// Returns the definition for this command in the CLI.
+///
+/// This function dictates options available to this command and what
+/// can be asserted to exist, as well as the other optional arguments.
+pub fn cmd<'a>() -> Command<'a> {
+    Command::new("infer-mapping")
+        .about("Propose a mapping for the documents of a dump")
+        .args(&[
+            // format: --format [ndjson]
+            Arg::new("format")
+                .help("The format to read documents in")
+                .long("format")
+                .env("LIMBER_FORMAT")
+                .takes_value(true)
+                .possible_values(["ndjson", "json-seq"])
+                .default_value("ndjson")
+                .hide_default_value(true),
+            // index: --index
+            Arg::new("index")
+                .help("Only sample documents belonging to an index")
+                .long("index")
+                .env("LIMBER_INDEX")
+                .takes_value(true),
+            // samples: --samples [10000]
+            Arg::new("samples")
+                .help("The number of documents to sample (or 0 for every document)")
+                .long("samples")
+                .env("LIMBER_SAMPLES")
+                .takes_value(true)
+                .default_value(DEFAULT_SAMPLES)
+                .hide_default_value(true),
+            // input: +required
+            Arg::new("input")
+                .help("A dump to read documents from, compressed or not (or - for stdin)")
+                .required(true),
+        ])
+}
+
+/// Document as written in the export format.
+#[derive(Deserialize)]
+struct Document {
+    #[serde(rename = "_index")]
+    index: Option<String>,
+    #[serde(rename = "_source")]
+    source: Option<Value>,
+}
+
+/// Executes the `infer-mapping` command, reading as much input as needed.
+pub async fn run(args: &ArgMatches) -> Result<()> {
+    let index = args.value_of("index");
+    let samples = args.value_of_t::<u64>("samples")?;
+    let mut inference = Inference::new();
+
+    // open the input, decompressing it when compressed
+    let path = args.value_of("input").expect("guaranteed by CLI");
+    let (reader, decompressor) = open_input(path).await?;
+
+    // observe the source of every document until there are enough samples
+    let format = parse_format(args, "format");
+    let mut records = Box::pin(input::records(BufReader::new(reader), 0, format));
+    let mut complete = true;
+    while let Some((record, offset)) = records.try_next().await? {
+        if samples > 0 && inference.documents >= samples {
+            complete = false;
+            break;
+        }
+        if record.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+        let document = match serde_json::from_slice::<Document>(&record) {
+            Ok(document) => document,
+            Err(err) => {
+                warn!(offset, error = %err, "Skipping invalid document");
+                continue;
+            }
+        };
+        if index.is_none() || document.index.as_deref() == index {
+            inference.observe(&document.source.unwrap_or_default());
+        }
+    }
+
+    // stopping early leaves the rest of the input unread, so any errors
+    // decompressing it are irrelevant and the decompressor is left behind
+    if complete {
+        finish_tasks(decompressor.into_iter().collect(), Ok(())).await?;
+    }
+
+    // write the mapping in the shape accepted by `import --create-with`
+    let mapping = json!({ "mappings": inference.mapping() });
+    println!("{}", serde_json::to_string_pretty(&mapping)?);
+
+    info!(documents = inference.documents, "Inferred mapping");
+    Ok(())
+}
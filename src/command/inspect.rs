@@ -0,0 +1,145 @@
+//! Inspect command module for Limber.
+//!
+//! This module exposes functions to summarize the contents of an existing
+//! dump without touching any cluster, to answer what's actually inside an
+//! old backup before deciding what to do with it.
+use anyhow::Result;
+use chrono::SecondsFormat;
+use clap::{Arg, ArgMatches, Command};
+use futures::TryStreamExt;
+use indicatif::HumanCount;
+use limber::input;
+use limber::inspect::{Inspection, SIZE_BUCKETS};
+use limber::units;
+use tokio::io::BufReader;
+
+use super::{finish_tasks, open_input, parse_format};
+
+/// The number of documents sampled by default.
+const DEFAULT_SAMPLES: &str = "3";
+
+/// Returns the definition for this command in the CLI.
+///
+/// This function dictates options available to this command and what
+/// can be asserted to exist, as well as the other optional arguments.
+pub fn cmd<'a>() -> Command<'a> {
+    Command::new("inspect")
+        .about("Summarize the contents of a dump of documents")
+        .args(&[
+            // format: --format [ndjson]
+            Arg::new("format")
+                .help("The format to read documents in")
+                .long("format")
+                .env("LIMBER_FORMAT")
+                .takes_value(true)
+                .possible_values(["ndjson", "json-seq"])
+                .default_value("ndjson")
+                .hide_default_value(true),
+            // samples: --samples [3]
+            Arg::new("samples")
+                .help("The number of documents to sample, each from a different index")
+                .long("samples")
+                .env("LIMBER_SAMPLES")
+                .takes_value(true)
+                .default_value(DEFAULT_SAMPLES)
+                .hide_default_value(true),
+            // time-field: --time-field
+            Arg::new("time-field")
+                .help("A timestamp field to report the earliest and latest values of")
+                .long("time-field")
+                .env("LIMBER_TIME_FIELD")
+                .takes_value(true),
+            // input: +required
+            Arg::new("input")
+                .help("A dump to read documents from, compressed or not (or - for stdin)")
+                .required(true),
+        ])
+}
+
+/// Executes the `inspect` command, reading the input through to the end.
+pub async fn run(args: &ArgMatches) -> Result<()> {
+    let mut inspection = Inspection::new(
+        args.value_of("time-field").map(str::to_owned),
+        args.value_of_t::<usize>("samples")?,
+    );
+
+    // open the input, decompressing it when compressed
+    let path = args.value_of("input").expect("guaranteed by CLI");
+    let (reader, decompressor) = open_input(path).await?;
+
+    // observe every record of the input, skipping empty records
+    let format = parse_format(args, "format");
+    let mut records = Box::pin(input::records(BufReader::new(reader), 0, format));
+    while let Some((record, _)) = records.try_next().await? {
+        if !record.iter().all(u8::is_ascii_whitespace) {
+            inspection.observe(&record);
+        }
+    }
+    finish_tasks(decompressor.into_iter().collect(), Ok(())).await?;
+
+    print!("{}", describe(&inspection));
+    Ok(())
+}
+
+/// Describes an inspection in a human readable format.
+fn describe(inspection: &Inspection) -> String {
+    // describe the totals of the dump
+    let mut description = format!(
+        "documents: {} ({})\n",
+        HumanCount(inspection.documents),
+        units::format_bytes(inspection.bytes)
+    );
+    if inspection.invalid > 0 {
+        description.push_str(&format!("invalid: {}\n", HumanCount(inspection.invalid)));
+    }
+
+    // break down the documents per index
+    if !inspection.indices.is_empty() {
+        description.push_str("indices:\n");
+        for (index, count) in &inspection.indices {
+            description.push_str(&format!("  {}: {}\n", index, HumanCount(*count)));
+        }
+    }
+
+    // describe the distribution of document sizes
+    if let Some(average) = inspection.bytes.checked_div(inspection.documents) {
+        description.push_str(&format!(
+            "sizes:\n  smallest: {}\n  average: {}\n  largest: {}\n",
+            units::format_bytes(inspection.smallest),
+            units::format_bytes(average),
+            units::format_bytes(inspection.largest)
+        ));
+        for (bucket, count) in inspection.sizes.iter().enumerate() {
+            let label = match SIZE_BUCKETS.get(bucket) {
+                Some(bound) => format!("<= {}", units::format_bytes(*bound)),
+                None => format!("> {}", units::format_bytes(SIZE_BUCKETS[bucket - 1])),
+            };
+            if *count > 0 {
+                description.push_str(&format!("  {}: {}\n", label, HumanCount(*count)));
+            }
+        }
+    }
+
+    // describe the range of time covered, when asked to
+    if let Some(ref field) = inspection.time_field {
+        description.push_str(&format!("time ({}):\n", field));
+        if let (Some(earliest), Some(latest)) = (inspection.earliest, inspection.latest) {
+            description.push_str(&format!(
+                "  earliest: {}\n  latest: {}\n",
+                earliest.to_rfc3339_opts(SecondsFormat::Millis, true),
+                latest.to_rfc3339_opts(SecondsFormat::Millis, true)
+            ));
+        }
+        description.push_str(&format!("  missing: {}\n", HumanCount(inspection.untimed)));
+    }
+
+    // include a few documents to look at
+    if !inspection.samples.is_empty() {
+        description.push_str("samples:\n");
+        for sample in &inspection.samples {
+            description.push_str(&format!("  {}\n", sample));
+        }
+    }
+
+    description
+}
@@ -0,0 +1,158 @@
+//! Jobs command module for Limber.
+//!
+//! This module exposes commands to inspect (and tidy up) the state kept for
+//! named jobs, such as their checkpoints and the history of their runs. The
+//! state itself is managed by the `state` module of the library.
+use anyhow::Result;
+use clap::{Arg, ArgMatches, Command};
+use indicatif::HumanCount;
+use limber::checkpoint::Checkpoint;
+use limber::state::{Job, Run};
+use limber::units;
+use serde_json::Value;
+
+use std::time::Duration;
+
+use super::state_dir;
+
+/// The number of runs kept for each job when pruning by default.
+const DEFAULT_KEEP: &str = "10";
+
+/// Returns the definition for this command in the CLI.
+///
+/// This function dictates options available to this command and what
+/// can be asserted to exist, as well as the other optional arguments.
+pub fn cmd<'a>() -> Command<'a> {
+    Command::new("jobs")
+        .about("Inspect the state kept for named jobs")
+        .subcommand(Command::new("list").about("List all jobs, with their latest run"))
+        .subcommand(
+            Command::new("describe")
+                .about("Describe a job, including each of its runs")
+                .arg(
+                    Arg::new("name")
+                        .help("The name of the job to describe")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("prune")
+                .about("Remove all but the most recent runs of jobs")
+                .args(&[
+                    // keep: --keep [10]
+                    Arg::new("keep")
+                        .help("The number of runs to keep for each job")
+                        .long("keep")
+                        .env("LIMBER_KEEP")
+                        .takes_value(true)
+                        .default_value(DEFAULT_KEEP)
+                        .hide_default_value(true),
+                    // name: +optional
+                    Arg::new("name").help("The name of a job to prune, instead of all jobs"),
+                ]),
+        )
+        .subcommand(
+            Command::new("remove")
+                .about("Remove a job entirely, including its checkpoint")
+                .arg(
+                    Arg::new("name")
+                        .help("The name of the job to remove")
+                        .required(true),
+                ),
+        )
+}
+
+/// Executes the `jobs` command, listing jobs when no subcommand is provided.
+pub fn run(args: &ArgMatches) -> Result<()> {
+    let dir = state_dir(args)?;
+
+    match args.subcommand() {
+        Some(("describe", args)) => describe(&Job::find(
+            &dir,
+            args.value_of("name").expect("guaranteed by CLI"),
+        )?),
+        Some(("prune", args)) => {
+            // prune a single job, or all of them
+            let keep = args.value_of_t::<usize>("keep")?;
+            let jobs = match args.value_of("name") {
+                Some(name) => vec![Job::find(&dir, name)?],
+                None => Job::list(&dir)?,
+            };
+
+            for job in jobs {
+                let removed = job.prune(keep)?;
+                println!("{}: removed {} runs", job.name(), removed);
+            }
+            Ok(())
+        }
+        Some(("remove", args)) => {
+            let job = Job::find(&dir, args.value_of("name").expect("guaranteed by CLI"))?;
+            let name = job.name().to_owned();
+            job.remove()?;
+            println!("{}: removed", name);
+            Ok(())
+        }
+        _ => list(&Job::list(&dir)?),
+    }
+}
+
+/// Lists jobs alongside the outcome of their latest run.
+fn list(jobs: &[Job]) -> Result<()> {
+    println!("{:<24} {:>6}  {:<20} STATUS", "NAME", "RUNS", "LAST RUN");
+    for job in jobs {
+        let runs = job.runs()?;
+        let last = runs.last();
+        println!(
+            "{:<24} {:>6}  {:<20} {}",
+            job.name(),
+            runs.len(),
+            last.and_then(Run::started).unwrap_or("-"),
+            last.map_or("-", Run::status)
+        );
+    }
+    Ok(())
+}
+
+/// Describes a job, including its checkpoint and each of its runs.
+fn describe(job: &Job) -> Result<()> {
+    println!("Job: {}", job.name());
+    println!("  path: {}", job.path().display());
+
+    // the checkpoint only exists once an import has run
+    let checkpoint = job.checkpoint();
+    if checkpoint.exists() {
+        let offset = Checkpoint::read(&checkpoint.to_string_lossy())?;
+        println!("  checkpoint: {}", units::format_bytes(offset));
+    }
+
+    // the dead letter file only exists once a document has failed
+    if let Ok(metadata) = std::fs::metadata(job.dead_letter()) {
+        println!("  dead letter: {}", units::format_bytes(metadata.len()));
+    }
+
+    // list every run, with the totals of those which finished
+    println!("  runs:");
+    for run in job.runs()? {
+        let statistic = |name| run.record().pointer(name).and_then(Value::as_u64);
+        let command = run.record().get("command").and_then(Value::as_str);
+        print!(
+            "    {} {} {}",
+            run.id(),
+            command.unwrap_or("-"),
+            run.status()
+        );
+        if let (Some(documents), Some(elapsed)) = (
+            statistic("/statistics/documents"),
+            statistic("/statistics/elapsed_ms"),
+        ) {
+            print!(
+                " ({} documents in {})",
+                HumanCount(documents),
+                units::format_duration(Duration::from_millis(elapsed))
+            );
+        }
+        println!();
+    }
+
+    Ok(())
+}
@@ -0,0 +1,206 @@
+//! Mapping diff command module for Limber.
+//!
+//! This module exposes functions to compare the mappings of two indices, each
+//! fetched from a cluster or read from a file, as mapping drift between the
+//! source and target of an import is the usual cause of failed documents.
+use anyhow::{anyhow, Result};
+use clap::{Arg, ArgMatches, Command, ValueHint};
+use limber::mapping::{self, Diff};
+use limber::{units, ClientOptions};
+use serde_json::Value;
+use tracing::info;
+
+use std::path::Path;
+
+use super::parse_auth;
+
+/// Returns the definition for this command in the CLI.
+///
+/// This function dictates options available to this command and what
+/// can be asserted to exist, as well as the other optional arguments.
+pub fn cmd<'a>() -> Command<'a> {
+    Command::new("mapping-diff")
+        .about("Compare the mappings of two indices, or mapping files")
+        .args(&[
+            // auth: --auth
+            Arg::new("auth")
+                .help("Credentials to authenticate with both clusters, in the form user:password")
+                .long("auth")
+                .env("LIMBER_AUTH")
+                .hide_env_values(true)
+                .takes_value(true),
+            // ca-cert: --ca-cert
+            Arg::new("ca-cert")
+                .help("A CA certificate (PEM) used to verify both clusters")
+                .long("ca-cert")
+                .env("LIMBER_CA_CERT")
+                .takes_value(true),
+            // connect-timeout: --connect-timeout
+            Arg::new("connect-timeout")
+                .help("The maximum time to wait for the cluster to accept a connection at startup")
+                .long("connect-timeout")
+                .env("LIMBER_CONNECT_TIMEOUT")
+                .takes_value(true),
+            // debug-http: --debug-http
+            Arg::new("debug-http")
+                .help("Print each request sent to the cluster as a curl command")
+                .long("debug-http")
+                .env("LIMBER_DEBUG_HTTP"),
+            // from-auth: --from-auth
+            Arg::new("from-auth")
+                .help("Credentials to authenticate with the cluster compared from, overriding --auth")
+                .long("from-auth")
+                .env("LIMBER_FROM_AUTH")
+                .hide_env_values(true)
+                .takes_value(true),
+            // from-ca-cert: --from-ca-cert
+            Arg::new("from-ca-cert")
+                .help("A CA certificate (PEM) used to verify the cluster compared from, overriding --ca-cert")
+                .long("from-ca-cert")
+                .env("LIMBER_FROM_CA_CERT")
+                .takes_value(true),
+            // request-timeout: --request-timeout
+            Arg::new("request-timeout")
+                .help("The maximum time to wait for each request to complete")
+                .long("request-timeout")
+                .env("LIMBER_REQUEST_TIMEOUT")
+                .takes_value(true),
+            // to-auth: --to-auth
+            Arg::new("to-auth")
+                .help("Credentials to authenticate with the cluster compared to, overriding --auth")
+                .long("to-auth")
+                .env("LIMBER_TO_AUTH")
+                .hide_env_values(true)
+                .takes_value(true),
+            // to-ca-cert: --to-ca-cert
+            Arg::new("to-ca-cert")
+                .help("A CA certificate (PEM) used to verify the cluster compared to, overriding --ca-cert")
+                .long("to-ca-cert")
+                .env("LIMBER_TO_CA_CERT")
+                .takes_value(true),
+            // from: +required
+            Arg::new("from")
+                .help("The index (or mapping file) to compare from")
+                .value_hint(ValueHint::Url)
+                .required(true),
+            // to: +required
+            Arg::new("to")
+                .help("The index (or mapping file) to compare to")
+                .value_hint(ValueHint::Url)
+                .required(true),
+        ])
+}
+
+/// Executes the `mapping-diff` command, printing every changed field.
+pub async fn run(args: &ArgMatches) -> Result<()> {
+    // load both mappings, from either a cluster or a file
+    let from = load(
+        args.value_of("from").expect("guaranteed by CLI"),
+        &parse_options(args, "from")?,
+    )
+    .await?;
+    let to = load(
+        args.value_of("to").expect("guaranteed by CLI"),
+        &parse_options(args, "to")?,
+    )
+    .await?;
+
+    let diff = mapping::diff(&from, &to);
+    print!("{}", describe(&diff));
+
+    info!(
+        added = diff.added.len(),
+        removed = diff.removed.len(),
+        retyped = diff.retyped.len(),
+        "Compared mappings"
+    );
+    Ok(())
+}
+
+/// Parses the options used to connect to one side of the comparison.
+///
+/// Options for a single side (such as `--from-auth`) take precedence over
+/// those shared by both sides (such as `--auth`).
+fn parse_options(args: &ArgMatches, side: &str) -> Result<ClientOptions> {
+    // locate the flag used for an option, preferring that of the side
+    let flag = |name: &str| {
+        let scoped = format!("{}-{}", side, name);
+        match args.is_present(&scoped) {
+            true => scoped,
+            false => name.to_owned(),
+        }
+    };
+
+    Ok(ClientOptions {
+        auth: parse_auth(args, &flag("auth"))?,
+        ca_cert: args.value_of(flag("ca-cert")).map(str::to_owned),
+        connect_timeout: args
+            .value_of("connect-timeout")
+            .map(units::parse_duration)
+            .transpose()?,
+        debug_http: args.is_present("debug-http"),
+        request_timeout: args
+            .value_of("request-timeout")
+            .map(units::parse_duration)
+            .transpose()?,
+        ..ClientOptions::default()
+    })
+}
+
+/// Loads a mapping from a cluster (such as `host/index`) or a file.
+///
+/// Files can contain a mapping on its own, an index creation body (such as
+/// written by `infer-mapping`), or the response of the get mapping API for
+/// a single index.
+async fn load(location: &str, options: &ClientOptions) -> Result<Value> {
+    // anything with a scheme lives within a cluster
+    if location.contains("://") {
+        return mapping::fetch(location, options).await;
+    }
+
+    // anything else has to be a file, as profiles have already been resolved
+    let path = Path::new(location);
+    if !path.is_file() {
+        return Err(anyhow!("Unable to locate cluster or file: {}", location));
+    }
+    let contents = tokio::fs::read(path)
+        .await
+        .map_err(|err| anyhow!("Unable to read mapping {}: {}", location, err))?;
+    let mut body = serde_json::from_slice::<Value>(&contents)
+        .map_err(|err| anyhow!("Invalid mapping provided in {}: {}", location, err))?;
+
+    // unwrap the response of the get mapping API, which is keyed by index
+    let index = body
+        .as_object()
+        .filter(|body| body.len() == 1 && !body.contains_key("properties"))
+        .and_then(|indices| indices.values().next())
+        .filter(|index| index.get("mappings").is_some())
+        .cloned();
+    if let Some(index) = index {
+        body = index;
+    }
+
+    // unwrap the mappings of an index creation body
+    match body.get_mut("mappings") {
+        Some(mappings) => Ok(mappings.take()),
+        None => Ok(body),
+    }
+}
+
+/// Describes the differences between two mappings, one field per line.
+///
+/// Added fields are prefixed with `+`, removed fields with `-`, and fields
+/// which have changed type with `~` (alongside both types).
+fn describe(diff: &Diff) -> String {
+    let mut description = String::new();
+    for (path, kind) in &diff.added {
+        description.push_str(&format!("+ {}: {}\n", path, kind));
+    }
+    for (path, kind) in &diff.removed {
+        description.push_str(&format!("- {}: {}\n", path, kind));
+    }
+    for (path, old, new) in &diff.retyped {
+        description.push_str(&format!("~ {}: {} -> {}\n", path, old, new));
+    }
+    description
+}
@@ -0,0 +1,170 @@
+//! Merge command module for Limber.
+//!
+//! This module exposes functions to compact a full dump and any number of
+//! incremental dumps into a single dump without touching any cluster. Only
+//! the last occurrence of each document is kept, so the result restores the
+//! same state as importing every dump in order.
+use anyhow::{anyhow, Result};
+use clap::{Arg, ArgMatches, Command};
+use futures::TryStreamExt;
+use limber::dedupe::Dedupe;
+use limber::format::Format;
+use limber::{input, units};
+use serde::Deserialize;
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufReader};
+use tracing::info;
+
+use super::{finish_tasks, open_input, open_outputs, parse_format};
+
+/// Returns the definition for this command in the CLI.
+///
+/// This function dictates options available to this command and what
+/// can be asserted to exist, as well as the other optional arguments.
+pub fn cmd<'a>() -> Command<'a> {
+    Command::new("merge")
+        .about("Merge dumps of documents, keeping the last of each document")
+        .args(&[
+            // compress: --compress
+            Arg::new("compress")
+                .help("Compress output using gzip or zstd, with an optional level (e.g. zstd:9)")
+                .long("compress")
+                .env("LIMBER_COMPRESS")
+                .takes_value(true),
+            // dedupe-memory: --dedupe-memory [1gb]
+            Arg::new("dedupe-memory")
+                .help("The maximum memory used to track the last of each document")
+                .long("dedupe-memory")
+                .env("LIMBER_DEDUPE_MEMORY")
+                .takes_value(true)
+                .default_value("1gb")
+                .hide_default_value(true),
+            // force: --force
+            Arg::new("force")
+                .help("Write documents to stdout even when it's a terminal")
+                .long("force")
+                .env("LIMBER_FORCE"),
+            // format: --format [ndjson]
+            Arg::new("format")
+                .help("The format documents are read (and written) in")
+                .long("format")
+                .env("LIMBER_FORMAT")
+                .takes_value(true)
+                .possible_values(["ndjson", "json-seq"])
+                .default_value("ndjson")
+                .hide_default_value(true),
+            // out: --out
+            Arg::new("out")
+                .help("A file (or kafka:// topic, sqlite:// database) to write documents to")
+                .long("out")
+                .env("LIMBER_OUT")
+                .takes_value(true)
+                .required(true),
+            // inputs: +required
+            Arg::new("inputs")
+                .help("Dumps to read documents from in order, compressed or not")
+                .multiple_values(true)
+                .required(true),
+        ])
+}
+
+/// Location of a document, used to identify repeated documents.
+#[derive(Deserialize)]
+struct Location {
+    #[serde(rename = "_index")]
+    index: Option<String>,
+    #[serde(rename = "_id")]
+    id: Option<String>,
+}
+
+/// Executes the `merge` command, reading every input twice.
+pub async fn run(args: &ArgMatches) -> Result<()> {
+    // inputs are read twice, so can't be streamed
+    let paths = args.values_of("inputs").expect("guaranteed by CLI");
+    let paths = paths.collect::<Vec<_>>();
+    if paths.contains(&"-") {
+        return Err(anyhow!(
+            "Merging can't read from stdin, as inputs are read twice"
+        ));
+    }
+
+    // locate the last occurrence of every document
+    let format = parse_format(args, "format");
+    let limit = units::parse_bytes(args.value_of("dedupe-memory").expect("guaranteed by CLI"))?;
+    let mut dedupe = Dedupe::last().with_limit(limit);
+    let mut sequence = 0;
+    for path in &paths {
+        let (reader, decompressor) = open_input(path).await?;
+        let mut records = Box::pin(input::records(BufReader::new(reader), 0, format));
+        while let Some((record, _)) = records.try_next().await? {
+            if let Some((index, id)) = locate(&record) {
+                dedupe.track_document(index.as_deref(), &id, sequence)?;
+            }
+            sequence += 1;
+        }
+        finish_tasks(decompressor.into_iter().collect(), Ok(())).await?;
+    }
+
+    // open the output, alongside any tasks feeding it
+    let output = args.value_of("out").expect("guaranteed by CLI");
+    let (writer, tasks) = open_outputs(args, &[output]).await?;
+
+    // write out only the last occurrence of each document
+    let (documents, result) = match merge(&paths, &dedupe, format, writer).await {
+        Ok(documents) => (documents, Ok(())),
+        Err(err) => (0, Err(err)),
+    };
+    finish_tasks(tasks, result).await?;
+
+    info!(
+        documents,
+        dropped = sequence - documents,
+        "Merged documents"
+    );
+    Ok(())
+}
+
+/// Writes every document of the inputs which is the last of its kind.
+///
+/// Documents are numbered in the same order as they were tracked, so the
+/// number of each can be checked against the tracker. Empty records (and
+/// documents without an identifier) are never considered duplicates, but
+/// empty records are dropped from the output.
+async fn merge<W>(paths: &[&str], dedupe: &Dedupe, format: Format, mut writer: W) -> Result<u64>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut sequence = 0;
+    let mut documents = 0;
+
+    for path in paths {
+        let (reader, decompressor) = open_input(path).await?;
+        let mut records = Box::pin(input::records(BufReader::new(reader), 0, format));
+        while let Some((record, _)) = records.try_next().await? {
+            // only keep the last occurrence of each document
+            let keep = match locate(&record) {
+                Some((index, id)) => dedupe.keep_document(index.as_deref(), &id, sequence)?,
+                None => !record.iter().all(u8::is_ascii_whitespace),
+            };
+            sequence += 1;
+            if !keep {
+                continue;
+            }
+
+            // write each record with the framing of the format
+            writer.write_all(format.prefix()).await?;
+            writer.write_all(&record).await?;
+            writer.write_all(b"\n").await?;
+            documents += 1;
+        }
+        finish_tasks(decompressor.into_iter().collect(), Ok(())).await?;
+    }
+
+    writer.shutdown().await?;
+    Ok(documents)
+}
+
+/// Locates the index and identifier of a document, if it has an identifier.
+fn locate(record: &[u8]) -> Option<(Option<String>, String)> {
+    let location = serde_json::from_slice::<Location>(record).ok()?;
+    Some((location.index, location.id?))
+}
@@ -0,0 +1,192 @@
+//! Schedule command module for Limber.
+//!
+//! This module exposes a resident scheduler, which runs an export or import
+//! job on a cron schedule. This is intended for environments where adding a
+//! system cron entry isn't possible (such as within a container).
+//!
+//! Each run is carried out by a separate process, so runs never share any
+//! state with each other (or with the scheduler). The output, logs and
+//! statistics of each run are kept within a directory named after the time
+//! it started, inside the directory of runs.
+use anyhow::{anyhow, Result};
+use chrono::{Local, Utc};
+use clap::{Arg, ArgMatches, Command};
+use croner::Cron;
+use limber::Shutdown;
+use tracing::{error, info, warn};
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::path::Path;
+use std::process::Stdio;
+
+use crate::signals;
+
+/// Returns the definition for this command in the CLI.
+///
+/// This function dictates options available to this command and what
+/// can be asserted to exist, as well as the other optional arguments.
+pub fn cmd<'a>() -> Command<'a> {
+    Command::new("schedule")
+        .about("Run an export or import on a cron schedule")
+        .trailing_var_arg(true)
+        .args(&[
+            // cron: --cron
+            Arg::new("cron")
+                .help("A cron expression to run the job on, such as '0 2 * * *'")
+                .long("cron")
+                .env("LIMBER_CRON")
+                .takes_value(true)
+                .required(true),
+            // runs-dir: --runs-dir [runs]
+            Arg::new("runs-dir")
+                .help("A directory to keep the output, logs and stats of each run")
+                .long("runs-dir")
+                .env("LIMBER_RUNS_DIR")
+                .takes_value(true)
+                .default_value("runs")
+                .hide_default_value(true),
+            // job: +required
+            Arg::new("job")
+                .help("The export or import command to run, with its arguments")
+                .multiple_values(true)
+                .allow_hyphen_values(true)
+                .allow_invalid_utf8(true)
+                .required(true),
+        ])
+}
+
+/// Constructs a `Future` to execute the `schedule` command.
+///
+/// The job is validated up front, so mistakes are reported immediately
+/// rather than at the time of the first run. Runs never overlap; a run
+/// which is still going at the next scheduled time causes that time to
+/// be skipped. Failed runs are logged, but never stop the scheduler.
+pub async fn run(args: &ArgMatches) -> Result<()> {
+    // parse the cron expression the job runs on
+    let expression = args.value_of("cron").expect("guaranteed by CLI");
+    let cron = Cron::new(expression)
+        .parse()
+        .map_err(|err| anyhow!("Invalid cron expression provided: {}", err))?;
+
+    // fetch the job to run, which must be a valid command
+    let job = args
+        .values_of_os("job")
+        .expect("guaranteed by CLI")
+        .map(OsString::from)
+        .collect::<Vec<_>>();
+    validate(&job)?;
+
+    // fetch the directory to keep runs in
+    let runs = Path::new(args.value_of("runs-dir").expect("guaranteed by CLI"));
+    fs::create_dir_all(runs)?;
+
+    // shut down gracefully when interrupted
+    let shutdown = Shutdown::new();
+    let signals = signals::listen(shutdown.clone());
+
+    loop {
+        // locate the next time to run the job
+        let next = cron.find_next_occurrence(&Local::now(), false)?;
+        info!(next = %next.to_rfc3339(), "Waiting for next scheduled run");
+
+        // wait until then, unless shutting down first
+        let delay = (next - Local::now()).to_std().unwrap_or_default();
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => (),
+            _ = shutdown.triggered() => break,
+        }
+
+        // carry out the run, logging any failure without stopping
+        if let Err(err) = execute(&job, runs, args).await {
+            error!(error = %err, "Scheduled run failed");
+        }
+
+        // a shutdown during the run stops any further runs
+        if shutdown.is_triggered() {
+            break;
+        }
+    }
+
+    signals.abort();
+    info!("Scheduler stopped");
+    Ok(())
+}
+
+/// Executes a single run of a job, within its own directory.
+///
+/// The output of the job is written to `output.jsonl`, its logs are written
+/// to `limber.log` and its statistics are written to `stats.json`. Nothing
+/// is provided as input, so imports must read from a file via `--input`.
+async fn execute(job: &[OsString], runs: &Path, args: &ArgMatches) -> Result<()> {
+    // create a directory for this run, named after the time it started
+    let id = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+    let directory = runs.join(&id);
+    fs::create_dir_all(&directory)?;
+
+    info!(run = %id, "Starting scheduled run");
+
+    // run the job using this same binary
+    let mut command = tokio::process::Command::new(std::env::current_exe()?);
+
+    // pass on any configuration, as it's provided before the job
+    if let Some(config) = args.value_of("config") {
+        command.arg("--config").arg(config);
+    }
+
+    // write statistics alongside the run, unless the job says otherwise
+    command.arg(&job[0]);
+    if !job
+        .iter()
+        .any(|arg| arg.to_string_lossy().starts_with("--stats-file"))
+    {
+        command
+            .arg("--stats-file")
+            .arg(directory.join("stats.json"));
+    }
+
+    // attach the rest of the job, and route output into the run directory
+    let status = command
+        .args(&job[1..])
+        .stdin(Stdio::null())
+        .stdout(File::create(directory.join("output.jsonl"))?)
+        .stderr(File::create(directory.join("limber.log"))?)
+        .status()
+        .await?;
+
+    // report the outcome of the run, pointing to the logs on failure
+    if !status.success() {
+        warn!(
+            run = %id,
+            code = status.code().unwrap_or(-1),
+            logs = %directory.join("limber.log").display(),
+            "Scheduled run exited unsuccessfully"
+        );
+        return Ok(());
+    }
+
+    info!(run = %id, "Completed scheduled run");
+    Ok(())
+}
+
+/// Validates that a job is a valid export or import command.
+fn validate(job: &[OsString]) -> Result<()> {
+    // only exports and imports can be scheduled
+    match job[0].to_str() {
+        Some("export" | "import") => (),
+        _ => {
+            return Err(anyhow!(
+                "Scheduled jobs must be an export or import command"
+            ))
+        }
+    }
+
+    // parse the job as if it was run directly, to catch mistakes early
+    let mut args = vec![OsString::from(env!("CARGO_PKG_NAME"))];
+    args.extend(job.iter().cloned());
+    crate::build_cli()
+        .try_get_matches_from(args)
+        .map_err(|err| anyhow!("Invalid scheduled job: {}", err))?;
+
+    Ok(())
+}
@@ -0,0 +1,223 @@
+//! Split command module for Limber.
+//!
+//! This module exposes functions to partition an existing dump into several
+//! separate files without touching any cluster, such as to restore indices
+//! individually from a backup taken of every index at once.
+use anyhow::{anyhow, Result};
+use clap::{Arg, ArgMatches, Command};
+use futures::TryStreamExt;
+use limber::format::Format;
+use limber::input;
+use limber::split::{Split, Splitter};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufReader, BufWriter};
+use tracing::info;
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use super::{finish_tasks, open_input, parse_format};
+
+/// The size of the buffer used when writing documents to each partition.
+const PARTITION_BUFFER: usize = 64 * 1024;
+
+/// Returns the definition for this command in the CLI.
+///
+/// This function dictates options available to this command and what
+/// can be asserted to exist, as well as the other optional arguments.
+pub fn cmd<'a>() -> Command<'a> {
+    Command::new("split")
+        .about("Split a dump of documents into separate files")
+        .args(&[
+            // by: --by
+            Arg::new("by")
+                .help("How to split documents (index, size:<bytes>, field:<name>)")
+                .long("by")
+                .env("LIMBER_BY")
+                .takes_value(true)
+                .required(true),
+            // format: --format [ndjson]
+            Arg::new("format")
+                .help("The format documents are read (and written) in")
+                .long("format")
+                .env("LIMBER_FORMAT")
+                .takes_value(true)
+                .possible_values(["ndjson", "json-seq"])
+                .default_value("ndjson")
+                .hide_default_value(true),
+            // max-open-files: --max-open-files [256]
+            Arg::new("max-open-files")
+                .help("The maximum number of partition files to keep open at once")
+                .long("max-open-files")
+                .env("LIMBER_MAX_OPEN_FILES")
+                .takes_value(true)
+                .default_value("256")
+                .hide_default_value(true),
+            // out: --out
+            Arg::new("out")
+                .help("A directory to write each partition to as a file")
+                .long("out")
+                .env("LIMBER_OUT")
+                .takes_value(true)
+                .required(true),
+            // input: +required
+            Arg::new("input")
+                .help("A dump to read documents from, compressed or not (or - for stdin)")
+                .required(true),
+        ])
+}
+
+/// Executes the `split` command, reading the input through to the end.
+pub async fn run(args: &ArgMatches) -> Result<()> {
+    // parse how to split documents before anything else
+    let mut splitter = Splitter::new(Split::parse(
+        args.value_of("by").expect("guaranteed by CLI"),
+    )?);
+
+    // open the input, decompressing it when compressed
+    let path = args.value_of("input").expect("guaranteed by CLI");
+    let (reader, decompressor) = open_input(path).await?;
+
+    // make sure the output directory exists
+    let dir = Path::new(args.value_of("out").expect("guaranteed by CLI"));
+    fs::create_dir_all(dir).await?;
+
+    // partitions are written in the same format as they were read
+    let format = parse_format(args, "format");
+    let extension = match format {
+        Format::Ndjson => "jsonl",
+        Format::JsonSeq => "seq",
+    };
+
+    // fetch the limit of partitions to keep open at once
+    let limit = args.value_of_t::<usize>("max-open-files").unwrap_or(256);
+    if limit == 0 {
+        return Err(anyhow!(
+            "The maximum number of open files must be greater than zero"
+        ));
+    }
+
+    let mut records = Box::pin(input::records(BufReader::new(reader), 0, format));
+    let mut partitions = Partitions::new(dir, extension, limit);
+    let mut previous = None;
+    let mut documents = 0;
+
+    while let Some((record, _)) = records.try_next().await? {
+        // nothing to write for empty records
+        if record.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+
+        // sequential partitions are complete once the next one starts
+        let name = splitter.partition(&record)?;
+        if splitter.is_sequential() {
+            if let Some(previous) = previous.as_deref().filter(|prev| *prev != name) {
+                partitions.close(previous).await?;
+            }
+        }
+
+        // write each record with the framing of the format
+        let writer = partitions.open(&name).await?;
+        writer.write_all(format.prefix()).await?;
+        writer.write_all(&record).await?;
+        writer.write_all(b"\n").await?;
+        previous = Some(name);
+        documents += 1;
+    }
+
+    // flush every partition, and catch any errors in the input
+    let files = partitions.finish().await?;
+    finish_tasks(decompressor.into_iter().collect(), Ok(())).await?;
+
+    info!(documents, files, "Split documents");
+    Ok(())
+}
+
+/// Files written for each partition, with a limit on how many are open.
+///
+/// Once the limit is reached, the least recently written partition is
+/// flushed and closed. Closed partitions are reopened in append mode if
+/// they're written to again, so a split never runs out of file handles.
+struct Partitions<'a> {
+    dir: &'a Path,
+    extension: &'a str,
+    limit: usize,
+    writes: u64,
+    open: HashMap<String, (BufWriter<File>, u64)>,
+    created: HashSet<String>,
+}
+
+impl<'a> Partitions<'a> {
+    /// Constructs an empty set of partitions within a directory.
+    fn new(dir: &'a Path, extension: &'a str, limit: usize) -> Self {
+        Self {
+            dir,
+            extension,
+            limit,
+            writes: 0,
+            open: HashMap::new(),
+            created: HashSet::new(),
+        }
+    }
+
+    /// Returns a writer for a partition, opening its file if needed.
+    async fn open(&mut self, name: &str) -> Result<&mut BufWriter<File>> {
+        self.writes += 1;
+
+        // make room for the partition by closing the least recently written
+        if !self.open.contains_key(name) {
+            if self.open.len() >= self.limit {
+                let oldest = self
+                    .open
+                    .iter()
+                    .min_by_key(|(_, (_, used))| *used)
+                    .map(|(name, _)| name.clone())
+                    .expect("limit is above zero");
+                self.close(&oldest).await?;
+            }
+
+            // files are created the first time, and appended to afterwards
+            let path = self.path(name);
+            let file = match self.created.insert(name.to_owned()) {
+                true => File::create(&path).await,
+                false => OpenOptions::new().append(true).open(&path).await,
+            }
+            .map_err(|err| anyhow!("Unable to open output {}: {}", path.display(), err))?;
+
+            let writer = BufWriter::with_capacity(PARTITION_BUFFER, file);
+            self.open.insert(name.to_owned(), (writer, 0));
+        }
+
+        let (writer, used) = self.open.get_mut(name).expect("partition opened");
+        *used = self.writes;
+        Ok(writer)
+    }
+
+    /// Flushes and closes the file of a partition, if it's open.
+    async fn close(&mut self, name: &str) -> Result<()> {
+        if let Some((mut writer, _)) = self.open.remove(name) {
+            writer.flush().await.map_err(|err| {
+                anyhow!(
+                    "Unable to write output {}: {}",
+                    self.path(name).display(),
+                    err
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Flushes and closes every partition, returning how many were written.
+    async fn finish(mut self) -> Result<usize> {
+        let names = self.open.keys().cloned().collect::<Vec<_>>();
+        for name in names {
+            self.close(&name).await?;
+        }
+        Ok(self.created.len())
+    }
+
+    /// Returns the path of the file written for a partition.
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", name, self.extension))
+    }
+}
@@ -0,0 +1,203 @@
+//! Built-in compression of exported documents.
+//!
+//! Compressing a stream on a single thread quickly becomes the bottleneck
+//! of an export from a fast cluster, so output is instead split into fixed
+//! size blocks which are compressed concurrently. Each block is written as
+//! an independent gzip member (or zstd frame); as both formats allow these
+//! to be concatenated, the output is still a single valid file which can be
+//! decompressed by the standard tools.
+//!
+//! Compressed input (in either format) can also be read back, as is needed
+//! when converting an existing dump.
+use anyhow::{anyhow, Result};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use futures::stream::{FuturesOrdered, StreamExt};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::thread;
+
+use crate::input::Reader;
+
+/// The size of each block of input compressed independently.
+const BLOCK_SIZE: usize = 4 * 1024 * 1024;
+
+/// The size of the buffer between the writer and the compressor.
+const PIPE_SIZE: usize = 1024 * 1024;
+
+/// Algorithms available to compress output with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    /// Gzip, supported (almost) everywhere.
+    Gzip,
+    /// Zstandard, which is both faster and smaller than gzip.
+    Zstd,
+}
+
+/// Compression applied to output, with its level and worker count.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Compression {
+    /// The algorithm used to compress output.
+    pub algorithm: Algorithm,
+    /// The level of compression, within the range of the algorithm.
+    pub level: i32,
+    /// The number of blocks to compress concurrently.
+    pub workers: usize,
+}
+
+impl Compression {
+    /// Parses compression from an algorithm name and an optional level.
+    ///
+    /// Levels follow the algorithm after a colon (such as `zstd:9`), and
+    /// default to that of the algorithm's own command line tool. Workers
+    /// default to the number of available CPUs.
+    pub fn parse(input: &str) -> Result<Self> {
+        // split the algorithm from the level, if any
+        let (name, level) = match input.split_once(':') {
+            Some((name, level)) => (name, Some(level)),
+            None => (input, None),
+        };
+
+        // look up the algorithm, alongside its supported levels
+        let (algorithm, levels, default) = match name {
+            "gzip" => (Algorithm::Gzip, 0..=9, 6),
+            "zstd" => (Algorithm::Zstd, zstd::compression_level_range(), 3),
+            _ => return Err(anyhow!("Unknown compression algorithm: {}", name)),
+        };
+
+        // parse the level, making sure it's supported
+        let level = match level {
+            None => default,
+            Some(level) => level
+                .parse::<i32>()
+                .ok()
+                .filter(|level| levels.contains(level))
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Invalid {} compression level {}, expected {} to {}",
+                        name,
+                        level,
+                        levels.start(),
+                        levels.end()
+                    )
+                })?,
+        };
+
+        // compress as many blocks at once as there are CPUs
+        let workers = thread::available_parallelism().map_or(1, usize::from);
+
+        Ok(Self {
+            algorithm,
+            level,
+            workers,
+        })
+    }
+
+    /// Compresses a block of input into a standalone member (or frame).
+    pub fn compress(&self, block: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self.algorithm {
+            Algorithm::Gzip => {
+                let level = flate2::Compression::new(self.level as u32);
+                let mut encoder = GzEncoder::new(Vec::with_capacity(block.len() / 4), level);
+                encoder.write_all(block)?;
+                encoder.finish()
+            }
+            Algorithm::Zstd => zstd::bulk::compress(block, self.level),
+        }
+    }
+}
+
+/// Spawns a task compressing everything written to the returned writer.
+///
+/// Compressed output is written to the provided writer in order, and the
+/// task completes once the returned writer has been dropped and everything
+/// written to it has been compressed and flushed.
+pub fn spawn<W>(writer: W, compression: Compression) -> (DuplexStream, JoinHandle<Result<()>>)
+where
+    W: AsyncWrite + Send + Unpin + 'static,
+{
+    let (input, output) = io::duplex(PIPE_SIZE);
+    let task = tokio::spawn(pump(output, writer, compression));
+    (input, task)
+}
+
+/// Opens a file for reading, decompressing it when compressed.
+///
+/// Compression is detected via the magic bytes at the start of the file,
+/// rather than its extension. Compressed files are decompressed by a task
+/// on a blocking thread, which completes once the file has been read in
+/// full; it must be awaited to catch any errors in the compressed input.
+pub fn decompress(path: &str) -> Result<(Reader, Option<JoinHandle<Result<()>>>)> {
+    // read the magic bytes from the start of the file
+    let mut file = File::open(path)?;
+    let mut magic = Vec::with_capacity(4);
+    (&mut file).take(4).read_to_end(&mut magic)?;
+    file.seek(SeekFrom::Start(0))?;
+
+    // pick a decoder based on the magic bytes, if any
+    let mut decoder: Box<dyn Read + Send> = match magic.as_slice() {
+        [0x1F, 0x8B, ..] => Box::new(MultiGzDecoder::new(file)),
+        [0x28, 0xB5, 0x2F, 0xFD] => Box::new(zstd::Decoder::new(file)?),
+        _ => return Ok((Box::new(tokio::fs::File::from_std(file)), None)),
+    };
+
+    // decode on a blocking thread, writing through to the reader
+    let (input, mut output) = io::duplex(PIPE_SIZE);
+    let handle = Handle::current();
+    let task = tokio::task::spawn_blocking(move || {
+        let mut buffer = vec![0; PIPE_SIZE];
+        loop {
+            let read = decoder.read(&mut buffer)?;
+            if read == 0 {
+                return Ok(());
+            }
+            handle.block_on(output.write_all(&buffer[..read]))?;
+        }
+    });
+
+    Ok((Box::new(input), Some(task)))
+}
+
+/// Reads blocks of input, compressing them concurrently into a writer.
+async fn pump<R, W>(mut reader: R, mut writer: W, compression: Compression) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut pending = FuturesOrdered::new();
+    let workers = compression.workers.max(1);
+
+    loop {
+        // read through to the end of the next block
+        let mut block = Vec::with_capacity(BLOCK_SIZE);
+        let read = (&mut reader)
+            .take(BLOCK_SIZE as u64)
+            .read_to_end(&mut block)
+            .await?;
+
+        // hand the block off to be compressed on another thread
+        if read > 0 {
+            pending.push_back(tokio::task::spawn_blocking(move || {
+                compression.compress(&block)
+            }));
+        }
+
+        // write out blocks in order once every worker is busy (or at the end)
+        while pending.len() >= workers || (read == 0 && !pending.is_empty()) {
+            let compressed = pending.next().await.expect("pending block")??;
+            writer.write_all(&compressed).await?;
+        }
+
+        // the end of the input has been reached
+        if read == 0 {
+            break;
+        }
+    }
+
+    writer.flush().await?;
+    Ok(())
+}
@@ -0,0 +1,340 @@
+//! Configuration file support for the Limber CLI.
+//!
+//! Configuration files are written in TOML, and provide the default values
+//! of any CLI flags. Top level keys apply to every command, whereas keys
+//! within a table named after a command (such as `[import]`) only apply to
+//! that command. Keys are named exactly as the long form of each flag, and
+//! flags provided via the CLI always take precedence:
+//!
+//! ```toml
+//! concurrency = 4
+//!
+//! [import]
+//! target-hosts = ["http://es-1:9200", "http://es-2:9200"]
+//! retries = 5
+//!
+//! [clusters.prod-eu]
+//! url = "https://es.eu.example.com:9200"
+//! auth = "elastic:changeme"
+//! ca-cert = "/etc/limber/prod-eu.pem"
+//! ```
+//!
+//! Clusters defined within `clusters` can be referenced by name in place of
+//! a URL, such as `prod-eu/my-index`, to keep endpoints and credentials in
+//! a single place. Commands working with two clusters can define options for
+//! each of them (such as `--from-auth`), which profiles fill in instead.
+use anyhow::{anyhow, Result};
+use clap::{Arg, ArgMatches, Command, ValueHint, ValueSource};
+use serde_json::{Map, Value};
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Parses CLI arguments, applying defaults from any configuration file.
+///
+/// The configuration file is located via the `--config` flag, falling back
+/// to `config.toml` within the user configuration directory (if it exists).
+/// Defaults are applied by re-parsing the arguments with any missing flags
+/// attached, so that configured values are validated exactly as flags are.
+pub fn parse(cli: Command, args: Vec<OsString>) -> Result<ArgMatches> {
+    // parse the arguments as provided
+    let matches = cli.clone().get_matches_from(args.iter());
+
+    // locate the configuration file, if any
+    let path = match matches.value_of("config") {
+        Some(path) => PathBuf::from(path),
+        None => match default_path().filter(|path| path.is_file()) {
+            Some(path) => path,
+            None => return Ok(matches),
+        },
+    };
+
+    // read and parse the configuration file
+    let contents = std::fs::read_to_string(&path)
+        .map_err(|err| anyhow!("Unable to read config {}: {}", path.display(), err))?;
+    let config = toml::from_str::<Map<String, Value>>(&contents)
+        .map_err(|err| anyhow!("Invalid config {}: {}", path.display(), err))?;
+
+    // configuration only applies to commands
+    let (name, submatches) = match matches.subcommand() {
+        Some(subcommand) => subcommand,
+        None => return Ok(matches),
+    };
+
+    // fetch the definition of the command being run
+    let command = cli
+        .find_subcommand(name)
+        .expect("guaranteed by CLI")
+        .clone();
+
+    // collect defaults, with command tables overriding the top level
+    let mut defaults = BTreeMap::new();
+    for (key, value) in &config {
+        if !value.is_object() {
+            defaults.insert(key.to_owned(), (value.clone(), None));
+        }
+    }
+    if let Some(Value::Object(table)) = config.get(name) {
+        for (key, value) in table {
+            defaults.insert(key.to_owned(), (value.clone(), Some(name)));
+        }
+    }
+
+    // fetch the positional arguments naming clusters, which are marked as
+    // URLs to tell them apart from positional files (such as dumps)
+    let mut args = args;
+    let mut changed = false;
+    let clusters = command
+        .get_arguments()
+        .filter(|arg| arg.is_positional() && arg.get_value_hint() == ValueHint::Url)
+        .filter_map(|arg| Some((arg.get_id(), submatches.value_of(arg.get_id())?)));
+
+    // clusters without a scheme refer to a named profile, unless they're files
+    let mut profiled = Map::new();
+    for (id, cluster) in
+        clusters.filter(|(_, cluster)| !cluster.contains("://") && !Path::new(cluster).is_file())
+    {
+        let (url, profile) = resolve_profile(&config, cluster)?;
+
+        for (key, value) in profile {
+            // commands comparing clusters accept options for each of them
+            // (such as `--from-auth`), which the CLI can still override
+            let scoped = format!("{}-{}", id, key);
+            if command
+                .get_arguments()
+                .any(|arg| arg.get_long() == Some(&scoped))
+            {
+                let shared = command
+                    .get_arguments()
+                    .find(|arg| arg.get_long() == Some(&key));
+                if !shared.is_some_and(|arg| provided(submatches, arg)) {
+                    defaults.insert(scoped, (value, None));
+                }
+                continue;
+            }
+
+            // profile options override any other configured defaults, but several
+            // profiles can only share options when their values agree
+            if profiled
+                .get(&key)
+                .is_some_and(|existing| *existing != value)
+            {
+                return Err(anyhow!(
+                    "Cluster profiles set conflicting values for {}",
+                    key
+                ));
+            }
+            profiled.insert(key.clone(), value.clone());
+            defaults.insert(key, (value, None));
+        }
+
+        // swap the profile out for the resolved cluster
+        match args.iter().rposition(|arg| arg == cluster) {
+            Some(position) => args[position] = OsString::from(url),
+            None => args.push(OsString::from(url)),
+        }
+        changed = true;
+    }
+
+    // flags to attach to the arguments
+    let mut flags = Vec::new();
+
+    // convert each default into flags, unless provided via the CLI
+    for (key, (value, scope)) in &defaults {
+        // locate the flag with the same name in the command (or globally)
+        let arg = command
+            .get_arguments()
+            .chain(cli.get_arguments().filter(|arg| arg.is_global_set()))
+            .find(|arg| arg.get_long() == Some(key));
+
+        // unknown flags are an error, unless they apply to another command
+        let arg = match (arg, scope) {
+            (Some(arg), _) => arg,
+            (None, Some(scope)) => {
+                return Err(anyhow!("Unknown option in config: {}.{}", scope, key));
+            }
+            (None, None) if is_known(&cli, key) => continue,
+            (None, None) => return Err(anyhow!("Unknown option in config: {}", key)),
+        };
+
+        // flags provided via the CLI (or environment) take precedence
+        if provided(submatches, arg) {
+            continue;
+        }
+
+        // arrays are provided as repeated flags
+        let values = match value {
+            Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+
+        // attach each value as a flag
+        for value in values {
+            match value {
+                Value::Bool(true) if !arg.is_takes_value_set() => flags.push(format!("--{}", key)),
+                Value::Bool(false) if !arg.is_takes_value_set() => (),
+                Value::String(value) => flags.push(format!("--{}={}", key, value)),
+                Value::Number(value) => flags.push(format!("--{}={}", key, value)),
+                Value::Bool(value) => flags.push(format!("--{}={}", key, value)),
+                _ => return Err(anyhow!("Invalid value in config for {}", key)),
+            }
+        }
+    }
+
+    // nothing to change, so no need to parse again
+    if flags.is_empty() && !changed {
+        return Ok(matches);
+    }
+
+    // attach the flags ahead of any trailing positional arguments
+    let position = args
+        .iter()
+        .position(|arg| arg == "--")
+        .unwrap_or(args.len());
+    args.splice(position..position, flags.into_iter().map(OsString::from));
+
+    Ok(cli.get_matches_from(args))
+}
+
+/// Resolves a cluster in the form `profile/index` using configured profiles.
+///
+/// Profiles are defined as tables within `clusters`, containing the `url`
+/// of the cluster alongside any flags used to connect to it (such as the
+/// `auth` or `ca-cert` flags). The resolved URL is returned alongside these
+/// flags, to be applied as defaults.
+fn resolve_profile(
+    config: &Map<String, Value>,
+    cluster: &str,
+) -> Result<(String, Map<String, Value>)> {
+    // split the profile name from the index, if any
+    let (name, index) = match cluster.split_once('/') {
+        Some((name, index)) => (name, index),
+        None => (cluster, ""),
+    };
+
+    // locate the profile in the configuration
+    let mut profile = match config
+        .get("clusters")
+        .and_then(|clusters| clusters.get(name))
+    {
+        Some(Value::Object(profile)) => profile.clone(),
+        _ => return Err(anyhow!("Unknown cluster profile: {}", name)),
+    };
+
+    // every profile needs a url to connect to
+    let url = match profile.remove("url") {
+        Some(Value::String(url)) => url,
+        _ => return Err(anyhow!("Cluster profile {} is missing a url", name)),
+    };
+
+    // attach the index to the url, keeping a trailing slash without one so
+    // that the last segment of a base path isn't mistaken for an index
+    let url = format!("{}/{}", url.trim_end_matches('/'), index);
+
+    Ok((url, profile))
+}
+
+/// Determines whether a flag was provided via the CLI (or environment).
+fn provided(matches: &ArgMatches, arg: &Arg) -> bool {
+    matches!(
+        matches.value_source(arg.get_id()),
+        Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+    )
+}
+
+/// Determines whether a flag is known to any command.
+fn is_known(cli: &Command, key: &str) -> bool {
+    cli.get_subcommands()
+        .flat_map(Command::get_arguments)
+        .any(|arg| arg.get_long() == Some(key))
+}
+
+/// Returns the default location of the configuration file, if any.
+fn default_path() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(base) if !base.is_empty() => PathBuf::from(base),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("limber").join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses arguments for a command, with a configuration file.
+    fn parse_with(config: &str, args: &[&str]) -> Result<ArgMatches> {
+        let path = std::env::temp_dir().join(format!(
+            "limber-config-{}-{}.toml",
+            std::process::id(),
+            args.join("-").replace(['/', ':'], "_")
+        ));
+        std::fs::write(&path, config)?;
+
+        let mut argv = vec!["limber".into(), "--config".into(), path.clone().into()];
+        argv.extend(args.iter().map(OsString::from));
+        let matches = parse(crate::build_cli(), argv);
+
+        std::fs::remove_file(&path)?;
+        matches
+    }
+
+    const PROFILES: &str = r#"
+        [clusters.prod]
+        url = "https://prod:9200"
+        auth = "alice:secret"
+        ca-cert = "/etc/prod.pem"
+
+        [clusters.staging]
+        url = "https://staging:9200/"
+        auth = "bob:hunter2"
+    "#;
+
+    #[test]
+    fn profiles_fill_options_for_each_side() {
+        let matches = parse_with(PROFILES, &["mapping-diff", "prod/logs", "staging/logs"]).unwrap();
+        let (_, args) = matches.subcommand().unwrap();
+
+        assert_eq!(args.value_of("from"), Some("https://prod:9200/logs"));
+        assert_eq!(args.value_of("to"), Some("https://staging:9200/logs"));
+        assert_eq!(args.value_of("from-auth"), Some("alice:secret"));
+        assert_eq!(args.value_of("from-ca-cert"), Some("/etc/prod.pem"));
+        assert_eq!(args.value_of("to-auth"), Some("bob:hunter2"));
+        assert_eq!(args.value_of("to-ca-cert"), None);
+        assert_eq!(args.value_of("auth"), None);
+    }
+
+    #[test]
+    fn profiles_for_each_side_yield_to_the_cli() {
+        let matches = parse_with(
+            PROFILES,
+            &[
+                "mapping-diff",
+                "--auth",
+                "admin:admin",
+                "--to-auth",
+                "carol:pw",
+                "prod/logs",
+                "staging/logs",
+            ],
+        )
+        .unwrap();
+        let (_, args) = matches.subcommand().unwrap();
+
+        assert_eq!(args.value_of("auth"), Some("admin:admin"));
+        assert_eq!(args.value_of("from-auth"), None);
+        assert_eq!(args.value_of("to-auth"), Some("carol:pw"));
+        assert_eq!(args.value_of("from-ca-cert"), Some("/etc/prod.pem"));
+    }
+
+    #[test]
+    fn profiles_fill_shared_options() {
+        let matches = parse_with(PROFILES, &["export", "prod/logs"]).unwrap();
+        let (_, args) = matches.subcommand().unwrap();
+
+        assert_eq!(args.value_of("source"), Some("https://prod:9200/logs"));
+        assert_eq!(args.value_of("auth"), Some("alice:secret"));
+        assert_eq!(args.value_of("ca-cert"), Some("/etc/prod.pem"));
+    }
+}
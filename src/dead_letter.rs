@@ -0,0 +1,64 @@
+//! Dead letter output for documents which could not be imported.
+//!
+//! Documents which fail permanently are written to a file in the same
+//! format they were read in, with an additional `_error` field containing
+//! the reason for the failure. As the import ignores unknown fields, the
+//! file can be fed directly back into a later import to replay failures.
+use anyhow::Result;
+use serde_json::{Map, Value};
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::Mutex;
+
+/// Writer used to record documents which failed to import.
+pub struct DeadLetter {
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl DeadLetter {
+    /// Creates a new dead letter file at the provided path.
+    ///
+    /// Any existing file at the path will be truncated.
+    pub fn create(path: &str) -> Result<Self> {
+        Ok(Self {
+            writer: Mutex::new(BufWriter::new(File::create(path)?)),
+        })
+    }
+
+    /// Writes a failed document input alongside the reason for failure.
+    ///
+    /// Inputs which are not JSON objects (such as a bare document identifier)
+    /// are written as the `_id` field of a new object.
+    pub fn write(&self, input: &[u8], reason: Value) -> Result<()> {
+        // parse the input back into an object, or wrap it as an identifier
+        let mut document = match serde_json::from_slice::<Value>(input) {
+            Ok(Value::Object(document)) => document,
+            Ok(Value::String(id)) => wrap(id),
+            _ => wrap(String::from_utf8_lossy(input).trim().to_owned()),
+        };
+
+        // attach the reason for failure
+        document.insert("_error".to_owned(), reason);
+
+        // write the document as a single line
+        let mut writer = self.writer.lock().expect("poisoned lock");
+        serde_json::to_writer(&mut *writer, &document)?;
+        writer.write_all(b"\n")?;
+
+        Ok(())
+    }
+
+    /// Flushes all buffered documents to the underlying file.
+    pub fn flush(&self) -> Result<()> {
+        self.writer.lock().expect("poisoned lock").flush()?;
+        Ok(())
+    }
+}
+
+/// Wraps a document identifier into an object.
+fn wrap(id: String) -> Map<String, Value> {
+    let mut document = Map::new();
+    document.insert("_id".to_owned(), Value::String(id));
+    document
+}
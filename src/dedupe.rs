@@ -0,0 +1,214 @@
+//! De-duplication of documents repeated within an input stream.
+//!
+//! Documents are identified by their target index and identifier, which are
+//! stored in full so that distinct documents can never be mistaken for each
+//! other. As every distinct document has to be remembered, memory grows with
+//! the input; a limit can be placed on the memory used, which fails the run
+//! with an error once reached rather than exhausting the memory available.
+use anyhow::{anyhow, Result};
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::bulk::Operation;
+use crate::units;
+
+/// The estimated memory used to track each document, aside from its key.
+///
+/// This covers the allocations of the key, the stored offset, and the spare
+/// capacity kept by the table, which is close enough to bound memory usage.
+const ENTRY_OVERHEAD: usize = 64;
+
+/// Tracker used to filter out duplicate documents.
+pub struct Dedupe(Keep);
+
+/// Occurrence of each document to keep.
+enum Keep {
+    /// Keeps the first occurrence of each document.
+    First(Mutex<Seen<()>>),
+    /// Keeps the last occurrence of each document, by input offset.
+    Last(Seen<u64>),
+}
+
+/// Documents seen so far, alongside an estimate of the memory they use.
+struct Seen<V> {
+    entries: HashMap<Key, V>,
+    size: usize,
+    limit: Option<usize>,
+}
+
+/// Key of a document, made of its index and identifier.
+type Key = (Option<Box<str>>, Box<str>);
+
+impl Dedupe {
+    /// Constructs a tracker keeping the first occurrence of each document.
+    pub fn first() -> Self {
+        Dedupe(Keep::First(Mutex::new(Seen::new())))
+    }
+
+    /// Constructs a tracker keeping the last occurrence of each document.
+    ///
+    /// As this requires knowing the future, every operation must be passed
+    /// to `track` before any operations are checked via `keep`.
+    pub fn last() -> Self {
+        Dedupe(Keep::Last(Seen::new()))
+    }
+
+    /// Limits the memory used to track documents, in bytes.
+    ///
+    /// Tracking a document which would take the memory used past the limit
+    /// is an error, as dropping it would let duplicates through silently.
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        match self.0 {
+            Keep::First(ref mut seen) => seen.get_mut().expect("poisoned lock").limit = Some(limit),
+            Keep::Last(ref mut seen) => seen.limit = Some(limit),
+        }
+        self
+    }
+
+    /// Tracks the input offset of an operation, to find the last occurrence.
+    ///
+    /// This has no effect when keeping the first occurrence of documents.
+    pub fn track(&mut self, operation: &Operation, offset: u64) -> Result<()> {
+        match operation.id() {
+            Some(id) => self.track_document(operation.index(), id, offset),
+            None => Ok(()),
+        }
+    }
+
+    /// Tracks the input offset of a document, by its index and identifier.
+    ///
+    /// This is the same as `track`, for documents read outside of an import.
+    pub fn track_document(&mut self, index: Option<&str>, id: &str, offset: u64) -> Result<()> {
+        if let Keep::Last(ref mut offsets) = self.0 {
+            offsets.insert(key(index, id), offset)?;
+        }
+        Ok(())
+    }
+
+    /// Determines whether an operation should be kept, or dropped.
+    ///
+    /// Operations without an identifier are never considered duplicates.
+    pub fn keep(&self, operation: &Operation, offset: u64) -> Result<bool> {
+        match operation.id() {
+            Some(id) => self.keep_document(operation.index(), id, offset),
+            None => Ok(true),
+        }
+    }
+
+    /// Determines whether a document should be kept, by its index and identifier.
+    ///
+    /// This is the same as `keep`, for documents read outside of an import.
+    pub fn keep_document(&self, index: Option<&str>, id: &str, offset: u64) -> Result<bool> {
+        let key = key(index, id);
+        match self.0 {
+            Keep::First(ref seen) => {
+                let mut seen = seen.lock().expect("poisoned lock");
+                Ok(seen.insert(key, ())?.is_none())
+            }
+            Keep::Last(ref offsets) => {
+                Ok(offsets.entries.get(&key).is_none_or(|last| *last == offset))
+            }
+        }
+    }
+}
+
+impl<V> Seen<V> {
+    /// Constructs an empty set of documents, without a limit.
+    fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+            size: 0,
+            limit: None,
+        }
+    }
+
+    /// Stores the value of a document, returning any previous value.
+    ///
+    /// New documents which would take the memory used past the limit are
+    /// rejected with an error, rather than being stored.
+    fn insert(&mut self, key: Key, value: V) -> Result<Option<V>> {
+        if let Some(previous) = self.entries.get_mut(&key) {
+            return Ok(Some(std::mem::replace(previous, value)));
+        }
+
+        // check the new document fits within the limit
+        let size = key.0.as_ref().map_or(0, |index| index.len()) + key.1.len() + ENTRY_OVERHEAD;
+        if let Some(limit) = self.limit.filter(|limit| self.size + size > *limit) {
+            return Err(anyhow!(
+                "Unable to track more than {} documents for de-duplication within {}",
+                self.entries.len(),
+                units::format_bytes(limit as u64)
+            ));
+        }
+
+        self.size += size;
+        self.entries.insert(key, value);
+        Ok(None)
+    }
+}
+
+/// Generates the key of a document from the index and identifier.
+fn key(index: Option<&str>, id: &str) -> Key {
+    (index.map(Box::from), Box::from(id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_occurrences_are_kept() {
+        let dedupe = Dedupe::first();
+        assert!(dedupe.keep_document(Some("a"), "1", 0).unwrap());
+        assert!(dedupe.keep_document(Some("b"), "1", 1).unwrap());
+        assert!(dedupe.keep_document(None, "1", 2).unwrap());
+        assert!(!dedupe.keep_document(Some("a"), "1", 3).unwrap());
+        assert!(!dedupe.keep_document(None, "1", 4).unwrap());
+    }
+
+    #[test]
+    fn last_occurrences_are_kept() {
+        let mut dedupe = Dedupe::last();
+        for (id, offset) in [("1", 0), ("2", 1), ("1", 2)] {
+            dedupe.track_document(Some("a"), id, offset).unwrap();
+        }
+        assert!(!dedupe.keep_document(Some("a"), "1", 0).unwrap());
+        assert!(dedupe.keep_document(Some("a"), "2", 1).unwrap());
+        assert!(dedupe.keep_document(Some("a"), "1", 2).unwrap());
+        assert!(dedupe.keep_document(Some("b"), "1", 3).unwrap());
+    }
+
+    #[test]
+    fn keys_are_compared_in_full() {
+        // index and identifier are never joined, so can't run together
+        let dedupe = Dedupe::first();
+        assert!(dedupe.keep_document(Some("ab"), "c", 0).unwrap());
+        assert!(dedupe.keep_document(Some("a"), "bc", 1).unwrap());
+        assert!(dedupe.keep_document(None, "abc", 2).unwrap());
+        assert!(dedupe.keep_document(Some(""), "abc", 3).unwrap());
+    }
+
+    #[test]
+    fn limits_reject_new_documents() {
+        let limit = 2 * (ENTRY_OVERHEAD + 2);
+        let dedupe = Dedupe::first().with_limit(limit);
+        assert!(dedupe.keep_document(Some("a"), "1", 0).unwrap());
+        assert!(dedupe.keep_document(Some("a"), "2", 1).unwrap());
+
+        // documents already seen can still be checked
+        assert!(!dedupe.keep_document(Some("a"), "1", 2).unwrap());
+
+        let err = dedupe.keep_document(Some("a"), "3", 3).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unable to track more than 2 documents for de-duplication within 132b"
+        );
+
+        let mut dedupe = Dedupe::last().with_limit(limit);
+        dedupe.track_document(Some("a"), "1", 0).unwrap();
+        dedupe.track_document(Some("a"), "2", 1).unwrap();
+        dedupe.track_document(Some("a"), "1", 2).unwrap();
+        assert!(dedupe.track_document(Some("a"), "3", 3).is_err());
+    }
+}
@@ -0,0 +1,154 @@
+//! Utility functions for inspecting document contents.
+//!
+//! This module offers helpers to locate (and modify) fields within a document
+//! source, and to interpret field values in the formats Elasticsearch accepts.
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, SecondsFormat, TimeZone, Utc};
+use serde_json::{Map, Value};
+
+/// Timestamp formats without timezone information, assumed to be UTC.
+const NAIVE_FORMATS: [&str; 2] = ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"];
+
+/// Locates a field within a document source.
+///
+/// Fields can be provided using dot notation to locate nested fields, but
+/// a field containing literal dots is always checked first, to match the
+/// way Elasticsearch resolves field names.
+pub fn lookup<'a>(source: &'a Value, field: &str) -> Option<&'a Value> {
+    // check for an exact match first
+    if let Some(value) = source.get(field) {
+        return Some(value);
+    }
+
+    // walk through the nested objects on each dot
+    let (head, tail) = field.split_once('.')?;
+    lookup(source.get(head)?, tail)
+}
+
+/// Removes a field from a document source, returning the removed value.
+///
+/// Fields are located in the same way as `lookup`, so literal dots are
+/// checked before walking into nested fields.
+pub fn remove(source: &mut Value, field: &str) -> Option<Value> {
+    let object = source.as_object_mut()?;
+
+    // check for an exact match first
+    if let Some(value) = object.remove(field) {
+        return Some(value);
+    }
+
+    // walk through the nested objects on each dot
+    let (head, tail) = field.split_once('.')?;
+    remove(object.get_mut(head)?, tail)
+}
+
+/// Inserts a field into a document source, replacing any existing value.
+///
+/// Existing fields are located in the same way as `lookup`. If the field
+/// does not exist, any missing objects along the dotted path are created.
+pub fn insert(source: &mut Value, field: &str, value: Value) {
+    // only objects can contain fields
+    let object = match source.as_object_mut() {
+        Some(object) => object,
+        None => return,
+    };
+
+    // replace any exact match, or insert a field without dots
+    let (head, tail) = match field.split_once('.') {
+        Some(split) if !object.contains_key(field) => split,
+        _ => {
+            object.insert(field.to_owned(), value);
+            return;
+        }
+    };
+
+    // walk into the nested object, creating it if needed
+    match object
+        .entry(head)
+        .or_insert_with(|| Value::Object(Map::new()))
+    {
+        nested @ Value::Object(_) => insert(nested, tail, value),
+        _ => {
+            object.insert(field.to_owned(), value);
+        }
+    }
+}
+
+/// Parses a field value into a UTC timestamp.
+///
+/// Numbers (and numeric strings) are treated as milliseconds since epoch,
+/// which is the default format used by Elasticsearch. Strings are parsed
+/// as RFC 3339 timestamps, falling back to a few common variants without
+/// timezones (which are then assumed to be UTC).
+pub fn parse_date(value: &Value) -> Option<DateTime<Utc>> {
+    // numeric values are always epoch millis
+    if let Some(millis) = value.as_i64() {
+        return Utc.timestamp_millis_opt(millis).single();
+    }
+
+    // anything else has to be a string
+    let input = value.as_str()?;
+
+    // numeric strings are also treated as epoch millis
+    if let Ok(millis) = input.parse::<i64>() {
+        return Utc.timestamp_millis_opt(millis).single();
+    }
+
+    // the most common case of a full timestamp with offset
+    if let Ok(parsed) = DateTime::parse_from_rfc3339(input) {
+        return Some(parsed.with_timezone(&Utc));
+    }
+
+    // timestamps without any timezone information
+    for format in NAIVE_FORMATS {
+        if let Ok(parsed) = NaiveDateTime::parse_from_str(input, format) {
+            return Some(Utc.from_utc_datetime(&parsed));
+        }
+    }
+
+    // plain dates, assumed to be the start of the day
+    let parsed = NaiveDate::parse_from_str(input, "%Y-%m-%d").ok()?;
+    Some(Utc.from_utc_datetime(&parsed.and_hms_opt(0, 0, 0)?))
+}
+
+/// Shifts a date field value by an offset, keeping the original format.
+///
+/// Values are interpreted in the same way as `parse_date`, and written back
+/// in the same form they were provided in. Plain dates are only shifted by
+/// whole days. If the value cannot be parsed as a date, `None` is returned.
+pub fn shift_date(value: &Value, offset: Duration) -> Option<Value> {
+    // numeric values are always epoch millis
+    if let Some(millis) = value.as_i64() {
+        return Some(Value::from(millis.checked_add(offset.num_milliseconds())?));
+    }
+
+    // anything else has to be a string
+    let input = value.as_str()?;
+
+    // numeric strings are also treated as epoch millis
+    if let Ok(millis) = input.parse::<i64>() {
+        let shifted = millis.checked_add(offset.num_milliseconds())?;
+        return Some(Value::String(shifted.to_string()));
+    }
+
+    // the most common case of a full timestamp with offset
+    if let Ok(parsed) = DateTime::parse_from_rfc3339(input) {
+        let shifted = parsed.checked_add_signed(offset)?;
+        let zulu = input.ends_with('Z') || input.ends_with('z');
+        return Some(Value::String(
+            shifted.to_rfc3339_opts(SecondsFormat::AutoSi, zulu),
+        ));
+    }
+
+    // timestamps without any timezone information
+    for format in NAIVE_FORMATS {
+        if let Ok(parsed) = NaiveDateTime::parse_from_str(input, format) {
+            let shifted = parsed.checked_add_signed(offset)?;
+            return Some(Value::String(shifted.format(format).to_string()));
+        }
+    }
+
+    // plain dates, shifted by whole days
+    let parsed = NaiveDate::parse_from_str(input, "%Y-%m-%d").ok()?;
+    let shifted = parsed.checked_add_signed(Duration::days(offset.num_days()))?;
+    Some(Value::String(shifted.format("%Y-%m-%d").to_string()))
+}
@@ -0,0 +1,208 @@
+//! Controls over the encoding of exported documents.
+//!
+//! Clusters will happily return documents containing invalid UTF-8, or JSON
+//! escapes of lone UTF-16 surrogates, neither of which can be decoded by a
+//! strict consumer. This module allows either to be rejected or replaced
+//! before a page is parsed, and allows output to be restricted to ASCII by
+//! escaping everything else.
+use anyhow::{anyhow, Result};
+
+use std::borrow::Cow;
+
+/// Policies available to handle invalid Unicode within a response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnicodePolicy {
+    /// Passes surrogates through as is, and fails on invalid UTF-8.
+    Keep,
+    /// Fails on anything which isn't valid Unicode.
+    Reject,
+    /// Replaces anything which isn't valid Unicode with `U+FFFD`.
+    Replace,
+}
+
+/// Escape used to replace a lone surrogate.
+const REPLACEMENT: &[u8] = b"\\ufffd";
+
+/// Applies a policy to the invalid Unicode within a JSON response body.
+///
+/// The body is only copied when something has to be replaced.
+pub fn sanitize(body: &[u8], policy: UnicodePolicy) -> Result<Cow<'_, [u8]>> {
+    // nothing to check when keeping everything
+    if policy == UnicodePolicy::Keep {
+        return Ok(Cow::Borrowed(body));
+    }
+
+    // invalid byte sequences are replaced with the replacement character
+    let body = match (std::str::from_utf8(body), policy) {
+        (Ok(_), _) => Cow::Borrowed(body),
+        (Err(err), UnicodePolicy::Reject) => {
+            return Err(anyhow!(
+                "Response contains invalid UTF-8 at byte {}",
+                err.valid_up_to()
+            ))
+        }
+        (Err(_), _) => match String::from_utf8_lossy(body) {
+            Cow::Owned(body) => Cow::Owned(body.into_bytes()),
+            Cow::Borrowed(_) => Cow::Borrowed(body),
+        },
+    };
+
+    // locate any lone surrogates written as escapes
+    let lone = surrogates(&body);
+    if lone.is_empty() {
+        return Ok(body);
+    }
+    if policy == UnicodePolicy::Reject {
+        return Err(anyhow!(
+            "Response contains a lone surrogate at byte {}",
+            lone[0]
+        ));
+    }
+
+    // swap each lone surrogate escape for the replacement character
+    let mut replaced = Vec::with_capacity(body.len());
+    let mut start = 0;
+    for offset in lone {
+        replaced.extend_from_slice(&body[start..offset]);
+        replaced.extend_from_slice(REPLACEMENT);
+        start = offset + REPLACEMENT.len();
+    }
+    replaced.extend_from_slice(&body[start..]);
+
+    Ok(Cow::Owned(replaced))
+}
+
+/// Escapes every non-ASCII character within a JSON document as `\uXXXX`.
+///
+/// Characters outside of the basic multilingual plane are written as a
+/// surrogate pair, as required by JSON.
+pub fn escape_ascii(input: &[u8]) -> Result<Cow<'_, [u8]>> {
+    // nothing to do when everything is already ASCII
+    if input.is_ascii() {
+        return Ok(Cow::Borrowed(input));
+    }
+
+    // every escape takes six bytes per UTF-16 unit
+    let mut escaped = Vec::with_capacity(input.len() * 2);
+    let mut units = [0; 2];
+    for c in std::str::from_utf8(input)?.chars() {
+        if c.is_ascii() {
+            escaped.push(c as u8);
+            continue;
+        }
+        for unit in c.encode_utf16(&mut units) {
+            escaped.extend_from_slice(format!("\\u{:04X}", unit).as_bytes());
+        }
+    }
+
+    Ok(Cow::Owned(escaped))
+}
+
+/// Locates the offset of every lone surrogate escape within a JSON body.
+///
+/// Backslashes can only appear inside strings within valid JSON, so each
+/// escape can be found without tracking whether we're inside a string.
+fn surrogates(body: &[u8]) -> Vec<usize> {
+    let mut lone = Vec::new();
+    let mut offset = 0;
+    while offset < body.len() {
+        // skip straight to the next escape
+        if body[offset] != b'\\' {
+            offset += 1;
+            continue;
+        }
+
+        // only unicode escapes can contain a surrogate
+        let unit = match unicode_escape(body, offset) {
+            Some(unit) => unit,
+            None => {
+                offset += 2;
+                continue;
+            }
+        };
+
+        // high surrogates must be followed by a low surrogate
+        match unit {
+            0xD800..=0xDBFF => match unicode_escape(body, offset + 6) {
+                Some(0xDC00..=0xDFFF) => offset += 12,
+                _ => {
+                    lone.push(offset);
+                    offset += 6;
+                }
+            },
+            0xDC00..=0xDFFF => {
+                lone.push(offset);
+                offset += 6;
+            }
+            _ => offset += 6,
+        }
+    }
+    lone
+}
+
+/// Parses the unit of a `\uXXXX` escape at an offset, if there is one.
+fn unicode_escape(body: &[u8], offset: usize) -> Option<u16> {
+    let escape = body.get(offset..offset + 6)?;
+    if &escape[..2] != b"\\u" {
+        return None;
+    }
+    u16::from_str_radix(std::str::from_utf8(&escape[2..]).ok()?, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_keeps_everything() {
+        let body = b"{\"a\":\"\\ud800\",\"b\":\"\xff\"}";
+        let sanitized = sanitize(body, UnicodePolicy::Keep).unwrap();
+        assert!(matches!(sanitized, Cow::Borrowed(_)));
+        assert_eq!(&*sanitized, &body[..]);
+    }
+
+    #[test]
+    fn sanitize_rejects_invalid_utf8() {
+        let err = sanitize(b"{\"a\":\"\xff\"}", UnicodePolicy::Reject).unwrap_err();
+        assert_eq!(err.to_string(), "Response contains invalid UTF-8 at byte 6");
+    }
+
+    #[test]
+    fn sanitize_rejects_lone_surrogates() {
+        let err = sanitize(br#"{"a":"x\udc00"}"#, UnicodePolicy::Reject).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Response contains a lone surrogate at byte 7"
+        );
+
+        // pairs are valid, and escaped backslashes aren't escapes at all
+        let body = br#"{"a":"\ud83d\ude00","b":"\\ud800"}"#;
+        let sanitized = sanitize(body, UnicodePolicy::Reject).unwrap();
+        assert!(matches!(sanitized, Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn sanitize_replaces_invalid_unicode() {
+        let body = b"{\"a\":\"\xff\",\"b\":\"\\ud83d\",\"c\":\"\\ud83d\\ude00\"}";
+        let sanitized = sanitize(body, UnicodePolicy::Replace).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&sanitized).unwrap(),
+            "{\"a\":\"\u{fffd}\",\"b\":\"\\ufffd\",\"c\":\"\\ud83d\\ude00\"}"
+        );
+    }
+
+    #[test]
+    fn escape_ascii_leaves_ascii_alone() {
+        let escaped = escape_ascii(br#"{"a":"plain"}"#).unwrap();
+        assert!(matches!(escaped, Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn escape_ascii_escapes_astral_characters_as_pairs() {
+        let escaped = escape_ascii("{\"a\":\"é😀\"}".as_bytes()).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&escaped).unwrap(),
+            r#"{"a":"\u00E9\uD83D\uDE00"}"#
+        );
+    }
+}
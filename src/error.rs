@@ -0,0 +1,77 @@
+//! Error classification for imports and exports.
+//!
+//! Errors are generally surfaced via `anyhow`, but some failures are worth
+//! distinguishing from others so that callers can react to them (such as by
+//! exiting with a specific code). These are raised as an `Error` with a kind
+//! attached, and can be located again in any error chain via `ErrorKind::of`.
+use std::error;
+use std::fmt::{self, Display, Formatter};
+
+/// Kinds of failure which can be distinguished by callers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The cluster could not be reached, or rejected the credentials.
+    Connection,
+    /// Some documents failed to be processed.
+    Documents,
+    /// The cluster failed verification once the process completed.
+    Verification,
+    /// The process was shut down before it could complete.
+    Interrupted,
+}
+
+impl ErrorKind {
+    /// Locates the kind of failure within an error chain, if known.
+    ///
+    /// Any `Error` in the chain takes priority, otherwise connection errors
+    /// are detected from the underlying HTTP errors (including responses
+    /// rejecting the credentials provided).
+    pub fn of(err: &anyhow::Error) -> Option<Self> {
+        // explicit kinds always take priority
+        if let Some(err) = err.chain().find_map(|err| err.downcast_ref::<Error>()) {
+            return Some(err.kind);
+        }
+
+        // detect failures to connect or authenticate with the cluster
+        err.chain()
+            .filter_map(|err| err.downcast_ref::<reqwest::Error>())
+            .any(|err| {
+                err.is_connect()
+                    || err.is_timeout()
+                    || err
+                        .status()
+                        .is_some_and(|status| status.as_u16() == 401 || status.as_u16() == 403)
+            })
+            .then_some(ErrorKind::Connection)
+    }
+}
+
+/// Error raised with a distinguishable kind of failure.
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    message: String,
+}
+
+impl Error {
+    /// Constructs a new error of a kind, with a message.
+    pub fn new<S: Into<String>>(kind: ErrorKind, message: S) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    /// Retrieves the kind of failure this error represents.
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl error::Error for Error {}
@@ -0,0 +1,776 @@
+//! Export module for Limber.
+//!
+//! This module exposes functions to export an Elasticsearch target index to
+//! any async writer. This allows the caller to pipe into any compression
+//! algorithms they may wish to use, and store in any container they might
+//! wish to use.
+//!
+//! This interface also allows chaining into another instance of Limber, to
+//! enable piping from one cluster/index to another in a streaming fashion.
+use anyhow::{anyhow, Result};
+use elasticsearch::{ClearScrollParts, Elasticsearch, ScrollParts, SearchParts};
+use futures::prelude::*;
+use serde::de::{MapAccess, Visitor};
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::value::RawValue;
+use serde_json::{json, Value};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::Mutex;
+use tracing::{debug, debug_span, info, warn, Instrument};
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::cluster::Cluster;
+use crate::encoding::{self, UnicodePolicy};
+use crate::error::{Error, ErrorKind};
+use crate::format::Format;
+use crate::join;
+use crate::preflight;
+use crate::remote::{self, ClientOptions};
+use crate::retry::Policy;
+use crate::shutdown::Shutdown;
+use crate::stats::Tracker;
+use crate::throttle::Throttle;
+use crate::transform::Transform;
+
+/// Options used to control an export.
+///
+/// Options should be constructed via `ExportOptions::new`, which provides
+/// the same defaults as the CLI, before adjusting any fields as necessary.
+pub struct ExportOptions {
+    /// The source cluster (and optionally index) to export documents from.
+    pub source: String,
+    /// Options used to connect to the source cluster.
+    pub client: ClientOptions,
+    /// The number of slices to scroll through concurrently.
+    pub concurrency: usize,
+    /// A query to use to filter exported documents.
+    pub query: Value,
+    /// The number of documents to pull per request.
+    pub size: usize,
+    /// The number of times to retry a failed request.
+    pub retries: usize,
+    /// The initial delay between retries of a failed request.
+    pub retry_backoff: Duration,
+    /// Whether to check the health of the cluster before starting.
+    pub preflight: bool,
+    /// Whether to export each index separately, scheduled by size.
+    pub per_index: bool,
+    /// Whether to export parent documents before their children.
+    pub parents_first: bool,
+    /// Transforms applied to the source of each document.
+    pub transforms: Vec<Transform>,
+    /// Whether to write the source of each document exactly as received.
+    pub exact: bool,
+    /// The format documents are written in.
+    pub format: Format,
+    /// Whether to escape every non-ASCII character in the output.
+    pub ascii_only: bool,
+    /// How to handle invalid Unicode returned by the cluster.
+    pub unicode: UnicodePolicy,
+    /// A tracker to observe the state of the export with.
+    pub tracker: Arc<Tracker>,
+    /// A signal used to shut down the export early.
+    pub shutdown: Shutdown,
+}
+
+impl ExportOptions {
+    /// Constructs options to export all documents from a source.
+    pub fn new<S: Into<String>>(source: S) -> Self {
+        Self {
+            source: source.into(),
+            client: ClientOptions::default(),
+            concurrency: 1,
+            query: json!({ "match_all": {} }),
+            size: 100,
+            retries: 3,
+            retry_backoff: Duration::from_secs(1),
+            preflight: true,
+            per_index: false,
+            parents_first: false,
+            transforms: Vec::new(),
+            exact: false,
+            format: Format::Ndjson,
+            ascii_only: false,
+            unicode: UnicodePolicy::Keep,
+            tracker: Tracker::shared(),
+            shutdown: Shutdown::new(),
+        }
+    }
+}
+
+/// Exports documents from a source cluster to a writer.
+///
+/// Each document is written as a single line of JSON (optionally prefixed
+/// with a record separator), in the format expected by an import. When using
+/// concurrency, each slice is scrolled concurrently and pages are written to
+/// the writer as they arrive.
+///
+/// Transient failures of each request are retried, but any other failure
+/// halts the export, as skipping a page would silently lose documents.
+///
+/// The `_routing` of each document is always retained, as it's required to
+/// restore the children of any join field. When exporting parents first, the
+/// export is run once for each depth of relation, from parents to children.
+///
+/// When exporting exactly, the `_source` of each document is written as the
+/// exact bytes returned by the cluster, and any source which can't be kept
+/// on a single line fails the export rather than being reformatted.
+///
+/// If shut down early, every page already fetched is written before the
+/// scrolls are cleared, and an `ErrorKind::Interrupted` error is returned.
+pub async fn export<W>(options: ExportOptions, writer: W) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    // transforms have to rewrite the source, so can't be exact
+    if options.exact && !options.transforms.is_empty() {
+        return Err(anyhow!(
+            "Transforms cannot be applied when exporting sources exactly"
+        ));
+    }
+
+    // escaping and replacing characters both rewrite the source
+    if options.exact && (options.ascii_only || options.unicode == UnicodePolicy::Replace) {
+        return Err(anyhow!(
+            "Characters cannot be escaped or replaced when exporting sources exactly"
+        ));
+    }
+
+    // parse the source into a host/index pairing for later
+    let (host, index) = remote::parse_cluster(&options.source)?;
+
+    // shim the index value when needed by defaulting to all
+    let index = index.unwrap_or_else(|| "_all".to_string());
+
+    // construct a single client instance for all slices
+    let client = remote::connect(&[host], &options.client).await?;
+
+    // detect the cluster, rejecting any which aren't supported
+    Cluster::detect(&client).await?;
+
+    // make sure the indices are healthy enough to export from
+    if options.preflight {
+        preflight::check(&client, Some(&index)).await?;
+    }
+
+    // report a malformed query once, rather than from every slice
+    preflight::validate_query(&client, &index, &options.query).await?;
+
+    // create shared state
+    let tracker = &options.tracker;
+    let writer = Mutex::new(writer);
+
+    // mark the start of the export for any observers
+    tracker.start();
+
+    // count the documents to export, to allow tracking progress
+    match remote::count(&client, &index, Some(&options.query)).await {
+        Ok(total) => tracker.set_total_documents(total),
+        Err(err) => debug!(error = %err, "Unable to count documents to export"),
+    }
+
+    // fetch the concurrency factor, with at least a single slice
+    let concurrency = options.concurrency.max(1);
+
+    // share a single throttle across all slices, if limited
+    let throttle = options.client.max_bandwidth.map(Throttle::new);
+
+    // split the export by depth of relation when ordering parents first
+    let queries = if options.parents_first {
+        let fields = join::detect(&client, &index).await?;
+        for field in &fields {
+            info!(field = %field.path, "Exporting parents before children");
+        }
+        join::passes(&options.query, &fields)
+    } else {
+        vec![options.query.clone()]
+    };
+
+    for (depth, query) in queries.iter().enumerate() {
+        // skip any further depths when shutting down
+        if options.shutdown.is_triggered() {
+            break;
+        }
+
+        if queries.len() > 1 {
+            debug!(depth, "Exporting relations");
+        }
+
+        if options.per_index {
+            // schedule each index across the workers separately
+            schedule(&client, &options, throttle.as_ref(), &writer, &index, query).await?;
+            continue;
+        }
+
+        // create a scroll for each slice
+        let mut tasks = Vec::with_capacity(concurrency);
+        for idx in 0..concurrency {
+            let task = scroll(
+                &client,
+                &options,
+                idx,
+                throttle.as_ref(),
+                &writer,
+                &index,
+                construct_query(&options, query, idx, concurrency),
+            );
+
+            // attach the slice to any events emitted by the scroll
+            tasks.push(task.instrument(debug_span!("slice", id = idx)));
+        }
+
+        // attempt to join all scrolls
+        future::try_join_all(tasks).await?;
+    }
+
+    // make sure everything is written out
+    writer.into_inner().flush().await?;
+
+    // report an early shutdown, now that everything is written
+    if options.shutdown.is_triggered() {
+        return Err(Error::new(ErrorKind::Interrupted, "Export was interrupted").into());
+    }
+
+    // report the outcome of the export
+    debug!(
+        processed = tracker.documents(),
+        elapsed_ms = tracker.elapsed().as_millis() as u64,
+        "Export complete"
+    );
+
+    // complete!
+    Ok(())
+}
+
+/// Unit of work assigned to a worker when exporting each index separately.
+struct Unit {
+    /// The index to scroll through.
+    index: String,
+    /// The slice of the index to scroll through.
+    slice: usize,
+    /// The number of slices the index is split into.
+    max: usize,
+    /// The estimated size of this slice of the index, in bytes.
+    size: u64,
+}
+
+/// Exports each index matching a pattern separately, scheduled by size.
+///
+/// Rather than scrolling through every index as a single scroll, indices are
+/// queued from largest to smallest and handed out to a pool of workers (one
+/// per unit of concurrency) as they become free. Indices much larger than
+/// their share of the export are sliced, so that a single large index never
+/// leaves the rest of the workers idle at the end of the export.
+///
+/// Indices which fail are retried once every other index has finished. As
+/// documents already written by a failed index are written again when it's
+/// retried, the output may contain duplicates (which are simply overwritten
+/// when imported again).
+async fn schedule<W>(
+    client: &Elasticsearch,
+    options: &ExportOptions,
+    throttle: Option<&Throttle>,
+    writer: &Mutex<W>,
+    index: &str,
+    query: &Value,
+) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    // fetch the concurrency factor, with at least a single worker
+    let workers = options.concurrency.max(1);
+
+    // list every index to export, alongside their size
+    let indices = remote::list_indices(client, index).await?;
+    let total = indices.iter().map(|(_, size)| size).sum::<u64>().max(1);
+
+    // slice each index by its share of the export, largest first
+    let mut units = Vec::new();
+    for (index, size) in indices {
+        let share = (size as f64 * workers as f64 / total as f64).ceil() as usize;
+        let max = share.clamp(1, workers);
+        for slice in 0..max {
+            units.push(Unit {
+                index: index.clone(),
+                slice,
+                max,
+                size: size / max as u64,
+            });
+        }
+    }
+    units.sort_by_key(|unit| std::cmp::Reverse(unit.size));
+
+    debug!(units = units.len(), workers, "Scheduling indices");
+
+    // run everything once, retrying any failures at the end
+    let failed = drain(client, options, throttle, writer, query, workers, units).await;
+    if failed.is_empty() || options.shutdown.is_triggered() {
+        return Ok(());
+    }
+
+    warn!(count = failed.len(), "Retrying failed indices");
+    let units = failed.into_iter().map(|(unit, _)| unit).collect();
+    let failed = drain(client, options, throttle, writer, query, workers, units).await;
+
+    // fail with the first error of anything which failed twice
+    match failed.into_iter().next() {
+        Some((unit, err)) => Err(err.context(format!("Unable to export index {}", unit.index))),
+        None => Ok(()),
+    }
+}
+
+/// Drains a queue of units across a pool of workers.
+///
+/// Each unit which fails is returned alongside its error, rather than
+/// halting the rest of the workers.
+async fn drain<W>(
+    client: &Elasticsearch,
+    options: &ExportOptions,
+    throttle: Option<&Throttle>,
+    writer: &Mutex<W>,
+    query: &Value,
+    workers: usize,
+    units: Vec<Unit>,
+) -> Vec<(Unit, anyhow::Error)>
+where
+    W: AsyncWrite + Unpin,
+{
+    let queue = std::sync::Mutex::new(VecDeque::from(units));
+    let failed = std::sync::Mutex::new(Vec::new());
+
+    // each worker takes the next unit from the queue until it's empty
+    let tasks = (0..workers).map(|worker| {
+        let queue = &queue;
+        let failed = &failed;
+        async move {
+            loop {
+                if options.shutdown.is_triggered() {
+                    break;
+                }
+
+                let unit = match queue.lock().expect("poisoned lock").pop_front() {
+                    Some(unit) => unit,
+                    None => break,
+                };
+
+                // scroll through the unit, recording any failure
+                let query = construct_query(options, query, unit.slice, unit.max);
+                let result = scroll(
+                    client,
+                    options,
+                    worker,
+                    throttle,
+                    writer,
+                    &unit.index,
+                    query,
+                )
+                .instrument(debug_span!("index", name = %unit.index, slice = unit.slice))
+                .await;
+
+                if let Err(err) = result {
+                    warn!(index = %unit.index, error = %err, "Failed to export index");
+                    failed.lock().expect("poisoned lock").push((unit, err));
+                }
+            }
+        }
+        .instrument(debug_span!("worker", id = worker))
+    });
+
+    future::join_all(tasks).await;
+    failed.into_inner().expect("poisoned lock")
+}
+
+/// Executes an async scroll against a given index set using a provided query.
+///
+/// This is separated out from the main loop so it can be run multiple times
+/// concurrently to allow for easy concurrency control via sliced scrolls.
+async fn scroll<W>(
+    client: &Elasticsearch,
+    options: &ExportOptions,
+    slice: usize,
+    throttle: Option<&Throttle>,
+    writer: &Mutex<W>,
+    index: &str,
+    query: Value,
+) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    // scroll params
+    let scroll = "1m";
+
+    // fetch the shared state of the export
+    let tracker = &options.tracker;
+    let shutdown = &options.shutdown;
+
+    // construct the policy used to retry failed requests
+    let policy = Policy::new(options.retries, options.retry_backoff);
+
+    // track the time spent on each page
+    let mut started = Instant::now();
+
+    // reuse a single buffer for every page of the slice
+    let mut buffer = Vec::new();
+
+    // initialize the search request
+    debug!(%query, "Opening scroll");
+    let request = tracker.begin_request();
+    let names = [index];
+    let response = policy
+        .send_counted(|| {
+            client
+                .search(SearchParts::Index(&names))
+                .scroll(scroll)
+                .body(query.clone())
+                .send()
+        })
+        .await;
+    tracker.end_request(request);
+    let (response, mut retries) = response?;
+    let mut response = response.error_for_status_code()?;
+
+    loop {
+        // parse the response body, borrowing each hit as is
+        let body = response.bytes().await?;
+        let latency = started.elapsed();
+        let sanitized = encoding::sanitize(&body, options.unicode)?;
+        let page = serde_json::from_slice::<Page>(&sanitized)?;
+
+        // record the time taken by the cluster against the transfer
+        debug!(
+            took_ms = page.took,
+            latency_ms = latency.as_millis() as u64,
+            bytes = body.len(),
+            retries,
+            "Received scroll page"
+        );
+        if let Some(took) = page.took {
+            tracker.observe_took(Duration::from_millis(took));
+        }
+
+        // pull the hits back out of the page
+        let hits = page
+            .hits
+            .map(|hits| hits.hits)
+            .ok_or_else(|| anyhow!("Unable to locate hits in scroll page"))?;
+
+        // empty hits means we're done
+        if hits.is_empty() {
+            break;
+        }
+
+        // store hit length
+        let length = hits.len();
+
+        // buffer the page so it's written in one go
+        buffer.clear();
+
+        // count the documents in the page from each index
+        let mut indices = BTreeMap::new();
+
+        // iterate docs
+        for hit in hits {
+            // tally the document against the index it came from
+            if let Some(index) = hit.field("_index") {
+                let index = serde_json::from_str::<Cow<str>>(index.get())?;
+                *indices.entry(index).or_insert(0) += 1;
+            }
+
+            // sources spread across lines can't be written as is
+            if options.exact {
+                if let Some(source) = hit.field("_source") {
+                    if source.get().contains('\n') {
+                        return Err(anyhow!(
+                            "Document source spans multiple lines, so cannot be exported exactly"
+                        ));
+                    }
+                }
+            }
+
+            // drop it into the buffer, without any query based fields
+            buffer.extend_from_slice(options.format.prefix());
+            if options.transforms.is_empty() {
+                serde_json::to_writer(&mut buffer, &hit)?;
+            } else {
+                serde_json::to_writer(&mut buffer, &transform(&hit, &options.transforms)?)?;
+            }
+            buffer.push(b'\n');
+        }
+
+        // escape anything outside of ASCII, when asked to
+        let output = if options.ascii_only {
+            encoding::escape_ascii(&buffer)?
+        } else {
+            Cow::Borrowed(&buffer[..])
+        };
+
+        // write the page out
+        writer.lock().await.write_all(&output).await?;
+        tracker.add_bytes(output.len() as u64);
+        for (index, count) in indices {
+            tracker.tally(&index, count);
+        }
+
+        // increment the counter and log the state
+        let processed = tracker.document_counter().increment(length);
+        debug!(documents = length, processed, "Fetched another batch");
+        tracker.record(slice, length, started.elapsed());
+        tracker.sample();
+
+        // fetch the new scroll_id
+        let scroll_id = page
+            .scroll_id
+            .ok_or_else(|| anyhow!("Unable to locate scroll_id in scroll page"))?
+            .into_owned();
+
+        // stop scrolling when shutting down, clearing the scroll context
+        if shutdown.is_triggered() {
+            debug!("Clearing scroll");
+            let cleared = client
+                .clear_scroll(ClearScrollParts::None)
+                .body(json!({ "scroll_id": [scroll_id] }))
+                .send()
+                .await
+                .and_then(|response| response.error_for_status_code());
+            if let Err(err) = cleared {
+                debug!(error = %err, "Unable to clear scroll");
+            }
+            break;
+        }
+
+        // hold off on the next page until the transfer rate allows for it
+        if let Some(throttle) = throttle {
+            throttle.consume(body.len()).await;
+        }
+
+        // fetch next page
+        debug!("Fetching next scroll page");
+        started = Instant::now();
+        let request = tracker.begin_request();
+        let next = policy
+            .send_counted(|| {
+                client
+                    .scroll(ScrollParts::None)
+                    .body(json!({
+                        "scroll": scroll,
+                        "scroll_id": scroll_id
+                    }))
+                    .send()
+            })
+            .await;
+        tracker.end_request(request);
+        let (next, attempts) = next?;
+        response = next.error_for_status_code()?;
+        retries = attempts;
+    }
+
+    Ok(())
+}
+
+/// Page of hits returned by a scroll.
+#[derive(Deserialize)]
+struct Page<'a> {
+    took: Option<u64>,
+    #[serde(rename = "_scroll_id", borrow)]
+    scroll_id: Option<Cow<'a, str>>,
+    #[serde(borrow)]
+    hits: Option<Hits<'a>>,
+}
+
+/// Hits nested within a `Page`.
+#[derive(Deserialize)]
+struct Hits<'a> {
+    #[serde(borrow)]
+    hits: Vec<Hit<'a>>,
+}
+
+/// Hit borrowed from a `Page`, as the raw JSON of each field.
+///
+/// Fields are kept in the order they were received, and are written back
+/// out as is (minus any query based fields), avoiding the cost of parsing
+/// every document into a `Value` only to serialize it straight back again.
+/// The only exception is a field spanning multiple lines (such as a source
+/// stored pretty printed), which is compacted to keep each hit on one line.
+struct Hit<'a> {
+    fields: Vec<(Cow<'a, str>, &'a RawValue)>,
+}
+
+impl<'a> Hit<'a> {
+    /// Fields which only describe the query, and are dropped from output.
+    const DROPPED: [&'static str; 2] = ["sort", "_score"];
+
+    /// Retrieves the raw JSON of a field of the hit, if present.
+    fn field(&self, name: &str) -> Option<&'a RawValue> {
+        self.fields
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| *value)
+    }
+}
+
+impl<'de: 'a, 'a> Deserialize<'de> for Hit<'a> {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        /// Key of a hit, borrowed whenever it contains no escapes.
+        #[derive(Deserialize)]
+        struct Key<'a>(#[serde(borrow)] Cow<'a, str>);
+
+        /// Visitor collecting the fields of a hit in order.
+        struct HitVisitor<'a>(PhantomData<Hit<'a>>);
+
+        impl<'de: 'a, 'a> Visitor<'de> for HitVisitor<'a> {
+            type Value = Hit<'a>;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a hit object")
+            }
+
+            fn visit_map<A>(self, mut map: A) -> std::result::Result<Self::Value, A::Error>
+            where
+                A: MapAccess<'de>,
+            {
+                let mut fields = Vec::with_capacity(map.size_hint().unwrap_or(8));
+                while let Some(Key(key)) = map.next_key()? {
+                    fields.push((key, map.next_value()?));
+                }
+                Ok(Hit { fields })
+            }
+        }
+
+        deserializer.deserialize_map(HitVisitor(PhantomData))
+    }
+}
+
+impl Serialize for Hit<'_> {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = serializer.serialize_map(None)?;
+        for (key, value) in &self.fields {
+            if Self::DROPPED.contains(&key.as_ref()) {
+                continue;
+            }
+            // newlines can only be whitespace, but would split the output line
+            if value.get().contains('\n') {
+                map.serialize_entry(key, &compact(value.get()))?;
+            } else {
+                map.serialize_entry(key, value)?;
+            }
+        }
+        map.end()
+    }
+}
+
+/// Compacts raw JSON by removing any whitespace outside of strings.
+///
+/// Everything else is kept byte for byte, so field order and the formatting
+/// of numbers are exactly as they were received.
+fn compact(raw: &str) -> Box<RawValue> {
+    let mut compacted = String::with_capacity(raw.len());
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for c in raw.chars() {
+        if in_string {
+            // track escapes, so an escaped quote doesn't end the string
+            match (escaped, c) {
+                (true, _) => escaped = false,
+                (false, '\\') => escaped = true,
+                (false, '"') => in_string = false,
+                _ => (),
+            }
+        } else if matches!(c, ' ' | '\t' | '\n' | '\r') {
+            continue;
+        } else if c == '"' {
+            in_string = true;
+        }
+        compacted.push(c);
+    }
+
+    // the input was already valid JSON, and only whitespace was removed
+    RawValue::from_string(compacted).expect("compacted JSON is valid")
+}
+
+/// Applies transforms to the source of a hit.
+///
+/// Hits are otherwise written out without ever being parsed, so this is only
+/// used when transforms are provided. Fields are written in sorted order, as
+/// the order received can't be kept once parsed.
+fn transform(hit: &Hit, transforms: &[Transform]) -> Result<Value> {
+    let mut document = serde_json::to_value(hit)?;
+    for transform in transforms {
+        transform.apply(&mut document["_source"])?;
+    }
+    Ok(document)
+}
+
+/// Constructs a query instance based on the handle count and identifier.
+///
+/// Each handle is assigned a slice of the scroll when using concurrency,
+/// so that every document is only exported by a single handle.
+fn construct_query(options: &ExportOptions, query: &Value, id: usize, max: usize) -> Value {
+    // construct query
+    let mut query = json!({
+        "query": query,
+        "size": options.size,
+        "sort": [
+            "_doc"
+        ],
+        "version": true
+    });
+
+    // handle multiple handles...
+    if max > 1 {
+        // ... by adding the slice identifier
+        query["slice"] = json!({
+            "id": id,
+            "max": max
+        });
+    }
+
+    // pass back!
+    query
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hits_with_multiline_sources_are_compacted() {
+        let page = r#"{"_index":"idx","_id":"1","_score":1.0,"_source":{
+          "b": "line one\nline \"two\"",
+          "a": [1, 2.50, {"c": " spaced "}]
+        }}"#;
+        let hit = serde_json::from_str::<Hit>(page).unwrap();
+        let written = serde_json::to_string(&hit).unwrap();
+
+        assert!(!written.contains('\n'));
+        assert_eq!(
+            written,
+            r#"{"_index":"idx","_id":"1","_source":{"b":"line one\nline \"two\"","a":[1,2.50,{"c":" spaced "}]}}"#
+        );
+    }
+
+    #[test]
+    fn hits_on_a_single_line_are_written_as_is() {
+        let page = r#"{"_index":"idx","_id":"1","_source":{"b": 1,  "a": "x"},"sort":[0]}"#;
+        let hit = serde_json::from_str::<Hit>(page).unwrap();
+        let written = serde_json::to_string(&hit).unwrap();
+
+        assert_eq!(
+            written,
+            r#"{"_index":"idx","_id":"1","_source":{"b": 1,  "a": "x"}}"#
+        );
+    }
+}
@@ -0,0 +1,443 @@
+//! Filter expressions used to select documents by their fields.
+//!
+//! Expressions are deliberately simple, in the form of `field=value` (or any
+//! of `!=`, `>`, `>=`, `<` and `<=`). A bare `field` will match documents in
+//! which the field exists, and `!field` those where it does not. Fields can
+//! be provided in jq style with a leading dot, such as `.user.name=isaac`.
+//!
+//! A small subset of the Elasticsearch query DSL can also be evaluated
+//! locally via a `Query`, which is built up from the same filters.
+use anyhow::{anyhow, Result};
+use serde_json::{Map, Value};
+
+use std::cmp::Ordering;
+
+use crate::document;
+
+/// Filter expression evaluated against a document.
+pub struct Filter {
+    field: String,
+    operator: Operator,
+}
+
+/// Operators supported in a `Filter` expression.
+enum Operator {
+    Exists,
+    Missing,
+    Equal(Value),
+    NotEqual(Value),
+    Greater(Value),
+    GreaterOrEqual(Value),
+    Less(Value),
+    LessOrEqual(Value),
+}
+
+impl Filter {
+    /// Parses a filter from the provided expression.
+    pub fn parse(input: &str) -> Result<Self> {
+        let input = input.trim();
+
+        // a leading negation checks for a missing field
+        if let Some(field) = input.strip_prefix('!') {
+            return Ok(Self {
+                field: parse_field(field, input)?,
+                operator: Operator::Missing,
+            });
+        }
+
+        // locate the start of the operator, if any
+        let offset = match input.find(['=', '!', '<', '>']) {
+            Some(offset) => offset,
+            None => {
+                return Ok(Self {
+                    field: parse_field(input, input)?,
+                    operator: Operator::Exists,
+                })
+            }
+        };
+
+        // split the operator from the value
+        let (field, rest) = input.split_at(offset);
+        let (operator, value): (fn(Value) -> Operator, &str) = match rest.as_bytes() {
+            [b'=', b'=', ..] => (Operator::Equal, &rest[2..]),
+            [b'!', b'=', ..] => (Operator::NotEqual, &rest[2..]),
+            [b'>', b'=', ..] => (Operator::GreaterOrEqual, &rest[2..]),
+            [b'<', b'=', ..] => (Operator::LessOrEqual, &rest[2..]),
+            [b'=', ..] => (Operator::Equal, &rest[1..]),
+            [b'>', ..] => (Operator::Greater, &rest[1..]),
+            [b'<', ..] => (Operator::Less, &rest[1..]),
+            _ => return Err(anyhow!("Invalid filter expression provided: {}", input)),
+        };
+
+        // values are JSON when possible, otherwise a plain string
+        let value = value.trim();
+        let value = serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_owned()));
+
+        Ok(Self {
+            field: parse_field(field, input)?,
+            operator: operator(value),
+        })
+    }
+
+    /// Determines whether a document matches this filter.
+    ///
+    /// Fields are located within the `_source` of the document, unless they
+    /// begin with an underscore (such as `_id`), in which case they're read
+    /// from the document itself. If a field contains an array, the filter
+    /// will match if any of the values within the array match.
+    pub fn matches(&self, document: &Value) -> bool {
+        // locate the field within the document
+        let value = if self.field.starts_with('_') {
+            document::lookup(document, &self.field)
+        } else {
+            document
+                .get("_source")
+                .and_then(|source| document::lookup(source, &self.field))
+        };
+
+        // check for presence before anything else
+        let value = match (&self.operator, value) {
+            (Operator::Exists, value) => return value.is_some_and(|v| !v.is_null()),
+            (Operator::Missing, value) => return value.is_none_or(Value::is_null),
+            (Operator::NotEqual(_), None) => return true,
+            (_, None) => return false,
+            (_, Some(value)) => value,
+        };
+
+        // arrays match if any of the values match
+        match value {
+            Value::Array(values) => values.iter().any(|value| self.compare(value)),
+            value => self.compare(value),
+        }
+    }
+
+    /// Compares a single field value using the operator of this filter.
+    fn compare(&self, value: &Value) -> bool {
+        match &self.operator {
+            Operator::Exists | Operator::Missing => unreachable!("checked by caller"),
+            Operator::Equal(expected) => text(value) == text(expected),
+            Operator::NotEqual(expected) => text(value) != text(expected),
+            Operator::Greater(expected) => order(value, expected) == Some(Ordering::Greater),
+            Operator::GreaterOrEqual(expected) => {
+                order(value, expected).is_some_and(|ordering| ordering != Ordering::Less)
+            }
+            Operator::Less(expected) => order(value, expected) == Some(Ordering::Less),
+            Operator::LessOrEqual(expected) => {
+                order(value, expected).is_some_and(|ordering| ordering != Ordering::Greater)
+            }
+        }
+    }
+}
+
+/// Query evaluated against a document, in a subset of the query DSL.
+///
+/// Supported queries are `match_all`, `term`, `terms`, `range`, `exists`
+/// and `bool` (with `must`, `filter`, `should` and `must_not`). Values are
+/// compared in the same way as filters, rather than being analyzed.
+pub enum Query {
+    /// Matches when every query matches.
+    All(Vec<Query>),
+    /// Matches when any query matches.
+    Any(Vec<Query>),
+    /// Matches when no query matches.
+    Not(Vec<Query>),
+    /// Matches when a filter matches.
+    Filter(Filter),
+}
+
+impl Query {
+    /// Parses a query from its JSON form, with or without a `query` wrapper.
+    pub fn parse(input: &Value) -> Result<Self> {
+        // unwrap the query from a search body, if needed
+        let input = input.get("query").unwrap_or(input);
+        let (kind, body) = single(input, "query")?;
+
+        // construct a filter against a field of the query body
+        let filter = |field: &str, operator: Operator| -> Result<Query> {
+            Ok(Query::Filter(Filter {
+                field: parse_field(field, kind)?,
+                operator,
+            }))
+        };
+
+        match kind {
+            "match_all" => Ok(Query::All(Vec::new())),
+            "exists" => match body.get("field").and_then(Value::as_str) {
+                Some(field) => filter(field, Operator::Exists),
+                None => Err(anyhow!("Exists query must provide a field")),
+            },
+            "term" => {
+                let (field, value) = single(body, kind)?;
+                let value = value.get("value").unwrap_or(value);
+                filter(field, Operator::Equal(value.clone()))
+            }
+            "terms" => {
+                let (field, values) = single(body, kind)?;
+                let values = values
+                    .as_array()
+                    .ok_or_else(|| anyhow!("Terms query must provide an array of values"))?;
+                let filters = values
+                    .iter()
+                    .map(|value| filter(field, Operator::Equal(value.clone())))
+                    .collect::<Result<_>>()?;
+                Ok(Query::Any(filters))
+            }
+            "range" => {
+                let (field, bounds) = single(body, kind)?;
+                let bounds = bounds
+                    .as_object()
+                    .ok_or_else(|| anyhow!("Range query must provide an object of bounds"))?;
+                let filters = bounds
+                    .iter()
+                    .map(|(bound, value)| {
+                        let operator = match bound.as_str() {
+                            "gt" => Operator::Greater,
+                            "gte" => Operator::GreaterOrEqual,
+                            "lt" => Operator::Less,
+                            "lte" => Operator::LessOrEqual,
+                            _ => return Err(anyhow!("Unsupported range bound: {}", bound)),
+                        };
+                        filter(field, operator(value.clone()))
+                    })
+                    .collect::<Result<_>>()?;
+                Ok(Query::All(filters))
+            }
+            "bool" => {
+                let clauses = body
+                    .as_object()
+                    .ok_or_else(|| anyhow!("Bool query must provide an object of clauses"))?;
+                let mut all = Vec::new();
+                let mut should = None;
+                for (clause, queries) in clauses {
+                    // clauses accept a single query, or an array of them
+                    let queries = match queries {
+                        Value::Array(queries) => queries.iter().map(Query::parse).collect(),
+                        query => Query::parse(query).map(|query| vec![query]),
+                    };
+                    match clause.as_str() {
+                        "must" | "filter" => all.extend(queries?),
+                        "should" => should = Some(Query::Any(queries?)),
+                        "must_not" => all.push(Query::Not(queries?)),
+                        _ => return Err(anyhow!("Unsupported bool clause: {}", clause)),
+                    }
+                }
+
+                // should clauses are only required without any must (or filter) clauses
+                let required = !clauses.contains_key("must") && !clauses.contains_key("filter");
+                all.extend(should.filter(|_| required));
+                Ok(Query::All(all))
+            }
+            _ => Err(anyhow!("Unsupported query type: {}", kind)),
+        }
+    }
+
+    /// Determines whether a document matches this query.
+    pub fn matches(&self, document: &Value) -> bool {
+        match self {
+            Query::All(queries) => queries.iter().all(|query| query.matches(document)),
+            Query::Any(queries) => queries.iter().any(|query| query.matches(document)),
+            Query::Not(queries) => !queries.iter().any(|query| query.matches(document)),
+            Query::Filter(filter) => filter.matches(document),
+        }
+    }
+}
+
+/// Unwraps an object with a single key, such as the type of a query.
+fn single<'a>(value: &'a Value, kind: &str) -> Result<(&'a str, &'a Value)> {
+    match value
+        .as_object()
+        .map(Map::iter)
+        .map(|mut iter| (iter.next(), iter.next()))
+    {
+        Some((Some((key, value)), None)) => Ok((key, value)),
+        _ => Err(anyhow!(
+            "Invalid {}, expected an object with a single key",
+            kind
+        )),
+    }
+}
+
+/// Parses a field name from an expression, trimming any jq style prefix.
+fn parse_field(field: &str, input: &str) -> Result<String> {
+    let field = field.trim();
+    let field = field.strip_prefix('.').unwrap_or(field);
+
+    if field.is_empty() {
+        return Err(anyhow!("Invalid filter expression provided: {}", input));
+    }
+
+    Ok(field.to_owned())
+}
+
+/// Converts a value to text, to allow loose equality between types.
+///
+/// This means that a filter of `status=200` will match both the number `200`
+/// and the string `"200"`, as most input formats can't tell the difference.
+fn text(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.to_owned(),
+        value => value.to_string(),
+    }
+}
+
+/// Orders two values, numerically where possible or otherwise as text.
+fn order(value: &Value, expected: &Value) -> Option<Ordering> {
+    match (number(value), number(expected)) {
+        (Some(value), Some(expected)) => value.partial_cmp(&expected),
+        _ => Some(text(value).cmp(&text(expected))),
+    }
+}
+
+/// Converts a value to a number, including numeric strings.
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::String(value) => value.parse().ok(),
+        value => value.as_f64(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn doc(source: Value) -> Value {
+        json!({ "_index": "idx", "_id": "1", "_source": source })
+    }
+
+    #[test]
+    fn filter_parses_operators() {
+        let cases = [
+            ("status=200", "status", "=="),
+            ("status == 200", "status", "=="),
+            ("status!=200", "status", "!="),
+            ("size>1", "size", ">"),
+            ("size>=1", "size", ">="),
+            ("size<1", "size", "<"),
+            ("size<=1", "size", "<="),
+            (".user.name", "user.name", "exists"),
+            ("!.user.name", "user.name", "missing"),
+        ];
+        for (input, field, expected) in cases {
+            let filter = Filter::parse(input).unwrap();
+            let operator = match filter.operator {
+                Operator::Exists => "exists",
+                Operator::Missing => "missing",
+                Operator::Equal(_) => "==",
+                Operator::NotEqual(_) => "!=",
+                Operator::Greater(_) => ">",
+                Operator::GreaterOrEqual(_) => ">=",
+                Operator::Less(_) => "<",
+                Operator::LessOrEqual(_) => "<=",
+            };
+            assert_eq!(
+                (filter.field.as_str(), operator),
+                (field, expected),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn filter_parses_values_as_json_or_text() {
+        let filter = Filter::parse("tags=[1, 2]").unwrap();
+        assert!(matches!(filter.operator, Operator::Equal(Value::Array(_))));
+
+        let filter = Filter::parse("name = isaac newton ").unwrap();
+        assert!(
+            matches!(filter.operator, Operator::Equal(Value::String(ref name)) if name == "isaac newton")
+        );
+    }
+
+    #[test]
+    fn filter_rejects_invalid_expressions() {
+        for input in ["", "!", "=1", ".=1", "a!b", " >= 2"] {
+            let err = Filter::parse(input).err().expect(input);
+            assert_eq!(
+                err.to_string(),
+                format!("Invalid filter expression provided: {}", input.trim())
+            );
+        }
+    }
+
+    #[test]
+    fn filter_matches_documents() {
+        let document =
+            doc(json!({ "status": "200", "tags": ["a", "b"], "size": 10, "gone": null }));
+
+        assert!(Filter::parse("status=200").unwrap().matches(&document));
+        assert!(Filter::parse("tags=b").unwrap().matches(&document));
+        assert!(Filter::parse("size>9").unwrap().matches(&document));
+        assert!(!Filter::parse("size<=9").unwrap().matches(&document));
+        assert!(Filter::parse("_id=1").unwrap().matches(&document));
+        assert!(Filter::parse("!gone").unwrap().matches(&document));
+        assert!(Filter::parse("other!=1").unwrap().matches(&document));
+        assert!(!Filter::parse("other=1").unwrap().matches(&document));
+    }
+
+    #[test]
+    fn query_parses_supported_queries() {
+        let query = Query::parse(&json!({
+            "query": {
+                "bool": {
+                    "must": { "term": { "status": { "value": 200 } } },
+                    "filter": [
+                        { "range": { "size": { "gte": 10, "lt": 20 } } },
+                        { "exists": { "field": "tags" } }
+                    ],
+                    "must_not": { "terms": { "tags": ["c", "d"] } },
+                    "should": { "match_all": {} }
+                }
+            }
+        }))
+        .unwrap();
+
+        assert!(query.matches(&doc(json!({ "status": 200, "size": 10, "tags": "a" }))));
+        assert!(!query.matches(&doc(json!({ "status": 200, "size": 20, "tags": "a" }))));
+        assert!(!query.matches(&doc(json!({ "status": 200, "size": 10, "tags": "c" }))));
+        assert!(!query.matches(&doc(json!({ "status": 200, "size": 10 }))));
+    }
+
+    #[test]
+    fn query_requires_should_without_must() {
+        let query = Query::parse(&json!({
+            "bool": { "should": [{ "term": { "a": 1 } }, { "term": { "b": 1 } }] }
+        }))
+        .unwrap();
+
+        assert!(query.matches(&doc(json!({ "b": 1 }))));
+        assert!(!query.matches(&doc(json!({ "c": 1 }))));
+    }
+
+    #[test]
+    fn query_rejects_unsupported_queries() {
+        let cases = [
+            (
+                json!({ "match": { "a": 1 } }),
+                "Unsupported query type: match",
+            ),
+            (
+                json!({ "term": {}, "range": {} }),
+                "Invalid query, expected an object with a single key",
+            ),
+            (json!({ "exists": {} }), "Exists query must provide a field"),
+            (
+                json!({ "terms": { "a": 1 } }),
+                "Terms query must provide an array of values",
+            ),
+            (
+                json!({ "range": { "a": { "eq": 1 } } }),
+                "Unsupported range bound: eq",
+            ),
+            (
+                json!({ "bool": { "maybe": [] } }),
+                "Unsupported bool clause: maybe",
+            ),
+        ];
+        for (input, expected) in cases {
+            let err = Query::parse(&input).err().expect(expected);
+            assert_eq!(err.to_string(), expected);
+        }
+    }
+}
@@ -0,0 +1,37 @@
+//! Formats used to write (and read back) documents.
+//!
+//! Documents are written as newline delimited JSON by default. They can also
+//! be written as a JSON text sequence (RFC 7464), which prefixes each record
+//! with an ASCII record separator. As a separator can never appear within a
+//! JSON text, a reader can resynchronize on the next record after any part
+//! of the input is corrupted, which isn't possible with plain lines.
+
+/// The ASCII record separator written before each record of a sequence.
+pub const RECORD_SEPARATOR: u8 = 0x1E;
+
+/// Formats available to write documents in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Newline delimited JSON, one document per line.
+    Ndjson,
+    /// A JSON text sequence, with each document prefixed by a separator.
+    JsonSeq,
+}
+
+impl Format {
+    /// Returns the bytes written before each document.
+    pub fn prefix(self) -> &'static [u8] {
+        match self {
+            Format::Ndjson => b"",
+            Format::JsonSeq => &[RECORD_SEPARATOR],
+        }
+    }
+
+    /// Returns the byte which ends each record when reading.
+    pub(crate) fn delimiter(self) -> u8 {
+        match self {
+            Format::Ndjson => b'\n',
+            Format::JsonSeq => RECORD_SEPARATOR,
+        }
+    }
+}
@@ -0,0 +1,79 @@
+//! Identifiers generated from the fields of each document.
+//!
+//! Inputs which lack stable identifiers can't be imported more than once
+//! without duplicating documents. Generating each `_id` from fields which
+//! identify a document (such as a tenant and order number) makes imports of
+//! such inputs idempotent, as the same document always gets the same `_id`.
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use std::fmt::Write;
+
+use crate::naming::Template;
+
+/// Template used to generate the `_id` of each document.
+///
+/// Templates use the same `{field}` placeholders as index templates, and can
+/// optionally be wrapped in a hash function (such as `sha1({a}:{b})`) to keep
+/// identifiers short and free of any characters within the fields.
+pub struct IdTemplate {
+    template: Template,
+    hash: Option<Hash>,
+}
+
+/// Hash functions available to an `IdTemplate`.
+#[derive(Clone, Copy)]
+enum Hash {
+    Sha1,
+    Sha256,
+}
+
+impl IdTemplate {
+    /// Parses a template from an expression, such as `sha1({tenant}:{id})`.
+    pub fn parse(input: &str) -> Result<Self> {
+        let input = input.trim();
+
+        // split out any hash function wrapping the template
+        let (hash, template) = match input.split_once('(') {
+            Some((name, rest)) if rest.ends_with(')') => {
+                let hash = match name {
+                    "sha1" => Hash::Sha1,
+                    "sha256" => Hash::Sha256,
+                    _ => return Err(anyhow!("Unknown hash function in id template: {}", name)),
+                };
+                (Some(hash), &rest[..rest.len() - 1])
+            }
+            _ => (None, input),
+        };
+
+        if template.is_empty() {
+            return Err(anyhow!("Invalid id template provided: {}", input));
+        }
+
+        Ok(Self {
+            template: Template::parse(template)?,
+            hash,
+        })
+    }
+
+    /// Renders an identifier using the fields of a document source.
+    ///
+    /// Hashes are rendered in lowercase hex. If any field is missing, `None`
+    /// is returned, as is the case when rendering a `Template`.
+    pub fn render(&self, source: &Value) -> Option<String> {
+        let rendered = self.template.render_exact(source)?;
+        let digest = match self.hash {
+            None => return Some(rendered),
+            Some(Hash::Sha1) => Sha1::digest(rendered.as_bytes()).to_vec(),
+            Some(Hash::Sha256) => Sha256::digest(rendered.as_bytes()).to_vec(),
+        };
+
+        let mut hex = String::with_capacity(digest.len() * 2);
+        for byte in digest {
+            let _ = write!(hex, "{:02x}", byte);
+        }
+        Some(hex)
+    }
+}
@@ -0,0 +1,2126 @@
+//! Import module for Limber.
+//!
+//! This module exposes functions to import a set of documents to a target
+//! Elasticsearch cluster/index. Documents are read as lines from any async
+//! reader, such as `stdin`, a file, or the output of an export.
+//!
+//! This interface also allows chaining from another instance of Limber, to
+//! enable piping from one cluster/index to another in a streaming fashion.
+use anyhow::{anyhow, Result};
+use bytes::{Bytes, BytesMut};
+use elasticsearch::http::headers::{
+    HeaderMap, HeaderValue, ACCEPT, CONTENT_ENCODING, CONTENT_TYPE,
+};
+use elasticsearch::http::request::Body;
+use elasticsearch::http::response::Response;
+use elasticsearch::http::Method;
+use elasticsearch::indices::{IndicesRefreshParts, IndicesRolloverParts};
+use elasticsearch::params::Refresh;
+use elasticsearch::{BulkParts, Elasticsearch};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::future::{self, FutureExt};
+use futures::stream::{self, Stream, StreamExt, TryStreamExt};
+use serde::Deserialize;
+use serde_json::value::RawValue;
+use serde_json::{json, Map, Value};
+use tokio::io::{AsyncRead, BufReader};
+use tokio::sync::{mpsc, Mutex, Semaphore};
+use tracing::{debug, debug_span, info, warn, Instrument};
+
+use std::borrow::Cow;
+use std::collections::{HashSet, VecDeque};
+use std::io::Write;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::adaptive::Limiter;
+use crate::bulk::{Action, Operation};
+use crate::checkpoint::{Checkpoint, Lock};
+use crate::cluster::{Cluster, Types};
+use crate::dead_letter::DeadLetter;
+use crate::dedupe::Dedupe;
+use crate::error::{Error, ErrorKind};
+use crate::filter::Filter;
+use crate::format::Format;
+use crate::identity::IdTemplate;
+use crate::input;
+use crate::join;
+use crate::json;
+use crate::naming::{Renamer, Template};
+use crate::preflight;
+use crate::remote::{self, ClientOptions, Resource};
+use crate::retry::Policy;
+use crate::script::{Outcome, Script};
+use crate::shutdown::Shutdown;
+use crate::stats::{Counter, Progress, Tracker};
+use crate::throttle::Throttle;
+use crate::transform::Transform;
+
+/// Options used to control an import.
+///
+/// Options should be constructed via `ImportOptions::new`, which provides
+/// the same defaults as the CLI, before adjusting any fields as necessary.
+pub struct ImportOptions {
+    /// The target cluster (and optionally index) to import documents to.
+    pub target: String,
+    /// Additional hosts of the target cluster to spread load across.
+    pub target_hosts: Vec<String>,
+    /// Options used to connect to the target cluster.
+    pub client: ClientOptions,
+    /// The bulk action to apply to each document.
+    pub action: Action,
+    /// The maximum number of documents to send per request.
+    pub size: usize,
+    /// The maximum payload size of each request, if any.
+    pub batch_bytes: Option<usize>,
+    /// The longest time to wait to fill a batch before sending it, if any.
+    pub batch_timeout: Option<Duration>,
+    /// The number of requests to send concurrently.
+    pub concurrency: usize,
+    /// Whether to adapt concurrency to cluster load, up to `concurrency`.
+    pub adaptive: bool,
+    /// The maximum total size of batches buffered in memory, if any.
+    pub max_memory: Option<usize>,
+    /// A file to track progress through the input in, if any.
+    pub checkpoint: Option<String>,
+    /// A hook to commit the position of the input as batches complete, if any.
+    pub commit: Option<Arc<dyn Commit>>,
+    /// The byte offset of the reader within the input.
+    pub offset: u64,
+    /// The total length of the input in bytes, if known, to report progress.
+    pub length: Option<u64>,
+    /// Whether the import is resuming from a previous checkpoint.
+    pub resume: bool,
+    /// Whether to only report what would be imported.
+    pub dry_run: bool,
+    /// A body used to create any missing indices, if any.
+    pub create_with: Option<Value>,
+    /// Whether to disable replicas and refreshes on indices during import.
+    pub turbo: bool,
+    /// The indices to refresh once the import completes.
+    pub refresh: RefreshPolicy,
+    /// Whether to force merge target indices once the import completes.
+    pub force_merge: bool,
+    /// The maximum number of segments to force merge down to, if any.
+    pub max_segments: Option<i64>,
+    /// Whether to fail if document counts don't match once imported.
+    pub strict: bool,
+    /// Whether to check the health of the cluster before starting.
+    pub preflight: bool,
+    /// Roll over the target alias after this many documents, if any.
+    pub rollover_docs: Option<NonZeroUsize>,
+    /// An ingest pipeline to process documents with, if any.
+    pub pipeline: Option<String>,
+    /// The number of active shard copies required to write, if any.
+    pub wait_for_active_shards: Option<String>,
+    /// The number of times to retry a failed request.
+    pub retries: usize,
+    /// The initial delay between retries of a failed request.
+    pub retry_backoff: Duration,
+    /// How to handle documents which failed to import.
+    pub error_policy: ErrorPolicy,
+    /// A file to write documents which failed to import, if any.
+    pub dead_letter: Option<String>,
+    /// A threshold of failed documents to halt the import at, if any.
+    pub max_errors: Option<Budget>,
+    /// A tracker used to drop duplicate documents, if any.
+    pub dedupe: Option<Dedupe>,
+    /// Filters which each document must match to be imported.
+    pub filters: Vec<Filter>,
+    /// Transforms applied to the source of each document, in order.
+    pub transforms: Vec<Transform>,
+    /// Whether to coerce fields to their types within the target mapping.
+    pub coerce_mapping: bool,
+    /// A script run against each document after transforms, if any.
+    pub script: Option<Script>,
+    /// Whether to send the source of each document exactly as provided.
+    pub exact: bool,
+    /// The format documents are read in.
+    pub format: Format,
+    /// Rules used to rename the index of each document.
+    pub renamer: Renamer,
+    /// A template used to render the index of each document, if any.
+    pub template: Option<Template>,
+    /// Whether to index using the `_version` of each document.
+    pub preserve_version: bool,
+    /// Whether to drop the `_id` of each document, generating new ones.
+    pub regenerate_ids: bool,
+    /// Whether documents without an `_id` are given one by the cluster.
+    pub generate_missing_ids: bool,
+    /// A template used to generate the `_id` of each document, if any.
+    pub id_from: Option<IdTemplate>,
+    /// A prefix to attach to the `_id` of each document, if any.
+    pub id_prefix: Option<String>,
+    /// A suffix to attach to the `_id` of each document, if any.
+    pub id_suffix: Option<String>,
+    /// A document type to use for pre-7.x clusters, if any.
+    pub ty: Option<String>,
+    /// A tracker to observe the state of the import with.
+    pub tracker: Arc<Tracker>,
+    /// A signal used to shut down the import early.
+    pub shutdown: Shutdown,
+}
+
+impl ImportOptions {
+    /// Constructs options to import documents to a target.
+    pub fn new<S: Into<String>>(target: S) -> Self {
+        Self {
+            target: target.into(),
+            target_hosts: Vec::new(),
+            client: ClientOptions::default(),
+            action: Action::Index,
+            size: 100,
+            batch_bytes: None,
+            batch_timeout: None,
+            concurrency: 1,
+            adaptive: false,
+            max_memory: None,
+            checkpoint: None,
+            commit: None,
+            offset: 0,
+            length: None,
+            resume: false,
+            dry_run: false,
+            create_with: None,
+            turbo: false,
+            refresh: RefreshPolicy::All,
+            force_merge: false,
+            max_segments: None,
+            strict: false,
+            preflight: true,
+            rollover_docs: None,
+            pipeline: None,
+            wait_for_active_shards: None,
+            retries: 3,
+            retry_backoff: Duration::from_secs(1),
+            error_policy: ErrorPolicy::Skip,
+            dead_letter: None,
+            max_errors: None,
+            dedupe: None,
+            filters: Vec::new(),
+            transforms: Vec::new(),
+            coerce_mapping: false,
+            script: None,
+            exact: false,
+            format: Format::Ndjson,
+            renamer: Renamer::default(),
+            template: None,
+            preserve_version: false,
+            regenerate_ids: false,
+            generate_missing_ids: false,
+            id_from: None,
+            id_prefix: None,
+            id_suffix: None,
+            ty: None,
+            tracker: Tracker::shared(),
+            shutdown: Shutdown::new(),
+        }
+    }
+}
+
+/// Hook used to commit the position of an input as an import progresses.
+///
+/// This allows inputs which aren't files (such as a Kafka topic) to track
+/// their own position, in place of a checkpoint file.
+pub trait Commit: Send + Sync {
+    /// Commits the input up to an offset.
+    ///
+    /// Every document ending at (or before) the offset has been handled, and
+    /// offsets only ever advance between calls.
+    fn commit(&self, offset: u64) -> Result<()>;
+}
+
+/// Policies available to refresh indices once an import completes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RefreshPolicy {
+    /// Refreshes all indices in the cluster.
+    All,
+    /// Refreshes nothing at all.
+    None,
+    /// Refreshes only the indices touched by the import.
+    Target,
+    /// Waits for each bulk request to become visible instead.
+    WaitFor,
+}
+
+/// Imports documents from a reader to a target cluster.
+///
+/// Documents are read as lines (or records of a JSON text sequence) in the
+/// format generated by an export, and sent to the target cluster in batches.
+/// The returned future completes once the reader is exhausted and every batch
+/// has been handled.
+///
+/// If shut down early, no further batches are sent but those in flight are
+/// completed, and the checkpoint (if any) is written before returning an
+/// `ErrorKind::Interrupted` error.
+pub async fn import<R>(options: ImportOptions, reader: R) -> Result<()>
+where
+    R: AsyncRead + Send + Unpin,
+{
+    import_all(options, vec![reader]).await
+}
+
+/// Imports documents from several readers to a target cluster.
+///
+/// Each reader is read (and parsed) concurrently, with batches from every
+/// reader sharing the same queue to be sent. The offsets of documents count
+/// the bytes read across all readers, so checkpoints (and starting offsets)
+/// are only supported with a single reader.
+pub async fn import_all<R>(mut options: ImportOptions, readers: Vec<R>) -> Result<()>
+where
+    R: AsyncRead + Send + Unpin,
+{
+    // offsets only map back to a position within a single input
+    if readers.len() > 1 && (options.checkpoint.is_some() || options.offset > 0) {
+        return Err(anyhow!(
+            "Checkpoints are only supported when importing from a single input"
+        ));
+    }
+
+    // lock the checkpoint up front, so no other import can touch it
+    let _lock = options
+        .checkpoint
+        .as_deref()
+        .filter(|_| !options.dry_run)
+        .map(Lock::acquire)
+        .transpose()?;
+
+    // parse the target into a host/index pairing for later
+    let (host, index) = remote::parse_cluster(&options.target)?;
+
+    // attach any additional hosts of the target cluster
+    let mut hosts = vec![host];
+    for host in &options.target_hosts {
+        hosts.push(remote::parse_cluster(host)?.0);
+    }
+
+    // construct a client spread across all hosts (or discovered nodes)
+    let client = Arc::new(remote::connect(&hosts, &options.client).await?);
+
+    // detect the cluster to decide how to handle document types
+    let cluster = Cluster::detect(&client).await?;
+    let types = match cluster {
+        Some(cluster) if options.ty.is_some() && cluster.types() == Types::Removed => {
+            return Err(anyhow!("Document types are not supported by {}", cluster));
+        }
+        Some(cluster) => cluster.types(),
+        None => Types::Optional,
+    };
+
+    // make sure the cluster is healthy enough to import to
+    if options.preflight {
+        preflight::check(&client, None).await?;
+
+        // make sure the cluster has room for the input, when its size is known
+        if let Some(length) = options.length {
+            preflight::check_capacity(&client, length.saturating_sub(options.offset)).await?;
+        }
+    }
+
+    // fetch the bulk action to use for each document
+    let mut action = options.action;
+
+    // resolve the target index in case it's an alias or data stream
+    let resource = match index {
+        Some(ref index) => remote::resolve_resource(&client, index).await?,
+        None => Resource::Index,
+    };
+
+    // transforms and scripts have to rewrite the source, so can't be exact
+    let rewrites = !options.transforms.is_empty() || options.coerce_mapping;
+    if options.exact && (rewrites || options.script.is_some()) {
+        return Err(anyhow!(
+            "Transforms and scripts cannot be applied when importing sources exactly"
+        ));
+    }
+
+    // coerce fields to the target mapping (or the mapping used to create it)
+    if options.coerce_mapping {
+        let index = index
+            .as_deref()
+            .ok_or_else(|| anyhow!("Coercing to the target mapping requires a target index"))?;
+        let mut mappings = remote::get_mappings(&client, index)
+            .await?
+            .into_iter()
+            .map(|(_, mapping)| mapping)
+            .collect::<Vec<_>>();
+        if mappings.is_empty() {
+            mappings.extend(
+                options
+                    .create_with
+                    .as_ref()
+                    .map(|body| body["mappings"].clone()),
+            );
+        }
+        if mappings.is_empty() {
+            warn!(index, "No mapping found to coerce fields to");
+        }
+
+        // coercions run last, so they see fields as they'll be indexed
+        let coercions = Transform::coerce_mappings(&mappings);
+        debug!(
+            fields = coercions.len(),
+            "Coercing fields to the target mapping"
+        );
+        options.transforms.extend(coercions);
+    }
+
+    // new identifiers can't be used to find documents to delete
+    if options.regenerate_ids && action == Action::Delete {
+        return Err(anyhow!(
+            "Regenerating identifiers is not supported when deleting"
+        ));
+    }
+
+    // data streams only support the create action for new documents
+    if resource == Resource::DataStream && action == Action::Index {
+        action = Action::Create;
+    }
+
+    // fetch whether to resume from a previous checkpoint
+    let resume = options.resume;
+
+    // resuming replays documents, so only create those which are missing
+    if resume && action == Action::Index {
+        action = Action::Create;
+    }
+
+    // fetch the document threshold to roll over the target alias, if any
+    let rollover = match options.rollover_docs {
+        Some(_) if resource == Resource::Index => {
+            return Err(anyhow!("Rollover requires an alias or data stream target"));
+        }
+        docs => docs,
+    };
+
+    // fetch the body used to create any missing indices, if any
+    let create_with = options.create_with.as_ref();
+
+    // detect any join fields in the target, to check the routing of children
+    let mut fields = create_with.map_or_else(Vec::new, |body| join::find(&body["mappings"]));
+    if let Some(ref index) = index {
+        match join::detect(&client, index).await {
+            Ok(detected) => fields.extend(detected),
+            Err(err) => debug!(error = %err, "Unable to detect join fields"),
+        }
+    }
+    let mut joins = fields
+        .into_iter()
+        .map(|field| field.path)
+        .collect::<Vec<_>>();
+    joins.sort();
+    joins.dedup();
+
+    // fetch whether to speed up indexing by disabling replicas and refreshes
+    let turbo = options.turbo;
+
+    // track indices which have been seen, and any settings to restore
+    let prepared = Mutex::new(HashSet::new());
+    let restore = Mutex::new(Map::new());
+
+    // fetch the refresh behaviour to use once complete
+    let refresh = options.refresh;
+
+    // construct the parameters used for every bulk request
+    let bulk = Bulk {
+        pipeline: options.pipeline.as_deref(),
+        active_shards: options.wait_for_active_shards.as_deref(),
+        wait_for: refresh == RefreshPolicy::WaitFor,
+        policy: Policy::new(options.retries, options.retry_backoff),
+        compression: options.client.compression,
+        compatibility: cluster.and_then(|cluster| cluster.compatibility()),
+        limiter: options.adaptive.then(|| Limiter::new(options.concurrency)),
+        throttle: options.client.max_bandwidth.map(Throttle::new),
+    };
+
+    // track the largest batch size known to be accepted by the server
+    let working = AtomicUsize::new(usize::MAX);
+
+    // a dead letter file is only used by the matching policy
+    match (options.error_policy, &options.dead_letter) {
+        (ErrorPolicy::DeadLetter, None) => {
+            return Err(anyhow!(
+                "The dead-letter error policy requires a dead letter file"
+            ));
+        }
+        (policy, Some(_)) if policy != ErrorPolicy::DeadLetter => {
+            return Err(anyhow!(
+                "Dead letter files require the dead-letter error policy"
+            ));
+        }
+        _ => (),
+    }
+
+    // a single batch must be able to fit within the memory limit
+    if let (Some(max), Some(bytes)) = (options.max_memory, options.batch_bytes) {
+        if bytes > max {
+            return Err(anyhow!(
+                "The memory limit must be at least the maximum payload size"
+            ));
+        }
+    }
+
+    // mark the start of the import for any observers
+    let tracker = &options.tracker;
+    tracker.start();
+
+    // open the file used to store failed documents, if any
+    let failures = Failures {
+        policy: options.error_policy,
+        dead_letter: options
+            .dead_letter
+            .as_deref()
+            .map(DeadLetter::create)
+            .transpose()?,
+        count: tracker.failure_counter(),
+        tracker,
+    };
+
+    // create a counter to track docs
+    let counter = tracker.document_counter();
+
+    // fetch the threshold of failed documents, if any
+    let budget = options.max_errors.as_ref();
+
+    // fetch whether to only report what would be imported
+    let dry_run = options.dry_run;
+
+    // create the checkpoint (only in memory for a commit hook), unless nothing is sent
+    let checkpoint = match options.checkpoint.as_deref().filter(|_| !dry_run) {
+        Some(checkpoint) => Some(Checkpoint::create(checkpoint, options.offset)?),
+        None if options.commit.is_some() && !dry_run => Some(Checkpoint::memory(options.offset)),
+        None => None,
+    };
+
+    // track progress through the input, when the length is known
+    let progress = options
+        .length
+        .map(|length| Progress::new(options.offset, length));
+
+    // observers track the position through the input too
+    tracker.advance(options.offset);
+    if let Some(length) = options.length {
+        tracker.set_total_bytes(length);
+    }
+
+    // construct the parser used to build operations
+    let parser = Parser::new(&options, index, action, types, joins);
+
+    // keep a reference to the target for any rollovers
+    let alias = parser.index.as_deref();
+
+    // fetch the tracker used to drop duplicate documents, if any
+    let dedupe = options.dedupe.as_ref();
+
+    // create a counter to track duplicate docs
+    let duplicates = Counter::new(0);
+
+    // track the number of batches generated during a dry run
+    let batches = Counter::new(0);
+
+    // fetch the number of batches to send concurrently
+    let concurrency = options.concurrency;
+
+    // bound the size of batches held in memory, if requested
+    let max_memory = options
+        .max_memory
+        .map(|max| max.clamp(1, u32::MAX as usize) as u32);
+    let memory = max_memory.map(|max| Semaphore::new(max as usize));
+
+    // bounded queue of batches, to apply backpressure to the input
+    let (sender, receiver) = mpsc::channel(concurrency.max(1));
+
+    // fetch the signal used to stop reading early
+    let shutdown = &options.shutdown;
+
+    // number batches across every reader, to track completion
+    let sequences = AtomicUsize::new(0);
+
+    // count the bytes read across every reader, to use as offsets
+    let consumed = AtomicU64::new(options.offset);
+
+    // read batches from each reader into the queue, waiting whenever it's full
+    let memory = &memory;
+    let read = |reader: R| {
+        let sender = sender.clone();
+        let (parser, failures, duplicates) = (&parser, &failures, &duplicates);
+        let (sequences, consumed) = (&sequences, &consumed);
+        let (offset, format) = (options.offset, options.format);
+        let (size, batch_bytes, batch_timeout) =
+            (options.size, options.batch_bytes, options.batch_timeout);
+        async move {
+            // fetch the input as lines (or records)
+            let lines = input::records(BufReader::new(reader), offset, format);
+
+            // measure each line, to offset it by everything read so far
+            let mut last = offset;
+            let lines = lines.map_ok(move |(input, end)| {
+                let length = end - last;
+                last = end;
+                (
+                    input,
+                    consumed.fetch_add(length, Ordering::Relaxed) + length,
+                )
+            });
+
+            // start streaming the lines and map into bulk operations
+            let filter = lines.into_stream().filter_map(|input| async move {
+                // read failures are never recoverable
+                let (input, offset) = match input {
+                    Ok(input) => input,
+                    Err(err) => return Some(Err(err.into())),
+                };
+
+                // construct the operation from the input line
+                let operation = match construct_operation(&input, parser) {
+                    Ok(Some(operation)) => operation,
+                    Ok(None) => {
+                        tracker.skip(1);
+                        return None;
+                    }
+                    Err(err) => {
+                        warn!(error = %err, "Failed to parse document");
+                        counter.increment(1);
+                        return failures
+                            .record(Some(&input), Value::String(err.to_string()))
+                            .err()
+                            .map(Err);
+                    }
+                };
+
+                // drop any duplicates of documents we're keeping
+                if let Some(dedupe) = dedupe {
+                    match dedupe.keep(&operation, offset) {
+                        Ok(true) => (),
+                        Ok(false) => {
+                            duplicates.increment(1);
+                            tracker.skip(1);
+                            return None;
+                        }
+                        Err(err) => return Some(Err(err)),
+                    }
+                }
+
+                // keep the offset around in case we need to track it
+                let operation = operation.with_offset(offset);
+
+                // keep the input around in case we need to dead letter it
+                match failures.dead_letter {
+                    Some(_) => Some(Ok(operation.with_input(input))),
+                    None => Some(Ok(operation)),
+                }
+            });
+
+            // chunk the stream into batches, numbered to track completion
+            let chunk = batch(Box::pin(filter), size, batch_bytes, batch_timeout)
+                .map(|batch| batch.map(|batch| (sequences.fetch_add(1, Ordering::Relaxed), batch)));
+            let mut chunk = Box::pin(chunk);
+
+            loop {
+                // stop reading new batches when shutting down
+                let batch = tokio::select! {
+                    batch = chunk.try_next() => batch?,
+                    _ = shutdown.triggered() => break,
+                };
+
+                // an empty stream means the input is exhausted
+                let batch = match batch {
+                    Some(batch) => batch,
+                    None => break,
+                };
+
+                // wait for enough memory to be released to hold the batch
+                let reservation = match (memory.as_ref(), max_memory) {
+                    (Some(memory), Some(max)) => {
+                        let size = batch.1.iter().map(Operation::size).sum::<usize>();
+                        let size = size.clamp(1, max as usize) as u32;
+                        Some(memory.acquire_many(size).await?)
+                    }
+                    _ => None,
+                };
+
+                // a closed queue means the worker has halted
+                let (sequence, batch) = batch;
+                if sender.send((sequence, batch, reservation)).await.is_err() {
+                    break;
+                }
+            }
+            Ok::<_, anyhow::Error>(())
+        }
+    };
+
+    // read every input concurrently, closing the queue once all are done
+    let reader = future::try_join_all(readers.into_iter().map(read));
+    drop(sender);
+
+    // pull batches from the queue, to be sent concurrently
+    let incoming = stream::unfold(receiver, |mut receiver| async {
+        let batch = receiver.recv().await?;
+        Some((Ok(batch), receiver))
+    });
+
+    // identifiers of idle workers, claimed by each batch while in flight
+    let idle = std::sync::Mutex::new((0..concurrency.max(1)).rev().collect());
+
+    // handle each batch concurrently and send each buffer to Elasticsearch directly
+    let worker = incoming.try_for_each_concurrent(concurrency, |batch| {
+        // attach the batch sequence to any events emitted by the worker
+        let span = debug_span!("batch", sequence = batch.0);
+
+        async {
+            // split the sequence number (and memory reservation) from the batch
+            let (sequence, batch, _reservation) = batch;
+
+            // queued batches are left unsent when shutting down
+            if shutdown.is_triggered() {
+                return Ok(());
+            }
+
+            // claim a worker to attribute the batch to
+            let slot = Slot::claim(&idle);
+            let mut started = Instant::now();
+
+            // fetch the end of the batch within the input
+            let end = batch.iter().filter_map(Operation::offset).max();
+
+            // dry runs only need to count where documents would go
+            if dry_run {
+                for operation in batch.iter() {
+                    tracker.tally(operation.index().unwrap_or_default(), 1);
+                }
+                batches.increment(1);
+                counter.increment(batch.len());
+                return Ok(());
+            }
+
+            // track (and prepare) any new indices before indexing
+            let preparation = async {
+                // lock to avoid preparing the same index concurrently
+                let mut prepared = prepared.lock().await;
+
+                // prepare each index we haven't already seen
+                for operation in batch.iter() {
+                    let index = match operation.index() {
+                        Some(index) if !prepared.contains(index) => index,
+                        _ => continue,
+                    };
+                    if operation.action() != Action::Delete && (create_with.is_some() || turbo) {
+                        prepare_index(&client, index, create_with, turbo, &restore)
+                            .await
+                            .map_err(|err| {
+                                err.context(format!("Unable to prepare index {}", index))
+                            })?;
+                    }
+                    prepared.insert(index.to_owned());
+                }
+
+                Ok::<_, anyhow::Error>(())
+            };
+
+            // queue of requests to send, split from the batch as needed
+            let mut queue = VecDeque::new();
+
+            // failing to prepare an index fails the entire batch
+            match preparation.await {
+                Ok(()) => queue.push_back((batch, 0)),
+                Err(err) => {
+                    counter.increment(batch.len());
+                    failures.reject(&batch, err)?;
+                    check_budget(budget, failures.count, counter, false)?;
+                }
+            }
+
+            // send each request in the queue in order
+            while let Some((mut batch, attempt)) = queue.pop_front() {
+                // split anything larger than the known working size
+                let limit = working.load(Ordering::Relaxed);
+                if batch.len() > limit {
+                    queue.push_front((batch.split_off(limit), attempt));
+                }
+
+                // index the batch
+                debug!(documents = batch.len(), "Sending bulk request");
+                let request = tracker.begin_request();
+                let sent = bulk.send(&client, &batch).await;
+                let latency = request.elapsed();
+                tracker.end_request(request);
+                let (response, bytes, retries) = match sent {
+                    Ok(sent) => (sent.response, sent.bytes, sent.retries),
+                    Err(err) => {
+                        counter.increment(batch.len());
+                        failures.reject(&batch, err)?;
+                        check_budget(budget, failures.count, counter, false)?;
+                        continue;
+                    }
+                };
+
+                // shrink the batch if the server says it's too large
+                if response.status_code().as_u16() == 413 && batch.len() > 1 {
+                    // reduce the working size for all future batches
+                    let half = batch.len().div_ceil(2);
+                    working.fetch_min(half, Ordering::Relaxed);
+
+                    // requeue both halves of the batch, in order
+                    queue.push_front((batch.split_off(half), attempt));
+                    queue.push_front((batch, attempt));
+
+                    warn!(size = half, "Request too large, reducing batch size");
+                    continue;
+                }
+
+                // any other failure status fails the entire batch
+                let response = match response.error_for_status_code() {
+                    Ok(response) => response,
+                    Err(err) => {
+                        counter.increment(batch.len());
+                        failures.reject(&batch, err.into())?;
+                        check_budget(budget, failures.count, counter, false)?;
+                        continue;
+                    }
+                };
+
+                // grab counter for later
+                let mut total = batch.len();
+
+                // turn the body back into an array of items to work with
+                let body = match response.json::<Value>().await {
+                    Ok(body) => body,
+                    Err(err) => {
+                        counter.increment(batch.len());
+                        failures.reject(&batch, err.into())?;
+                        check_budget(budget, failures.count, counter, false)?;
+                        continue;
+                    }
+                };
+
+                // malformed responses fail the entire batch
+                let (errors, items) = match (
+                    body.get("errors").and_then(Value::as_bool),
+                    body.get("items").and_then(Value::as_array),
+                ) {
+                    (Some(errors), Some(items)) => (errors, items),
+                    _ => {
+                        counter.increment(batch.len());
+                        let err = anyhow!("Unable to locate items in bulk response");
+                        failures.reject(&batch, err)?;
+                        check_budget(budget, failures.count, counter, false)?;
+                        continue;
+                    }
+                };
+
+                // record the time taken by the cluster against the transfer
+                let took = body.get("took").and_then(Value::as_u64);
+                debug!(
+                    documents = batch.len(),
+                    took_ms = took,
+                    latency_ms = latency.as_millis() as u64,
+                    bytes,
+                    retries,
+                    "Received bulk response"
+                );
+
+                // feed the time taken by the cluster back to the stats and limiter
+                if let Some(took) = took.map(Duration::from_millis) {
+                    tracker.observe_took(took);
+                    if let Some(ref limiter) = bulk.limiter {
+                        limiter.observe(took);
+                    }
+                }
+
+                // only check items if any of the requests returned an error
+                if !errors {
+                    // every operation in the batch succeeded
+                    for operation in batch.iter() {
+                        tracker.tally(operation.index().unwrap_or_default(), 1);
+                    }
+                } else {
+                    // documents rejected due to load, to be retried
+                    let mut rejected = Vec::new();
+
+                    // iterate through all items which came back in the response
+                    for (item, operation) in items.iter().zip(batch) {
+                        // fetch the result of the operation
+                        let result = item.as_object().and_then(|item| item.values().next());
+
+                        // requeue rejected documents while we have retries left
+                        if attempt < bulk.policy.retries() && result.is_some_and(is_rejected) {
+                            rejected.push(operation);
+                            continue;
+                        }
+
+                        // conflicts are expected when replaying documents on resume
+                        if resume
+                            && result
+                                .and_then(|result| result.get("status"))
+                                .and_then(Value::as_u64)
+                                == Some(409)
+                        {
+                            tracker.tally(operation.index().unwrap_or_default(), 1);
+                            continue;
+                        }
+
+                        // fetch the failed shard counter to check errors
+                        let failed = result
+                            .and_then(|result| result.pointer("/_shards/failed"))
+                            .and_then(Value::as_u64);
+
+                        // skip any successful operations
+                        if failed.unwrap_or(1) == 0 {
+                            tracker.tally(operation.index().unwrap_or_default(), 1);
+                            continue;
+                        }
+
+                        // handle the failed document with the reason
+                        let reason = result
+                            .and_then(|result| result.get("error"))
+                            .unwrap_or(item)
+                            .clone();
+
+                        // log errors if any happened (based on shards)
+                        warn!(%reason, "Failed to import document");
+
+                        failures.record(operation.input(), reason)?;
+                    }
+
+                    // retry rejected documents after backing off
+                    if !rejected.is_empty() {
+                        if let Some(ref limiter) = bulk.limiter {
+                            limiter.reject();
+                        }
+                        let delay = bulk.policy.delay(attempt);
+                        warn!(
+                            documents = rejected.len(),
+                            delay_ms = delay.as_millis() as u64,
+                            "Retrying rejected documents"
+                        );
+                        tokio::time::sleep(delay).await;
+                        total -= rejected.len();
+                        queue.push_front((rejected, attempt + 1));
+                    }
+                }
+
+                // increment the counter and log the state
+                let processed = counter.increment(total);
+                if let Some(end) = end {
+                    tracker.advance(end);
+                }
+                tracker.record(slot.id, total, started.elapsed());
+                started = Instant::now();
+                tracker.sample();
+                match (&progress, end) {
+                    (Some(progress), Some(end)) => {
+                        progress.advance(end);
+                        debug!(
+                            documents = total,
+                            processed,
+                            progress = %progress.describe(),
+                            "Indexed another batch"
+                        );
+                    }
+                    _ => debug!(documents = total, processed, "Indexed another batch"),
+                }
+
+                // roll over the target when crossing a threshold
+                if let (Some(docs), Some(alias)) = (rollover, alias) {
+                    if crosses_threshold(processed, total, docs) {
+                        let indices = client.indices();
+                        bulk.policy
+                            .send(|| indices.rollover(IndicesRolloverParts::Alias(alias)).send())
+                            .await
+                            .and_then(|response| Ok(response.error_for_status_code()?))
+                            .map_err(|err| err.context(format!("Unable to roll over {}", alias)))?;
+                    }
+                }
+
+                // halt the import if too many documents have failed
+                check_budget(budget, failures.count, counter, false)?;
+            }
+
+            // acknowledge the batch to advance the checkpoint
+            if let (Some(checkpoint), Some(end)) = (&checkpoint, end) {
+                checkpoint.acknowledge(sequence, end)?;
+                if let Some(ref commit) = options.commit {
+                    commit.commit(checkpoint.offset())?;
+                }
+            }
+
+            Ok::<_, anyhow::Error>(())
+        }
+        .instrument(span)
+    });
+
+    // await all, holding on to any errors until we've cleaned up
+    let result = future::try_join(reader, worker)
+        .await
+        .and_then(|_| check_budget(budget, failures.count, counter, true));
+
+    // report any duplicates which were dropped
+    let dropped = duplicates.get();
+    if dropped > 0 {
+        info!(documents = dropped, "Dropped duplicate documents");
+    }
+
+    // report what would have been imported during a dry run
+    if dry_run {
+        let documents = tracker.indices();
+        info!(
+            documents = documents.values().sum::<usize>(),
+            batches = batches.get(),
+            failed = failures.count.get(),
+            "Dry run complete"
+        );
+        for (index, count) in documents.iter() {
+            info!(%index, documents = count, "Dry run would import to index");
+        }
+    }
+
+    // make sure the latest progress is written out
+    if let Some(checkpoint) = checkpoint {
+        checkpoint.flush()?;
+    }
+
+    // make sure all failed documents are written out
+    if let Some(dead_letter) = failures.dead_letter {
+        dead_letter.flush()?;
+    }
+
+    // fetch all indices touched by the import
+    let indices = prepared.into_inner();
+
+    // restore any settings changed during import
+    for (index, settings) in restore.into_inner() {
+        remote::put_settings(&client, &index, &settings).await?;
+    }
+
+    // propagate any errors once cleaned up
+    result?;
+
+    // report an early shutdown, now that everything is cleaned up
+    if shutdown.is_triggered() {
+        return Err(Error::new(ErrorKind::Interrupted, "Import was interrupted").into());
+    }
+
+    // dry runs don't touch the cluster
+    if dry_run {
+        return Ok(());
+    }
+
+    // force merge all target indices when requested
+    if options.force_merge {
+        // merge each index in turn, as merges are expensive
+        for index in &indices {
+            remote::force_merge(&client, index, options.max_segments).await?;
+            info!(%index, "Force merged index");
+        }
+    }
+
+    // determine which indices (if any) should be refreshed
+    let refreshed = match refresh {
+        RefreshPolicy::All => vec!["_all"],
+        RefreshPolicy::Target => indices.iter().map(String::as_str).collect(),
+        _ => vec![],
+    };
+
+    // execute a refresh against the cluster
+    if !refreshed.is_empty() {
+        client
+            .indices()
+            .refresh(IndicesRefreshParts::Index(&refreshed))
+            .send()
+            .await?
+            .error_for_status_code()?;
+    }
+
+    // verify document counts once visible, unless we were deleting
+    if refresh != RefreshPolicy::None && action != Action::Delete {
+        let mut mismatched = 0;
+
+        // compare each index against the documents we indexed
+        for (index, expected) in tracker.indices() {
+            let count = remote::count(&client, &index, None).await?;
+            if count < expected as u64 {
+                warn!(%index, count, expected, "Index is missing documents");
+                mismatched += 1;
+            }
+        }
+
+        // strict mode fails if anything went missing
+        if mismatched > 0 && options.strict {
+            return Err(Error::new(
+                ErrorKind::Verification,
+                format!("Document counts did not match for {} indices", mismatched),
+            )
+            .into());
+        }
+    }
+
+    // report the outcome of the import
+    debug!(
+        processed = counter.get(),
+        failed = failures.count.get(),
+        indices = indices.len(),
+        elapsed_ms = tracker.elapsed().as_millis() as u64,
+        "Import complete"
+    );
+
+    // done!
+    Ok(())
+}
+
+/// Scans an input to locate the last occurrence of each document.
+///
+/// The returned tracker should be provided as the `dedupe` option of an
+/// import reading the same input (from the same offset). Any input which
+/// cannot be converted to an operation is ignored, as it will be handled
+/// when the input is read again for the import itself.
+///
+/// Any limit provided bounds the memory used to track documents, failing the
+/// scan once reached (see `Dedupe::with_limit`).
+pub async fn scan_last<R>(
+    options: &ImportOptions,
+    reader: R,
+    limit: Option<usize>,
+) -> Result<Dedupe>
+where
+    R: AsyncRead + Send + Unpin,
+{
+    let mut dedupe = match limit {
+        Some(limit) => Dedupe::last().with_limit(limit),
+        None => Dedupe::last(),
+    };
+
+    // build operations exactly as the import itself will (types aside)
+    let (_, index) = remote::parse_cluster(&options.target)?;
+    let parser = Parser::new(options, index, options.action, Types::Optional, Vec::new());
+
+    // read through the input from the starting offset
+    let mut lines = Box::pin(input::records(
+        BufReader::new(reader),
+        options.offset,
+        options.format,
+    ));
+
+    // track the offset of every operation
+    while let Some(line) = lines.next().await {
+        let (input, offset) = line?;
+        if let Ok(Some(operation)) = construct_operation(&input, &parser) {
+            dedupe.track(&operation, offset)?;
+        }
+    }
+
+    Ok(dedupe)
+}
+
+/// Parameters attached to every bulk request sent during an import.
+struct Bulk<'a> {
+    /// An ingest pipeline to process documents with.
+    pipeline: Option<&'a str>,
+    /// The number of active shards required for each write.
+    active_shards: Option<&'a str>,
+    /// Whether to wait for documents to become visible to search.
+    wait_for: bool,
+    /// The policy used to retry failed requests.
+    policy: Policy,
+    /// Whether to compress the body of each request.
+    compression: bool,
+    /// A REST API version to request compatibility with, if any.
+    compatibility: Option<u64>,
+    /// A limiter adapting the number of requests in flight, if any.
+    limiter: Option<Limiter>,
+    /// A throttle limiting the rate of transfer to the cluster, if any.
+    throttle: Option<Throttle>,
+}
+
+/// A bulk request sent to a cluster, alongside details of the transfer.
+struct Sent {
+    /// The final response from the cluster.
+    response: Response,
+    /// The size of the request body, in bytes.
+    bytes: usize,
+    /// The number of times the request was retried.
+    retries: usize,
+}
+
+impl<'a> Bulk<'a> {
+    /// Sends a batch of operations to a cluster as a bulk request.
+    ///
+    /// Transient failures are retried using the configured policy, but the
+    /// final response is returned regardless of the status code, to allow
+    /// the caller to decide how to handle failures.
+    async fn send(&self, client: &Elasticsearch, batch: &[Operation]) -> Result<Sent> {
+        // wait for room to send the request when adapting concurrency
+        let _permit = match self.limiter {
+            Some(ref limiter) => Some(limiter.acquire().await),
+            None => None,
+        };
+
+        // compress the body once up front, as it's reused on every retry
+        let compressed = self.compression.then(|| compress(batch)).transpose()?;
+
+        // measure what's sent on each attempt, to throttle the transfer
+        let size = match compressed {
+            Some(ref body) => body.len(),
+            None => batch.iter().map(Operation::size).sum(),
+        };
+
+        let (response, retries) = self
+            .policy
+            .send_counted(|| {
+                // compressed bodies bypass the bulk builder to set the encoding
+                let response = match compressed {
+                    Some(ref body) => self.send_compressed(client, body.clone()).boxed(),
+                    None => self.send_plain(client, batch).boxed(),
+                };
+
+                // wait on the throttle, then report any rejections to the limiter
+                async move {
+                    if let Some(ref throttle) = self.throttle {
+                        throttle.consume(size).await;
+                    }
+                    let response = response.await;
+                    if let (Some(limiter), Ok(response)) = (&self.limiter, &response) {
+                        if response.status_code().as_u16() == 429 {
+                            limiter.reject();
+                        }
+                    }
+                    response
+                }
+            })
+            .await?;
+
+        Ok(Sent {
+            response,
+            bytes: size,
+            retries,
+        })
+    }
+
+    /// Sends a batch of operations as a bulk request body.
+    async fn send_plain(
+        &self,
+        client: &Elasticsearch,
+        batch: &[Operation],
+    ) -> Result<Response, elasticsearch::Error> {
+        // construct the bulk request
+        let mut request = client.bulk(BulkParts::None);
+
+        // wait for documents to become visible when requested
+        if self.wait_for {
+            request = request.refresh(Refresh::WaitFor);
+        }
+
+        // require a number of active shards when provided
+        if let Some(shards) = self.active_shards {
+            request = request.wait_for_active_shards(shards);
+        }
+
+        // attach the ingest pipeline when provided
+        if let Some(pipeline) = self.pipeline {
+            request = request.pipeline(pipeline);
+        }
+
+        // mark the body as ndjson, in a compatible format if needed
+        for (name, value) in self.headers().iter() {
+            request = request.header(name.clone(), value.clone());
+        }
+
+        // send the batch as the request body
+        request.body(batch.iter().collect()).send().await
+    }
+
+    /// Constructs the content headers to send with a bulk request.
+    ///
+    /// When requesting compatibility with an older REST API, both headers
+    /// must carry the versioned media type rather than the usual one.
+    fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+
+        // without compatibility, only the ndjson content type is needed
+        let version = match self.compatibility {
+            Some(version) => version,
+            None => {
+                headers.insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static("application/x-ndjson"),
+                );
+                return headers;
+            }
+        };
+
+        // send the versioned media types for both directions
+        let accept = format!(
+            "application/vnd.elasticsearch+json; compatible-with={}",
+            version
+        );
+        let content = format!(
+            "application/vnd.elasticsearch+x-ndjson; compatible-with={}",
+            version
+        );
+
+        headers.insert(
+            ACCEPT,
+            HeaderValue::from_str(&accept).expect("valid header"),
+        );
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_str(&content).expect("valid header"),
+        );
+        headers
+    }
+
+    /// Sends a gzip compressed bulk request body.
+    async fn send_compressed(
+        &self,
+        client: &Elasticsearch,
+        body: Bytes,
+    ) -> Result<Response, elasticsearch::Error> {
+        // mark the body as compressed ndjson
+        let mut headers = self.headers();
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+
+        // attach the same parameters as the bulk builder would
+        let mut query = Vec::new();
+        if self.wait_for {
+            query.push(("refresh", "wait_for"));
+        }
+        if let Some(shards) = self.active_shards {
+            query.push(("wait_for_active_shards", shards));
+        }
+        if let Some(pipeline) = self.pipeline {
+            query.push(("pipeline", pipeline));
+        }
+
+        client
+            .send(
+                Method::Post,
+                "/_bulk",
+                headers,
+                Some(&query),
+                Some(body),
+                None,
+            )
+            .await
+    }
+}
+
+/// Checks whether a batch took the number of documents past a multiple of a threshold.
+///
+/// A batch crossing several multiples at once still only counts as crossing,
+/// as rolling over more than once would just leave empty indices behind.
+fn crosses_threshold(processed: usize, total: usize, docs: NonZeroUsize) -> bool {
+    let docs = docs.get();
+    processed / docs > (processed - total) / docs
+}
+
+/// Compresses a batch of operations into a gzip bulk request body.
+fn compress(batch: &[Operation]) -> Result<Bytes> {
+    // serialize the batch exactly as an uncompressed request would be
+    let mut body = BytesMut::new();
+    for operation in batch {
+        operation.write(&mut body)?;
+    }
+
+    // favour speed, as most of the gain comes from any compression at all
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+    encoder.write_all(&body)?;
+    Ok(encoder.finish()?.into())
+}
+
+/// The minimum number of documents required to check a ratio budget.
+///
+/// This avoids halting an import when the first few documents fail, as
+/// ratios are meaningless on such a small sample size.
+const MIN_BUDGET_SAMPLE: usize = 1000;
+
+/// Threshold of failed documents after which an import is halted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Budget {
+    /// Halts once more than this many documents have failed.
+    Count(usize),
+    /// Halts once more than this ratio of documents have failed.
+    Ratio(f64),
+}
+
+impl Budget {
+    /// Parses a budget from either an absolute count, or percentage.
+    pub fn parse(input: &str) -> Result<Self> {
+        let invalid = || anyhow!("Invalid error threshold provided: {}", input);
+
+        // percentages are converted into a ratio
+        if let Some(percentage) = input.trim().strip_suffix('%') {
+            let percentage = percentage
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|percentage| (0.0..=100.0).contains(percentage))
+                .ok_or_else(invalid)?;
+            return Ok(Budget::Ratio(percentage / 100.0));
+        }
+
+        // anything else should be a count
+        let count = input.trim().parse::<usize>().map_err(|_| invalid())?;
+        Ok(Budget::Count(count))
+    }
+
+    /// Determines whether this budget has been exceeded.
+    ///
+    /// Ratios are only checked after a minimum sample size, unless the import
+    /// is complete, in which case the final ratio is always checked.
+    fn exceeded(&self, errors: usize, processed: usize, complete: bool) -> bool {
+        match *self {
+            Budget::Count(max) => errors > max,
+            Budget::Ratio(_) if processed == 0 => false,
+            Budget::Ratio(_) if !complete && processed < MIN_BUDGET_SAMPLE => false,
+            Budget::Ratio(max) => errors as f64 / processed as f64 > max,
+        }
+    }
+}
+
+/// Checks whether the number of failed documents exceeds a budget.
+fn check_budget(
+    budget: Option<&Budget>,
+    errors: &Counter,
+    processed: &Counter,
+    complete: bool,
+) -> Result<()> {
+    // fetch the current counts
+    let errors = errors.get();
+    let processed = processed.get();
+
+    // exceeding the budget is an error
+    if budget.is_some_and(|budget| budget.exceeded(errors, processed, complete)) {
+        return Err(Error::new(
+            ErrorKind::Documents,
+            format!(
+                "Too many documents failed to import ({} of {})",
+                errors, processed
+            ),
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Policies available to handle documents which failed to import.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Halts the import on the first failure.
+    Abort,
+    /// Logs and skips each failure.
+    Skip,
+    /// Writes each failure to a dead letter file.
+    DeadLetter,
+}
+
+/// Identifier of a worker, claimed while handling a batch.
+///
+/// Batches are handled concurrently without any fixed set of workers, so
+/// identifiers are claimed from a pool of idle workers to attribute each
+/// batch to a worker. The identifier is returned to the pool on drop.
+struct Slot<'a> {
+    id: usize,
+    idle: &'a std::sync::Mutex<Vec<usize>>,
+}
+
+impl<'a> Slot<'a> {
+    /// Claims an idle worker identifier from a pool.
+    fn claim(idle: &'a std::sync::Mutex<Vec<usize>>) -> Self {
+        let id = idle
+            .lock()
+            .expect("poisoned lock")
+            .pop()
+            .unwrap_or_default();
+        Self { id, idle }
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        self.idle.lock().expect("poisoned lock").push(self.id);
+    }
+}
+
+/// Handler applying an `ErrorPolicy` to documents which failed to import.
+///
+/// This is used for every kind of failure (whether a document could not be
+/// parsed, a request could not be sent, or a cluster rejected a document)
+/// to make sure that all failures are handled consistently.
+struct Failures<'a> {
+    /// The policy to apply to each failure.
+    policy: ErrorPolicy,
+    /// The file to write failures to, for the dead letter policy.
+    dead_letter: Option<DeadLetter>,
+    /// The number of documents which have failed.
+    count: &'a Counter,
+    /// The tracker to sample the errors of failed documents in.
+    tracker: &'a Tracker,
+}
+
+impl Failures<'_> {
+    /// Records a single document which failed to import.
+    ///
+    /// An error is returned if the import should be halted, either due to
+    /// the abort policy, or because the dead letter file can't be written.
+    fn record(&self, input: Option<&[u8]>, reason: Value) -> Result<()> {
+        self.count.increment(1);
+        self.tracker.sample_error(match &reason {
+            Value::String(reason) => reason.clone(),
+            reason => reason.to_string(),
+        });
+
+        match (self.policy, &self.dead_letter, input) {
+            (ErrorPolicy::Abort, _, _) => Err(Error::new(
+                ErrorKind::Documents,
+                format!("Failed to import document: {}", reason),
+            )
+            .into()),
+            (ErrorPolicy::DeadLetter, Some(dead_letter), Some(input)) => {
+                dead_letter.write(input, reason)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Rejects an entire batch of operations which failed to import.
+    ///
+    /// Every operation in the batch is recorded with the error as the reason
+    /// for failure, unless the import is to be aborted.
+    fn reject(&self, batch: &[Operation], err: anyhow::Error) -> Result<()> {
+        warn!(documents = batch.len(), error = %err, "Failed to import batch");
+
+        // aborting doesn't need to visit every document
+        if self.policy == ErrorPolicy::Abort {
+            self.count.increment(batch.len());
+            return Err(err.context("Failed to import batch"));
+        }
+
+        // record every document in the batch with the error
+        let reason = Value::String(err.to_string());
+        for operation in batch {
+            self.record(operation.input(), reason.clone())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Determines whether a bulk item result was rejected due to cluster load.
+fn is_rejected(result: &Value) -> bool {
+    result.get("status").and_then(Value::as_u64) == Some(429)
+        || result.pointer("/error/type").and_then(Value::as_str)
+            == Some("es_rejected_execution_exception")
+}
+
+/// Batches a stream of operations by document count and payload size.
+///
+/// Operations are accumulated until either the batch contains `size`
+/// operations, or adding another operation would take the payload of the
+/// batch over the `bytes` limit (if any). A single operation larger than
+/// the limit will still be sent, as a batch of its own. With a timeout, a
+/// partially filled batch is also sent once the timeout has passed since
+/// its first operation arrived, so slow (or endless) inputs keep flowing.
+///
+/// Any error received from the stream is passed through immediately, and
+/// will end the stream (discarding any partially filled batch).
+fn batch<S>(
+    stream: S,
+    size: usize,
+    bytes: Option<usize>,
+    timeout: Option<Duration>,
+) -> impl Stream<Item = Result<Vec<Operation>>>
+where
+    S: Stream<Item = Result<Operation>> + Unpin,
+{
+    let limit = bytes.unwrap_or(usize::MAX);
+    let state = (stream, None::<Operation>, false);
+
+    stream::unfold(state, move |(mut stream, mut pending, done)| async move {
+        // the stream was exhausted on the last batch
+        if done {
+            return None;
+        }
+
+        let mut batch = Vec::new();
+        let mut total = 0;
+
+        // start with anything left over from the last batch
+        if let Some(operation) = pending.take() {
+            total += operation.size();
+            batch.push(operation);
+        }
+
+        // fill up the batch until one of the limits is hit
+        let mut deadline = None;
+        while batch.len() < size {
+            // start the timeout once the batch has anything to send
+            if deadline.is_none() && !batch.is_empty() {
+                deadline = timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+            }
+
+            // send a partial batch once the timeout has passed
+            let next = match deadline {
+                Some(deadline) => match tokio::time::timeout_at(deadline, stream.next()).await {
+                    Ok(next) => next,
+                    Err(_) => break,
+                },
+                None => stream.next().await,
+            };
+
+            let operation = match next {
+                Some(Ok(operation)) => operation,
+                Some(Err(err)) => return Some((Err(err), (stream, None, true))),
+                None if batch.is_empty() => return None,
+                None => return Some((Ok(batch), (stream, None, true))),
+            };
+
+            // hold the operation back if it would take us over the limit
+            let length = operation.size();
+            if !batch.is_empty() && total + length > limit {
+                pending = Some(operation);
+                break;
+            }
+
+            total += length;
+            batch.push(operation);
+        }
+
+        Some((Ok(batch), (stream, pending, false)))
+    })
+}
+
+/// Prepares an index the first time it's seen during an import.
+///
+/// If a creation body is provided, the index will be created if it does not
+/// already exist. If turbo mode is enabled, replicas and refreshes will be
+/// disabled on the index, with the original values stored in `restore` to
+/// allow resetting the index once the import has completed.
+async fn prepare_index(
+    client: &Elasticsearch,
+    index: &str,
+    create_with: Option<&Value>,
+    turbo: bool,
+    restore: &Mutex<Map<String, Value>>,
+) -> Result<()> {
+    // create the index if we have a body to do so
+    if let Some(body) = create_with {
+        remote::create_index(client, index, body).await?;
+    }
+
+    // nothing more to do outside of turbo
+    if !turbo {
+        return Ok(());
+    }
+
+    // settings changed in turbo mode, and their fast values
+    let settings = json!({
+        "index.number_of_replicas": 0,
+        "index.refresh_interval": "-1"
+    });
+
+    // fetch the current values of the settings we're going to change
+    let names = ["index.number_of_replicas", "index.refresh_interval"];
+    let originals = remote::get_settings(client, index, &names).await?;
+
+    // missing indices are created with fast settings, and reset to defaults
+    if originals.is_empty() {
+        let body = json!({ "settings": settings });
+        remote::create_index(client, index, &body).await?;
+
+        let defaults = json!({
+            "index.number_of_replicas": null,
+            "index.refresh_interval": null
+        });
+
+        restore.lock().await.insert(index.to_owned(), defaults);
+        return Ok(());
+    }
+
+    // store the original values of each concrete index
+    {
+        let mut restore = restore.lock().await;
+        for (concrete, current) in originals {
+            let mut values = Map::new();
+            for name in &names {
+                values.insert(name.to_string(), current[name].clone());
+            }
+            restore
+                .entry(concrete)
+                .or_insert_with(|| Value::Object(values));
+        }
+    }
+
+    // apply the fast settings to the index
+    remote::put_settings(client, index, &settings).await
+}
+
+/// Parser used to construct bulk operations from lines of input.
+struct Parser<'a> {
+    /// An index to override the index of each document.
+    index: Option<String>,
+    /// Filters which each document must match to be imported.
+    filters: &'a [Filter],
+    /// Transforms applied to the source of each document.
+    transforms: &'a [Transform],
+    /// A script run against each document, if any.
+    script: Option<&'a Script>,
+    /// Whether document sources can be passed through without parsing.
+    raw: bool,
+    /// Whether document sources must be sent exactly as provided.
+    exact: bool,
+    /// Rules used to rename the index of each document.
+    renamer: &'a Renamer,
+    /// A template used to render the index of each document.
+    template: Option<&'a Template>,
+    /// The bulk action to use for each document.
+    action: Action,
+    /// Whether to index using the `_version` of each document.
+    preserve_version: bool,
+    /// Whether to drop the `_id` of each document, generating new ones.
+    regenerate_ids: bool,
+    /// Whether documents without an `_id` are given one by the cluster.
+    generate_missing_ids: bool,
+    /// A template used to generate the `_id` of each document, if any.
+    id_from: Option<&'a IdTemplate>,
+    /// A prefix to attach to the `_id` of each document, if any.
+    id_prefix: Option<&'a str>,
+    /// A suffix to attach to the `_id` of each document, if any.
+    id_suffix: Option<&'a str>,
+    /// A document type to override the type of each document.
+    ty: Option<&'a str>,
+    /// How document types are handled by the target cluster.
+    types: Types,
+    /// The paths of any join fields within the target.
+    joins: Vec<String>,
+}
+
+impl<'a> Parser<'a> {
+    /// Constructs a parser from import options, and the resolved target.
+    fn new(
+        options: &'a ImportOptions,
+        index: Option<String>,
+        action: Action,
+        types: Types,
+        joins: Vec<String>,
+    ) -> Self {
+        // sources only need to be parsed when inspected or modified
+        let raw = options.filters.is_empty()
+            && options.transforms.is_empty()
+            && options.script.is_none()
+            && options.template.is_none()
+            && options.id_from.is_none()
+            && joins.is_empty();
+
+        Self {
+            index,
+            filters: &options.filters,
+            transforms: &options.transforms,
+            script: options.script.as_ref(),
+            raw,
+            exact: options.exact,
+            renamer: &options.renamer,
+            template: options.template.as_ref(),
+            action,
+            preserve_version: options.preserve_version,
+            regenerate_ids: options.regenerate_ids,
+            generate_missing_ids: options.generate_missing_ids,
+            id_from: options.id_from.as_ref(),
+            id_prefix: options.id_prefix.as_deref(),
+            id_suffix: options.id_suffix.as_deref(),
+            ty: options.ty.as_deref(),
+            types,
+            joins,
+        }
+    }
+
+    /// Attaches any prefix and suffix to the identifier of a document.
+    ///
+    /// Empty identifiers are left empty, as they're skipped when deleting.
+    fn affix_id(&self, id: &str) -> String {
+        if id.is_empty() {
+            return String::new();
+        }
+        format!(
+            "{}{}{}",
+            self.id_prefix.unwrap_or_default(),
+            id,
+            self.id_suffix.unwrap_or_default()
+        )
+    }
+
+    /// Resolves the type to attach to a document, if any.
+    ///
+    /// Any type override is used over the type of the document, unless the
+    /// cluster has removed types entirely. The `_doc` type is only carried
+    /// across when the cluster requires a type, as it's the 7.x default.
+    fn resolve_type<'b>(&'b self, ty: Option<&'b str>) -> Option<&'b str> {
+        match self.types {
+            Types::Removed => None,
+            Types::Optional => self.ty.or_else(|| ty.filter(|ty| *ty != "_doc")),
+            Types::Required(default) => self.ty.or(ty).or(Some(default)),
+        }
+    }
+}
+
+/// Constructs a bulk operation from a line of input.
+///
+/// In `index` (or `create`) mode the input is expected to be a document in
+/// the format generated by the `export` command. If versions are preserved,
+/// the `_version` of the document is sent as an external version to ensure
+/// that newer documents in the target are never overwritten.
+///
+/// In `delete` mode the input can be either an exported document, or simply
+/// the `_id` of a document on each line (in which case the target index must
+/// be provided).
+///
+/// Blank lines (and documents which are filtered out, or dropped by a script)
+/// will return `None` to be skipped, whereas any other input which cannot be
+/// converted will return an error describing why.
+fn construct_operation(input: &[u8], parser: &Parser) -> Result<Option<Operation>> {
+    // fetch the action and index override from the options
+    let action = parser.action;
+    let index = parser.index.as_deref();
+
+    // skip any blank lines in the input
+    if input.iter().all(u8::is_ascii_whitespace) {
+        return Ok(None);
+    }
+
+    // sources which don't need to be inspected can skip parsing
+    if parser.raw && action != Action::Delete {
+        return construct_raw(input, parser).map(Some);
+    }
+
+    // parsed the bytes into a `Value` so we can fetch JSON data back from it
+    let mut parsed = match json::parse(input) {
+        Ok(parsed) if parsed.is_object() => parsed,
+        Ok(Value::String(id)) if action == Action::Delete => {
+            let id = parser.affix_id(&id);
+            return construct_delete(id, index, parser.resolve_type(None), None);
+        }
+        _ if action == Action::Delete => {
+            let id = parser.affix_id(std::str::from_utf8(input)?.trim());
+            return construct_delete(id, index, parser.resolve_type(None), None);
+        }
+        Ok(_) => return Err(anyhow!("Document is not a JSON object")),
+        Err(err) => return Err(err),
+    };
+
+    // skip any documents which don't match all filters
+    if !parser.filters.iter().all(|filter| filter.matches(&parsed)) {
+        return Ok(None);
+    }
+
+    // apply all transforms to the document source
+    for transform in parser.transforms {
+        transform.apply(&mut parsed["_source"])?;
+    }
+
+    // run any script, which can drop or reroute the document
+    let route = match parser.script.map(|script| script.run(&mut parsed)) {
+        Some(Ok(Outcome::Drop)) => return Ok(None),
+        Some(Ok(Outcome::Import(route))) => route,
+        Some(Err(err)) => return Err(err),
+        None => None,
+    };
+
+    // shim the index to the doc index, unless rerouted
+    let index = match route.as_deref().or(index) {
+        Some(index) => index.to_owned(),
+        None => match parser.template {
+            Some(template) => parser.renamer.rename(
+                &template
+                    .render(&parsed["_source"])
+                    .ok_or_else(|| anyhow!("Unable to render index template for document"))?,
+            ),
+            None => parser.renamer.rename(
+                parsed
+                    .get("_index")
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| anyhow!("Document is missing an _index"))?,
+            ),
+        },
+    };
+
+    // fetch the identifier of the document, rendering it when templated
+    let id = match (parser.id_from, parsed.get("_id").and_then(Value::as_str)) {
+        (Some(template), _) => template
+            .render(&parsed["_source"])
+            .ok_or_else(|| anyhow!("Unable to render id template for document"))?,
+        (None, Some(id)) => id.to_owned(),
+        (None, None) if parser.regenerate_ids => String::new(),
+        (None, None) if parser.generate_missing_ids && action != Action::Delete => String::new(),
+        (None, None) => return Err(anyhow!("Document is missing an _id")),
+    };
+    let id = parser.affix_id(&id);
+
+    // resolve the type of the document to suit the cluster
+    let ty = parser
+        .resolve_type(parsed.get("_type").and_then(Value::as_str))
+        .map(str::to_owned);
+
+    // children of a join field must be routed, so fall back to the parent
+    let routing = match parsed.get("_routing").and_then(Value::as_str) {
+        Some(routing) => Some(routing.to_owned()),
+        None => join::parent(&parsed["_source"], &parser.joins).map(|parent| {
+            warn!(id = %id, parent, "Document is missing a _routing, routing to its parent");
+            parent.to_owned()
+        }),
+    };
+
+    // delete mode only needs the document identifier (and routing)
+    if action == Action::Delete {
+        return construct_delete(id, Some(&index), ty.as_deref(), routing.as_deref());
+    }
+
+    // create our bulk request using the source, as provided when exact
+    let mut operation = if parser.exact {
+        let source = serde_json::from_slice::<RawDocument>(input)?.source;
+        let source = source.map_or("null", RawValue::get);
+        Operation::raw(action, Bytes::copy_from_slice(source.as_bytes()))
+    } else {
+        Operation::new(action, Some(parsed["_source"].take()))
+    };
+    // ids left empty when missing are generated by the cluster instead
+    let generated = parser.regenerate_ids || (parser.generate_missing_ids && id.is_empty());
+    if !generated {
+        operation = operation.metadata("_id", id);
+    }
+    let operation = operation.metadata("_index", index);
+
+    // fetch the exported version of the document
+    let version = parsed.get("_version").and_then(Value::as_i64);
+
+    Ok(Some(attach_metadata(
+        operation,
+        ty.as_deref(),
+        routing.as_deref(),
+        version,
+        parser,
+    )))
+}
+
+/// Document in the format generated by the `export` command.
+///
+/// The source is borrowed as raw JSON, which allows it to be passed through
+/// to the bulk request as is, avoiding the cost of parsing the document into
+/// a `Value` only to serialize it straight back again.
+#[derive(Deserialize)]
+struct RawDocument<'a> {
+    #[serde(rename = "_index", borrow)]
+    index: Option<Cow<'a, str>>,
+    #[serde(rename = "_id", borrow)]
+    id: Option<Cow<'a, str>>,
+    #[serde(rename = "_type", borrow)]
+    ty: Option<Cow<'a, str>>,
+    #[serde(rename = "_routing", borrow)]
+    routing: Option<Cow<'a, str>>,
+    #[serde(rename = "_version")]
+    version: Option<i64>,
+    #[serde(rename = "_source", borrow)]
+    source: Option<&'a RawValue>,
+}
+
+/// Constructs a bulk operation from a line of input, without parsing the source.
+///
+/// This is only possible when nothing needs to inspect or modify the source
+/// of the document, but is significantly faster than `construct_operation`.
+fn construct_raw(input: &[u8], parser: &Parser) -> Result<Operation> {
+    // structs can also be parsed from arrays, so check for an object first
+    if input.iter().find(|byte| !byte.is_ascii_whitespace()) != Some(&b'{') {
+        return Err(anyhow!("Document is not a JSON object"));
+    }
+
+    // parse the document, borrowing the source as is
+    let document = serde_json::from_slice::<RawDocument>(input)?;
+
+    // shim the index to the doc index
+    let index = match parser.index {
+        Some(ref index) => index.to_owned(),
+        None => parser.renamer.rename(
+            document
+                .index
+                .as_deref()
+                .ok_or_else(|| anyhow!("Document is missing an _index"))?,
+        ),
+    };
+
+    // fetch the identifier of the document, unless generating new ones
+    let id = match document.id {
+        Some(id) if !parser.regenerate_ids => Some(id),
+        None if !parser.regenerate_ids && !parser.generate_missing_ids => {
+            return Err(anyhow!("Document is missing an _id"))
+        }
+        _ => None,
+    };
+
+    // resolve the type of the document to suit the cluster
+    let ty = parser.resolve_type(document.ty.as_deref());
+
+    // create our bulk request using the raw source
+    let source = document.source.map_or("null", RawValue::get);
+    let mut operation = Operation::raw(parser.action, Bytes::copy_from_slice(source.as_bytes()));
+    if let Some(id) = id {
+        operation = operation.metadata("_id", parser.affix_id(&id));
+    }
+    let operation = operation.metadata("_index", index);
+
+    Ok(attach_metadata(
+        operation,
+        ty,
+        document.routing.as_deref(),
+        document.version,
+        parser,
+    ))
+}
+
+/// Attaches the type, routing and version metadata of a document to an operation.
+fn attach_metadata(
+    mut operation: Operation,
+    ty: Option<&str>,
+    routing: Option<&str>,
+    version: Option<i64>,
+    parser: &Parser,
+) -> Operation {
+    // attach the document type for older clusters
+    if let Some(ty) = ty {
+        operation = operation.metadata("_type", ty);
+    }
+
+    // attach the routing, keeping children on the same shard as parents
+    if let Some(routing) = routing {
+        operation = operation.metadata("routing", routing);
+    }
+
+    // attach the exported version as an external version when requested
+    if let (true, Some(version)) = (parser.preserve_version, version) {
+        operation = operation
+            .metadata("version", version)
+            .metadata("version_type", "external");
+    }
+
+    operation
+}
+
+/// Constructs a bulk delete operation for an identifier and index.
+///
+/// Empty identifiers will return `None`, and a missing index is an error.
+fn construct_delete<S>(
+    id: S,
+    index: Option<&str>,
+    ty: Option<&str>,
+    routing: Option<&str>,
+) -> Result<Option<Operation>>
+where
+    S: Into<String>,
+{
+    let id = id.into();
+    if id.is_empty() {
+        return Ok(None);
+    }
+
+    let index = index.ok_or_else(|| anyhow!("Deleting by identifier requires a target index"))?;
+    let mut operation = Operation::new(Action::Delete, None)
+        .metadata("_id", id)
+        .metadata("_index", index);
+
+    if let Some(ty) = ty {
+        operation = operation.metadata("_type", ty);
+    }
+    if let Some(routing) = routing {
+        operation = operation.metadata("routing", routing);
+    }
+
+    Ok(Some(operation))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thresholds_are_crossed_by_reaching_a_multiple() {
+        let docs = NonZeroUsize::new(10).unwrap();
+
+        // batches within a multiple never cross
+        assert!(!crosses_threshold(5, 5, docs));
+        assert!(!crosses_threshold(19, 8, docs));
+
+        // reaching or passing a multiple crosses, once per batch
+        assert!(crosses_threshold(10, 10, docs));
+        assert!(crosses_threshold(12, 4, docs));
+        assert!(crosses_threshold(35, 30, docs));
+        assert!(!crosses_threshold(21, 1, docs));
+    }
+
+    #[test]
+    fn missing_ids_are_left_to_the_cluster() {
+        let mut options = ImportOptions::new("http://localhost:9200/idx");
+        options.generate_missing_ids = true;
+        let index = Some("idx".to_owned());
+        let input = br#"{"_source":{"a":1}}"#;
+
+        // both raw and parsed documents are sent without an id
+        let mut parser = Parser::new(
+            &options,
+            index.clone(),
+            Action::Index,
+            Types::Removed,
+            vec![],
+        );
+        for raw in [true, false] {
+            parser.raw = raw;
+            let operation = construct_operation(input, &parser).unwrap().unwrap();
+            assert_eq!(operation.id(), None);
+        }
+
+        // ids which are present are kept as they are
+        let operation = construct_operation(br#"{"_id":"1","_source":{}}"#, &parser)
+            .unwrap()
+            .unwrap();
+        assert_eq!(operation.id(), Some("1"));
+
+        // deletes still require an id to delete
+        let parser = Parser::new(&options, index, Action::Delete, Types::Removed, vec![]);
+        let err = construct_operation(input, &parser).unwrap_err();
+        assert_eq!(err.to_string(), "Document is missing an _id");
+    }
+
+    #[test]
+    fn budget_parses_counts() {
+        assert_eq!(Budget::parse("10").unwrap(), Budget::Count(10));
+        assert_eq!(Budget::parse(" 0 ").unwrap(), Budget::Count(0));
+    }
+
+    #[test]
+    fn budget_parses_percentages() {
+        assert_eq!(Budget::parse("5%").unwrap(), Budget::Ratio(0.05));
+        assert_eq!(Budget::parse(" 0.5 % ").unwrap(), Budget::Ratio(0.005));
+        assert_eq!(Budget::parse("100%").unwrap(), Budget::Ratio(1.0));
+    }
+
+    #[test]
+    fn budget_rejects_invalid_thresholds() {
+        for input in ["", "%", "-1", "1.5", "ten", "-5%", "150%", "NaN%"] {
+            let err = Budget::parse(input).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("Invalid error threshold provided: {}", input)
+            );
+        }
+    }
+
+    #[test]
+    fn budget_ratios_wait_for_a_sample() {
+        let budget = Budget::Ratio(0.1);
+        assert!(!budget.exceeded(0, 0, true));
+        assert!(!budget.exceeded(50, 100, false));
+        assert!(budget.exceeded(50, 100, true));
+        assert!(budget.exceeded(101, MIN_BUDGET_SAMPLE, false));
+        assert!(!budget.exceeded(100, MIN_BUDGET_SAMPLE, false));
+        assert!(Budget::Count(2).exceeded(3, 3, false));
+    }
+}
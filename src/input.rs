@@ -0,0 +1,276 @@
+//! Input sources used to read documents during import.
+//!
+//! Documents are read as lines from either `stdin` or a file. Each line is
+//! paired with the byte offset at which it ends in the input, which allows
+//! an import to track exactly how much of a file has been handled.
+//!
+//! Input is always read as raw bytes (even on Windows, where there's no
+//! translation of line endings), so dumps written with `\r\n` line endings
+//! or a byte order mark (as is common on Windows) are handled when read.
+//!
+//! Input written as a JSON text sequence is read record by record instead,
+//! in which case any truncated record is skipped rather than corrupting the
+//! record following it.
+use anyhow::{anyhow, Result};
+use futures::stream::{self, Stream};
+use tokio::fs::File;
+use tokio::io::{self, AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncSeekExt};
+use tracing::warn;
+
+use std::io::SeekFrom;
+
+use crate::format::{Format, RECORD_SEPARATOR};
+
+/// The byte order mark written at the start of some UTF-8 files.
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// The byte order marks written at the start of UTF-16 files.
+const UTF16_BOMS: [&[u8]; 2] = [b"\xFF\xFE", b"\xFE\xFF"];
+
+/// Reader for any input source.
+pub type Reader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Opens an input source for reading.
+///
+/// If no path is provided, `stdin` is used. Files can be opened from a byte
+/// offset, to skip any input which has already been handled.
+pub async fn open(path: Option<&str>, offset: u64) -> Result<Reader> {
+    // no path means we just read from stdin
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(Box::new(io::stdin())),
+    };
+
+    // open the file and skip to the offset
+    let mut file = File::open(path).await?;
+    if offset > 0 {
+        file.seek(SeekFrom::Start(offset)).await?;
+    }
+
+    Ok(Box::new(file))
+}
+
+/// Expands any glob patterns within a set of input paths.
+///
+/// Paths without any pattern characters are kept as is, whereas patterns
+/// are replaced by every path they match (in sorted order). Patterns which
+/// match nothing are rejected, as they're most likely a mistake.
+pub fn expand<'a, I>(inputs: I) -> Result<Vec<String>>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut paths = Vec::new();
+    for input in inputs {
+        // plain paths are passed through, even if missing
+        if !input.contains(['*', '?', '[']) {
+            paths.push(input.to_owned());
+            continue;
+        }
+
+        // collect every path matching the pattern
+        let mut matched = glob::glob(input)?
+            .map(|path| Ok(path?.to_string_lossy().into_owned()))
+            .collect::<Result<Vec<_>>>()?;
+        if matched.is_empty() {
+            return Err(anyhow!("No inputs match the pattern {}", input));
+        }
+        matched.sort();
+        paths.extend(matched);
+    }
+    Ok(paths)
+}
+
+/// Reads an input source as a stream of lines.
+///
+/// Each line is provided without the trailing line break (either `\n` or
+/// `\r\n`), alongside the byte offset of the end of the line within the
+/// input. The offset provided is the position the input was opened at.
+///
+/// A UTF-8 byte order mark at the start of the input is skipped. Input
+/// encoded as UTF-16 (such as that written by redirection in PowerShell)
+/// is rejected with an error, as it can't be parsed as documents.
+pub fn lines<R>(reader: R, offset: u64) -> impl Stream<Item = io::Result<(Vec<u8>, u64)>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    records(reader, offset, Format::Ndjson)
+}
+
+/// Reads an input source as a stream of records in a format.
+///
+/// Records are provided in the same way as `lines`, with any separator and
+/// trailing line break removed. Records of a sequence which don't end in a
+/// line break have been truncated, so are provided as empty (and skipped).
+///
+/// The offset of each record of a sequence is that of the separator which
+/// starts the following record, so resuming from an offset starts reading
+/// from the next record.
+pub fn records<R>(
+    reader: R,
+    offset: u64,
+    format: Format,
+) -> impl Stream<Item = io::Result<(Vec<u8>, u64)>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    stream::unfold((reader, offset), move |(mut reader, offset)| async move {
+        let mut line = Vec::new();
+
+        // read through to the next delimiter
+        let read = match reader.read_until(format.delimiter(), &mut line).await {
+            Ok(0) => return None,
+            Ok(read) => read,
+            Err(err) => return Some((Err(err), (reader, offset))),
+        };
+
+        // handle any byte order mark at the very start of the input
+        if offset == 0 {
+            if UTF16_BOMS.iter().any(|bom| line.starts_with(bom)) {
+                let err = io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Input is encoded as UTF-16 (as written by PowerShell), convert it to UTF-8",
+                );
+                return Some((Err(err), (reader, offset)));
+            }
+            if line.starts_with(UTF8_BOM) {
+                line.drain(..UTF8_BOM.len());
+            }
+        }
+
+        // strip the separator of the next record, checking for truncation
+        if format == Format::JsonSeq {
+            if line.last() == Some(&RECORD_SEPARATOR) {
+                line.pop();
+            }
+            if !line.ends_with(b"\n") && !line.iter().all(u8::is_ascii_whitespace) {
+                warn!(offset, "Skipping truncated record in sequence");
+                line.clear();
+            }
+        }
+
+        // strip the line break from the line
+        if line.ends_with(b"\n") {
+            line.pop();
+            if line.ends_with(b"\r") {
+                line.pop();
+            }
+        }
+
+        let offset = offset + read as u64;
+        Some((Ok((line, offset)), (reader, offset)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::TryStreamExt;
+
+    async fn read(input: &[u8], format: Format) -> io::Result<Vec<(String, u64)>> {
+        read_from(input, 0, format).await
+    }
+
+    async fn read_from(
+        input: &[u8],
+        offset: u64,
+        format: Format,
+    ) -> io::Result<Vec<(String, u64)>> {
+        records(input, offset, format)
+            .map_ok(|(record, offset)| (String::from_utf8(record).unwrap(), offset))
+            .try_collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn lines_strip_line_breaks() {
+        let records = read(b"{\"a\":1}\r\n{\"b\":2}\n{\"c\":3}", Format::Ndjson)
+            .await
+            .unwrap();
+        assert_eq!(
+            records,
+            vec![
+                ("{\"a\":1}".to_owned(), 9),
+                ("{\"b\":2}".to_owned(), 17),
+                ("{\"c\":3}".to_owned(), 24),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn lines_skip_a_utf8_bom() {
+        let records = read(b"\xEF\xBB\xBF{\"a\":1}\n{\"b\":2}\n", Format::Ndjson)
+            .await
+            .unwrap();
+        assert_eq!(
+            records,
+            vec![("{\"a\":1}".to_owned(), 11), ("{\"b\":2}".to_owned(), 19)]
+        );
+    }
+
+    #[tokio::test]
+    async fn lines_only_skip_a_bom_at_the_start() {
+        let records = read(b"{\"a\":1}\n\xEF\xBB\xBF{\"b\":2}\n", Format::Ndjson)
+            .await
+            .unwrap();
+        assert_eq!(records[1].0, "\u{feff}{\"b\":2}");
+
+        // resumed input isn't at the start, even if the bytes look like a bom
+        let records = read_from(b"\xEF\xBB\xBF{\"b\":2}\n", 8, Format::Ndjson)
+            .await
+            .unwrap();
+        assert_eq!(records, vec![("\u{feff}{\"b\":2}".to_owned(), 19)]);
+    }
+
+    #[tokio::test]
+    async fn lines_reject_utf16() {
+        for input in [
+            &b"\xFF\xFE{\x00}\x00\n\x00"[..],
+            b"\xFE\xFF\x00{\x00}\x00\n",
+        ] {
+            let err = read(input, Format::Ndjson).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[tokio::test]
+    async fn sequences_strip_separators() {
+        let records = read(b"\x1e{\"a\":1}\n\x1e{\"b\":2}\r\n", Format::JsonSeq)
+            .await
+            .unwrap();
+        assert_eq!(
+            records,
+            vec![
+                ("".to_owned(), 1),
+                ("{\"a\":1}".to_owned(), 10),
+                ("{\"b\":2}".to_owned(), 19),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn sequences_skip_truncated_records() {
+        let records = read(b"\x1e{\"a\":\x1e{\"b\":2}\n\x1e{\"c\"", Format::JsonSeq)
+            .await
+            .unwrap();
+        assert_eq!(
+            records,
+            vec![
+                ("".to_owned(), 1),
+                ("".to_owned(), 7),
+                ("{\"b\":2}".to_owned(), 16),
+                ("".to_owned(), 20),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn sequences_skip_a_utf8_bom() {
+        let records = read(b"\xEF\xBB\xBF\x1e{\"a\":1}\n", Format::JsonSeq)
+            .await
+            .unwrap();
+        assert_eq!(
+            records,
+            vec![("".to_owned(), 4), ("{\"a\":1}".to_owned(), 12)]
+        );
+    }
+}
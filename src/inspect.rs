@@ -0,0 +1,125 @@
+//! Inspection of the contents of a dump.
+//!
+//! Old backups are often opaque, so this module gathers a summary of what a
+//! dump actually contains: the number of documents within each index, how
+//! large those documents are, the range of time they cover (based on a
+//! chosen field), and a sample of documents to look at.
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::value::RawValue;
+use serde_json::Value;
+
+use std::collections::BTreeMap;
+
+use crate::document;
+
+/// The upper bounds of each bucket of document sizes, in bytes.
+pub const SIZE_BUCKETS: [u64; 6] = [1 << 10, 4 << 10, 16 << 10, 64 << 10, 256 << 10, 1 << 20];
+
+/// Summary of the documents within a dump.
+#[derive(Debug, Default)]
+pub struct Inspection {
+    /// The field used to locate the time range of documents, if any.
+    pub time_field: Option<String>,
+    /// The number of documents seen.
+    pub documents: u64,
+    /// The number of records which couldn't be parsed as documents.
+    pub invalid: u64,
+    /// The total size of every document, in bytes.
+    pub bytes: u64,
+    /// The size of the smallest document, in bytes.
+    pub smallest: u64,
+    /// The size of the largest document, in bytes.
+    pub largest: u64,
+    /// The number of documents within each bucket of `SIZE_BUCKETS`.
+    ///
+    /// The extra bucket at the end holds anything larger than the last bound.
+    pub sizes: [u64; SIZE_BUCKETS.len() + 1],
+    /// The number of documents within each index.
+    pub indices: BTreeMap<String, u64>,
+    /// The earliest time found in the time field.
+    pub earliest: Option<DateTime<Utc>>,
+    /// The latest time found in the time field.
+    pub latest: Option<DateTime<Utc>>,
+    /// The number of documents without a valid time in the time field.
+    pub untimed: u64,
+    /// A sample of documents, taking the first from each index.
+    pub samples: Vec<String>,
+    /// The maximum number of documents to sample.
+    limit: usize,
+}
+
+/// Document as written in the export format.
+#[derive(Deserialize)]
+struct Document<'a> {
+    #[serde(rename = "_index")]
+    index: Option<String>,
+    #[serde(rename = "_source", borrow)]
+    source: Option<&'a RawValue>,
+}
+
+impl Inspection {
+    /// Constructs a new `Inspection`, with a time field and sample size.
+    pub fn new(time_field: Option<String>, limit: usize) -> Self {
+        Self {
+            time_field,
+            limit,
+            ..Self::default()
+        }
+    }
+
+    /// Observes a record of a dump, adding it to the summary.
+    ///
+    /// Records which can't be parsed as documents are only counted.
+    pub fn observe(&mut self, record: &[u8]) {
+        let parsed = match serde_json::from_slice::<Document>(record) {
+            Ok(parsed) => parsed,
+            Err(_) => {
+                self.invalid += 1;
+                return;
+            }
+        };
+
+        // track the size of the document
+        let size = record.len() as u64;
+        if self.documents == 0 || size < self.smallest {
+            self.smallest = size;
+        }
+        self.largest = self.largest.max(size);
+        self.bytes += size;
+        self.documents += 1;
+
+        // place the size into the first bucket which holds it
+        let bucket = SIZE_BUCKETS
+            .iter()
+            .position(|bound| size <= *bound)
+            .unwrap_or(SIZE_BUCKETS.len());
+        self.sizes[bucket] += 1;
+
+        // sample the first document of each index, up to the limit
+        let index = parsed.index.unwrap_or_default();
+        if !self.indices.contains_key(&index) && self.samples.len() < self.limit {
+            let sample = String::from_utf8_lossy(record).into_owned();
+            self.samples.push(sample);
+        }
+        *self.indices.entry(index).or_default() += 1;
+
+        // widen the time range to cover the document, if possible
+        if let Some(ref field) = self.time_field {
+            let source = parsed
+                .source
+                .and_then(|source| serde_json::from_str::<Value>(source.get()).ok());
+            let time = source
+                .as_ref()
+                .and_then(|source| document::lookup(source, field))
+                .and_then(document::parse_date);
+            match time {
+                Some(time) => {
+                    self.earliest = Some(self.earliest.map_or(time, |first| first.min(time)));
+                    self.latest = Some(self.latest.map_or(time, |last| last.max(time)));
+                }
+                None => self.untimed += 1,
+            }
+        }
+    }
+}
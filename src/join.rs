@@ -0,0 +1,186 @@
+//! Detection of join (parent/child) fields within index mappings.
+//!
+//! Documents within an index containing a join field must live on the same
+//! shard as their parent, so the `_routing` of each document has to survive
+//! a backup and restore. Relations can also be ordered by depth, to allow
+//! parents to be exported ahead of their children.
+use anyhow::Result;
+use elasticsearch::Elasticsearch;
+use serde_json::{json, Map, Value};
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::document;
+use crate::remote;
+
+/// Join field located within the mapping of an index.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct JoinField {
+    /// The path of the field within the document source.
+    pub path: String,
+    /// The relations of the field, as each parent and its children.
+    pub relations: BTreeMap<String, Vec<String>>,
+}
+
+impl JoinField {
+    /// Groups the relations of this field by depth, starting with the roots.
+    pub fn levels(&self) -> Vec<Vec<String>> {
+        let children = self.relations.values().flatten().collect::<BTreeSet<_>>();
+
+        // roots are any parents which are never a child themselves
+        let mut level = self
+            .relations
+            .keys()
+            .filter(|parent| !children.contains(parent))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        // walk down through each level, skipping anything already seen
+        let mut seen = BTreeSet::new();
+        let mut levels = Vec::new();
+        while !level.is_empty() {
+            level.retain(|name| seen.insert(name.clone()));
+            let next = level
+                .iter()
+                .filter_map(|name| self.relations.get(name))
+                .flatten()
+                .cloned()
+                .collect();
+            if !level.is_empty() {
+                levels.push(level);
+            }
+            level = next;
+        }
+
+        levels
+    }
+}
+
+/// Detects every join field within the indices matching a pattern.
+///
+/// Fields shared by several indices (with the same relations) are only
+/// returned once.
+pub(crate) async fn detect(client: &Elasticsearch, index: &str) -> Result<Vec<JoinField>> {
+    let mut fields = Vec::new();
+    for mappings in remote::get_mappings(client, index).await?.values() {
+        for field in find(mappings) {
+            if !fields.contains(&field) {
+                fields.push(field);
+            }
+        }
+    }
+    Ok(fields)
+}
+
+/// Locates every join field within the mappings of an index.
+///
+/// Mappings are accepted both with and without a document type, as older
+/// clusters nest the properties of an index beneath its type.
+pub(crate) fn find(mappings: &Value) -> Vec<JoinField> {
+    let mut fields = Vec::new();
+    match mappings.get("properties").and_then(Value::as_object) {
+        Some(properties) => walk(properties, "", &mut fields),
+        None => {
+            let types = mappings.as_object().into_iter().flat_map(Map::values);
+            for properties in types.filter_map(|ty| ty.get("properties")?.as_object()) {
+                walk(properties, "", &mut fields);
+            }
+        }
+    }
+    fields
+}
+
+/// Constructs a query for each depth of relation, from parents to children.
+///
+/// Running each query in turn selects every document matching the original
+/// query exactly once, with parents selected before any of their children.
+/// Documents without a join value are selected alongside the roots.
+pub(crate) fn passes(query: &Value, fields: &[JoinField]) -> Vec<Value> {
+    let levels = fields.iter().map(JoinField::levels).collect::<Vec<_>>();
+    let depth = levels.iter().map(Vec::len).max().unwrap_or(0);
+
+    // nothing to order without relations spanning multiple levels
+    if depth < 2 {
+        return vec![query.clone()];
+    }
+
+    let mut passes = Vec::with_capacity(depth);
+    for level in 0..depth {
+        // match any field with a relation at this depth
+        let mut should = fields
+            .iter()
+            .zip(&levels)
+            .filter_map(|(field, levels)| {
+                let names = levels.get(level)?;
+                Some(json!({ "terms": { field.path.clone(): names } }))
+            })
+            .collect::<Vec<_>>();
+
+        // the roots also include any documents without a relation
+        if level == 0 {
+            let exists = fields
+                .iter()
+                .map(|field| json!({ "exists": { "field": field.path } }))
+                .collect::<Vec<_>>();
+            should.push(json!({ "bool": { "must_not": exists } }));
+        }
+
+        passes.push(json!({
+            "bool": {
+                "must": [query],
+                "filter": {
+                    "bool": {
+                        "should": should,
+                        "minimum_should_match": 1
+                    }
+                }
+            }
+        }));
+    }
+
+    passes
+}
+
+/// Locates the parent of a document from the value of its join fields.
+pub(crate) fn parent<'a>(source: &'a Value, fields: &[String]) -> Option<&'a str> {
+    fields
+        .iter()
+        .filter_map(|field| document::lookup(source, field))
+        .find_map(|value| value.get("parent")?.as_str())
+}
+
+/// Walks the properties of a mapping, collecting any join fields.
+fn walk(properties: &Map<String, Value>, prefix: &str, fields: &mut Vec<JoinField>) {
+    for (name, property) in properties {
+        let path = format!("{}{}", prefix, name);
+
+        // join fields describe each parent alongside one or many children
+        if property.get("type").and_then(Value::as_str) == Some("join") {
+            let relations = property
+                .get("relations")
+                .and_then(Value::as_object)
+                .into_iter()
+                .flatten()
+                .map(|(parent, children)| {
+                    let children = match children {
+                        Value::String(child) => vec![child.clone()],
+                        Value::Array(children) => children
+                            .iter()
+                            .filter_map(Value::as_str)
+                            .map(str::to_owned)
+                            .collect(),
+                        _ => Vec::new(),
+                    };
+                    (parent.clone(), children)
+                })
+                .collect();
+            fields.push(JoinField { path, relations });
+            continue;
+        }
+
+        // objects can nest further fields beneath them
+        if let Some(properties) = property.get("properties").and_then(Value::as_object) {
+            walk(properties, &format!("{}.", path), fields);
+        }
+    }
+}
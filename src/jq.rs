@@ -0,0 +1,129 @@
+//! Inline jq expressions used to transform documents.
+//!
+//! Most transforms beyond setting, removing and renaming fields are still
+//! fairly small (such as deleting a nested field, or deriving a field from
+//! another), and don't justify writing a plugin. These can instead be given
+//! as a jq expression, such as `.user |= del(.email) | .env = "staging"`,
+//! which is evaluated against each document source via an embedded engine.
+//!
+//! The jq standard library is available, but expressions are unable to read
+//! any input beyond the document source. Expressions must produce exactly
+//! one output for each source, which replaces the source.
+use anyhow::{anyhow, Result};
+use jaq_core::load::{self, Arena, File, Loader};
+use jaq_core::{compile, Compiler, Ctx, Filter, Native, RcIter};
+use jaq_json::Val;
+use serde_json::Value;
+
+use std::fmt::{self, Debug, Formatter};
+
+/// Transform evaluating a jq expression against each document source.
+pub struct Jq {
+    expression: String,
+    filter: Filter<Native<Val>>,
+}
+
+impl Jq {
+    /// Compiles a jq expression, reporting any mistakes within it.
+    pub fn compile(expression: &str) -> Result<Self> {
+        let invalid = |reason: String| anyhow!("Invalid jq expression provided: {}", reason);
+
+        // parse the expression, alongside the standard library
+        let arena = Arena::default();
+        let loader = Loader::new(jaq_std::defs().chain(jaq_json::defs()));
+        let program = File {
+            code: expression,
+            path: (),
+        };
+        let modules = loader
+            .load(&arena, program)
+            .map_err(|errors| invalid(describe_load(expression, errors)))?;
+
+        // compile the expression, failing on any unknown symbols
+        let filter = Compiler::default()
+            .with_funs(jaq_std::funs().chain(jaq_json::funs()))
+            .compile(modules)
+            .map_err(|errors| invalid(describe_compile(errors)))?;
+
+        Ok(Self {
+            expression: expression.to_owned(),
+            filter,
+        })
+    }
+
+    /// Evaluates this expression against a document source.
+    ///
+    /// The source is replaced with the output of the expression. Errors raised
+    /// by the expression (or a number of outputs other than one) are returned
+    /// as errors.
+    pub fn apply(&self, source: &mut Value) -> Result<()> {
+        let failed = |reason: &dyn fmt::Display| {
+            anyhow!("Expression {} failed: {}", self.expression, reason)
+        };
+
+        // expressions can't read any further inputs
+        let inputs = RcIter::new(std::iter::empty());
+        let input = Val::from(source.take());
+        let mut outputs = self.filter.run((Ctx::new([], &inputs), input));
+
+        // exactly one output is required to replace the source
+        let output = match (outputs.next(), outputs.next()) {
+            (Some(Ok(output)), None) => output,
+            (Some(Err(err)), _) => return Err(failed(&err)),
+            (None, _) => return Err(failed(&"no output produced")),
+            (Some(_), Some(_)) => return Err(failed(&"multiple outputs produced")),
+        };
+
+        *source = Value::from(output);
+        Ok(())
+    }
+}
+
+impl Debug for Jq {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Jq")
+            .field("expression", &self.expression)
+            .finish()
+    }
+}
+
+/// Describes the first error raised when parsing an expression.
+fn describe_load(expression: &str, errors: load::Errors<&str, ()>) -> String {
+    // locate the position of a remaining slice of the expression
+    let position = |remaining: &str| expression.len() - remaining.len();
+
+    match errors.into_iter().next().map(|(_, error)| error) {
+        Some(load::Error::Lex(errors)) => match errors.first() {
+            Some((expect, remaining)) => format!(
+                "expected {} at position {}",
+                expect.as_str(),
+                position(remaining)
+            ),
+            None => "unknown lexing error".to_owned(),
+        },
+        Some(load::Error::Parse(errors)) => match errors.first() {
+            Some((expect, remaining)) => format!(
+                "expected {} at position {}",
+                expect.as_str(),
+                position(remaining)
+            ),
+            None => "unknown parsing error".to_owned(),
+        },
+        Some(load::Error::Io(errors)) => match errors.first() {
+            Some((path, reason)) => format!("unable to load module {}: {}", path, reason),
+            None => "unknown loading error".to_owned(),
+        },
+        None => "unknown error".to_owned(),
+    }
+}
+
+/// Describes the first error raised when compiling an expression.
+fn describe_compile(errors: compile::Errors<&str, ()>) -> String {
+    match errors.into_iter().flat_map(|(_, errors)| errors).next() {
+        Some((name, compile::Undefined::Filter(arity))) => {
+            format!("undefined filter {}/{}", name, arity)
+        }
+        Some((name, _)) => format!("undefined symbol {}", name),
+        None => "unknown error".to_owned(),
+    }
+}
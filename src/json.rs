@@ -0,0 +1,22 @@
+//! JSON parsing used on the hot path of an import.
+//!
+//! Documents are parsed with `serde_json` by default, but can be parsed with
+//! `simd-json` instead by enabling the `simd` feature. This is considerably
+//! faster on CPUs supporting SIMD, at the cost of copying each input line,
+//! as `simd-json` parses input in place. Numbers parsed by `simd-json` are
+//! limited to 64 bits, so any which are larger (or more precise) than that
+//! will not be preserved exactly.
+use anyhow::Result;
+use serde_json::Value;
+
+/// Parses a slice of JSON into a `Value`.
+#[cfg(not(feature = "simd"))]
+pub(crate) fn parse(input: &[u8]) -> Result<Value> {
+    Ok(serde_json::from_slice(input)?)
+}
+
+/// Parses a slice of JSON into a `Value`, using `simd-json`.
+#[cfg(feature = "simd")]
+pub(crate) fn parse(input: &[u8]) -> Result<Value> {
+    Ok(simd_json::serde::from_slice(&mut input.to_vec())?)
+}
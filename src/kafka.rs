@@ -0,0 +1,323 @@
+//! Kafka topics used as a source (or sink) of documents.
+//!
+//! Messages are consumed from a topic as part of a consumer group, and are
+//! converted into lines in the format generated by an export. Documents in
+//! that format are passed through as is, whereas anything else is treated
+//! as the source of a document, using the key of the message as its `_id`.
+//!
+//! Offsets are never committed automatically. Instead, the offset of each
+//! message is only committed once every document up to (and including) it
+//! has been acknowledged by the cluster, so restarting an import replays
+//! anything which may not have been indexed.
+//!
+//! Exported documents can be published to a topic in the same format, with
+//! each message keyed by the `_id` of the document it contains.
+//!
+//! This module is only available with the `kafka` feature enabled.
+use anyhow::{anyhow, Result};
+use futures::stream::{FuturesUnordered, TryStreamExt};
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{CommitMode, Consumer, StreamConsumer};
+use rdkafka::producer::{DeliveryFuture, FutureProducer, FutureRecord};
+use rdkafka::{Message, Offset, TopicPartitionList};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream};
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use crate::format::RECORD_SEPARATOR;
+use crate::import::Commit;
+use crate::input::Reader;
+
+/// The size of the buffer between the consumer and the import.
+const PIPE_SIZE: usize = 1024 * 1024;
+
+/// The consumer group used when none is provided.
+const DEFAULT_GROUP: &str = "limber";
+
+/// The number of messages awaiting delivery before publishing waits.
+const MAX_IN_FLIGHT: usize = 10_000;
+
+/// Source of documents consumed from a Kafka topic.
+///
+/// Sources are cheap to clone, with every clone sharing the same consumer.
+#[derive(Clone)]
+pub struct Source {
+    topic: String,
+    consumer: Arc<StreamConsumer>,
+    positions: Arc<Mutex<Positions>>,
+}
+
+/// Positions of consumed messages, tracked until they can be committed.
+#[derive(Default)]
+struct Positions {
+    /// The end of each message within the input, alongside its partition and offset.
+    pending: VecDeque<(u64, i32, i64)>,
+    /// The next offset to commit for each partition, once advanced.
+    committed: BTreeMap<i32, i64>,
+}
+
+impl Source {
+    /// Connects to a topic via a URL in the form `kafka://brokers/topic`.
+    ///
+    /// Multiple brokers can be separated by commas. The consumer group is
+    /// set via the `group` parameter (defaulting to `limber`), and any other
+    /// parameters are passed through as configuration of the consumer (such
+    /// as `security.protocol=ssl`).
+    pub fn connect(url: &str) -> Result<Self> {
+        // start from a configuration which never commits automatically
+        let mut config = ClientConfig::new();
+        config
+            .set("group.id", DEFAULT_GROUP)
+            .set("enable.auto.commit", "false")
+            .set("enable.auto.offset.store", "false")
+            .set("auto.offset.reset", "earliest");
+
+        // subscribe to the topic, ready to consume
+        let topic = configure(url, &mut config)?;
+        let consumer = config.create::<StreamConsumer>()?;
+        consumer.subscribe(&[&topic])?;
+
+        Ok(Self {
+            topic,
+            consumer: Arc::new(consumer),
+            positions: Arc::default(),
+        })
+    }
+
+    /// Returns a reader of every message consumed from the topic, as lines.
+    ///
+    /// The reader never ends on its own, as more messages may arrive at any
+    /// time, so the import should be shut down once no longer needed.
+    pub fn reader(&self) -> Reader {
+        let (input, output) = io::duplex(PIPE_SIZE);
+        let source = self.clone();
+        tokio::spawn(async move {
+            if let Err(err) = source.consume(output).await {
+                warn!(error = %err, "Stopped consuming from Kafka");
+            }
+        });
+        Box::new(input)
+    }
+
+    /// Commits every advanced partition synchronously, before finishing.
+    pub fn finish(&self) -> Result<()> {
+        let positions = self.positions.lock().expect("poisoned lock");
+        let partitions = positions.committed.iter().map(|(p, o)| (*p, *o));
+        self.send(partitions, CommitMode::Sync)
+    }
+
+    /// Consumes messages from the topic, writing each as a line of output.
+    async fn consume(&self, mut output: DuplexStream) -> Result<()> {
+        let mut position = 0;
+        loop {
+            let message = self.consumer.recv().await?;
+
+            // convert the message into a line, keeping track of where it ends
+            let line = convert(message.payload(), message.key());
+            position += line.len() as u64;
+            self.positions
+                .lock()
+                .expect("poisoned lock")
+                .pending
+                .push_back((position, message.partition(), message.offset()));
+
+            // a closed output means the import has finished
+            output.write_all(&line).await?;
+        }
+    }
+
+    /// Commits offsets of partitions which are currently assigned to us.
+    fn send<I>(&self, partitions: I, mode: CommitMode) -> Result<()>
+    where
+        I: IntoIterator<Item = (i32, i64)>,
+    {
+        // never commit to partitions handed to another consumer
+        let assignment = self.consumer.assignment()?;
+        let mut list = TopicPartitionList::new();
+        for (partition, offset) in partitions {
+            if assignment.find_partition(&self.topic, partition).is_some() {
+                list.add_partition_offset(&self.topic, partition, Offset::Offset(offset))?;
+            }
+        }
+
+        // nothing to commit when no partitions remain
+        if list.count() == 0 {
+            return Ok(());
+        }
+
+        debug!(partitions = list.count(), "Committing Kafka offsets");
+        Ok(self.consumer.commit(&list, mode)?)
+    }
+}
+
+impl Commit for Source {
+    /// Commits the offset of every message ending at (or before) an offset.
+    fn commit(&self, offset: u64) -> Result<()> {
+        let mut positions = self.positions.lock().expect("poisoned lock");
+        let positions = &mut *positions;
+
+        // collect the next offset of every partition which has advanced
+        let mut advanced = BTreeMap::new();
+        while let Some(&(end, partition, next)) = positions.pending.front() {
+            if end > offset {
+                break;
+            }
+            positions.pending.pop_front();
+            advanced.insert(partition, next + 1);
+        }
+        positions.committed.extend(&advanced);
+
+        // nothing to do without any progress
+        if advanced.is_empty() {
+            return Ok(());
+        }
+
+        self.send(advanced, CommitMode::Async)
+    }
+}
+
+/// Sink of documents published to a Kafka topic.
+pub struct Sink {
+    topic: String,
+    producer: FutureProducer,
+}
+
+/// Identifier of a document, used to key the message containing it.
+#[derive(Deserialize)]
+struct Key {
+    #[serde(rename = "_id")]
+    id: Option<String>,
+}
+
+impl Sink {
+    /// Connects to a topic via a URL in the form `kafka://brokers/topic`.
+    ///
+    /// Parameters are passed through as configuration of the producer, in
+    /// the same way as those of a [`Source`].
+    pub fn connect(url: &str) -> Result<Self> {
+        let mut config = ClientConfig::new();
+        let topic = configure(url, &mut config)?;
+        let producer = config.create::<FutureProducer>()?;
+        Ok(Self { topic, producer })
+    }
+
+    /// Spawns a task publishing every document written to the returned writer.
+    ///
+    /// The task completes once the returned writer has been dropped and every
+    /// document written to it has been delivered to the topic.
+    pub fn spawn(self) -> (DuplexStream, JoinHandle<Result<()>>) {
+        let (input, output) = io::duplex(PIPE_SIZE);
+        let task = tokio::spawn(self.publish(output));
+        (input, task)
+    }
+
+    /// Publishes each line of input as a message, keyed by document identifier.
+    async fn publish(self, reader: DuplexStream) -> Result<()> {
+        let mut lines = BufReader::new(reader).split(b'\n');
+        let mut pending = FuturesUnordered::new();
+
+        while let Some(line) = lines.next_segment().await? {
+            // records of a sequence are also written with a trailing line break
+            let document = line.strip_prefix(&[RECORD_SEPARATOR]).unwrap_or(&line);
+            if document.is_empty() {
+                continue;
+            }
+
+            // key each message by the identifier of the document, if any
+            let key = serde_json::from_slice::<Key>(document)
+                .ok()
+                .and_then(|key| key.id);
+            let mut record = FutureRecord::<String, [u8]>::to(&self.topic).payload(document);
+            if let Some(ref key) = key {
+                record = record.key(key);
+            }
+
+            // queue the message, waiting for deliveries when the queue is full
+            let delivery = self.producer.send_result(record).map_err(|(err, _)| err)?;
+            pending.push(delivered(delivery));
+            if pending.len() >= MAX_IN_FLIGHT {
+                pending.try_next().await?;
+            }
+        }
+
+        // wait for everything left to be delivered
+        while pending.try_next().await?.is_some() {}
+        Ok(())
+    }
+}
+
+/// Waits for a message to be delivered, surfacing any error on failure.
+async fn delivered(delivery: DeliveryFuture) -> Result<()> {
+    match delivery.await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err((err, _))) => Err(anyhow!("Unable to publish to Kafka: {}", err)),
+        Err(_) => Err(anyhow!("Kafka producer shut down before delivery")),
+    }
+}
+
+/// Applies the brokers and parameters of a URL to a configuration.
+///
+/// The topic is returned, with the `group` parameter accepted as shorthand
+/// for the consumer group.
+fn configure(url: &str, config: &mut ClientConfig) -> Result<String> {
+    // split the brokers from the topic and parameters
+    let rest = url
+        .strip_prefix("kafka://")
+        .ok_or_else(|| anyhow!("Invalid Kafka URL: {}", url))?;
+    let (location, query) = rest.split_once('?').unwrap_or((rest, ""));
+    let (brokers, topic) = location
+        .split_once('/')
+        .filter(|(brokers, topic)| !brokers.is_empty() && !topic.is_empty())
+        .ok_or_else(|| anyhow!("Kafka URL must contain brokers and a topic: {}", url))?;
+    config.set("bootstrap.servers", brokers);
+
+    // apply any parameters, with the group as a shorthand
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Invalid Kafka parameter: {}", pair))?;
+        match key {
+            "group" => config.set("group.id", value),
+            key => config.set(key, value),
+        };
+    }
+
+    Ok(topic.to_owned())
+}
+
+/// Converts the payload of a message into lines in the export format.
+///
+/// Payloads which aren't a single JSON value (such as several lines of
+/// documents) are passed through as is, and empty payloads are skipped.
+/// Sources of messages without a key are left without an `_id`, which the
+/// import leaves to the cluster to generate.
+fn convert(payload: Option<&[u8]>, key: Option<&[u8]>) -> Vec<u8> {
+    // trim the payload, skipping any which are empty
+    let payload = payload.unwrap_or_default().trim_ascii();
+    if payload.is_empty() {
+        return Vec::new();
+    }
+
+    // parse the payload, falling back to passing it through
+    let document = match serde_json::from_slice::<Value>(payload) {
+        Ok(document) if document.get("_source").is_some() => document,
+        Ok(source) => match key.map(String::from_utf8_lossy) {
+            Some(id) => json!({ "_id": id, "_source": source }),
+            None => json!({ "_source": source }),
+        },
+        Err(_) => {
+            let mut line = payload.to_vec();
+            line.push(b'\n');
+            return line;
+        }
+    };
+
+    let mut line = serde_json::to_vec(&document).expect("valid JSON");
+    line.push(b'\n');
+    line
+}
@@ -0,0 +1,79 @@
+//! A simple (but quick) tool for backing up Elasticsearch documents.
+//!
+//! It's designed for efficient import/export of Elasticsearch indices,
+//! rather than complex use cases. If you want anything more than simple
+//! backup/restore, you probably want to look somewhere else (or extend
+//! this tool as necessary).
+//!
+//! Limber is primarily built as a command line tool, but the export and
+//! import processes are also available as a library. This allows backup
+//! and restore to be embedded in other services, without having to shell
+//! out to the binary:
+//!
+//! ```no_run
+//! # async fn run() -> anyhow::Result<()> {
+//! let export = limber::ExportOptions::new("http://localhost:9200/my-index");
+//! let file = tokio::fs::File::create("my-index.json").await?;
+//! limber::export(export, file).await?;
+//!
+//! let import = limber::ImportOptions::new("http://localhost:9200/my-copy");
+//! let file = tokio::fs::File::open("my-index.json").await?;
+//! limber::import(import, file).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Progress and failures are emitted as `tracing` events rather than being
+//! printed, so they can be routed into whichever subscriber is installed.
+#![doc(html_root_url = "https://docs.rs/limber/1.1.1")]
+pub mod bulk;
+pub mod checkpoint;
+pub mod cluster;
+pub mod codec;
+pub mod compress;
+pub mod dedupe;
+pub mod encoding;
+pub mod error;
+pub mod export;
+pub mod filter;
+pub mod format;
+pub mod identity;
+pub mod import;
+pub mod input;
+pub mod inspect;
+pub mod jq;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+pub mod mapping;
+pub mod naming;
+#[cfg(feature = "parquet")]
+pub mod parquet;
+pub mod plugin;
+pub mod script;
+pub mod shutdown;
+pub mod split;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod state;
+pub mod stats;
+pub mod tee;
+pub mod transform;
+pub mod units;
+
+mod adaptive;
+mod dead_letter;
+mod document;
+mod join;
+mod json;
+mod preflight;
+mod recording;
+mod remote;
+mod retry;
+mod throttle;
+
+pub use elasticsearch::auth::Credentials;
+pub use export::{export, ExportOptions};
+pub use import::{import, import_all, ImportOptions};
+pub use remote::ClientOptions;
+pub use shutdown::Shutdown;
+pub use stats::Tracker;
@@ -5,27 +5,93 @@
 //! backup/restore, you probably want to look somewhere else (or extend
 //! this tool as necessary).
 //!
-//! Limber is only built as a command line tool, as it's simply a small
-//! CLI binding around the fairly low-level Elasticsearch library APIs.
-#![doc(html_root_url = "https://docs.rs/limber/1.1.1")]
-use anyhow::Result;
-use clap::Command;
+//! This binary is a thin CLI binding around the export and import APIs
+//! offered by the library, which can also be embedded in other services.
+use clap::{Arg, ArgMatches, Command};
+use limber::error::ErrorKind;
+use tracing::Level;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::prelude::*;
+
+use std::io::IsTerminal;
+use std::process::ExitCode;
 
 mod command;
 use command::*;
 
-mod remote;
-mod stats;
+mod config;
+mod metrics;
+mod progress;
+mod signals;
+mod summary;
+
+/// Exit code used for any failure not covered by another code.
+const EXIT_FAILURE: u8 = 1;
+
+/// Exit code used for invalid usage, matching the code used by `clap`.
+const EXIT_USAGE: u8 = 2;
+
+/// Exit code used when unable to connect or authenticate with a cluster.
+const EXIT_CONNECTION: u8 = 3;
+
+/// Exit code used when some documents failed to be processed.
+const EXIT_DOCUMENTS: u8 = 4;
+
+/// Exit code used when a cluster failed verification after an import.
+const EXIT_VERIFICATION: u8 = 5;
+
+/// Exit code used when interrupted, matching the convention for `SIGINT`.
+const EXIT_INTERRUPTED: u8 = 130;
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    match build_cli().get_matches().subcommand() {
+async fn main() -> ExitCode {
+    // parse arguments, applying any configured defaults
+    let args = match config::parse(build_cli(), std::env::args_os().collect()) {
+        Ok(args) => args,
+        Err(err) => return exit(err, EXIT_USAGE),
+    };
+
+    // log to stderr at the requested verbosity
+    init_logging(&args);
+
+    let result = match args.subcommand() {
+        Some(("anonymize", args)) => anonymize::run(args).await,
+        Some(("convert", args)) => convert::run(args).await,
         Some(("export", args)) => export::run(args).await,
+        Some(("filter", args)) => filter::run(args).await,
         Some(("import", args)) => import::run(args).await,
+        Some(("infer-mapping", args)) => infer_mapping::run(args).await,
+        Some(("inspect", args)) => inspect::run(args).await,
+        Some(("jobs", args)) => jobs::run(args),
+        Some(("mapping-diff", args)) => mapping_diff::run(args).await,
+        Some(("merge", args)) => merge::run(args).await,
+        Some(("schedule", args)) => schedule::run(args).await,
+        Some(("split", args)) => split::run(args).await,
         _ => build_cli().print_help().map_err(Into::into),
+    };
+
+    // exit with a code based on the kind of failure
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            let code = match ErrorKind::of(&err) {
+                Some(ErrorKind::Connection) => EXIT_CONNECTION,
+                Some(ErrorKind::Documents) => EXIT_DOCUMENTS,
+                Some(ErrorKind::Verification) => EXIT_VERIFICATION,
+                Some(ErrorKind::Interrupted) => EXIT_INTERRUPTED,
+                None => EXIT_FAILURE,
+            };
+            exit(err, code)
+        }
     }
 }
 
+/// Reports an error before exiting with the provided code.
+fn exit(err: anyhow::Error, code: u8) -> ExitCode {
+    eprintln!("Error: {:?}", err);
+    ExitCode::from(code)
+}
+
 /// Creates a parser used to generate `Options`.
 ///
 /// All command line usage information can be found in the definitions
@@ -39,10 +105,180 @@ fn build_cli<'a>() -> Command<'a> {
         .name(env!("CARGO_PKG_NAME"))
         .about(env!("CARGO_PKG_DESCRIPTION"))
         .version(env!("CARGO_PKG_VERSION"))
+        // attach all global flags
+        .arg(
+            Arg::new("config")
+                .help("A configuration file to read default options from")
+                .long("config")
+                .env("LIMBER_CONFIG")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::new("log-format")
+                .help("The format to log in (text, json)")
+                .long("log-format")
+                .env("LIMBER_LOG_FORMAT")
+                .takes_value(true)
+                .possible_values(["text", "json"])
+                .global(true),
+        )
+        .arg(
+            Arg::new("log-level")
+                .help("The level to log at (error, warn, info, debug, trace)")
+                .long("log-level")
+                .env("LIMBER_LOG_LEVEL")
+                .takes_value(true)
+                .possible_values(["error", "warn", "info", "debug", "trace"])
+                .global(true),
+        )
+        .arg(
+            Arg::new("metrics-listen")
+                .help("An address to serve Prometheus metrics on while running")
+                .long("metrics-listen")
+                .env("LIMBER_METRICS_LISTEN")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::new("notify-url")
+                .help("A URL to POST the statistics of a run to once complete")
+                .long("notify-url")
+                .env("LIMBER_NOTIFY_URL")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::new("otlp-endpoint")
+                .help("An OpenTelemetry collector to push metrics to via OTLP/HTTP")
+                .long("otlp-endpoint")
+                .env("LIMBER_OTLP_ENDPOINT")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::new("progress")
+                .help("The format to report progress in (bar, log, json)")
+                .long("progress")
+                .env("LIMBER_PROGRESS")
+                .takes_value(true)
+                .possible_values(["bar", "log", "json"])
+                .global(true),
+        )
+        .arg(
+            Arg::new("progress-fd")
+                .help("A file descriptor to write JSON progress to")
+                .long("progress-fd")
+                .env("LIMBER_PROGRESS_FD")
+                .takes_value(true)
+                .default_value("2")
+                .global(true),
+        )
+        .arg(
+            Arg::new("progress-every")
+                .help("How often to report progress, as a duration or document count")
+                .long("progress-every")
+                .env("LIMBER_PROGRESS_EVERY")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::new("quiet")
+                .help("Only report warnings and errors, without any progress")
+                .long("quiet")
+                .env("LIMBER_QUIET")
+                .conflicts_with("verbose")
+                .global(true),
+        )
+        .arg(
+            Arg::new("state-dir")
+                .help("A directory to keep the state of named jobs in")
+                .long("state-dir")
+                .env("LIMBER_STATE_DIR")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::new("statsd")
+                .help("A StatsD server to push metrics to, as host:port")
+                .long("statsd")
+                .env("LIMBER_STATSD")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::new("stats-file")
+                .help("A file to write statistics of the run to, as JSON")
+                .long("stats-file")
+                .env("LIMBER_STATS_FILE")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::new("verbose")
+                .help("Increases the verbosity of logging (up to -vv)")
+                .short('v')
+                .long("verbose")
+                .multiple_occurrences(true)
+                .global(true),
+        )
         // attach all commands
+        .subcommand(anonymize::cmd())
+        .subcommand(convert::cmd())
         .subcommand(export::cmd())
+        .subcommand(filter::cmd())
         .subcommand(import::cmd())
+        .subcommand(infer_mapping::cmd())
+        .subcommand(inspect::cmd())
+        .subcommand(jobs::cmd())
+        .subcommand(mapping_diff::cmd())
+        .subcommand(merge::cmd())
+        .subcommand(schedule::cmd())
+        .subcommand(split::cmd())
         // settings required for parsing
         .arg_required_else_help(true)
         .hide_possible_values(true)
 }
+
+/// Initializes logging to `stderr` based on the provided arguments.
+///
+/// Messages are logged at `info` by default, with each `-v` increasing the
+/// verbosity to `debug` and then `trace` (and `--quiet` reducing it down to
+/// `warn`). An explicit `--log-level` always takes precedence. Only messages
+/// emitted by Limber itself are logged, either as plain text or as one JSON
+/// object per line.
+fn init_logging(args: &ArgMatches) {
+    // global flags are propagated down to the command being run
+    let args = args.subcommand().map_or(args, |(_, args)| args);
+
+    // determine the level from the flags provided
+    let level = match args.value_of("log-level") {
+        Some(level) => level.parse().expect("guaranteed by CLI"),
+        None if args.is_present("quiet") => Level::WARN,
+        None => match args.occurrences_of("verbose") {
+            0 => Level::INFO,
+            1 => Level::DEBUG,
+            _ => Level::TRACE,
+        },
+    };
+
+    // only colour output for a terminal, unless asked not to via `NO_COLOR`
+    let ansi = std::io::stderr().is_terminal()
+        && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty());
+
+    // write to stderr, leaving stdout free for exported documents
+    let layer = tracing_subscriber::fmt::layer()
+        .with_ansi(ansi)
+        .with_writer(|| progress::Stderr);
+
+    // structured logs flatten each event into a single JSON object
+    let layer = match args.value_of("log-format") {
+        Some("json") => layer.json().flatten_event(true).boxed(),
+        _ => layer.boxed(),
+    };
+
+    tracing_subscriber::registry()
+        .with(layer)
+        .with(Targets::new().with_target(env!("CARGO_CRATE_NAME"), level))
+        .init();
+}
@@ -0,0 +1,322 @@
+//! Inference of index mappings from the contents of a dump.
+//!
+//! When a mapping has been lost, the documents of a dump are the only record
+//! of what it used to look like. This module samples document sources and
+//! proposes a mapping for them: a type for each field (including guesses at
+//! the format of any date fields), with arrays of objects mapped as `nested`
+//! so that the fields within each object stay associated with each other.
+//!
+//! Mappings can also be compared against each other, as drift between the
+//! mapping of a source and a target is the usual cause of failed imports.
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use serde_json::{json, Map, Value};
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::remote::{self, ClientOptions};
+
+/// The longest string which is mapped as a `keyword`, matching Elasticsearch.
+const IGNORE_ABOVE: usize = 256;
+
+/// Inference of the mapping of a set of documents.
+#[derive(Debug, Default)]
+pub struct Inference {
+    /// The number of documents observed.
+    pub documents: u64,
+    /// The fields found within every document.
+    root: Field,
+}
+
+/// Differences between the fields of two mappings.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Diff {
+    /// Fields only found in the second mapping, alongside their types.
+    pub added: Vec<(String, String)>,
+    /// Fields only found in the first mapping, alongside their types.
+    pub removed: Vec<(String, String)>,
+    /// Fields found in both mappings, alongside their type in each.
+    pub retyped: Vec<(String, String, String)>,
+}
+
+impl Diff {
+    /// Determines whether the mappings compared had the same fields.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.retyped.is_empty()
+    }
+}
+
+/// Everything observed about the values of a single field.
+#[derive(Debug, Default)]
+struct Field {
+    boolean: bool,
+    long: bool,
+    double: bool,
+    strings: u64,
+    dates: u64,
+    formats: BTreeSet<&'static str>,
+    text: bool,
+    object: bool,
+    nested: bool,
+    properties: BTreeMap<String, Field>,
+}
+
+impl Inference {
+    /// Constructs a new, empty `Inference`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Observes the source of a document, adding its fields to the inference.
+    pub fn observe(&mut self, source: &Value) {
+        self.root.observe(source);
+        self.documents += 1;
+    }
+
+    /// Proposes a mapping for every document observed so far.
+    ///
+    /// The mapping is returned in the same shape as the `mappings` of an index
+    /// creation body. Fields which have only ever been `null` (or empty arrays)
+    /// are left out, as there's nothing to infer a type from.
+    pub fn mapping(&self) -> Value {
+        json!({ "properties": self.root.properties() })
+    }
+}
+
+impl Field {
+    /// Observes a single value of this field.
+    fn observe(&mut self, value: &Value) {
+        match value {
+            Value::Null => (),
+            Value::Bool(_) => self.boolean = true,
+            Value::Number(number) if number.is_f64() => self.double = true,
+            Value::Number(_) => self.long = true,
+            Value::String(string) => {
+                self.strings += 1;
+                if let Some(format) = date_format(string) {
+                    self.formats.insert(format);
+                    self.dates += 1;
+                }
+                if string.len() > IGNORE_ABOVE || string.contains(char::is_whitespace) {
+                    self.text = true;
+                }
+            }
+            Value::Array(values) => {
+                // flattening several objects loses which fields belong together
+                if values.iter().filter(|value| value.is_object()).count() > 1 {
+                    self.nested = true;
+                }
+                for value in values {
+                    self.observe(value);
+                }
+            }
+            Value::Object(object) => {
+                self.object = true;
+                for (key, value) in object {
+                    // dotted keys are treated as paths, as Elasticsearch does
+                    let field = key.split('.').fold(&mut *self, |field, name| {
+                        field.properties.entry(name.to_owned()).or_default()
+                    });
+                    field.observe(value);
+                }
+            }
+        }
+    }
+
+    /// Proposes a mapping for this field, if any values have been observed.
+    fn mapping(&self) -> Option<Value> {
+        let scalar = self.boolean || self.long || self.double || self.strings > 0;
+
+        // objects map their own properties, unless values can't agree on that
+        if self.object {
+            if scalar {
+                return Some(json!({ "type": "object", "enabled": false }));
+            }
+            let properties = self.properties();
+            return Some(match (self.nested, properties.is_empty()) {
+                (true, _) => json!({ "type": "nested", "properties": properties }),
+                (false, true) => json!({ "type": "object" }),
+                (false, false) => json!({ "properties": properties }),
+            });
+        }
+
+        // strings which are all dates keep the formats they were written in
+        if self.strings > 0 {
+            if self.boolean || self.long || self.double {
+                return Some(json!({ "type": "keyword" }));
+            }
+            if self.dates == self.strings {
+                let formats = self.formats.iter().copied().collect::<Vec<_>>();
+                return Some(json!({ "type": "date", "format": formats.join("||") }));
+            }
+            if self.text {
+                return Some(json!({
+                    "type": "text",
+                    "fields": {
+                        "keyword": { "type": "keyword", "ignore_above": IGNORE_ABOVE }
+                    }
+                }));
+            }
+            return Some(json!({ "type": "keyword" }));
+        }
+
+        // numbers widen into doubles, but can't share a field with booleans
+        let kind = match (self.boolean, self.long, self.double) {
+            (true, false, false) => "boolean",
+            (true, _, _) => "keyword",
+            (false, _, true) => "double",
+            (false, true, false) => "long",
+            (false, false, false) => return None,
+        };
+        Some(json!({ "type": kind }))
+    }
+
+    /// Proposes a mapping for every property of this field.
+    fn properties(&self) -> Map<String, Value> {
+        self.properties
+            .iter()
+            .filter_map(|(name, field)| Some((name.clone(), field.mapping()?)))
+            .collect()
+    }
+}
+
+/// Guesses the Elasticsearch date format of a string, if it looks like a date.
+///
+/// Only unambiguous formats are recognised, so numeric strings (which could
+/// be epoch millis, or just numbers) are never treated as dates.
+fn date_format(input: &str) -> Option<&'static str> {
+    // every recognised format begins with a full date
+    if input.len() < 10 || !input.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+
+    // plain dates, and ISO 8601 timestamps with or without an offset
+    if NaiveDate::parse_from_str(input, "%Y-%m-%d").is_ok() {
+        return Some("strict_date");
+    }
+    if DateTime::parse_from_rfc3339(input).is_ok()
+        || NaiveDateTime::parse_from_str(input, "%Y-%m-%dT%H:%M:%S%.f").is_ok()
+    {
+        return Some("strict_date_optional_time");
+    }
+
+    // timestamps separated by a space, with or without milliseconds
+    match NaiveDateTime::parse_from_str(input, "%Y-%m-%d %H:%M:%S%.f") {
+        Ok(_) if input.len() == 19 => Some("yyyy-MM-dd HH:mm:ss"),
+        Ok(_) if input.len() == 23 => Some("yyyy-MM-dd HH:mm:ss.SSS"),
+        _ => None,
+    }
+}
+
+/// Compares the fields of two mappings, as changes from the first to the second.
+pub fn diff(first: &Value, second: &Value) -> Diff {
+    let first = fields(first);
+    let mut second = fields(second);
+    let mut diff = Diff::default();
+
+    // walk the first mapping, removing anything shared from the second
+    for (path, old) in first {
+        match second.remove(&path) {
+            None => diff.removed.push((path, old)),
+            Some(new) if new != old => diff.retyped.push((path, old, new)),
+            Some(_) => (),
+        }
+    }
+
+    // anything left over only exists within the second mapping
+    diff.added.extend(second);
+    diff
+}
+
+/// Flattens a mapping into the type of every field, keyed by path.
+///
+/// Mappings are accepted both with and without a document type, as older
+/// clusters nest their properties beneath one. Multi-fields are included
+/// beneath their parent (such as `name.keyword`), and date fields include
+/// their format within the type (such as `date (yyyy-MM-dd)`).
+pub fn fields(mapping: &Value) -> BTreeMap<String, String> {
+    flatten(mapping, &["properties", "fields"])
+}
+
+/// Flattens a mapping into the type of every field which can hold a value.
+///
+/// This is the same as `fields`, except that multi-fields are left out as
+/// they're indexed from their parent, rather than appearing in documents.
+pub fn types(mapping: &Value) -> BTreeMap<String, String> {
+    flatten(mapping, &["properties"])
+}
+
+/// Flattens a mapping, walking the nested fields found under each key.
+fn flatten(mapping: &Value, keys: &[&str]) -> BTreeMap<String, String> {
+    let mut fields = BTreeMap::new();
+    let properties = mapping.get("properties").or_else(|| {
+        // older mappings have a single type containing the properties
+        let mut types = mapping.as_object()?.values();
+        match (types.next(), types.next()) {
+            (Some(mapping), None) => mapping.get("properties"),
+            _ => None,
+        }
+    });
+    if let Some(properties) = properties.and_then(Value::as_object) {
+        walk(properties, "", keys, &mut fields);
+    }
+    fields
+}
+
+/// Fetches the mapping of a single index from a cluster, such as `host/index`.
+///
+/// Patterns and aliases are accepted, but must resolve to exactly one index
+/// so that there's only a single mapping to return.
+pub async fn fetch(target: &str, options: &ClientOptions) -> Result<Value> {
+    let (host, index) = remote::parse_cluster(target)?;
+    let index = index.ok_or_else(|| anyhow!("No index provided in {}", target))?;
+
+    // fetch the mappings of every index matching the name
+    let client = remote::connect(&[host], options).await?;
+    let mut mappings = remote::get_mappings(&client, &index).await?;
+
+    // only a single index can be compared at a time
+    match mappings.len() {
+        0 => Err(anyhow!("Unable to locate index {}", index)),
+        1 => Ok(mappings
+            .values_mut()
+            .next()
+            .map(Value::take)
+            .unwrap_or_default()),
+        count => Err(anyhow!(
+            "Index {} matches {} indices, expected one",
+            index,
+            count
+        )),
+    }
+}
+
+/// Walks the properties of a mapping, collecting the type of each field.
+fn walk(
+    properties: &Map<String, Value>,
+    prefix: &str,
+    keys: &[&str],
+    fields: &mut BTreeMap<String, String>,
+) {
+    for (name, property) in properties {
+        let path = format!("{}{}", prefix, name);
+
+        // fields without a type are objects, but dates include their format
+        let kind = property
+            .get("type")
+            .and_then(Value::as_str)
+            .unwrap_or("object");
+        let kind = match property.get("format").and_then(Value::as_str) {
+            Some(format) if kind.contains("date") => format!("{} ({})", kind, format),
+            _ => kind.to_owned(),
+        };
+        fields.insert(path.clone(), kind);
+
+        // objects and multi-fields can both nest further fields beneath them
+        for key in keys {
+            if let Some(nested) = property.get(key).and_then(Value::as_object) {
+                walk(nested, &format!("{}.", path), keys, fields);
+            }
+        }
+    }
+}
@@ -0,0 +1,477 @@
+//! Metrics publishing for the Limber CLI.
+//!
+//! Long running jobs can expose metrics via `--metrics-listen`, which are
+//! served in the Prometheus text format on `/metrics` for as long as the
+//! job is running. Metrics can also be pushed periodically to StatsD via
+//! `--statsd`, or to an OpenTelemetry collector via `--otlp-endpoint`. In
+//! all cases metrics are read from the `Tracker` shared with the job.
+use anyhow::{anyhow, Result};
+use clap::ArgMatches;
+use limber::stats::{Measure, Metric};
+use limber::Tracker;
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The maximum size of a request to read before responding.
+const MAX_REQUEST: usize = 8 * 1024;
+
+/// The maximum size of a StatsD packet, to avoid any fragmentation.
+const MAX_PACKET: usize = 1432;
+
+/// Interval between each push of metrics.
+const PUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Publisher of the metrics of a running import or export.
+pub struct Publisher {
+    command: &'static str,
+    tracker: Arc<Tracker>,
+    tasks: Vec<JoinHandle<()>>,
+    sinks: Vec<Arc<Mutex<Sink>>>,
+}
+
+impl Publisher {
+    /// Starts publishing metrics, based on the flags provided.
+    ///
+    /// Listeners and sockets are opened before returning, so that a job
+    /// fails fast if an address is unavailable, rather than running without
+    /// any metrics.
+    pub async fn start(
+        command: &'static str,
+        tracker: Arc<Tracker>,
+        args: &ArgMatches,
+    ) -> Result<Self> {
+        let mut tasks = Vec::new();
+        let mut sinks = Vec::new();
+
+        // serve metrics to be scraped by Prometheus
+        if let Some(address) = args.value_of("metrics-listen") {
+            tasks.push(serve(address, command, tracker.clone()).await?);
+        }
+
+        // push metrics to a StatsD server
+        if let Some(address) = args.value_of("statsd") {
+            let socket = UdpSocket::bind("0.0.0.0:0").await?;
+            socket
+                .connect(address)
+                .await
+                .map_err(|err| anyhow!("Unable to connect to StatsD on {}: {}", address, err))?;
+            sinks.push(Sink::Statsd {
+                socket,
+                previous: HashMap::new(),
+            });
+        }
+
+        // push metrics to an OpenTelemetry collector
+        if let Some(endpoint) = args.value_of("otlp-endpoint") {
+            let endpoint = endpoint.trim_end_matches('/');
+            let endpoint = match endpoint.ends_with("/v1/metrics") {
+                true => endpoint.to_owned(),
+                false => format!("{}/v1/metrics", endpoint),
+            };
+            sinks.push(Sink::Otlp {
+                client: reqwest::Client::new(),
+                endpoint,
+            });
+        }
+
+        // push to every sink periodically in the background
+        let sinks = sinks
+            .into_iter()
+            .map(|sink| Arc::new(Mutex::new(sink)))
+            .collect::<Vec<_>>();
+        for sink in &sinks {
+            let sink = sink.clone();
+            let tracker = tracker.clone();
+            tasks.push(tokio::spawn(async move {
+                let mut interval = tokio::time::interval(PUSH_INTERVAL);
+
+                // the first tick completes immediately
+                interval.tick().await;
+
+                loop {
+                    interval.tick().await;
+                    sink.lock().await.push(command, &tracker).await;
+                }
+            }));
+        }
+
+        Ok(Self {
+            command,
+            tracker,
+            tasks,
+            sinks,
+        })
+    }
+
+    /// Stops publishing metrics.
+    ///
+    /// A final push is made to every sink, so that the final state of the
+    /// job is always published.
+    pub async fn stop(self) {
+        for task in self.tasks {
+            task.abort();
+        }
+        for sink in self.sinks {
+            sink.lock().await.push(self.command, &self.tracker).await;
+        }
+    }
+}
+
+/// Destination which metrics are periodically pushed to.
+enum Sink {
+    /// A StatsD server, with the previous values of any cumulative metrics.
+    Statsd {
+        socket: UdpSocket,
+        previous: HashMap<String, (f64, u64)>,
+    },
+    /// An OpenTelemetry collector, accepting OTLP over HTTP.
+    Otlp {
+        client: reqwest::Client,
+        endpoint: String,
+    },
+}
+
+impl Sink {
+    /// Pushes the current metrics of a tracker to the sink.
+    ///
+    /// Failures to push are logged rather than halting the process, as the
+    /// metrics system going away is no reason to abandon the work itself.
+    async fn push(&mut self, command: &str, tracker: &Tracker) {
+        let result = match self {
+            Sink::Statsd { socket, previous } => {
+                let lines = statsd(command, &tracker.metrics(), previous);
+                send(socket, &lines).await
+            }
+            Sink::Otlp { client, endpoint } => client
+                .post(endpoint.as_str())
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(otlp(command, tracker).to_string())
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status)
+                .map(|_| ())
+                .map_err(Into::into),
+        };
+
+        if let Err(err) = result {
+            warn!(error = %err, "Unable to push metrics");
+        }
+    }
+}
+
+/// Serves metrics on an address in the background.
+async fn serve(
+    address: &str,
+    command: &'static str,
+    tracker: Arc<Tracker>,
+) -> Result<JoinHandle<()>> {
+    // bind the listener up front to surface any errors
+    let listener = TcpListener::bind(address)
+        .await
+        .map_err(|err| anyhow!("Unable to listen for metrics on {}: {}", address, err))?;
+
+    info!(%address, "Serving metrics");
+
+    // accept connections in the background
+    Ok(tokio::spawn(async move {
+        loop {
+            let (stream, _) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(err) => {
+                    debug!(error = %err, "Unable to accept metrics connection");
+                    continue;
+                }
+            };
+            let tracker = tracker.clone();
+            tokio::spawn(async move {
+                if let Err(err) = respond(stream, command, &tracker).await {
+                    debug!(error = %err, "Unable to serve metrics");
+                }
+            });
+        }
+    }))
+}
+
+/// Responds to a single request for metrics.
+///
+/// Only the request line is inspected; anything other than a `GET` for the
+/// `/metrics` path receives a `404`.
+async fn respond(mut stream: TcpStream, command: &str, tracker: &Tracker) -> Result<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+
+    // read through to the end of the request headers
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 || request.len() > MAX_REQUEST {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+
+    // only serve metrics from the metrics path
+    let line = request
+        .split(|byte| *byte == b'\n')
+        .next()
+        .unwrap_or_default();
+    let (status, body) = match line.starts_with(b"GET /metrics ") {
+        true => ("200 OK", render(command, &tracker.metrics())?),
+        false => ("404 Not Found", String::new()),
+    };
+
+    // write the response and close the connection
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+/// Renders a set of metrics in the Prometheus text format.
+fn render(command: &str, metrics: &[Metric]) -> Result<String> {
+    let mut output = String::new();
+    let mut described = HashSet::new();
+
+    for metric in metrics {
+        // counters are suffixed as totals, by convention
+        let (name, kind) = match metric.value {
+            Measure::Counter(_) => (format!("limber_{}_total", metric.name), "counter"),
+            Measure::Gauge(_) => (format!("limber_{}", metric.name), "gauge"),
+            Measure::Histogram { .. } => (format!("limber_{}", metric.name), "histogram"),
+        };
+
+        // only describe each metric once, regardless of labels
+        if described.insert(metric.name) {
+            writeln!(output, "# HELP {} {}", name, metric.help)?;
+            writeln!(output, "# TYPE {} {}", name, kind)?;
+        }
+
+        // every metric is labelled with the command
+        let mut labels = format!("command=\"{}\"", command);
+        for (key, value) in &metric.labels {
+            write!(labels, ",{}=\"{}\"", key, escape(value))?;
+        }
+
+        match &metric.value {
+            Measure::Counter(value) | Measure::Gauge(value) => {
+                writeln!(output, "{}{{{}}} {}", name, labels, value)?;
+            }
+            Measure::Histogram {
+                buckets,
+                count,
+                sum,
+            } => {
+                for (bound, value) in buckets {
+                    writeln!(
+                        output,
+                        "{}_bucket{{{},le=\"{}\"}} {}",
+                        name, labels, bound, value
+                    )?;
+                }
+                writeln!(
+                    output,
+                    "{}_bucket{{{},le=\"+Inf\"}} {}",
+                    name, labels, count
+                )?;
+                writeln!(output, "{}_sum{{{}}} {}", name, labels, sum)?;
+                writeln!(output, "{}_count{{{}}} {}", name, labels, count)?;
+            }
+        }
+    }
+
+    Ok(output)
+}
+
+/// Escapes a label value for the Prometheus text format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Renders a set of metrics as StatsD lines.
+///
+/// StatsD aggregates counters itself, so counters are sent as the change
+/// since the previous push. Histograms are sent as the mean duration of any
+/// requests since the previous push, sampled to reflect the request count.
+fn statsd(
+    command: &str,
+    metrics: &[Metric],
+    previous: &mut HashMap<String, (f64, u64)>,
+) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    for metric in metrics {
+        // labels are folded into the name, as StatsD has no labels
+        let mut name = format!("limber.{}.{}", command, metric.name);
+        for (_, value) in &metric.labels {
+            name.push('.');
+            name.extend(value.chars().map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+                _ => '_',
+            }));
+        }
+
+        match &metric.value {
+            Measure::Counter(value) => {
+                let (last, _) = previous
+                    .insert(name.clone(), (*value, 0))
+                    .unwrap_or_default();
+                if *value > last {
+                    lines.push(format!("{}:{}|c", name, value - last));
+                }
+            }
+            Measure::Gauge(value) => {
+                lines.push(format!("{}:{}|g", name, value));
+            }
+            Measure::Histogram { count, sum, .. } => {
+                let (last_sum, last_count) = previous
+                    .insert(name.clone(), (*sum, *count))
+                    .unwrap_or_default();
+                let observed = count.saturating_sub(last_count);
+                if observed > 0 {
+                    let mean = (sum - last_sum) / observed as f64 * 1000.0;
+                    let rate = 1.0 / observed as f64;
+                    lines.push(format!("{}:{:.3}|ms|@{}", name, mean, rate));
+                }
+            }
+        }
+    }
+
+    lines
+}
+
+/// Sends a set of StatsD lines, batched into as few packets as possible.
+async fn send(socket: &UdpSocket, lines: &[String]) -> Result<()> {
+    let mut packet = String::new();
+
+    for line in lines {
+        // flush the packet when the next line won't fit
+        if !packet.is_empty() && packet.len() + line.len() + 1 > MAX_PACKET {
+            socket.send(packet.as_bytes()).await?;
+            packet.clear();
+        }
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        packet.push_str(line);
+    }
+
+    if !packet.is_empty() {
+        socket.send(packet.as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+/// Renders the metrics of a tracker as an OTLP (JSON) request body.
+///
+/// Data points for the same metric (such as those for each index) are all
+/// grouped beneath a single metric, as is expected by OTLP.
+fn otlp(command: &str, tracker: &Tracker) -> Value {
+    // timestamps are sent as nanoseconds since the epoch
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let start = now.saturating_sub(tracker.elapsed());
+    let now = now.as_nanos().to_string();
+    let start = start.as_nanos().to_string();
+
+    let mut metrics: Vec<Value> = Vec::new();
+
+    for metric in tracker.metrics() {
+        // every data point is labelled with the command
+        let mut attributes = vec![json!({ "key": "command", "value": { "stringValue": command } })];
+        for (key, value) in &metric.labels {
+            attributes.push(json!({ "key": key, "value": { "stringValue": value } }));
+        }
+
+        // construct the data point, and the type of metric it belongs to
+        let (kind, mut point) = match &metric.value {
+            Measure::Counter(value) => ("sum", json!({ "asDouble": value })),
+            Measure::Gauge(value) => ("gauge", json!({ "asDouble": value })),
+            Measure::Histogram {
+                buckets,
+                count,
+                sum,
+            } => {
+                // OTLP buckets are not cumulative, and include an overflow
+                let mut last = 0;
+                let mut counts = Vec::with_capacity(buckets.len() + 1);
+                for (_, cumulative) in buckets {
+                    counts.push((cumulative - last).to_string());
+                    last = *cumulative;
+                }
+                counts.push((count - last).to_string());
+
+                let bounds = buckets.iter().map(|(bound, _)| *bound).collect::<Vec<_>>();
+                let point = json!({
+                    "count": count.to_string(),
+                    "sum": sum,
+                    "bucketCounts": counts,
+                    "explicitBounds": bounds,
+                });
+                ("histogram", point)
+            }
+        };
+
+        point["attributes"] = json!(attributes);
+        point["startTimeUnixNano"] = json!(start);
+        point["timeUnixNano"] = json!(now);
+
+        // add the point to an existing metric with the same name, if any
+        let name = format!("limber.{}", metric.name);
+        if let Some(existing) = metrics.iter_mut().find(|existing| existing["name"] == name) {
+            if let Some(points) = existing[kind]["dataPoints"].as_array_mut() {
+                points.push(point);
+            }
+            continue;
+        }
+
+        // otherwise create the metric, with cumulative temporality
+        let mut data = json!({ "dataPoints": [point] });
+        if kind != "gauge" {
+            data["aggregationTemporality"] = json!(2);
+        }
+        if kind == "sum" {
+            data["isMonotonic"] = json!(true);
+        }
+        metrics.push(json!({
+            "name": name,
+            "description": metric.help,
+            kind: data,
+        }));
+    }
+
+    json!({
+        "resourceMetrics": [{
+            "resource": {
+                "attributes": [
+                    { "key": "service.name", "value": { "stringValue": "limber" } }
+                ]
+            },
+            "scopeMetrics": [{
+                "scope": {
+                    "name": "limber",
+                    "version": env!("CARGO_PKG_VERSION")
+                },
+                "metrics": metrics
+            }]
+        }]
+    })
+}
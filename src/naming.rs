@@ -0,0 +1,247 @@
+//! Utility functions for deriving target index names.
+//!
+//! This module offers the ability to rewrite the index name of a document
+//! during import, such as renaming with wildcard patterns, attaching prefixes
+//! and suffixes, or rendering names from templates using document fields.
+use anyhow::{anyhow, Result};
+use chrono::format::{Item, StrftimeItems};
+use serde_json::Value;
+
+use crate::document;
+
+/// Rewrite rules applied to the index name of each document.
+#[derive(Default)]
+pub struct Renamer {
+    rules: Vec<(String, String)>,
+    prefix: Option<String>,
+    suffix: Option<String>,
+}
+
+impl Renamer {
+    /// Constructs a new renamer from a set of rules and affixes.
+    ///
+    /// Rules are provided in the form `pattern=replacement`, where each `*`
+    /// in the pattern will match any text, which will then be placed in the
+    /// same position within the replacement. Only the first matching rule
+    /// is applied to an index.
+    pub fn new<'a, I>(rules: I, prefix: Option<&str>, suffix: Option<&str>) -> Result<Self>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut parsed = Vec::new();
+
+        // parse all rules into their pattern/replacement pairs
+        for rule in rules {
+            let (pattern, replacement) = rule
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Invalid rename rule provided: {}", rule))?;
+
+            parsed.push((pattern.to_owned(), replacement.to_owned()));
+        }
+
+        Ok(Self {
+            rules: parsed,
+            prefix: prefix.map(str::to_owned),
+            suffix: suffix.map(str::to_owned),
+        })
+    }
+
+    /// Applies all rewrite rules to the provided index name.
+    pub fn rename(&self, index: &str) -> String {
+        // find the first matching rule, or keep the name as is
+        let mut renamed = self
+            .rules
+            .iter()
+            .find_map(|(pattern, replacement)| {
+                let captures = capture(pattern, index)?;
+                Some(replace(replacement, &captures))
+            })
+            .unwrap_or_else(|| index.to_owned());
+
+        // attach the prefix if provided
+        if let Some(ref prefix) = self.prefix {
+            renamed.insert_str(0, prefix);
+        }
+
+        // attach the suffix if provided
+        if let Some(ref suffix) = self.suffix {
+            renamed.push_str(suffix);
+        }
+
+        renamed
+    }
+}
+
+/// Matches a wildcard pattern against an input, returning the wildcard captures.
+///
+/// Wildcards are matched lazily, so `a-*-*` against `a-b-c-d` will capture
+/// `b` and `c-d`. If the pattern does not match, `None` is returned.
+fn capture<'a>(pattern: &str, input: &'a str) -> Option<Vec<&'a str>> {
+    let mut segments = pattern.split('*');
+    let mut captures = Vec::new();
+
+    // the first segment must be a prefix of the input
+    let mut remaining = input.strip_prefix(segments.next()?)?;
+
+    // every other segment is preceded by a wildcard
+    let segments = segments.collect::<Vec<_>>();
+    for (idx, segment) in segments.iter().enumerate() {
+        // the final segment must be a suffix of the input
+        let offset = if idx == segments.len() - 1 {
+            remaining.len().checked_sub(segment.len())?
+        } else {
+            remaining.find(segment)?
+        };
+
+        // the final segment must actually match the end
+        if !remaining.get(offset..)?.starts_with(segment) {
+            return None;
+        }
+
+        captures.push(&remaining[..offset]);
+        remaining = &remaining[offset + segment.len()..];
+    }
+
+    // anything left over means the pattern did not match fully
+    if !remaining.is_empty() {
+        return None;
+    }
+
+    Some(captures)
+}
+
+/// Replaces each wildcard in a replacement with the provided captures.
+///
+/// Any wildcards beyond the number of captures are replaced with nothing.
+fn replace(replacement: &str, captures: &[&str]) -> String {
+    let mut output = String::with_capacity(replacement.len());
+    let mut captures = captures.iter();
+
+    for (idx, segment) in replacement.split('*').enumerate() {
+        if idx > 0 {
+            output.push_str(captures.next().unwrap_or(&""));
+        }
+        output.push_str(segment);
+    }
+
+    output
+}
+
+/// Template used to render a name (such as an index) from the fields of a document.
+///
+/// Templates contain placeholders in the form `{field}`, which are replaced
+/// by the value of the field within the document source. Date fields can be
+/// formatted by providing a format, such as `{@timestamp:%Y.%m}`.
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+/// Individual segments of a parsed `Template`.
+enum Segment {
+    Literal(String),
+    Field(String),
+    Date(String, String),
+}
+
+impl Template {
+    /// Parses a template from the provided input string.
+    pub fn parse(input: &str) -> Result<Self> {
+        let mut segments = Vec::new();
+        let mut remaining = input;
+
+        // pull out each placeholder in turn
+        while let Some(start) = remaining.find('{') {
+            // everything before the placeholder is literal
+            if start > 0 {
+                segments.push(Segment::Literal(remaining[..start].to_owned()));
+            }
+
+            // locate the end of the placeholder
+            let length = remaining[start..]
+                .find('}')
+                .ok_or_else(|| anyhow!("Unclosed placeholder in template: {}", input))?;
+
+            // split the placeholder into the field and optional format
+            let placeholder = &remaining[start + 1..start + length];
+            let segment = match placeholder.split_once(':') {
+                None => Segment::Field(placeholder.to_owned()),
+                Some((field, format)) => {
+                    validate_format(format)?;
+                    Segment::Date(field.to_owned(), format.to_owned())
+                }
+            };
+
+            segments.push(segment);
+            remaining = &remaining[start + length + 1..];
+        }
+
+        // anything left over is also literal
+        if !remaining.is_empty() {
+            segments.push(Segment::Literal(remaining.to_owned()));
+        }
+
+        Ok(Self { segments })
+    }
+
+    /// Constructs a template rendering a date field with a format.
+    ///
+    /// The entire format is used to render the name, so any literal text
+    /// within the format will also be included in the index name.
+    pub fn date(field: &str, format: &str) -> Result<Self> {
+        validate_format(format)?;
+        Ok(Self {
+            segments: vec![Segment::Date(field.to_owned(), format.to_owned())],
+        })
+    }
+
+    /// Renders this template using the fields of a document source.
+    ///
+    /// Field values are lowercased as Elasticsearch does not permit upper
+    /// case index names. If any field is missing (or cannot be parsed as a
+    /// date when a format is provided), `None` is returned.
+    pub fn render(&self, source: &Value) -> Option<String> {
+        self.fill(source, true)
+    }
+
+    /// Renders this template using the fields of a document source, as is.
+    ///
+    /// This is the same as `render`, except that field values keep their case.
+    pub fn render_exact(&self, source: &Value) -> Option<String> {
+        self.fill(source, false)
+    }
+
+    /// Fills in the placeholders of this template, optionally lowercasing values.
+    fn fill(&self, source: &Value, lowercase: bool) -> Option<String> {
+        let mut output = String::new();
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => output.push_str(literal),
+                Segment::Field(field) => {
+                    let rendered = match document::lookup(source, field)? {
+                        Value::String(value) if lowercase => value.to_lowercase(),
+                        Value::String(value) => value.clone(),
+                        Value::Number(value) => value.to_string(),
+                        Value::Bool(value) => value.to_string(),
+                        _ => return None,
+                    };
+                    output.push_str(&rendered);
+                }
+                Segment::Date(field, format) => {
+                    let date = document::parse_date(document::lookup(source, field)?)?;
+                    output.push_str(&date.format(format).to_string());
+                }
+            }
+        }
+
+        Some(output)
+    }
+}
+
+/// Validates that a date format can be safely used to format dates.
+pub fn validate_format(format: &str) -> Result<()> {
+    if StrftimeItems::new(format).any(|item| matches!(item, Item::Error)) {
+        return Err(anyhow!("Invalid date format provided: {}", format));
+    }
+    Ok(())
+}
@@ -0,0 +1,290 @@
+//! Parquet files used to store (and read back) documents as a table.
+//!
+//! Documents are written as rows holding each of the `codec::COLUMNS`, into
+//! a directory of files which each hold up to a million documents. This is
+//! the layout expected by most tools reading Parquet, which treat every file
+//! within a directory as part of the same dataset.
+//!
+//! This module is only available with the `parquet` feature enabled.
+use ::parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use ::parquet::arrow::ArrowWriter;
+use ::parquet::basic::{Compression, ZstdLevel};
+use ::parquet::file::properties::WriterProperties;
+use anyhow::{anyhow, Result};
+use arrow_array::{Array, ArrayRef, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream};
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::codec::{Row, COLUMNS};
+use crate::format::RECORD_SEPARATOR;
+use crate::input::Reader;
+
+/// The size of the buffer between the writer (or reader) and the files.
+const PIPE_SIZE: usize = 1024 * 1024;
+
+/// The number of documents written within each batch.
+const BATCH_SIZE: usize = 10_000;
+
+/// The number of documents written to each file before starting another.
+const FILE_SIZE: usize = 1_000_000;
+
+/// Sink of documents written to a directory of Parquet files.
+pub struct Sink {
+    dir: PathBuf,
+    schema: SchemaRef,
+}
+
+/// File currently being written within a directory.
+struct Part {
+    writer: ArrowWriter<File>,
+    rows: usize,
+}
+
+impl Sink {
+    /// Creates a directory to write files into, if it doesn't already exist.
+    ///
+    /// Directories already containing Parquet files are rejected, as files
+    /// left over from a previous run would be mistaken for new documents.
+    pub fn create(dir: &str) -> Result<Self> {
+        let dir = PathBuf::from(dir);
+        std::fs::create_dir_all(&dir)
+            .map_err(|err| anyhow!("Unable to create output {}: {}", dir.display(), err))?;
+        if !files(&dir)?.is_empty() {
+            return Err(anyhow!(
+                "Output {} already contains Parquet files",
+                dir.display()
+            ));
+        }
+        Ok(Self {
+            dir,
+            schema: schema(),
+        })
+    }
+
+    /// Spawns a task writing every document written to the returned writer.
+    ///
+    /// The task completes once the returned writer has been dropped and every
+    /// document written to it has been written to a file.
+    pub fn spawn(self) -> (DuplexStream, JoinHandle<Result<()>>) {
+        let (input, output) = io::duplex(PIPE_SIZE);
+        let task = tokio::spawn(self.write(output));
+        (input, task)
+    }
+
+    /// Writes each line of input as a row, in batches of documents.
+    async fn write(self, reader: DuplexStream) -> Result<()> {
+        let mut lines = BufReader::new(reader).split(b'\n');
+        let sink = Arc::new(self);
+        let mut part = None;
+        let mut files = 0;
+        let mut batch = Vec::with_capacity(BATCH_SIZE);
+
+        loop {
+            // records of a sequence are also written with a trailing line break
+            let line = lines.next_segment().await?;
+            if let Some(line) = line.as_ref() {
+                let document = line.strip_prefix(&[RECORD_SEPARATOR]).unwrap_or(line);
+                if !document.iter().all(u8::is_ascii_whitespace) {
+                    batch.push(Row::parse(document)?);
+                }
+            }
+
+            // hand each full batch (and the last) off to a blocking thread
+            if batch.len() >= BATCH_SIZE || (line.is_none() && !batch.is_empty()) {
+                let rows = std::mem::replace(&mut batch, Vec::with_capacity(BATCH_SIZE));
+                let sink = sink.clone();
+                let current = part.take();
+                let number = files;
+                let written =
+                    tokio::task::spawn_blocking(move || sink.insert(current, number, rows))
+                        .await??;
+                if written.rows >= FILE_SIZE {
+                    written.writer.close()?;
+                    files += 1;
+                } else {
+                    part = Some(written);
+                }
+            }
+
+            // the end of the input has been reached, so finish the last file
+            if line.is_none() {
+                if let Some(part) = part {
+                    tokio::task::spawn_blocking(move || part.writer.close()).await??;
+                }
+                return Ok(());
+            }
+        }
+    }
+
+    /// Writes a batch of documents into a file, opening it if needed.
+    fn insert(&self, part: Option<Part>, number: usize, rows: Vec<Row>) -> Result<Part> {
+        let mut part = match part {
+            Some(part) => part,
+            None => {
+                let path = self.dir.join(format!("part-{:05}.parquet", number));
+                let file = File::create(&path).map_err(|err| {
+                    anyhow!("Unable to create output {}: {}", path.display(), err)
+                })?;
+                let properties = WriterProperties::builder()
+                    .set_compression(Compression::ZSTD(ZstdLevel::default()))
+                    .build();
+                Part {
+                    writer: ArrowWriter::try_new(file, self.schema.clone(), Some(properties))?,
+                    rows: 0,
+                }
+            }
+        };
+
+        // convert the batch into a column for each field
+        let column = |field: fn(&Row) -> Option<&str>| -> ArrayRef {
+            Arc::new(rows.iter().map(field).collect::<StringArray>())
+        };
+        let columns = vec![
+            column(|row| row.index.as_deref()),
+            column(|row| row.id.as_deref()),
+            column(|row| row.source.as_deref()),
+            column(|row| row.metadata.as_deref()),
+        ];
+
+        part.writer
+            .write(&RecordBatch::try_new(self.schema.clone(), columns)?)?;
+        part.rows += rows.len();
+        Ok(part)
+    }
+}
+
+/// Reads the Parquet files at a path as documents in the export format.
+///
+/// The path can either be a single file, or a directory of files (such as
+/// written by a `Sink`), which are read in order of their names. The returned
+/// task must be awaited to catch any errors once the reader is exhausted.
+pub fn read(path: &str) -> Result<(Reader, JoinHandle<Result<()>>)> {
+    let path = Path::new(path);
+    let paths = match path.is_dir() {
+        true => files(path)?,
+        false => vec![path.to_path_buf()],
+    };
+
+    let (input, mut output) = io::duplex(PIPE_SIZE);
+    let handle = Handle::current();
+    let task = tokio::task::spawn_blocking(move || {
+        for path in paths {
+            let file = File::open(&path)
+                .map_err(|err| anyhow!("Unable to open input {}: {}", path.display(), err))?;
+            let reader = ParquetRecordBatchReaderBuilder::try_new(file)?.build()?;
+
+            for batch in reader {
+                let batch = batch?;
+
+                // locate each column within the batch, if present
+                let columns = COLUMNS
+                    .iter()
+                    .map(|name| column(&batch, name))
+                    .collect::<Result<Vec<_>>>()?;
+                if columns[2].is_none() {
+                    return Err(anyhow!(
+                        "Parquet input {} must contain a source column",
+                        path.display()
+                    ));
+                }
+
+                // convert every row back into a document
+                let mut lines = Vec::new();
+                for position in 0..batch.num_rows() {
+                    let value = |index: usize| {
+                        columns[index]
+                            .filter(|column| column.is_valid(position))
+                            .map(|column| column.value(position).to_owned())
+                    };
+                    let row = Row {
+                        index: value(0),
+                        id: value(1),
+                        source: value(2),
+                        metadata: value(3),
+                    };
+                    lines.extend(row.document()?);
+                    lines.push(b'\n');
+                }
+                handle.block_on(output.write_all(&lines))?;
+            }
+        }
+        Ok(())
+    });
+
+    Ok((Box::new(input), task))
+}
+
+/// Returns the schema of every file, with a nullable string for each column.
+fn schema() -> SchemaRef {
+    let fields = COLUMNS
+        .iter()
+        .map(|name| Field::new(*name, DataType::Utf8, true))
+        .collect::<Vec<_>>();
+    Arc::new(Schema::new(fields))
+}
+
+/// Locates a column of strings within a batch, if it exists.
+fn column<'a>(batch: &'a RecordBatch, name: &str) -> Result<Option<&'a StringArray>> {
+    match batch.column_by_name(name) {
+        None => Ok(None),
+        Some(column) => match column.as_any().downcast_ref::<StringArray>() {
+            Some(column) => Ok(Some(column)),
+            None => Err(anyhow!("Parquet column {} must contain strings", name)),
+        },
+    }
+}
+
+/// Lists every Parquet file within a directory, sorted by name.
+fn files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = std::fs::read_dir(dir)
+        .map_err(|err| anyhow!("Unable to read directory {}: {}", dir.display(), err))?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<_>>>()?;
+    files.retain(|path| {
+        path.extension()
+            .is_some_and(|extension| extension == "parquet")
+    });
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn documents_round_trip_through_a_directory() {
+        let dir = std::env::temp_dir().join(format!("limber-parquet-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let dir = dir.to_string_lossy().into_owned();
+
+        let input = "{\"_index\":\"idx\",\"_id\":\"1\",\"_source\":{\"a\":1}}\n\
+                     \x1e{\"_id\":\"2\",\"_routing\":\"r\",\"_source\":{\"b\":[true]}}\n";
+        let (mut writer, task) = Sink::create(&dir).unwrap().spawn();
+        writer.write_all(input.as_bytes()).await.unwrap();
+        drop(writer);
+        task.await.unwrap().unwrap();
+
+        // the directory now has a file, so can't be written to again
+        assert!(Sink::create(&dir).is_err());
+
+        let (mut reader, task) = read(&dir).unwrap();
+        let mut output = String::new();
+        reader.read_to_string(&mut output).await.unwrap();
+        task.await.unwrap().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            output,
+            "{\"_index\":\"idx\",\"_id\":\"1\",\"_source\":{\"a\":1}}\n\
+             {\"_id\":\"2\",\"_routing\":\"r\",\"_source\":{\"b\":[true]}}\n"
+        );
+    }
+}
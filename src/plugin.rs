@@ -0,0 +1,161 @@
+//! WebAssembly plugins used to transform documents.
+//!
+//! Plugins allow arbitrary changes to be made to documents without having
+//! to fork Limber, by passing each document source through a user-supplied
+//! WebAssembly module. Modules are run within an interpreter, with no access
+//! to anything outside of their own memory.
+//!
+//! Modules communicate with Limber through a small interface, which can be
+//! implemented in any language able to target WebAssembly. Modules must
+//! export the following:
+//!
+//! * `memory`, the linear memory used to pass documents in and out.
+//! * `alloc(len: i32) -> i32`, to allocate room for an incoming document.
+//! * `transform(ptr: i32, len: i32) -> i64`, to transform a document.
+//!
+//! Each document source is written (as JSON) into memory allocated via the
+//! `alloc` function, before being passed to `transform`. The transformed
+//! source is returned as JSON within memory, with the offset of it packed
+//! into the upper 32 bits of the result, and the length in the lower 32.
+//! Modules can optionally export `dealloc(ptr: i32, len: i32)`, which is
+//! called to release both buffers once the transform completes.
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use wasmi::{Engine, Instance, Linker, Memory, Module, Store, TypedFunc};
+
+use std::convert::TryFrom;
+use std::fmt::{self, Debug, Formatter};
+use std::sync::Mutex;
+
+/// Plugin transforming documents via a WebAssembly module.
+pub struct Plugin {
+    path: String,
+    state: Mutex<State>,
+}
+
+/// Instantiated state of a plugin module, guarded by a lock.
+struct State {
+    store: Store<()>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    dealloc: Option<TypedFunc<(i32, i32), ()>>,
+    transform: TypedFunc<(i32, i32), i64>,
+}
+
+impl Plugin {
+    /// Loads a plugin from a WebAssembly module on disk.
+    ///
+    /// The module is validated and instantiated immediately, so that any
+    /// problems with it are reported before any documents are processed.
+    pub fn load(path: &str) -> Result<Self> {
+        let invalid = |err: wasmi::Error| anyhow!("Invalid plugin {}: {}", path, err);
+
+        // read and compile the module
+        let bytes = std::fs::read(path)
+            .map_err(|err| anyhow!("Unable to read plugin {}: {}", path, err))?;
+        let engine = Engine::default();
+        let module = Module::new(&engine, &bytes).map_err(invalid)?;
+
+        // instantiate the module, without providing any imports
+        let mut store = Store::new(&engine, ());
+        let instance = Linker::<()>::new(&engine)
+            .instantiate(&mut store, &module)
+            .and_then(|instance| instance.start(&mut store))
+            .map_err(invalid)?;
+
+        // locate all of the exports making up the interface
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or_else(|| anyhow!("Invalid plugin {}: missing memory export", path))?;
+        let alloc = export(&instance, &store, path, "alloc")?;
+        let transform = export(&instance, &store, path, "transform")?;
+        let dealloc = instance.get_typed_func(&store, "dealloc").ok();
+
+        Ok(Self {
+            path: path.to_owned(),
+            state: Mutex::new(State {
+                store,
+                memory,
+                alloc,
+                dealloc,
+                transform,
+            }),
+        })
+    }
+
+    /// Passes a document source through this plugin.
+    ///
+    /// The source is replaced with the source returned by the plugin, which
+    /// must be valid JSON. Any failure within the plugin (such as a trap) is
+    /// returned as an error.
+    pub fn apply(&self, source: &mut Value) -> Result<()> {
+        let failed = |err: &dyn fmt::Display| anyhow!("Plugin {} failed: {}", self.path, err);
+
+        let mut guard = self.state.lock().expect("poisoned lock");
+        let state = &mut *guard;
+
+        // copy the source into memory allocated by the plugin
+        let input = serde_json::to_vec(source)?;
+        let length = i32::try_from(input.len()).map_err(|err| failed(&err))?;
+        let pointer = state
+            .alloc
+            .call(&mut state.store, length)
+            .map_err(|err| failed(&err))?;
+        state
+            .memory
+            .write(&mut state.store, pointer as usize, &input)
+            .map_err(|err| failed(&err))?;
+
+        // transform the source, unpacking the location of the output
+        let packed = state
+            .transform
+            .call(&mut state.store, (pointer, length))
+            .map_err(|err| failed(&err))?;
+        let (offset, size) = (
+            (packed as u64 >> 32) as usize,
+            (packed as u64 & 0xFFFF_FFFF) as usize,
+        );
+
+        // copy the output back out of memory
+        let mut output = vec![0; size];
+        state
+            .memory
+            .read(&state.store, offset, &mut output)
+            .map_err(|err| failed(&err))?;
+
+        // release both buffers, when the plugin allows it
+        if let Some(dealloc) = state.dealloc {
+            dealloc
+                .call(&mut state.store, (pointer, length))
+                .and_then(|_| dealloc.call(&mut state.store, (offset as i32, size as i32)))
+                .map_err(|err| failed(&err))?;
+        }
+
+        *source = serde_json::from_slice(&output)
+            .map_err(|err| anyhow!("Plugin {} returned invalid JSON: {}", self.path, err))?;
+
+        Ok(())
+    }
+}
+
+impl Debug for Plugin {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Plugin").field("path", &self.path).finish()
+    }
+}
+
+/// Locates a function exported by a plugin module.
+fn export<Params, Results>(
+    instance: &Instance,
+    store: &Store<()>,
+    path: &str,
+    name: &str,
+) -> Result<TypedFunc<Params, Results>>
+where
+    Params: wasmi::WasmParams,
+    Results: wasmi::WasmResults,
+{
+    instance
+        .get_typed_func(store, name)
+        .map_err(|err| anyhow!("Invalid plugin {}: {} export: {}", path, name, err))
+}
@@ -0,0 +1,287 @@
+//! Preflight checks carried out before starting a job.
+//!
+//! Clusters in a bad state tend to surface as confusing failures partway
+//! through a job (such as every bulk request timing out), so the health of
+//! the cluster is checked up front. Anything which would prevent the job
+//! from succeeding is raised as an error, with a description of why, and
+//! anything which might only slow it down is logged as a warning.
+use anyhow::{anyhow, Result};
+use elasticsearch::cat::CatAllocationParts;
+use elasticsearch::cluster::ClusterHealthParts;
+use elasticsearch::indices::IndicesValidateQueryParts;
+use elasticsearch::params::Bytes;
+use elasticsearch::Elasticsearch;
+use serde_json::{json, Value};
+use tracing::{debug, warn};
+
+use crate::units;
+
+/// The time a task can wait in the queue before the cluster is considered busy.
+const MAX_TASK_WAIT_MS: u64 = 10_000;
+
+/// Checks the health of a cluster (or a set of indices) before starting.
+///
+/// A red cluster is always an error, as documents either can't be read
+/// or can't be written. Yellow clusters, unassigned shards and long queues
+/// of pending tasks are only reported as warnings, as the job can still
+/// complete (although perhaps slowly). Clusters which don't allow access
+/// to their health (such as with restricted credentials) are skipped.
+pub(crate) async fn check(client: &Elasticsearch, indices: Option<&str>) -> Result<()> {
+    // fetch the health of the cluster, or of the indices provided
+    let names = indices.map(|indices| indices.split(',').collect::<Vec<_>>());
+    let parts = match names {
+        Some(ref names) => ClusterHealthParts::Index(names),
+        None => ClusterHealthParts::None,
+    };
+    let response = client.cluster().health(parts).send().await?;
+
+    // credentials without access to health shouldn't block the job
+    if !response.status_code().is_success() {
+        let status = response.status_code().as_u16();
+        warn!(status, "Unable to check cluster health, skipping preflight");
+        return Ok(());
+    }
+
+    // pull the relevant fields back out of the response
+    let health = response.json::<Value>().await?;
+    let status = health
+        .get("status")
+        .and_then(Value::as_str)
+        .unwrap_or("unknown");
+    let unassigned = health
+        .get("unassigned_shards")
+        .and_then(Value::as_u64)
+        .unwrap_or(0);
+    let pending = health
+        .get("number_of_pending_tasks")
+        .and_then(Value::as_u64)
+        .unwrap_or(0);
+    let waiting = health
+        .get("task_max_waiting_in_queue_millis")
+        .and_then(Value::as_u64)
+        .unwrap_or(0);
+
+    debug!(status, unassigned, pending, "Checked cluster health");
+
+    // explain why shards are unassigned, as the status alone is rarely enough
+    let explanation = match unassigned {
+        0 => None,
+        _ => explain_allocation(client).await,
+    };
+
+    // a red cluster has missing primaries, so the job can't succeed
+    if status == "red" {
+        return Err(anyhow!(
+            "Cluster health is red ({} unassigned shards){}",
+            unassigned,
+            explanation
+                .map(|explanation| format!(": {}", explanation))
+                .unwrap_or_default()
+        ));
+    }
+
+    // anything else unassigned is only a warning
+    if unassigned > 0 {
+        warn!(
+            status,
+            unassigned,
+            explanation = explanation.as_deref().unwrap_or("unknown"),
+            "Cluster has unassigned shards"
+        );
+    }
+
+    // a long queue of pending tasks means the cluster is struggling
+    if waiting >= MAX_TASK_WAIT_MS {
+        warn!(
+            pending,
+            waiting_ms = waiting,
+            "Cluster has a queue of pending tasks, which may slow things down"
+        );
+    }
+
+    Ok(())
+}
+
+/// Validates a query against a set of indices before using it.
+///
+/// Malformed queries are otherwise only reported once a scroll starts, with
+/// an opaque error from each slice. This reports them a single time, along
+/// with the explanation of what's wrong provided by the cluster. Failures to
+/// validate (such as the indices not existing) are left for the job itself
+/// to report, as they're not a problem with the query.
+pub(crate) async fn validate_query(
+    client: &Elasticsearch,
+    indices: &str,
+    query: &Value,
+) -> Result<()> {
+    // ask the cluster to validate (and explain) the query
+    let names = indices.split(',').collect::<Vec<_>>();
+    let response = client
+        .indices()
+        .validate_query(IndicesValidateQueryParts::Index(&names))
+        .explain(true)
+        .body(json!({ "query": query }))
+        .send()
+        .await?;
+
+    if !response.status_code().is_success() {
+        let status = response.status_code().as_u16();
+        debug!(status, "Unable to validate query, skipping validation");
+        return Ok(());
+    }
+
+    // valid queries need no further handling
+    let body = response.json::<Value>().await?;
+    if body.get("valid").and_then(Value::as_bool) != Some(false) {
+        return Ok(());
+    }
+
+    // locate the reason the query is invalid, on its own or per index
+    let reason = body
+        .get("error")
+        .or_else(|| {
+            body.get("explanations")?
+                .as_array()?
+                .iter()
+                .find_map(|explanation| explanation.get("error"))
+        })
+        .and_then(Value::as_str)
+        .unwrap_or("no explanation provided");
+
+    Err(anyhow!("Invalid query provided: {}", reason))
+}
+
+/// Checks that a cluster has room for an amount of incoming data.
+///
+/// The room on each data node is the space left before reaching the flood
+/// stage watermark, at which point the cluster blocks writes to any index
+/// with a shard on that node. Estimates are rough (as data takes up more or
+/// less room once indexed, and replicas take up room too), so this only
+/// catches imports which clearly won't fit. Clusters which don't allow
+/// access to their allocation or settings are skipped.
+pub(crate) async fn check_capacity(client: &Elasticsearch, incoming: u64) -> Result<()> {
+    // fetch the flood stage watermark, unless disk thresholds are disabled
+    let watermark = match flood_stage(client).await {
+        Some(watermark) => watermark,
+        None => return Ok(()),
+    };
+
+    // fetch the disk usage of every node
+    let response = client
+        .cat()
+        .allocation(CatAllocationParts::None)
+        .format("json")
+        .bytes(Bytes::B)
+        .send()
+        .await?;
+
+    if !response.status_code().is_success() {
+        let status = response.status_code().as_u16();
+        warn!(
+            status,
+            "Unable to check cluster disk space, skipping preflight"
+        );
+        return Ok(());
+    }
+
+    // sum the room left on each node before the watermark
+    let nodes = response.json::<Vec<Value>>().await?;
+    let room = nodes
+        .iter()
+        .filter_map(|node| {
+            let field = |name| node.get(name)?.as_str()?.parse::<u64>().ok();
+            let (used, avail, total) = (
+                field("disk.used")?,
+                field("disk.avail")?,
+                field("disk.total")?,
+            );
+            Some(match watermark {
+                Watermark::Ratio(ratio) => ((total as f64 * ratio) as u64).saturating_sub(used),
+                Watermark::Free(free) => avail.saturating_sub(free),
+            })
+        })
+        .sum::<u64>();
+
+    debug!(incoming, room, "Checked cluster disk space");
+
+    // fail if the incoming data clearly won't fit
+    if incoming > room {
+        return Err(anyhow!(
+            "Importing roughly {} would exceed the flood stage watermark, as the cluster only has room for {}",
+            units::format_bytes(incoming),
+            units::format_bytes(room)
+        ));
+    }
+
+    Ok(())
+}
+
+/// Watermark used to block writes when a node runs low on disk.
+#[derive(Clone, Copy, Debug)]
+enum Watermark {
+    /// Blocks writes once this ratio of the disk is used.
+    Ratio(f64),
+    /// Blocks writes once less than this many bytes are free.
+    Free(u64),
+}
+
+/// Fetches the flood stage watermark of a cluster, if enabled.
+///
+/// Settings are resolved in the same order as the cluster itself does, with
+/// transient settings overriding persistent settings overriding defaults.
+async fn flood_stage(client: &Elasticsearch) -> Option<Watermark> {
+    let response = client
+        .cluster()
+        .get_settings()
+        .include_defaults(true)
+        .flat_settings(true)
+        .send()
+        .await
+        .ok()?;
+
+    if !response.status_code().is_success() {
+        return None;
+    }
+
+    // locate a setting in the first block it's defined in
+    let settings = response.json::<Value>().await.ok()?;
+    let setting = |name: &str| {
+        ["transient", "persistent", "defaults"]
+            .iter()
+            .find_map(|block| settings.get(*block)?.get(name)?.as_str())
+    };
+
+    // thresholds can be disabled entirely
+    if setting("cluster.routing.allocation.disk.threshold_enabled") == Some("false") {
+        return None;
+    }
+
+    // watermarks are either a percentage/ratio of disk used, or bytes left free
+    let watermark = setting("cluster.routing.allocation.disk.watermark.flood_stage")?.trim();
+    match watermark.strip_suffix('%') {
+        Some(percentage) => Some(Watermark::Ratio(percentage.parse::<f64>().ok()? / 100.0)),
+        None => match watermark.parse::<f64>() {
+            Ok(ratio) => Some(Watermark::Ratio(ratio)),
+            Err(_) => Some(Watermark::Free(units::parse_bytes(watermark).ok()? as u64)),
+        },
+    }
+}
+
+/// Fetches an explanation of why a shard in the cluster is unassigned.
+///
+/// This is only ever used to describe a failure, so any errors fetching
+/// the explanation are ignored.
+async fn explain_allocation(client: &Elasticsearch) -> Option<String> {
+    let response = client.cluster().allocation_explain().send().await.ok()?;
+    let body = response.json::<Value>().await.ok()?;
+
+    // describe which shard is unassigned, and why
+    let explanation = body.get("allocate_explanation").and_then(Value::as_str)?;
+    match (
+        body.get("index").and_then(Value::as_str),
+        body.get("shard").and_then(Value::as_u64),
+    ) {
+        (Some(index), Some(shard)) => Some(format!("[{}][{}] {}", index, shard, explanation)),
+        _ => Some(explanation.to_owned()),
+    }
+}
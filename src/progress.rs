@@ -0,0 +1,338 @@
+//! Progress reporting for the Limber CLI.
+//!
+//! Progress is drawn as a bar on `stderr` when attached to a terminal, and
+//! is otherwise logged as a periodic message to avoid flooding any logs.
+//! Progress can also be written as JSON records to any file descriptor, to
+//! be consumed by whatever is running Limber. In all cases progress is read
+//! from the `Tracker` shared with the running import or export.
+use anyhow::{anyhow, Result};
+use clap::ArgMatches;
+use indicatif::{HumanCount, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use limber::{units, Tracker};
+use serde_json::json;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use std::io::{self, IsTerminal, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Interval between each redraw of a progress bar.
+const DRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Interval between each progress report when not drawing a bar.
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The progress bar currently being drawn, if any.
+static BAR: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+/// Cadence at which progress is reported when not drawing a bar.
+#[derive(Clone, Copy)]
+enum Cadence {
+    /// Report after a fixed interval of time.
+    Interval(Duration),
+    /// Report after each multiple of a number of documents.
+    Documents(usize),
+}
+
+impl Cadence {
+    /// Parses a cadence from either a duration or a number of documents.
+    ///
+    /// Plain numbers are treated as a number of documents, with anything
+    /// else parsed as a duration (such as `30s` or `5m`).
+    fn parse(input: &str) -> Result<Self> {
+        match input.parse::<usize>() {
+            Ok(0) => Err(anyhow!("Invalid progress cadence provided: {}", input)),
+            Ok(documents) => Ok(Cadence::Documents(documents)),
+            Err(_) => match units::parse_duration(input)? {
+                duration if duration.is_zero() => {
+                    Err(anyhow!("Invalid progress cadence provided: {}", input))
+                }
+                duration => Ok(Cadence::Interval(duration)),
+            },
+        }
+    }
+}
+
+/// Output shared between a reporter and its task.
+type Output = Arc<Mutex<Box<dyn Write + Send>>>;
+
+/// Reporter of the progress of a running import or export.
+pub struct Reporter {
+    task: Option<JoinHandle<()>>,
+    json: Option<(Arc<Tracker>, Output)>,
+}
+
+impl Reporter {
+    /// Starts reporting the progress of a tracker.
+    ///
+    /// The format of progress is based on the `--progress` flag, defaulting
+    /// to a bar when `stderr` is a terminal, and to log messages otherwise
+    /// (including when piping `stdout` on Windows).
+    /// Running with `--quiet` reports nothing unless a format is provided.
+    pub fn start(tracker: Arc<Tracker>, args: &ArgMatches) -> Result<Self> {
+        // determine the format to report progress in
+        let format = match args.value_of("progress") {
+            Some(format) => format,
+            None if args.is_present("quiet") => {
+                return Ok(Self {
+                    task: None,
+                    json: None,
+                })
+            }
+            // consoles on Windows query their width on every redraw, which
+            // stalls output piped through PowerShell, so bars are avoided
+            None if cfg!(windows) && !io::stdout().is_terminal() => "log",
+            None if io::stderr().is_terminal() => "bar",
+            None => "log",
+        };
+
+        // determine how often to report progress
+        let cadence = match args.value_of("progress-every") {
+            Some(cadence) => Cadence::parse(cadence)?,
+            None => Cadence::Interval(REPORT_INTERVAL),
+        };
+
+        // start reporting in the background
+        let (task, json) = match format {
+            "bar" => (tokio::spawn(draw(tracker)), None),
+            "log" => (tokio::spawn(every(cadence, tracker, log)), None),
+            _ => {
+                let fd = args.value_of_t::<i32>("progress-fd")?;
+                let output = Arc::new(Mutex::new(open(fd)?));
+                let emitted = output.clone();
+                let task = tokio::spawn(every(cadence, tracker.clone(), move |tracker| {
+                    record(tracker, &emitted)
+                }));
+                (task, Some((tracker, output)))
+            }
+        };
+
+        Ok(Self {
+            task: Some(task),
+            json,
+        })
+    }
+
+    /// Stops reporting progress, clearing any progress bar.
+    ///
+    /// When writing JSON records, a final record is written so that the
+    /// final state of the process is always available.
+    pub fn stop(self) {
+        if let Some(task) = self.task {
+            task.abort();
+        }
+        if let Some(bar) = BAR.lock().expect("poisoned lock").take() {
+            bar.finish_and_clear();
+        }
+        if let Some((tracker, output)) = self.json {
+            record(&tracker, &output);
+        }
+    }
+}
+
+/// Writer to `stderr` which avoids drawing over any progress bar.
+///
+/// Any progress bar is hidden while writing, and redrawn below the output
+/// afterwards, so that log messages don't interfere with the bar.
+pub struct Stderr;
+
+impl Write for Stderr {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match BAR.lock().expect("poisoned lock").as_ref() {
+            Some(bar) => bar.suspend(|| io::stderr().write(buf)),
+            None => io::stderr().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+/// Units which progress is measured in.
+#[derive(Clone, Copy, PartialEq)]
+enum Unit {
+    Documents,
+    Bytes,
+    Unknown,
+}
+
+/// Draws a progress bar on `stderr` until aborted.
+///
+/// Progress is measured in documents when the total is known (such as for
+/// an export), falling back to bytes when only the size of the input is
+/// known. Without either, a spinner is drawn with the number of documents.
+async fn draw(tracker: Arc<Tracker>) {
+    // create the bar, and register it to coordinate with logging
+    let bar = ProgressBar::with_draw_target(None, ProgressDrawTarget::stderr());
+    *BAR.lock().expect("poisoned lock") = Some(bar.clone());
+
+    let mut unit = None;
+    let mut interval = tokio::time::interval(DRAW_INTERVAL);
+
+    // the first tick completes immediately, before any totals are known
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        // determine the unit to measure in from the known totals
+        let current = match (tracker.total_documents(), tracker.total_bytes()) {
+            (Some(_), _) => Unit::Documents,
+            (None, Some(_)) => Unit::Bytes,
+            (None, None) => Unit::Unknown,
+        };
+
+        // restyle the bar whenever the unit changes
+        if unit != Some(current) {
+            bar.set_style(style(current));
+            unit = Some(current);
+        }
+
+        // move the bar along, with the document count when measuring bytes
+        match current {
+            Unit::Documents => {
+                bar.set_length(tracker.total_documents().unwrap_or_default());
+                bar.set_position(tracker.documents() as u64);
+            }
+            Unit::Bytes => {
+                bar.set_length(tracker.total_bytes().unwrap_or_default());
+                bar.set_position(tracker.bytes());
+                bar.set_message(format!("{} docs", HumanCount(tracker.documents() as u64)));
+            }
+            Unit::Unknown => {
+                bar.set_position(tracker.documents() as u64);
+            }
+        }
+    }
+}
+
+/// Reports progress periodically at a cadence until aborted.
+async fn every<F>(cadence: Cadence, tracker: Arc<Tracker>, report: F)
+where
+    F: Fn(&Tracker),
+{
+    // document counts are checked at the same rate as a bar is drawn
+    let mut interval = tokio::time::interval(match cadence {
+        Cadence::Interval(duration) => duration,
+        Cadence::Documents(_) => DRAW_INTERVAL,
+    });
+
+    // the first tick completes immediately
+    interval.tick().await;
+
+    // the number of documents to report at next
+    let mut next = 0;
+
+    loop {
+        interval.tick().await;
+
+        // only report document cadences once crossing the next multiple
+        if let Cadence::Documents(documents) = cadence {
+            let processed = tracker.documents();
+            if processed < next.max(documents) {
+                continue;
+            }
+            next = (processed / documents + 1) * documents;
+        }
+
+        report(&tracker);
+    }
+}
+
+/// Logs the current state of a tracker as a progress message.
+fn log(tracker: &Tracker) {
+    // calculate how far through we are, if known
+    let percent = match (tracker.total_documents(), tracker.total_bytes()) {
+        (Some(total), _) => Some(tracker.documents() as f64 / total.max(1) as f64),
+        (None, Some(total)) => Some(tracker.bytes() as f64 / total.max(1) as f64),
+        (None, None) => None,
+    };
+
+    // round the values to keep messages readable
+    let rate = tracker.rate().round() as u64;
+    let percent = percent.map(|percent| (percent * 1000.0).round() / 10.0);
+
+    match percent {
+        Some(percent) => info!(
+            processed = tracker.documents(),
+            failed = tracker.failed(),
+            rate,
+            percent,
+            "Progress"
+        ),
+        None => info!(
+            processed = tracker.documents(),
+            failed = tracker.failed(),
+            rate,
+            "Progress"
+        ),
+    }
+}
+
+/// Writes the current state of a tracker as a JSON record.
+///
+/// Failures to write are logged rather than halting the process, as the
+/// reader of progress going away is no reason to abandon the work itself.
+fn record(tracker: &Tracker, output: &Output) {
+    let record = json!({
+        "processed": tracker.documents(),
+        "failed": tracker.failed(),
+        "bytes": tracker.bytes(),
+        "total_documents": tracker.total_documents(),
+        "total_bytes": tracker.total_bytes(),
+        "rate": tracker.rate(),
+        "elapsed_ms": tracker.elapsed().as_millis() as u64,
+    });
+
+    let mut output = output.lock().expect("poisoned lock");
+    if let Err(err) = writeln!(output, "{}", record).and_then(|_| output.flush()) {
+        warn!(error = %err, "Unable to write progress");
+    }
+}
+
+/// Opens a file descriptor to write progress records to.
+fn open(fd: i32) -> Result<Box<dyn Write + Send>> {
+    match fd {
+        1 => Ok(Box::new(io::stdout())),
+        2 => Ok(Box::new(io::stderr())),
+        fd => open_raw(fd),
+    }
+}
+
+/// Opens any other file descriptor, which must be opened by the caller.
+#[cfg(unix)]
+fn open_raw(fd: i32) -> Result<Box<dyn Write + Send>> {
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(format!("/dev/fd/{}", fd))
+        .map(|file| Box::new(file) as Box<dyn Write + Send>)
+        .map_err(|err| anyhow!("Unable to open progress file descriptor {}: {}", fd, err))
+}
+
+/// Opens any other file descriptor, which is only supported on Unix.
+#[cfg(not(unix))]
+fn open_raw(fd: i32) -> Result<Box<dyn Write + Send>> {
+    Err(anyhow!(
+        "Progress file descriptor {} is not supported on this platform",
+        fd
+    ))
+}
+
+/// Creates the style of progress bar used to measure in a unit.
+fn style(unit: Unit) -> ProgressStyle {
+    let template = match unit {
+        Unit::Documents => {
+            "{elapsed_precise} [{wide_bar}] {human_pos}/{human_len} docs ({per_sec}, ETA {eta})"
+        }
+        Unit::Bytes => {
+            "{elapsed_precise} [{wide_bar}] {bytes}/{total_bytes} ({msg}, {bytes_per_sec}, ETA {eta})"
+        }
+        Unit::Unknown => "{spinner} {elapsed_precise} {human_pos} docs ({per_sec})",
+    };
+
+    ProgressStyle::with_template(template)
+        .expect("valid template")
+        .progress_chars("=> ")
+}
@@ -0,0 +1,504 @@
+//! Recording (and replaying) of the requests sent to a cluster.
+//!
+//! Recording places a local proxy in front of the cluster, which forwards
+//! every request and writes each exchange to a directory as JSON. Replaying
+//! serves those exchanges back from a local server, so that a job can be run
+//! again without access to the original cluster (such as when testing a new
+//! transform, or a different combination of flags).
+//!
+//! Only the method, path and body of each request are recorded, so neither
+//! credentials nor any other headers are ever written to disk.
+//!
+//! The same proxy is used to debug the requests sent to a cluster, printing
+//! each as an equivalent `curl` command (with any credentials masked), along
+//! with the status and the start of the body of the response.
+use anyhow::{anyhow, Result};
+use flate2::read::GzDecoder;
+use reqwest::{Certificate, Client, Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info};
+use url::Url;
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::remote::ClientOptions;
+
+/// The maximum size of the headers of a request.
+const MAX_HEADERS: usize = 64 * 1024;
+
+/// The maximum number of characters of a body to print when debugging.
+const MAX_SNIPPET: usize = 1024;
+
+/// Headers which only apply to a single connection, and are never passed on.
+const HOP_HEADERS: [&str; 5] = [
+    "connection",
+    "content-length",
+    "host",
+    "keep-alive",
+    "transfer-encoding",
+];
+
+/// A single request sent to the cluster, alongside its response.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Exchange {
+    method: String,
+    path: String,
+    request: String,
+    status: u16,
+    headers: BTreeMap<String, String>,
+    response: String,
+}
+
+/// A request read from a client connection.
+struct Request {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    /// Returns the body of this request as text, decompressing when needed.
+    fn text(&self) -> String {
+        let compressed = self.headers.iter().any(|(name, value)| {
+            name.eq_ignore_ascii_case("content-encoding") && value.eq_ignore_ascii_case("gzip")
+        });
+
+        let mut text = String::new();
+        if compressed
+            && GzDecoder::new(&self.body[..])
+                .read_to_string(&mut text)
+                .is_ok()
+        {
+            return text;
+        }
+
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+/// Starts proxying every request sent to a cluster.
+///
+/// Returns the address of a local proxy which should be used in place of
+/// the cluster. Requests are forwarded as is, with exchanges written to the
+/// directory provided by `record` (if any) in the order they complete, and
+/// printed when `debug_http` is enabled.
+pub(crate) async fn proxy(host: &str, options: &ClientOptions) -> Result<String> {
+    // create the directory, refusing to mix with an existing recording
+    if let Some(ref dir) = options.record {
+        std::fs::create_dir_all(dir)
+            .map_err(|err| anyhow!("Unable to create recording {}: {}", dir.display(), err))?;
+        if std::fs::read_dir(dir)?.next().is_some() {
+            return Err(anyhow!(
+                "Recording directory {} is not empty",
+                dir.display()
+            ));
+        }
+        info!(dir = %dir.display(), "Recording requests");
+    }
+
+    // verify the cluster using the provided certificate, if any
+    let mut builder = Client::builder();
+    if let Some(ref path) = options.ca_cert {
+        let pem = std::fs::read(path)
+            .map_err(|err| anyhow!("Unable to read CA certificate {}: {}", path, err))?;
+        builder = builder.add_root_certificate(Certificate::from_pem(&pem)?);
+    }
+
+    let recorder = Arc::new(Recorder {
+        client: builder.build()?,
+        host: host.trim_end_matches('/').to_owned(),
+        dir: options.record.clone(),
+        sequence: AtomicUsize::new(0),
+    });
+
+    // print requests against the cluster, rather than the proxy
+    let (listener, address) = bind().await?;
+    let debug = options.debug_http.then(|| recorder.host.clone());
+
+    listen(listener, debug, move |request| {
+        let recorder = recorder.clone();
+        async move { recorder.forward(request).await }
+    });
+
+    Ok(address)
+}
+
+/// Starts replaying the requests recorded within a directory.
+///
+/// Returns the address of a local server which should be used in place of
+/// the cluster. Each request is answered with the first unused exchange for
+/// the same method, path and body, falling back to any unused exchange for
+/// the same method and path (as the order of concurrent requests can vary
+/// between runs), and finally to the last exchange used for either.
+pub(crate) async fn replay(dir: &Path, debug: bool) -> Result<String> {
+    let invalid = |err: &dyn std::fmt::Display| {
+        anyhow!("Unable to read recording {}: {}", dir.display(), err)
+    };
+
+    // read every exchange, in the order they were recorded
+    let mut paths = std::fs::read_dir(dir)
+        .map_err(|err| invalid(&err))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<PathBuf>>>()
+        .map_err(|err| invalid(&err))?;
+    paths.retain(|path| {
+        path.extension()
+            .is_some_and(|extension| extension == "json")
+    });
+    paths.sort();
+
+    let mut exchanges = Vec::with_capacity(paths.len());
+    for path in paths {
+        let contents = std::fs::read(&path).map_err(|err| invalid(&err))?;
+        exchanges.push(serde_json::from_slice::<Exchange>(&contents).map_err(|err| invalid(&err))?);
+    }
+
+    let replayer = Arc::new(Replayer {
+        unused: Mutex::new(exchanges),
+        used: Mutex::new(Vec::new()),
+    });
+
+    let (listener, address) = bind().await?;
+    let debug = debug.then(|| address.clone());
+
+    info!(dir = %dir.display(), "Replaying requests");
+    listen(listener, debug, move |request| {
+        let replayer = replayer.clone();
+        async move { Ok(replayer.answer(&request)) }
+    });
+
+    Ok(address)
+}
+
+/// Proxy recording every exchange with a cluster.
+struct Recorder {
+    client: Client,
+    host: String,
+    dir: Option<PathBuf>,
+    sequence: AtomicUsize,
+}
+
+impl Recorder {
+    /// Forwards a request to the cluster, recording the exchange.
+    async fn forward(&self, request: Request) -> Result<Exchange> {
+        // pass the request on, minus any connection specific headers
+        let method = Method::from_bytes(request.method.as_bytes())?;
+        let mut builder = self
+            .client
+            .request(method, format!("{}{}", self.host, request.path));
+        for (name, value) in &request.headers {
+            if !is_hop(name) && !name.eq_ignore_ascii_case("accept-encoding") {
+                builder = builder.header(name, value);
+            }
+        }
+        let response = builder.body(request.body.clone()).send().await?;
+
+        // keep the headers describing the body (which is always decompressed)
+        let status = response.status().as_u16();
+        let headers = response
+            .headers()
+            .iter()
+            .filter(|(name, _)| !is_hop(name.as_str()) && *name != "content-encoding")
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_owned())))
+            .collect();
+        let body = response.bytes().await?;
+
+        let exchange = Exchange {
+            method: request.method.clone(),
+            path: request.path.clone(),
+            request: request.text(),
+            status,
+            headers,
+            response: String::from_utf8_lossy(&body).into_owned(),
+        };
+
+        // write the exchange out, numbered by the order of completion
+        if let Some(ref dir) = self.dir {
+            let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+            let path = dir.join(format!("{:06}.json", sequence));
+            std::fs::write(path, serde_json::to_vec_pretty(&exchange)?)?;
+        }
+
+        Ok(exchange)
+    }
+}
+
+/// Server answering requests from a set of recorded exchanges.
+struct Replayer {
+    unused: Mutex<Vec<Exchange>>,
+    used: Mutex<Vec<Exchange>>,
+}
+
+impl Replayer {
+    /// Answers a request with the closest matching exchange.
+    fn answer(&self, request: &Request) -> Exchange {
+        let body = request.text();
+        let same = |exchange: &Exchange| {
+            exchange.method == request.method && exchange.path == request.path
+        };
+
+        // prefer an unused exchange, matching the body if possible
+        let mut unused = self.unused.lock().expect("poisoned lock");
+        let position = unused
+            .iter()
+            .position(|exchange| same(exchange) && exchange.request == body)
+            .or_else(|| unused.iter().position(same));
+
+        let mut used = self.used.lock().expect("poisoned lock");
+        if let Some(position) = position {
+            let exchange = unused.remove(position);
+            used.push(exchange.clone());
+            return exchange;
+        }
+
+        // otherwise reuse the latest exchange, again matching the body if possible
+        let reused = used
+            .iter()
+            .rev()
+            .find(|exchange| same(exchange) && exchange.request == body)
+            .or_else(|| used.iter().rev().find(|exchange| same(exchange)));
+
+        match reused {
+            Some(exchange) => exchange.clone(),
+            None => Exchange {
+                method: request.method.clone(),
+                path: request.path.clone(),
+                request: body,
+                status: 404,
+                headers: BTreeMap::from([(
+                    "content-type".to_owned(),
+                    "application/json".to_owned(),
+                )]),
+                response: format!(
+                    r#"{{"error":"No recorded response for {} {}","status":404}}"#,
+                    request.method,
+                    request.path.replace('"', "\\\"")
+                ),
+            },
+        }
+    }
+}
+
+/// Binds a listener to a local port, returning it alongside its address.
+async fn bind() -> Result<(TcpListener, String)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let address = format!("http://{}", listener.local_addr()?);
+    Ok((listener, address))
+}
+
+/// Listens on a local port, answering each request via a handler.
+///
+/// Each connection carries a single request, as this keeps the handling of
+/// connections simple (and the cost of connecting locally is negligible).
+/// When a host is provided, every request is also printed as it would be
+/// sent to that host.
+fn listen<H, F>(listener: TcpListener, debug: Option<String>, handler: H)
+where
+    H: Fn(Request) -> F + Send + Sync + 'static,
+    F: std::future::Future<Output = Result<Exchange>> + Send,
+{
+    let handler = Arc::new(handler);
+    let debug = Arc::new(debug);
+
+    // accept connections in the background, for the rest of the process
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(err) => {
+                    debug!(error = %err, "Unable to accept connection");
+                    continue;
+                }
+            };
+            let handler = handler.clone();
+            let debug = debug.clone();
+            tokio::spawn(async move {
+                if let Err(err) = respond(stream, debug.as_deref(), &*handler).await {
+                    debug!(error = %err, "Unable to answer request");
+                }
+            });
+        }
+    });
+}
+
+/// Responds to a single request on a connection.
+async fn respond<H, F>(mut stream: TcpStream, debug: Option<&str>, handler: &H) -> Result<()>
+where
+    H: Fn(Request) -> F,
+    F: std::future::Future<Output = Result<Exchange>>,
+{
+    let request = read_request(&mut stream).await?;
+    let head = request.method == "HEAD";
+
+    // describe the request up front, as the handler takes ownership of it
+    let command = debug.map(|host| curl(host, &request));
+
+    // failures to reach the cluster are passed back as a gateway error
+    let (status, headers, body) = match handler(request).await {
+        Ok(exchange) => (exchange.status, exchange.headers, exchange.response),
+        Err(err) => (502, BTreeMap::new(), err.to_string()),
+    };
+
+    // print the whole exchange at once, to avoid interleaving with others
+    let reason = StatusCode::from_u16(status)
+        .ok()
+        .and_then(|status| status.canonical_reason())
+        .unwrap_or("Unknown");
+    if let Some(command) = command {
+        let body = snippet(body.trim_end());
+        eprint!("{}\n< {} {}\n< {}\n\n", command, status, reason, body);
+    }
+
+    // write the response and close the connection
+    let mut response = format!("HTTP/1.1 {} {}\r\n", status, reason);
+    for (name, value) in headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    response.push_str(&format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    ));
+
+    stream.write_all(response.as_bytes()).await?;
+    if !head {
+        stream.write_all(body.as_bytes()).await?;
+    }
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+/// Reads a request (including its body) from a connection.
+async fn read_request(stream: &mut TcpStream) -> Result<Request> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 8192];
+
+    // read through to the end of the request headers
+    let end = loop {
+        if let Some(position) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break position;
+        }
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 || buffer.len() > MAX_HEADERS {
+            return Err(anyhow!("Incomplete request headers"));
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    };
+
+    // parse the request line and headers
+    let head = std::str::from_utf8(&buffer[..end])?;
+    let mut lines = head.split("\r\n");
+    let mut line = lines.next().unwrap_or_default().split(' ');
+    let (method, path) = match (line.next(), line.next()) {
+        (Some(method), Some(path)) => (method.to_owned(), path.to_owned()),
+        _ => return Err(anyhow!("Invalid request line")),
+    };
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_owned(), value.trim().to_owned()))
+        .collect::<Vec<_>>();
+
+    // read the rest of the body, based on the length provided
+    if headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("transfer-encoding"))
+    {
+        return Err(anyhow!("Chunked requests are not supported"));
+    }
+    let length = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .map(|(_, value)| value.parse::<usize>())
+        .transpose()?
+        .unwrap_or(0);
+    let mut body = buffer.split_off(end + 4);
+    while body.len() < length {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Err(anyhow!("Incomplete request body"));
+        }
+        body.extend_from_slice(&chunk[..read]);
+    }
+
+    Ok(Request {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
+
+/// Formats a request as an equivalent `curl` command against a host.
+///
+/// Any credentials (in either the headers or the host) are masked, and any
+/// compressed body is included as plain text, as the cluster accepts both.
+/// Large bodies are cut short, so the command is only complete for smaller
+/// requests.
+fn curl(host: &str, request: &Request) -> String {
+    // mask any password within the host itself
+    let url = match Url::parse(&format!("{}{}", host, request.path)) {
+        Ok(mut url) if url.password().is_some() => {
+            let _ = url.set_password(Some("****"));
+            url.to_string()
+        }
+        _ => format!("{}{}", host, request.path),
+    };
+
+    let mut command = format!("curl -X {} {}", request.method, quote(&url));
+    for (name, value) in &request.headers {
+        // skip anything curl sets itself, or which no longer applies
+        if is_hop(name)
+            || name.eq_ignore_ascii_case("accept-encoding")
+            || name.eq_ignore_ascii_case("content-encoding")
+        {
+            continue;
+        }
+
+        // keep the scheme of any credentials, but never the credentials
+        let value = if name.eq_ignore_ascii_case("authorization") {
+            match value.split_once(' ') {
+                Some((scheme, _)) => format!("{} ****", scheme),
+                None => "****".to_owned(),
+            }
+        } else {
+            value.to_owned()
+        };
+
+        command.push_str(&format!(" -H {}", quote(&format!("{}: {}", name, value))));
+    }
+
+    // attach the body, cutting it short when too large to read
+    if !request.body.is_empty() {
+        command.push_str(&format!(
+            " --data-binary {}",
+            quote(&snippet(&request.text()))
+        ));
+    }
+
+    command
+}
+
+/// Trims a body down to a snippet suitable for printing.
+fn snippet(body: &str) -> String {
+    match body.char_indices().nth(MAX_SNIPPET) {
+        Some((end, _)) => format!("{}... ({} more bytes)", &body[..end], body.len() - end),
+        None => body.to_owned(),
+    }
+}
+
+/// Quotes a value for use within a shell command.
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Determines whether a header only applies to a single connection.
+fn is_hop(name: &str) -> bool {
+    HOP_HEADERS.iter().any(|hop| hop.eq_ignore_ascii_case(name))
+}
@@ -1,23 +1,292 @@
 //! Utility functions for dealing with remote locations.
 //!
 //! This module offers functions for interacting with a remote cluster,
-//! such as hostname parsing, client creation, etc.
+//! such as hostname parsing, client creation, resource resolution, etc.
 use anyhow::{anyhow, Result};
-use elasticsearch::http::transport::Transport;
-use elasticsearch::Elasticsearch;
-use url::Url;
+use elasticsearch::auth::Credentials;
+use elasticsearch::cat::CatIndicesParts;
+use elasticsearch::cert::{Certificate, CertificateValidation};
+use elasticsearch::http::transport::{
+    Connection, ConnectionPool, SingleNodeConnectionPool, TransportBuilder,
+};
+use elasticsearch::indices::{
+    IndicesCreateParts, IndicesExistsParts, IndicesForcemergeParts, IndicesGetAliasParts,
+    IndicesGetDataStreamParts, IndicesGetMappingParts, IndicesGetSettingsParts,
+    IndicesPutSettingsParts,
+};
+use elasticsearch::nodes::NodesInfoParts;
+use elasticsearch::params::{Bytes, ExpandWildcards};
+use elasticsearch::{CountParts, Elasticsearch};
+use percent_encoding::percent_decode_str;
+use serde_json::{json, Map, Value};
+use tokio::net::TcpStream;
+use tracing::{info, warn};
+use url::{Host, Url};
 
-/// Creates a new client based on the provided hostname.
-pub fn create_client(host: &str) -> Result<Elasticsearch> {
-    Ok(Elasticsearch::new(Transport::single_node(host)?))
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::error::{Error, ErrorKind};
+use crate::recording;
+
+/// Options used to control how a client connects to a cluster.
+#[derive(Clone, Debug, Default)]
+pub struct ClientOptions {
+    /// Credentials used to authenticate with the cluster, if any.
+    pub auth: Option<Credentials>,
+    /// A file containing a CA certificate (in PEM format) to verify with.
+    pub ca_cert: Option<String>,
+    /// Whether to compress request bodies sent to the cluster.
+    pub compression: bool,
+    /// Whether to print every request sent to the cluster, along with its response.
+    pub debug_http: bool,
+    /// The maximum time to wait for each host to accept a connection, if any.
+    ///
+    /// This is only checked when connecting (see `check_connect`), as the
+    /// transport offers no way to bound connections it opens later on.
+    pub connect_timeout: Option<Duration>,
+    /// The maximum number of bytes to transfer per second, if any.
+    pub max_bandwidth: Option<usize>,
+    /// A directory to record every request (and response) into, if any.
+    pub record: Option<PathBuf>,
+    /// A directory of recorded requests to replay instead of connecting, if any.
+    pub replay: Option<PathBuf>,
+    /// The maximum time to wait for each request to complete, if any.
+    pub request_timeout: Option<Duration>,
+    /// Whether to discover the nodes of the cluster from the hosts provided.
+    pub sniff: bool,
+}
+
+/// Connects to a cluster based on the provided hostnames.
+///
+/// This creates a client via `create_client`, before checking that every
+/// host is reachable (when a connection timeout is provided). When sniffing
+/// is enabled, the hosts provided are only used as seeds to discover the
+/// nodes of the cluster, and the returned client spreads requests across
+/// all data (and coordinating) nodes instead.
+///
+/// When recording (or replaying, or debugging requests), the returned client
+/// sends every request via a local proxy to a single host instead, so these
+/// modes can't be combined with sniffing (or several hosts).
+pub async fn connect<S: AsRef<str>>(hosts: &[S], options: &ClientOptions) -> Result<Elasticsearch> {
+    // proxied requests all go to a single host, so anything else would be lost
+    let proxied = options.replay.is_some() || options.record.is_some() || options.debug_http;
+    if proxied && (options.sniff || hosts.len() > 1) {
+        return Err(anyhow!(
+            "Recording, replaying and debugging requests can't be used with sniffing or multiple hosts"
+        ));
+    }
+
+    // replay a recording of the cluster, without connecting at all
+    if let Some(ref dir) = options.replay {
+        let server = recording::replay(dir, options.debug_http).await?;
+        return create_client(&[server], options);
+    }
+
+    // record (or print) every request, sending them all via the first host
+    if options.record.is_some() || options.debug_http {
+        let proxy = recording::proxy(hosts[0].as_ref(), options).await?;
+        return create_client(&[proxy], options);
+    }
+
+    // construct a client spread across all hosts
+    let client = create_client(hosts, options)?;
+
+    // make sure every host is reachable, if bounded
+    if let Some(timeout) = options.connect_timeout {
+        check_connect(hosts, timeout).await?;
+    }
+
+    // without sniffing, the hosts provided are all we need
+    if !options.sniff {
+        return Ok(client);
+    }
+
+    // discover the nodes of the cluster, using the scheme of the seeds
+    let scheme = Url::parse(hosts[0].as_ref())?.scheme().to_owned();
+    let nodes = sniff_nodes(&client, &scheme).await?;
+
+    // an empty list means no nodes publish an address, so stick to the seeds
+    if nodes.is_empty() {
+        warn!("Unable to discover any cluster nodes, using the hosts provided");
+        return Ok(client);
+    }
+
+    // discovered nodes are checked just like the seeds, if bounded
+    if let Some(timeout) = options.connect_timeout {
+        check_connect(&nodes, timeout).await?;
+    }
+
+    info!(nodes = nodes.len(), "Discovered cluster nodes");
+    create_client(&nodes, options)
+}
+
+/// Creates a new client based on the provided hostnames.
+///
+/// If multiple hosts are provided, requests are spread across all hosts in
+/// a round-robin fashion. As a failed request is retried against the next
+/// host, this also offers failover when a single node becomes unavailable.
+///
+/// Any credentials provided are attached to every request sent, and any
+/// certificate provided is used to verify the certificate of the cluster.
+/// Any request timeout provided applies to every request sent, including
+/// the time taken to connect.
+pub fn create_client<S: AsRef<str>>(hosts: &[S], options: &ClientOptions) -> Result<Elasticsearch> {
+    // parse the url of every host
+    let mut urls = hosts
+        .iter()
+        .map(|host| Ok(Url::parse(host.as_ref())?))
+        .collect::<Result<Vec<_>>>()?;
+
+    // a single host needs no pooling, and no hosts is not a valid client
+    let mut builder = match urls.len() {
+        0 => return Err(anyhow!("No cluster hosts provided")),
+        1 => TransportBuilder::new(SingleNodeConnectionPool::new(urls.remove(0))),
+        _ => TransportBuilder::new(RoundRobin {
+            connections: urls.into_iter().map(Connection::new).collect(),
+            offset: Arc::new(AtomicUsize::new(0)),
+        }),
+    };
+
+    // attach any credentials to the transport
+    if let Some(ref credentials) = options.auth {
+        builder = builder.auth(credentials.clone());
+    }
+
+    // verify the cluster using the provided certificate, if any
+    if let Some(ref path) = options.ca_cert {
+        let pem = std::fs::read(path)
+            .map_err(|err| anyhow!("Unable to read CA certificate {}: {}", path, err))?;
+        let certificate = Certificate::from_pem(&pem)?;
+        builder = builder.cert_validation(CertificateValidation::Full(certificate));
+    }
+
+    // bound the time taken by every request, if requested
+    if let Some(timeout) = options.request_timeout {
+        builder = builder.timeout(timeout);
+    }
+
+    Ok(Elasticsearch::new(builder.build()?))
+}
+
+/// Discovers the URLs of all data and coordinating nodes within a cluster.
+///
+/// Nodes are located via the nodes info API, using the HTTP address each
+/// node publishes. Dedicated master nodes (and others which hold no data)
+/// are skipped, as they're not suitable for handling bulk or search load.
+pub async fn sniff_nodes(client: &Elasticsearch, scheme: &str) -> Result<Vec<String>> {
+    // fetch the http info of every node in the cluster
+    let body = client
+        .nodes()
+        .info(NodesInfoParts::Metric(&["http"]))
+        .send()
+        .await?
+        .error_for_status_code()?
+        .json::<Value>()
+        .await?;
+
+    // pull the nodes back out of the response
+    let nodes = body
+        .get("nodes")
+        .and_then(Value::as_object)
+        .ok_or_else(|| anyhow!("Unable to locate nodes in cluster"))?;
+
+    let mut urls = Vec::new();
+    for node in nodes.values() {
+        // nodes without roles are coordinating only, otherwise require data
+        let roles = node.get("roles").and_then(Value::as_array);
+        let suitable = roles.is_none_or(|roles| {
+            roles.is_empty()
+                || roles
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .any(|role| role.starts_with("data"))
+        });
+
+        if !suitable {
+            continue;
+        }
+
+        // addresses may be prefixed with a hostname, as in `host/ip:port`
+        let address = node
+            .pointer("/http/publish_address")
+            .and_then(Value::as_str)
+            .map(|address| address.rsplit('/').next().unwrap_or(address));
+
+        if let Some(address) = address {
+            urls.push(format!("{}://{}", scheme, address));
+        }
+    }
+
+    // sort to keep the order of nodes stable across runs
+    urls.sort();
+    Ok(urls)
+}
+
+/// Checks that every host accepts a connection within a timeout.
+///
+/// The transport offers no way to bound the time taken to connect, so this
+/// is checked up front instead. Unreachable hosts then fail quickly with a
+/// clear error, rather than waiting on the operating system to give up.
+/// Connections opened by the transport afterwards (such as to replace one
+/// which was dropped) are only bounded by any request timeout.
+pub async fn check_connect<S: AsRef<str>>(hosts: &[S], timeout: Duration) -> Result<()> {
+    for host in hosts {
+        // parse the address of the host to connect to
+        let url = Url::parse(host.as_ref())?;
+        let port = url.port_or_known_default().unwrap_or(9200);
+        let address = match url.host() {
+            Some(Host::Domain(domain)) => domain.to_owned(),
+            Some(Host::Ipv4(ip)) => ip.to_string(),
+            Some(Host::Ipv6(ip)) => ip.to_string(),
+            None => return Err(anyhow!("Invalid cluster resource provided")),
+        };
+
+        // connect (including any lookup) within the timeout
+        let connect = TcpStream::connect((address.as_str(), port));
+        let message = match tokio::time::timeout(timeout, connect).await {
+            Ok(Ok(_)) => continue,
+            Ok(Err(err)) => format!("Unable to connect to {}: {}", host.as_ref(), err),
+            Err(_) => format!(
+                "Unable to connect to {} within {:?}",
+                host.as_ref(),
+                timeout
+            ),
+        };
+
+        return Err(Error::new(ErrorKind::Connection, message).into());
+    }
+
+    Ok(())
+}
+
+/// Connection pool rotating through a set of connections for each request.
+#[derive(Clone, Debug)]
+struct RoundRobin {
+    connections: Vec<Connection>,
+    offset: Arc<AtomicUsize>,
+}
+
+impl ConnectionPool for RoundRobin {
+    /// Fetches the next connection in the rotation.
+    fn next(&self) -> &Connection {
+        let offset = self.offset.fetch_add(1, Ordering::Relaxed);
+        &self.connections[offset % self.connections.len()]
+    }
 }
 
 /// Attempts to parse a host/index pair out of the CLI arguments.
 ///
 /// This logic is pretty vague; we don't actually test connection beyond
-/// looking to see if the provided scheme is HTTP(S). The index string
-/// returned will never be empty; if no index is provided, we'll use an
-/// empty `Option` type to allow the caller to decide how to handle it.
+/// looking to see if the provided scheme is HTTP(S). The index is always
+/// the last segment of the path, with anything before it treated as the
+/// base path of the cluster (such as when behind a proxy at `/es`). A path
+/// ending in a slash has no index, so `http://proxy/es/` refers to the base
+/// path alone. The index string returned will never be empty; if no index
+/// is provided, we'll use an empty `Option` type to allow the caller to
+/// decide how to handle it.
 pub fn parse_cluster(target: &str) -> Result<(String, Option<String>)> {
     // attempt to parse the resource
     let mut url = Url::parse(target)?;
@@ -27,19 +296,352 @@ pub fn parse_cluster(target: &str) -> Result<(String, Option<String>)> {
         return Err(anyhow!("Invalid cluster resource provided"));
     }
 
-    // fetch index from path, trimming the prefix
-    let index = url.path().trim_start_matches('/');
+    // split the index from the base path, which always starts with a slash
+    let path = url.path().to_owned();
+    let (base, index) = path.split_at(path.rfind('/').unwrap_or(0));
+
+    // decode the index, as names like `<logs-{now/d}>` arrive encoded
+    let index = percent_decode_str(index.trim_start_matches('/'))
+        .decode_utf8()
+        .map_err(|_| anyhow!("Invalid index name provided: {}", index))?;
 
     // set default index
     let index = if index.trim().is_empty() {
         None
     } else {
-        Some(index.to_owned())
+        Some(index.into_owned())
     };
 
-    // trim the path
-    url.set_path("");
+    // keep only the base path, as queries and fragments are never used
+    url.set_path(base.trim_end_matches('/'));
+    url.set_query(None);
+    url.set_fragment(None);
 
     // assume we have a cluster now, so pass it back
     Ok((url.as_str().trim_end_matches('/').to_owned(), index))
 }
+
+/// Kinds of resources an index name can refer to within a cluster.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resource {
+    Index,
+    Alias,
+    DataStream,
+}
+
+/// Resolves the kind of resource an index name refers to within a cluster.
+///
+/// Any name which is neither an alias or data stream is assumed to be an
+/// index (even if it doesn't yet exist). Aliases are only returned when it
+/// is possible to write through them, otherwise an error is returned as it
+/// would be impossible to write any documents to the target.
+pub async fn resolve_resource(client: &Elasticsearch, name: &str) -> Result<Resource> {
+    // data streams are checked first, as they're backed by aliases
+    let response = client
+        .indices()
+        .get_data_stream(IndicesGetDataStreamParts::Name(&[name]))
+        .send()
+        .await?;
+
+    // older clusters will reject this request, so only check success
+    if response.status_code().is_success() {
+        let body = response.json::<Value>().await?;
+        let found = body
+            .get("data_streams")
+            .and_then(Value::as_array)
+            .map(|streams| !streams.is_empty());
+
+        if found.unwrap_or(false) {
+            return Ok(Resource::DataStream);
+        }
+    }
+
+    // check for any aliases using the name
+    let response = client
+        .indices()
+        .get_alias(IndicesGetAliasParts::Name(&[name]))
+        .send()
+        .await?;
+
+    // not found means that this is not an alias
+    if !response.status_code().is_success() {
+        return Ok(Resource::Index);
+    }
+
+    // fetch all indices the alias points to
+    let body = response.json::<Map<String, Value>>().await?;
+    let indices = body
+        .values()
+        .filter_map(|index| index.pointer(&format!("/aliases/{}", name)))
+        .collect::<Vec<_>>();
+
+    // check whether any index is flagged as the write index
+    let writable = indices.iter().any(|alias| {
+        alias
+            .get("is_write_index")
+            .and_then(Value::as_bool)
+            .unwrap_or(indices.len() == 1)
+    });
+
+    // writing would fail, so bail early with a clear error
+    if !writable {
+        return Err(anyhow!("Alias {} does not have a write index", name));
+    }
+
+    Ok(Resource::Alias)
+}
+
+/// Creates an index using the provided body, unless it already exists.
+///
+/// This is safe to call concurrently for the same index, as any attempt
+/// to create an index which has since been created will be ignored.
+pub async fn create_index(client: &Elasticsearch, index: &str, body: &Value) -> Result<()> {
+    // check whether the index already exists
+    let response = client
+        .indices()
+        .exists(IndicesExistsParts::Index(&[index]))
+        .send()
+        .await?;
+
+    // nothing to do if it's already there
+    if response.status_code().is_success() {
+        return Ok(());
+    }
+
+    // create the index using the provided body
+    let response = client
+        .indices()
+        .create(IndicesCreateParts::Index(index))
+        .body(body.clone())
+        .send()
+        .await?;
+
+    // created successfully
+    if response.status_code().is_success() {
+        return Ok(());
+    }
+
+    // check the error to see if someone else created it first
+    let error = response.json::<Value>().await?;
+    let kind = error.pointer("/error/type").and_then(Value::as_str);
+
+    // concurrent creation is fine, anything else is an error
+    if kind == Some("resource_already_exists_exception") {
+        return Ok(());
+    }
+
+    Err(anyhow!("Unable to create index {}: {}", index, error))
+}
+
+/// Fetches the current values of a set of settings for an index.
+///
+/// Settings are returned in flat form for each concrete index, as the
+/// provided index may be an alias. Default values are included for any
+/// settings which have not been explicitly set. If the index does not
+/// exist, an empty map will be returned.
+pub async fn get_settings(
+    client: &Elasticsearch,
+    index: &str,
+    names: &[&str],
+) -> Result<Map<String, Value>> {
+    // fetch the settings, including defaults
+    let response = client
+        .indices()
+        .get_settings(IndicesGetSettingsParts::IndexName(&[index], names))
+        .flat_settings(true)
+        .include_defaults(true)
+        .send()
+        .await?;
+
+    // missing indices have no settings
+    if response.status_code().as_u16() == 404 {
+        return Ok(Map::new());
+    }
+
+    // parse the body of the response
+    let body = response
+        .error_for_status_code()?
+        .json::<Map<String, Value>>()
+        .await?;
+
+    // merge explicit settings over defaults for each index
+    let settings = body
+        .into_iter()
+        .map(|(index, mut value)| {
+            let mut merged = match value["defaults"].take() {
+                Value::Object(defaults) => defaults,
+                _ => Map::new(),
+            };
+            if let Value::Object(settings) = value["settings"].take() {
+                merged.extend(settings);
+            }
+            (index, Value::Object(merged))
+        })
+        .collect();
+
+    Ok(settings)
+}
+
+/// Retrieves the mappings of every index matching an index pattern.
+///
+/// Mappings are returned for each concrete index, as the provided index may
+/// be an alias or a pattern. If the index does not exist, an empty map will
+/// be returned.
+pub async fn get_mappings(client: &Elasticsearch, index: &str) -> Result<Map<String, Value>> {
+    // fetch the mappings of every matching index
+    let names = [index];
+    let response = client
+        .indices()
+        .get_mapping(IndicesGetMappingParts::Index(&names))
+        .send()
+        .await?;
+
+    // missing indices have no mappings
+    if response.status_code().as_u16() == 404 {
+        return Ok(Map::new());
+    }
+
+    // pull the mappings out of each index
+    let body = response
+        .error_for_status_code()?
+        .json::<Map<String, Value>>()
+        .await?;
+
+    Ok(body
+        .into_iter()
+        .map(|(index, mut value)| {
+            let mappings = value.get_mut("mappings").map(Value::take);
+            (index, mappings.unwrap_or_default())
+        })
+        .collect())
+}
+
+/// Updates a set of (flat) settings for an index.
+pub async fn put_settings(client: &Elasticsearch, index: &str, settings: &Value) -> Result<()> {
+    client
+        .indices()
+        .put_settings(IndicesPutSettingsParts::Index(&[index]))
+        .body(settings)
+        .send()
+        .await?
+        .error_for_status_code()?;
+    Ok(())
+}
+
+/// Counts the number of documents currently searchable in an index.
+///
+/// If a query is provided, only documents matching the query are counted.
+pub async fn count(client: &Elasticsearch, index: &str, query: Option<&Value>) -> Result<u64> {
+    // construct the count request for the index
+    let names = [index];
+    let request = client.count(CountParts::Index(&names));
+
+    // fetch the count of all (matching) documents
+    let response = match query {
+        Some(query) => request.body(json!({ "query": query })).send().await?,
+        None => request.send().await?,
+    };
+
+    // parse the count out of the response
+    let body = response.error_for_status_code()?.json::<Value>().await?;
+
+    // pull the count back out of the body
+    body.get("count")
+        .and_then(Value::as_u64)
+        .ok_or_else(|| anyhow!("Unable to count documents in index {}", index))
+}
+
+/// Lists the open indices matching an index pattern, alongside their size.
+///
+/// Sizes are the total size (in bytes) of each index on disk, including any
+/// replicas. Indices are listed in the order returned by the cluster.
+pub async fn list_indices(client: &Elasticsearch, index: &str) -> Result<Vec<(String, u64)>> {
+    // list every open index matching the pattern
+    let names = [index];
+    let response = client
+        .cat()
+        .indices(CatIndicesParts::Index(&names))
+        .expand_wildcards(&[ExpandWildcards::Open])
+        .format("json")
+        .bytes(Bytes::B)
+        .h(&["index", "store.size"])
+        .send()
+        .await?;
+
+    // parse the name and size of each index
+    let body = response
+        .error_for_status_code()?
+        .json::<Vec<Value>>()
+        .await?;
+    let indices = body
+        .iter()
+        .filter_map(|entry| {
+            let name = entry.get("index")?.as_str()?.to_owned();
+            let size = entry
+                .get("store.size")
+                .and_then(Value::as_str)
+                .and_then(|size| size.parse().ok())
+                .unwrap_or(0);
+            Some((name, size))
+        })
+        .collect();
+
+    Ok(indices)
+}
+
+/// Force merges an index, optionally down to a maximum number of segments.
+pub async fn force_merge(client: &Elasticsearch, index: &str, segments: Option<i64>) -> Result<()> {
+    let names = [index];
+    let indices = client.indices();
+    let mut request = indices.forcemerge(IndicesForcemergeParts::Index(&names));
+
+    if let Some(segments) = segments {
+        request = request.max_num_segments(segments);
+    }
+
+    request.send().await?.error_for_status_code()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_cluster_with_ipv6_host() {
+        let (host, index) = parse_cluster("http://[::1]:9200/idx").unwrap();
+        assert_eq!(host, "http://[::1]:9200");
+        assert_eq!(index.as_deref(), Some("idx"));
+    }
+
+    #[test]
+    fn parse_cluster_with_base_path() {
+        let (host, index) = parse_cluster("https://host/base/path/idx").unwrap();
+        assert_eq!(host, "https://host/base/path");
+        assert_eq!(index.as_deref(), Some("idx"));
+    }
+
+    #[test]
+    fn parse_cluster_with_trailing_slash() {
+        let (host, index) = parse_cluster("http://host:9200/").unwrap();
+        assert_eq!(host, "http://host:9200");
+        assert_eq!(index, None);
+
+        let (host, index) = parse_cluster("http://proxy/es/").unwrap();
+        assert_eq!(host, "http://proxy/es");
+        assert_eq!(index, None);
+    }
+
+    #[test]
+    fn parse_cluster_with_encoded_index() {
+        let (host, index) = parse_cluster("http://host:9200/%3Clogs-%7Bnow%2Fd%7D%3E").unwrap();
+        assert_eq!(host, "http://host:9200");
+        assert_eq!(index.as_deref(), Some("<logs-{now/d}>"));
+    }
+
+    #[test]
+    fn parse_cluster_rejects_other_schemes() {
+        assert!(parse_cluster("ftp://host/idx").is_err());
+        assert!(parse_cluster("not a url").is_err());
+    }
+}
@@ -0,0 +1,114 @@
+//! Retry policies used to recover from transient request failures.
+//!
+//! Clusters under heavy load will reject requests (with a `429`), or fail
+//! with transient server errors. Rather than failing an entire job due to
+//! these errors, requests are retried with an exponential backoff.
+use anyhow::Result;
+use elasticsearch::http::response::Response;
+use elasticsearch::Error;
+use tracing::warn;
+
+use std::future::Future;
+use std::time::Duration;
+
+/// The maximum delay between two attempts of the same request.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Policy used to control retries of failed requests.
+#[derive(Clone, Copy, Debug)]
+pub struct Policy {
+    retries: usize,
+    backoff: Duration,
+}
+
+impl Policy {
+    /// Constructs a new policy from a retry count and backoff.
+    ///
+    /// The backoff is doubled on each attempt, up to a maximum of a minute
+    /// between attempts. The initial backoff is used as is on the first retry.
+    pub fn new(retries: usize, backoff: Duration) -> Self {
+        Self { retries, backoff }
+    }
+
+    /// Sends a request, retrying according to this policy.
+    ///
+    /// Requests are retried on transport errors, rejections (`429`) and any
+    /// transient server errors (`502`, `503`, `504`). A `Retry-After` header
+    /// sent by the server is always honoured over the computed backoff. The
+    /// last response is returned once retries are exhausted, allowing the
+    /// caller to decide how to handle the failure.
+    pub async fn send<F, R>(&self, request: F) -> Result<Response>
+    where
+        F: Fn() -> R,
+        R: Future<Output = Result<Response, Error>>,
+    {
+        Ok(self.send_counted(request).await?.0)
+    }
+
+    /// Sends a request just like `send`, alongside the number of retries made.
+    pub async fn send_counted<F, R>(&self, request: F) -> Result<(Response, usize)>
+    where
+        F: Fn() -> R,
+        R: Future<Output = Result<Response, Error>>,
+    {
+        let mut attempt = 0;
+
+        loop {
+            let result = request().await;
+
+            // determine whether we're able to retry this attempt
+            let delay = match result {
+                Ok(ref response) if !retryable(response) => None,
+                _ if attempt >= self.retries => None,
+                Ok(ref response) => {
+                    Some(retry_after(response).unwrap_or_else(|| self.delay(attempt)))
+                }
+                Err(_) => Some(self.delay(attempt)),
+            };
+
+            // no delay means we're done
+            let delay = match delay {
+                Some(delay) => delay,
+                None => return Ok((result?, attempt)),
+            };
+
+            // log the retry so it's clear why things are slowing down
+            match result {
+                Ok(response) => warn!(
+                    status = response.status_code().as_u16(),
+                    delay_ms = delay.as_millis() as u64,
+                    "Request failed, retrying"
+                ),
+                Err(err) => {
+                    warn!(error = %err, delay_ms = delay.as_millis() as u64, "Request failed, retrying")
+                }
+            }
+
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Returns the maximum number of retries of this policy.
+    pub fn retries(&self) -> usize {
+        self.retries
+    }
+
+    /// Computes the backoff delay for a given attempt.
+    pub fn delay(&self, attempt: usize) -> Duration {
+        let factor = 2u32.saturating_pow(attempt as u32);
+        self.backoff.saturating_mul(factor).min(MAX_BACKOFF)
+    }
+}
+
+/// Determines whether a response is a transient failure.
+fn retryable(response: &Response) -> bool {
+    matches!(response.status_code().as_u16(), 429 | 502 | 503 | 504)
+}
+
+/// Parses the `Retry-After` header of a response (in seconds), if any.
+fn retry_after(response: &Response) -> Option<Duration> {
+    let header = response.headers().get("retry-after")?;
+    let seconds = header.to_str().ok()?.trim().parse::<u64>().ok()?;
+    Some(Duration::from_secs(seconds))
+}
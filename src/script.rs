@@ -0,0 +1,196 @@
+//! Rhai scripts used to customize documents during import.
+//!
+//! Scripts allow for logic which can't be expressed via the CLI (such as
+//! conditional routing) without having to preprocess input externally. Each
+//! script is run once per document, with the following API available:
+//!
+//! * `doc`, a map containing the source of the document.
+//! * `meta`, a map containing the metadata of the document (`_id`, etc).
+//! * `drop()`, to skip importing the document.
+//! * `route_to(index)`, to import the document into another index.
+//!
+//! Changes made to `doc` and `meta` are carried through to the import. The
+//! `print` and `debug` functions write to the Limber log, rather than to the
+//! standard output.
+//!
+//! Numbers are provided as Rhai integers and floats wherever they can be
+//! represented exactly. Any others (such as those beyond 64 bits) are left as
+//! opaque `number` values, which can be moved around or converted to strings
+//! (but not used in arithmetic) without losing any precision.
+use anyhow::{anyhow, Result};
+use rhai::packages::{Package, StandardPackage};
+use rhai::serde::from_dynamic;
+use rhai::{Dynamic, Engine, Scope, AST};
+use serde_json::{Map, Number, Value};
+use tracing::{debug, info};
+
+use std::fmt::{self, Debug, Formatter};
+use std::sync::{Arc, Mutex};
+
+/// Outcome of running a script against a document.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// Import the document, optionally into another index.
+    Import(Option<String>),
+    /// Skip importing the document.
+    Drop,
+}
+
+/// Script run against each document during import.
+pub struct Script {
+    path: String,
+    ast: AST,
+    package: StandardPackage,
+}
+
+impl Script {
+    /// Loads a script from disk, reporting any syntax errors within it.
+    pub fn load(path: &str) -> Result<Self> {
+        // read and compile the script
+        let source = std::fs::read_to_string(path)
+            .map_err(|err| anyhow!("Unable to read script {}: {}", path, err))?;
+        let ast = Engine::new_raw()
+            .compile(source)
+            .map_err(|err| anyhow!("Invalid script {}: {}", path, err))?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            ast,
+            package: StandardPackage::new(),
+        })
+    }
+
+    /// Runs this script against a document.
+    ///
+    /// The document is expected to be in the format generated by the `export`
+    /// command, and is modified in place to reflect any changes made by the
+    /// script. Any failure within the script is returned as an error.
+    pub fn run(&self, document: &mut Value) -> Result<Outcome> {
+        let failed = |err: &dyn fmt::Display| anyhow!("Script {} failed: {}", self.path, err);
+
+        // split the document into its source and metadata
+        let mut meta = match document.take() {
+            Value::Object(map) => map,
+            _ => return Err(anyhow!("Document is not a JSON object")),
+        };
+        let doc = meta
+            .remove("_source")
+            .unwrap_or_else(|| Value::Object(Map::new()));
+
+        // engines are cheap to create, so each run gets its own outcome
+        let outcome = Arc::new(Mutex::new(Outcome::Import(None)));
+        let engine = self.engine(&outcome);
+
+        // run the script with the document in scope
+        let mut scope = Scope::new();
+        scope.push("doc", to_dynamic(doc));
+        scope.push("meta", to_dynamic(Value::Object(meta)));
+        engine
+            .run_ast_with_scope(&mut scope, &self.ast)
+            .map_err(|err| failed(&err))?;
+
+        // reassemble the document from the scope
+        let mut meta = match extract(&scope, "meta").map_err(|err| failed(&err))? {
+            Value::Object(meta) => meta,
+            _ => return Err(failed(&"meta is no longer a map")),
+        };
+        let doc = extract(&scope, "doc").map_err(|err| failed(&err))?;
+        meta.insert("_source".to_owned(), doc);
+        *document = Value::Object(meta);
+
+        let outcome = outcome.lock().expect("poisoned lock");
+        Ok(outcome.clone())
+    }
+
+    /// Constructs an engine used to run this script, tracking the outcome.
+    fn engine(&self, outcome: &Arc<Mutex<Outcome>>) -> Engine {
+        let mut engine = Engine::new_raw();
+        engine.register_global_module(self.package.as_shared_module());
+
+        // numbers which can't be represented exactly are passed through as is
+        engine
+            .register_type_with_name::<Number>("number")
+            .register_fn("to_string", |number: &mut Number| number.to_string())
+            .register_fn("to_debug", |number: &mut Number| number.to_string());
+
+        // route output from the script to the log
+        engine.on_print(|text| info!("{}", text));
+        engine.on_debug(|text, _, _| debug!("{}", text));
+
+        // register the functions which control the outcome
+        let dropped = outcome.clone();
+        engine.register_fn("drop", move || {
+            *dropped.lock().expect("poisoned lock") = Outcome::Drop;
+        });
+        let routed = outcome.clone();
+        engine.register_fn("route_to", move |index: &str| {
+            let mut outcome = routed.lock().expect("poisoned lock");
+            if *outcome != Outcome::Drop {
+                *outcome = Outcome::Import(Some(index.to_owned()));
+            }
+        });
+
+        engine
+    }
+}
+
+impl Debug for Script {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Script").field("path", &self.path).finish()
+    }
+}
+
+/// Extracts a variable from a scope, converting it back into JSON.
+fn extract(scope: &Scope, name: &str) -> Result<Value> {
+    let value = scope
+        .get(name)
+        .ok_or_else(|| anyhow!("{} is no longer defined", name))?;
+    from_json(value).map_err(|err| anyhow!("{} is not valid JSON: {}", name, err))
+}
+
+/// Converts a JSON value into a Rhai value, without losing any precision.
+fn to_dynamic(value: Value) -> Dynamic {
+    match value {
+        Value::Null => Dynamic::UNIT,
+        Value::Bool(boolean) => Dynamic::from_bool(boolean),
+        Value::Number(number) => {
+            // only convert numbers which survive the trip to and from Rhai
+            if let Some(int) = number.as_i64() {
+                return Dynamic::from_int(int);
+            }
+            match number.as_f64().and_then(Number::from_f64) {
+                Some(float) if float == number => Dynamic::from_float(number.as_f64().unwrap()),
+                _ => Dynamic::from(number),
+            }
+        }
+        Value::String(string) => Dynamic::from(string),
+        Value::Array(values) => Dynamic::from_array(values.into_iter().map(to_dynamic).collect()),
+        Value::Object(map) => Dynamic::from_map(
+            map.into_iter()
+                .map(|(key, value)| (key.into(), to_dynamic(value)))
+                .collect(),
+        ),
+    }
+}
+
+/// Converts a Rhai value back into a JSON value, restoring any opaque numbers.
+fn from_json(value: &Dynamic) -> Result<Value, Box<rhai::EvalAltResult>> {
+    if let Some(number) = value.read_lock::<Number>() {
+        return Ok(Value::Number(number.clone()));
+    }
+    if let Some(array) = value.read_lock::<rhai::Array>() {
+        return array
+            .iter()
+            .map(from_json)
+            .collect::<Result<_, _>>()
+            .map(Value::Array);
+    }
+    if let Some(map) = value.read_lock::<rhai::Map>() {
+        return map
+            .iter()
+            .map(|(key, value)| Ok((key.to_string(), from_json(value)?)))
+            .collect::<Result<Map<_, _>, _>>()
+            .map(Value::Object);
+    }
+    from_dynamic(value)
+}
@@ -0,0 +1,58 @@
+//! Shutdown signalling for imports and exports.
+//!
+//! A `Shutdown` can be shared with a running process to request that it
+//! stops early. Rather than being aborted mid-request, the process stops
+//! taking on new work and finishes anything in flight, before cleaning up
+//! and returning an error of kind `ErrorKind::Interrupted`.
+use tokio::sync::Notify;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Signal used to request an orderly shutdown of a process.
+///
+/// Clones all refer to the same signal, so a clone can be triggered from
+/// elsewhere (such as a signal handler) while the process runs.
+#[derive(Clone, Debug, Default)]
+pub struct Shutdown {
+    inner: Arc<Inner>,
+}
+
+/// Internal state shared between clones of a signal.
+#[derive(Debug, Default)]
+struct Inner {
+    triggered: AtomicBool,
+    notify: Notify,
+}
+
+impl Shutdown {
+    /// Constructs a new signal, which has not yet been triggered.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Triggers the signal, requesting a shutdown.
+    pub fn trigger(&self) {
+        self.inner.triggered.store(true, Ordering::Relaxed);
+        self.inner.notify.notify_waiters();
+    }
+
+    /// Checks whether the signal has been triggered.
+    pub fn is_triggered(&self) -> bool {
+        self.inner.triggered.load(Ordering::Relaxed)
+    }
+
+    /// Waits for the signal to be triggered.
+    pub async fn triggered(&self) {
+        let notified = self.inner.notify.notified();
+        tokio::pin!(notified);
+
+        // register interest before checking, to avoid missing a trigger
+        notified.as_mut().enable();
+        if self.is_triggered() {
+            return;
+        }
+
+        notified.await
+    }
+}
@@ -0,0 +1,53 @@
+//! Signal handling for the Limber CLI.
+//!
+//! The first `SIGINT` (or `SIGTERM`) received requests an orderly shutdown
+//! of the running import or export, allowing in-flight requests to finish.
+//! A second signal exits immediately, for when waiting on those requests
+//! takes longer than anyone is willing to wait.
+use limber::Shutdown;
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+use std::io;
+
+/// Exit code used when exiting immediately on a second signal.
+const EXIT_INTERRUPTED: i32 = 130;
+
+/// Listens for signals in the background, triggering a shutdown.
+///
+/// The returned task should be aborted once the process completes, so that
+/// signals received afterwards are handled as usual.
+pub fn listen(shutdown: Shutdown) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        // the first signal shuts down gracefully
+        if let Err(err) = received().await {
+            warn!(error = %err, "Unable to listen for signals");
+            return;
+        }
+        warn!("Shutting down, waiting for in-flight requests (interrupt again to exit now)");
+        shutdown.trigger();
+
+        // the second signal exits immediately
+        if received().await.is_ok() {
+            std::process::exit(EXIT_INTERRUPTED);
+        }
+    })
+}
+
+/// Waits for either `SIGINT` or `SIGTERM` to be received.
+#[cfg(unix)]
+async fn received() -> io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+/// Waits for `Ctrl-C` to be received, as other signals are Unix only.
+#[cfg(not(unix))]
+async fn received() -> io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
@@ -0,0 +1,264 @@
+//! Partitioning of a dump into several separate files.
+//!
+//! Dumps taken of every index at once (such as via `_all`) are awkward to
+//! restore selectively, so documents can be partitioned by their index, by
+//! the value of a field, or simply into parts of a maximum size. Each
+//! partition is named so that it can be safely used as a file name, without
+//! two different partitions ever sharing a name.
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use serde_json::value::RawValue;
+use serde_json::Value;
+use tracing::warn;
+
+use std::collections::{HashMap, HashSet};
+
+use crate::document;
+use crate::units;
+
+/// The name of the partition used for documents missing a field.
+const MISSING: &str = "_missing";
+
+/// Strategies available to partition documents by.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Split {
+    /// Partition by the index of each document.
+    Index,
+    /// Partition into parts of (at most) a number of bytes.
+    Size(u64),
+    /// Partition by the value of a field of each document.
+    Field(String),
+}
+
+/// Location of a document, alongside its source when needed.
+#[derive(Deserialize)]
+struct Document<'a> {
+    #[serde(rename = "_index")]
+    index: Option<String>,
+    #[serde(rename = "_source", borrow)]
+    source: Option<&'a RawValue>,
+}
+
+impl Split {
+    /// Parses a strategy in the form `index`, `size:<bytes>` or `field:<name>`.
+    ///
+    /// Sizes accept the same units as elsewhere (such as `512mb`), and fields
+    /// can be provided using dot notation to locate nested fields.
+    pub fn parse(input: &str) -> Result<Self> {
+        match input.split_once(':') {
+            None if input == "index" => Ok(Split::Index),
+            Some(("size", size)) => match units::parse_bytes(size)? {
+                0 => Err(anyhow!("Split size must be greater than zero")),
+                size => Ok(Split::Size(size as u64)),
+            },
+            Some(("field", field)) if !field.is_empty() => Ok(Split::Field(field.to_owned())),
+            _ => Err(anyhow!(
+                "Invalid split {}, expected index, size:<bytes> or field:<name>",
+                input
+            )),
+        }
+    }
+}
+
+/// Assigns each document of a dump to a partition, based on a `Split`.
+pub struct Splitter {
+    split: Split,
+    part: usize,
+    written: u64,
+    names: HashMap<String, String>,
+    taken: HashSet<String>,
+}
+
+impl Splitter {
+    /// Constructs a new `Splitter` from a partitioning strategy.
+    pub fn new(split: Split) -> Self {
+        Self {
+            split,
+            part: 0,
+            written: 0,
+            names: HashMap::new(),
+            taken: HashSet::new(),
+        }
+    }
+
+    /// Returns the name of the partition a document belongs to.
+    ///
+    /// Documents are provided in the export format, with their length used
+    /// when splitting by size. Names only contain characters which are safe
+    /// to use within a file name, and are unique (ignoring case) to each
+    /// partition even when their values are sanitized to the same name.
+    pub fn partition(&mut self, document: &[u8]) -> Result<String> {
+        // sizes only need the length, so don't need to parse anything
+        if let Split::Size(size) = self.split {
+            let length = document.len() as u64 + 1;
+            if self.written > 0 && self.written + length > size {
+                self.part += 1;
+                self.written = 0;
+            }
+            self.written += length;
+            return Ok(format!("part-{:05}", self.part));
+        }
+
+        // everything else partitions by the contents of the document
+        let parsed = serde_json::from_slice::<Document>(document)?;
+        let name = match self.split {
+            Split::Field(ref field) => {
+                let source = match parsed.source {
+                    Some(source) => serde_json::from_str(source.get())?,
+                    None => Value::Null,
+                };
+                match document::lookup(&source, field) {
+                    None | Some(Value::Null) => MISSING.to_owned(),
+                    Some(Value::String(value)) => value.clone(),
+                    Some(value @ (Value::Number(_) | Value::Bool(_))) => value.to_string(),
+                    Some(_) => {
+                        return Err(anyhow!(
+                            "Unable to split by field {}, as it's an object or array",
+                            field
+                        ))
+                    }
+                }
+            }
+            _ => parsed.index.unwrap_or_else(|| MISSING.to_owned()),
+        };
+
+        Ok(self.name(name))
+    }
+
+    /// Returns whether partitions are written one after another.
+    ///
+    /// When true, a partition is complete as soon as another is returned.
+    pub fn is_sequential(&self) -> bool {
+        matches!(self.split, Split::Size(_))
+    }
+
+    /// Assigns a unique, sanitized name to the value of a partition.
+    ///
+    /// Values which sanitize to a name already taken by another value (such
+    /// as `a/b` and `a:b`) are given a numeric suffix, in order of appearance.
+    fn name(&mut self, value: String) -> String {
+        if let Some(name) = self.names.get(&value) {
+            return name.clone();
+        }
+
+        // file systems may ignore case, so names are compared without it
+        let base = sanitize(&value);
+        let mut name = base.clone();
+        let mut suffix = 0;
+        while !self.taken.insert(name.to_lowercase()) {
+            suffix += 1;
+            name = format!("{}-{}", base, suffix);
+        }
+        if suffix > 0 {
+            warn!(value = %value, name = %name, "Renamed partition to avoid a collision");
+        }
+
+        self.names.insert(value, name.clone());
+        name
+    }
+}
+
+/// Replaces any characters of a name which aren't safe in a file name.
+///
+/// Empty names (and those made up only of dots) are prefixed, so they
+/// can never refer to the current (or parent) directory.
+fn sanitize(name: &str) -> String {
+    let name = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect::<String>();
+
+    match name.chars().all(|c| c == '.') {
+        true => format!("_{}", name),
+        false => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_parses_strategies() {
+        assert_eq!(Split::parse("index").unwrap(), Split::Index);
+        assert_eq!(Split::parse("size:1kb").unwrap(), Split::Size(1024));
+        assert_eq!(Split::parse("size:100").unwrap(), Split::Size(100));
+        assert_eq!(
+            Split::parse("field:user.name").unwrap(),
+            Split::Field("user.name".to_owned())
+        );
+    }
+
+    #[test]
+    fn split_rejects_invalid_strategies() {
+        assert_eq!(
+            Split::parse("size:0").unwrap_err().to_string(),
+            "Split size must be greater than zero"
+        );
+        assert!(Split::parse("size:lots").is_err());
+        for input in ["", "indices", "index:foo", "field:", "date:day"] {
+            assert_eq!(
+                Split::parse(input).unwrap_err().to_string(),
+                format!(
+                    "Invalid split {}, expected index, size:<bytes> or field:<name>",
+                    input
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn splitter_sanitizes_names() {
+        let mut splitter = Splitter::new(Split::Field("name".to_owned()));
+        let mut partition = |source: &str| {
+            let document = format!(r#"{{"_index":"idx","_source":{}}}"#, source);
+            splitter.partition(document.as_bytes()).unwrap()
+        };
+
+        assert_eq!(partition(r#"{"name":"logs-2024.01"}"#), "logs-2024.01");
+        assert_eq!(partition(r#"{"name":".."}"#), "_..");
+        assert_eq!(partition(r#"{"name":""}"#), "_");
+        assert_eq!(partition(r#"{"name":5}"#), "5");
+        assert_eq!(partition(r#"{"other":5}"#), "_missing");
+    }
+
+    #[test]
+    fn splitter_disambiguates_collisions() {
+        let mut splitter = Splitter::new(Split::Index);
+        let mut partition = |index: &str| {
+            let document = format!(r#"{{"_index":"{}","_source":{{}}}}"#, index);
+            splitter.partition(document.as_bytes()).unwrap()
+        };
+
+        assert_eq!(partition("a/b"), "a_b");
+        assert_eq!(partition("a:b"), "a_b-1");
+        assert_eq!(partition("a_b-1"), "a_b-1-1");
+        assert_eq!(partition("A/B"), "A_B-2");
+        assert_eq!(partition("a:b"), "a_b-1");
+        assert_eq!(partition("a/b"), "a_b");
+    }
+
+    #[test]
+    fn splitter_fills_parts_by_size() {
+        let mut splitter = Splitter::new(Split::Size(10));
+        let parts = ["1234", "1234", "1", "123456789012", "1"]
+            .iter()
+            .map(|document| splitter.partition(document.as_bytes()).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            parts,
+            [
+                "part-00000",
+                "part-00000",
+                "part-00001",
+                "part-00002",
+                "part-00003"
+            ]
+        );
+        assert!(splitter.is_sequential());
+    }
+}
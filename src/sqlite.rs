@@ -0,0 +1,217 @@
+//! SQLite databases used as a target of exported documents.
+//!
+//! Documents are written into a single table holding the index, identifier
+//! and source (as JSON) of each document, alongside any fields extracted
+//! into columns of their own. This provides a single queryable artifact of
+//! an export, rather than lines of JSON which have to be loaded elsewhere.
+//!
+//! This module is only available with the `sqlite` feature enabled.
+use anyhow::{anyhow, Result};
+use rusqlite::types::Value as Column;
+use rusqlite::{params_from_iter, Connection};
+use serde::Deserialize;
+use serde_json::value::RawValue;
+use serde_json::Value;
+use tokio::io::{self, AsyncBufReadExt, BufReader, DuplexStream};
+use tokio::task::JoinHandle;
+
+use std::sync::Arc;
+
+use crate::document;
+use crate::format::RECORD_SEPARATOR;
+
+/// The size of the buffer between the writer and the database.
+const PIPE_SIZE: usize = 1024 * 1024;
+
+/// The number of documents written within each transaction.
+const BATCH_SIZE: usize = 1_000;
+
+/// The table written to when none is provided.
+const DEFAULT_TABLE: &str = "documents";
+
+/// Sink of documents written to a table of a SQLite database.
+pub struct Sink {
+    connection: Connection,
+    table: Table,
+}
+
+/// Definition of the table documents are written to.
+struct Table {
+    /// The statement used to insert a document.
+    insert: String,
+    /// The fields extracted into columns of their own.
+    fields: Vec<String>,
+}
+
+/// Document as written in the export format.
+#[derive(Deserialize)]
+struct Row<'a> {
+    #[serde(rename = "_index")]
+    index: Option<String>,
+    #[serde(rename = "_id")]
+    id: Option<String>,
+    #[serde(rename = "_source", borrow)]
+    source: &'a RawValue,
+}
+
+impl Sink {
+    /// Opens a database via a URL in the form `sqlite://path`.
+    ///
+    /// The table is set via the `table` parameter (defaulting to `documents`)
+    /// and is created if it doesn't already exist. Fields to extract into
+    /// columns are set via `columns` as a comma separated list, using dot
+    /// notation for nested fields.
+    pub fn open(url: &str) -> Result<Self> {
+        // split the path from the parameters
+        let rest = url
+            .strip_prefix("sqlite://")
+            .ok_or_else(|| anyhow!("Invalid SQLite URL: {}", url))?;
+        let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+        if path.is_empty() {
+            return Err(anyhow!("SQLite URL must contain a path: {}", url));
+        }
+
+        // parse the table and the fields to extract
+        let mut table = DEFAULT_TABLE;
+        let mut fields = Vec::new();
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            match pair.split_once('=') {
+                Some(("table", name)) if !name.is_empty() => table = name,
+                Some(("columns", columns)) => fields.extend(
+                    columns
+                        .split(',')
+                        .filter(|field| !field.is_empty())
+                        .map(str::to_owned),
+                ),
+                _ => return Err(anyhow!("Invalid SQLite parameter: {}", pair)),
+            }
+        }
+
+        // quote every column, as fields can contain dots (or anything else)
+        let columns = fields.iter().map(|field| quote(field)).collect::<Vec<_>>();
+        let definitions = columns
+            .iter()
+            .map(|column| format!(", {}", column))
+            .collect::<String>();
+
+        // create the table, keyed on the location of each document
+        let connection = Connection::open(path)?;
+        connection.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS {} (\"index\" TEXT, id TEXT, source TEXT NOT NULL{}, PRIMARY KEY (\"index\", id))",
+            quote(table),
+            definitions
+        ))?;
+
+        // documents exported again replace the previous version
+        let insert = format!(
+            "INSERT OR REPLACE INTO {} (\"index\", id, source{}) VALUES (?, ?, ?{})",
+            quote(table),
+            definitions,
+            ", ?".repeat(columns.len())
+        );
+
+        Ok(Self {
+            connection,
+            table: Table { insert, fields },
+        })
+    }
+
+    /// Spawns a task writing every document written to the returned writer.
+    ///
+    /// The task completes once the returned writer has been dropped and every
+    /// document written to it has been committed to the database.
+    pub fn spawn(self) -> (DuplexStream, JoinHandle<Result<()>>) {
+        let (input, output) = io::duplex(PIPE_SIZE);
+        let task = tokio::spawn(self.write(output));
+        (input, task)
+    }
+
+    /// Writes each line of input as a row, in batches of transactions.
+    async fn write(self, reader: DuplexStream) -> Result<()> {
+        let mut lines = BufReader::new(reader).split(b'\n');
+        let mut connection = self.connection;
+        let table = Arc::new(self.table);
+        let mut batch = Vec::with_capacity(BATCH_SIZE);
+
+        loop {
+            // records of a sequence are also written with a trailing line break
+            let line = lines.next_segment().await?;
+            if let Some(line) = line.as_ref() {
+                let document = line.strip_prefix(&[RECORD_SEPARATOR]).unwrap_or(line);
+                if !document.is_empty() {
+                    batch.push(document.to_vec());
+                }
+            }
+
+            // hand each full batch (and the last) off to a blocking thread
+            if batch.len() >= BATCH_SIZE || (line.is_none() && !batch.is_empty()) {
+                let rows = std::mem::replace(&mut batch, Vec::with_capacity(BATCH_SIZE));
+                let table = table.clone();
+                connection = tokio::task::spawn_blocking(move || {
+                    insert(&mut connection, &table, rows).map(|()| connection)
+                })
+                .await??;
+            }
+
+            // the end of the input has been reached
+            if line.is_none() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Inserts a batch of documents into a table, within a single transaction.
+fn insert(connection: &mut Connection, table: &Table, rows: Vec<Vec<u8>>) -> Result<()> {
+    let transaction = connection.transaction()?;
+    {
+        let mut statement = transaction.prepare_cached(&table.insert)?;
+        for row in rows {
+            let row = serde_json::from_slice::<Row>(&row)?;
+
+            // extract every field, leaving missing fields empty
+            let source = match table.fields.is_empty() {
+                true => Value::Null,
+                false => serde_json::from_str(row.source.get())?,
+            };
+            let columns = table
+                .fields
+                .iter()
+                .map(|field| column(document::lookup(&source, field)));
+
+            // bind the document location and source, followed by each field
+            let mut values = vec![
+                row.index.map_or(Column::Null, Column::Text),
+                row.id.map_or(Column::Null, Column::Text),
+                Column::Text(row.source.get().to_owned()),
+            ];
+            values.extend(columns);
+            statement.execute(params_from_iter(values))?;
+        }
+    }
+    transaction.commit()?;
+    Ok(())
+}
+
+/// Converts a field into a column value, keeping numbers and strings native.
+///
+/// Objects and arrays are written as JSON, to be queried via the JSON
+/// functions of SQLite.
+fn column(value: Option<&Value>) -> Column {
+    match value {
+        None | Some(Value::Null) => Column::Null,
+        Some(Value::Bool(value)) => Column::Integer(i64::from(*value)),
+        Some(Value::String(value)) => Column::Text(value.clone()),
+        Some(Value::Number(number)) => match (number.as_i64(), number.as_f64()) {
+            (Some(number), _) => Column::Integer(number),
+            (None, Some(number)) => Column::Real(number),
+            (None, None) => Column::Text(number.to_string()),
+        },
+        Some(value) => Column::Text(value.to_string()),
+    }
+}
+
+/// Quotes an identifier, so it can be used as a table or column name.
+fn quote(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
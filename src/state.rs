@@ -0,0 +1,249 @@
+//! Persistent state kept between the runs of a named job.
+//!
+//! Jobs which are run repeatedly (such as a nightly import) tend to need
+//! somewhere to keep their checkpoint and any failed documents, alongside
+//! a record of how each run went. Rather than scattering these files next
+//! to each input, each named job owns a directory within a state directory
+//! (`~/.local/state/limber` by default), laid out as:
+//!
+//! ```text
+//! <job>/checkpoint           the progress of the last import
+//! <job>/dead-letter.jsonl    documents which failed to import
+//! <job>/runs/<run>.json      a record of each run of the job
+//! ```
+use anyhow::{anyhow, Result};
+use chrono::{SecondsFormat, Utc};
+use serde_json::{json, Value};
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// Locates the default state directory, if possible.
+///
+/// This follows the XDG base directory specification, using the directory
+/// in `XDG_STATE_HOME` when set, and `~/.local/state` otherwise.
+pub fn default_dir() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_STATE_HOME") {
+        Some(base) if !base.is_empty() => PathBuf::from(base),
+        _ => PathBuf::from(std::env::var_os("HOME")?)
+            .join(".local")
+            .join("state"),
+    };
+    Some(base.join("limber"))
+}
+
+/// A named job, owning a directory within the state directory.
+#[derive(Clone, Debug)]
+pub struct Job {
+    name: String,
+    path: PathBuf,
+}
+
+impl Job {
+    /// Opens a job within a state directory, creating it if necessary.
+    ///
+    /// Job names are used as directory names, so are restricted to ASCII
+    /// letters, digits, `-`, `_` and `.` (without starting with a `.`).
+    pub fn open(dir: &Path, name: &str) -> Result<Self> {
+        let valid = !name.is_empty()
+            && !name.starts_with('.')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+
+        if !valid {
+            return Err(anyhow!("Invalid job name provided: {}", name));
+        }
+
+        let job = Self {
+            name: name.to_owned(),
+            path: dir.join(name),
+        };
+
+        fs::create_dir_all(job.path.join("runs"))?;
+        Ok(job)
+    }
+
+    /// Locates an existing job within a state directory.
+    pub fn find(dir: &Path, name: &str) -> Result<Self> {
+        match Self::list(dir)?.into_iter().find(|job| job.name == name) {
+            Some(job) => Ok(job),
+            None => Err(anyhow!("Unknown job: {}", name)),
+        }
+    }
+
+    /// Lists all jobs within a state directory, ordered by name.
+    ///
+    /// A missing state directory simply means there are no jobs yet.
+    pub fn list(dir: &Path) -> Result<Vec<Self>> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut jobs = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            jobs.push(Self {
+                name: entry.file_name().to_string_lossy().into_owned(),
+                path: entry.path(),
+            });
+        }
+
+        jobs.sort_by(|left, right| left.name.cmp(&right.name));
+        Ok(jobs)
+    }
+
+    /// Returns the name of this job.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the directory of this job.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the path of the checkpoint of this job.
+    pub fn checkpoint(&self) -> PathBuf {
+        self.path.join("checkpoint")
+    }
+
+    /// Returns the path of the dead letter file of this job.
+    pub fn dead_letter(&self) -> PathBuf {
+        self.path.join("dead-letter.jsonl")
+    }
+
+    /// Records the start of a new run of this job.
+    ///
+    /// Runs are named after the time they started, so they sort in order.
+    pub fn create_run(&self, command: &str) -> Result<Run> {
+        let started = Utc::now();
+        let id = started.format("%Y%m%dT%H%M%S%.3fZ").to_string();
+
+        let run = Run {
+            path: self.path.join("runs").join(format!("{}.json", id)),
+            record: json!({
+                "id": id,
+                "command": command,
+                "started": started.to_rfc3339_opts(SecondsFormat::Secs, true),
+            }),
+        };
+
+        run.write()?;
+        Ok(run)
+    }
+
+    /// Lists all runs of this job, from oldest to newest.
+    ///
+    /// Records which can't be read (such as one being written to by an
+    /// active run) are skipped, rather than failing the entire listing.
+    pub fn runs(&self) -> Result<Vec<Run>> {
+        let entries = match fs::read_dir(self.path.join("runs")) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut runs = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let record = match fs::read(&path).map(|contents| serde_json::from_slice(&contents)) {
+                Ok(Ok(record)) => record,
+                _ => continue,
+            };
+            runs.push(Run { path, record });
+        }
+
+        runs.sort_by(|left, right| left.id().cmp(right.id()));
+        Ok(runs)
+    }
+
+    /// Removes all but the most recent runs of this job.
+    ///
+    /// Returns the number of runs removed.
+    pub fn prune(&self, keep: usize) -> Result<usize> {
+        let runs = self.runs()?;
+        let count = runs.len().saturating_sub(keep);
+
+        for run in &runs[..count] {
+            fs::remove_file(&run.path)?;
+        }
+
+        Ok(count)
+    }
+
+    /// Removes this job entirely, including its checkpoint and runs.
+    pub fn remove(self) -> Result<()> {
+        fs::remove_dir_all(&self.path)?;
+        Ok(())
+    }
+}
+
+/// A single run of a job, recorded as JSON.
+#[derive(Clone, Debug)]
+pub struct Run {
+    path: PathBuf,
+    record: Value,
+}
+
+impl Run {
+    /// Returns the identifier of this run.
+    pub fn id(&self) -> &str {
+        self.field("id").unwrap_or_default()
+    }
+
+    /// Returns the time this run started, in RFC 3339 format.
+    pub fn started(&self) -> Option<&str> {
+        self.field("started")
+    }
+
+    /// Returns the time this run finished (in RFC 3339 format), if it did.
+    pub fn finished(&self) -> Option<&str> {
+        self.field("finished")
+    }
+
+    /// Returns the status of this run.
+    ///
+    /// Runs without a status never finished, either because they're still
+    /// running or because the process exited abruptly.
+    pub fn status(&self) -> &str {
+        self.record
+            .pointer("/statistics/status")
+            .and_then(Value::as_str)
+            .unwrap_or("unfinished")
+    }
+
+    /// Returns the full record of this run.
+    pub fn record(&self) -> &Value {
+        &self.record
+    }
+
+    /// Records the end of this run, alongside its statistics.
+    pub fn finish(&mut self, statistics: Value) -> Result<()> {
+        self.record["finished"] = json!(Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true));
+        self.record["statistics"] = statistics;
+        self.write()
+    }
+
+    /// Fetches a string field from the record of this run.
+    fn field(&self, name: &str) -> Option<&str> {
+        self.record.get(name).and_then(Value::as_str)
+    }
+
+    /// Writes the record of this run to disk, via a temporary file.
+    fn write(&self) -> Result<()> {
+        let temporary = self.path.with_extension("tmp");
+        fs::write(&temporary, serde_json::to_vec_pretty(&self.record)?)?;
+        fs::rename(&temporary, &self.path)?;
+        Ok(())
+    }
+}
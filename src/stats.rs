@@ -1,12 +1,25 @@
 //! Statistic structures used to track metrics at runtime.
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::units;
+
+/// Upper bounds (in seconds) of the buckets used to measure request latency.
+pub const LATENCY_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// The maximum number of errors retained as a sample by a `Tracker`.
+pub const MAX_ERRORS: usize = 10;
 
 /// Simple atomic counter structure (based on `usize`).
 ///
 /// The only difference between this and `AtomicUsize` is that the
 /// former returns the new value after an increment call. This value
 /// is not guaranteed, but should be considered eventually consistent.
+#[derive(Debug, Default)]
 pub struct Counter {
     inner: AtomicUsize,
 }
@@ -33,4 +46,528 @@ impl Counter {
     pub fn increment(&self, amount: usize) -> usize {
         self.inner.fetch_add(amount, Ordering::Relaxed) + amount
     }
+
+    /// Retrieves the current value of this counter.
+    ///
+    /// Just like `increment`, this value is only eventually consistent.
+    #[inline]
+    pub fn get(&self) -> usize {
+        self.inner.load(Ordering::Relaxed)
+    }
+}
+
+/// Progress tracker through an input of a known size (in bytes).
+///
+/// Progress is measured from the starting position of the input, which
+/// allows for an accurate rate (and estimated completion time) when an
+/// input is resumed from an offset.
+pub struct Progress {
+    start: u64,
+    total: u64,
+    position: AtomicU64,
+    started: Instant,
+}
+
+impl Progress {
+    /// Constructs a new tracker for an input size, from a starting position.
+    pub fn new(start: u64, total: u64) -> Self {
+        Self {
+            start,
+            total,
+            position: AtomicU64::new(start),
+            started: Instant::now(),
+        }
+    }
+
+    /// Advances the position through the input.
+    ///
+    /// As positions may be reported out of order, the position will only
+    /// ever move forward, never backward.
+    #[inline]
+    pub fn advance(&self, position: u64) {
+        self.position.fetch_max(position, Ordering::Relaxed);
+    }
+
+    /// Estimates the time remaining based on the rate of progress so far.
+    pub fn remaining(&self) -> Option<Duration> {
+        let position = self.position.load(Ordering::Relaxed);
+        let done = position.saturating_sub(self.start);
+
+        // no progress means no rate to estimate with
+        if done == 0 {
+            return None;
+        }
+
+        let left = self.total.saturating_sub(position) as f64;
+        let rate = done as f64 / self.started.elapsed().as_secs_f64();
+
+        Duration::try_from_secs_f64(left / rate).ok()
+    }
+
+    /// Describes the current progress in a human readable format.
+    pub fn describe(&self) -> String {
+        let position = self.position.load(Ordering::Relaxed).min(self.total);
+        let percent = match self.total {
+            0 => 100.0,
+            total => position as f64 / total as f64 * 100.0,
+        };
+
+        // format the position within the input
+        let mut description = format!(
+            "{} of {}, {:.1}%",
+            units::format_bytes(position),
+            units::format_bytes(self.total),
+            percent
+        );
+
+        // attach the estimate when available
+        if let Some(remaining) = self.remaining() {
+            description.push_str(", ETA ");
+            description.push_str(&units::format_duration(remaining));
+        }
+
+        description
+    }
+}
+
+/// Tracker of the state of a running import or export.
+///
+/// A tracker can be shared with a running import or export to observe it
+/// from elsewhere, such as to render a progress bar. The tracker is filled
+/// in as the process runs; every value is only eventually consistent.
+#[derive(Debug, Default)]
+pub struct Tracker {
+    documents: Counter,
+    failed: Counter,
+    skipped: Counter,
+    bytes: AtomicU64,
+    indices: Mutex<BTreeMap<String, usize>>,
+    workers: Mutex<BTreeMap<usize, Worker>>,
+    errors: Mutex<Vec<String>>,
+    in_flight: AtomicUsize,
+    latency: Histogram,
+    took: Histogram,
+    total_documents: OnceLock<u64>,
+    total_bytes: OnceLock<u64>,
+    started: OnceLock<Instant>,
+    window: Mutex<Option<(Instant, usize)>>,
+    peak: AtomicU64,
+}
+
+impl Tracker {
+    /// Constructs a new tracker, to be shared with a process.
+    pub fn shared() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Retrieves the number of documents processed so far.
+    ///
+    /// This includes any documents which failed to be processed.
+    pub fn documents(&self) -> usize {
+        self.documents.get()
+    }
+
+    /// Retrieves the number of documents which failed to be processed.
+    pub fn failed(&self) -> usize {
+        self.failed.get()
+    }
+
+    /// Retrieves the number of documents which were skipped.
+    ///
+    /// Documents are skipped when excluded by filters, or when dropped as
+    /// duplicates; they are not included in the processed documents.
+    pub fn skipped(&self) -> usize {
+        self.skipped.get()
+    }
+
+    /// Retrieves the number of documents handled per index.
+    ///
+    /// Only documents handled successfully are counted against an index.
+    pub fn indices(&self) -> BTreeMap<String, usize> {
+        self.indices.lock().expect("poisoned lock").clone()
+    }
+
+    /// Retrieves the statistics of each worker, by identifier.
+    ///
+    /// Workers are the concurrent units of a process; the slices of an
+    /// export, or the concurrent batches of an import.
+    pub fn workers(&self) -> BTreeMap<usize, Worker> {
+        self.workers.lock().expect("poisoned lock").clone()
+    }
+
+    /// Retrieves a sample of the errors which caused documents to fail.
+    ///
+    /// Only the first `MAX_ERRORS` errors are retained, to avoid holding
+    /// on to every error when a large number of documents fail.
+    pub fn errors(&self) -> Vec<String> {
+        self.errors.lock().expect("poisoned lock").clone()
+    }
+
+    /// Retrieves the number of requests currently in flight.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Retrieves the histogram of request latency.
+    ///
+    /// Requests are the bulk requests of an import, or the search requests
+    /// of an export, including any retries of the request.
+    pub fn latency(&self) -> &Histogram {
+        &self.latency
+    }
+
+    /// Retrieves the histogram of time taken by the cluster.
+    ///
+    /// This is the `took` reported by the cluster for each request, which
+    /// excludes time spent on the network (and any retries). Comparing this
+    /// against `latency` shows whether the cluster or the transfer is slower.
+    pub fn took(&self) -> &Histogram {
+        &self.took
+    }
+
+    /// Retrieves the number of bytes processed so far.
+    ///
+    /// For an export this is the number of bytes written, and for an
+    /// import this is the position reached within the input.
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Retrieves the total number of documents to process, if known.
+    pub fn total_documents(&self) -> Option<u64> {
+        self.total_documents.get().copied()
+    }
+
+    /// Retrieves the total number of bytes to process, if known.
+    pub fn total_bytes(&self) -> Option<u64> {
+        self.total_bytes.get().copied()
+    }
+
+    /// Retrieves the time elapsed since the process started.
+    pub fn elapsed(&self) -> Duration {
+        self.started.get().map(Instant::elapsed).unwrap_or_default()
+    }
+
+    /// Calculates the average number of documents processed per second.
+    pub fn rate(&self) -> f64 {
+        match self.elapsed().as_secs_f64() {
+            secs if secs > 0.0 => self.documents() as f64 / secs,
+            _ => 0.0,
+        }
+    }
+
+    /// Retrieves the highest number of documents processed in one second.
+    ///
+    /// The rate is sampled over windows of at least a second, so processes
+    /// which complete sooner will report their average rate instead.
+    pub fn peak_rate(&self) -> f64 {
+        (self.peak.load(Ordering::Relaxed) as f64).max(self.rate())
+    }
+
+    /// Marks the process as started, to measure elapsed time from.
+    pub(crate) fn start(&self) {
+        let now = Instant::now();
+        let _ = self.started.set(now);
+        *self.window.lock().expect("poisoned lock") = Some((now, self.documents()));
+    }
+
+    /// Samples the current rate, to track the peak rate of processing.
+    ///
+    /// This should be called after processing each batch of documents; the
+    /// rate is only sampled once at least a second has passed.
+    pub(crate) fn sample(&self) {
+        let mut window = self.window.lock().expect("poisoned lock");
+        let (started, documents) = match *window {
+            Some(window) => window,
+            None => return,
+        };
+
+        // wait until the window is long enough to be meaningful
+        let elapsed = started.elapsed();
+        if elapsed < Duration::from_secs(1) {
+            return;
+        }
+
+        // store the rate if it's the highest seen, and start a new window
+        let current = self.documents();
+        let rate = current.saturating_sub(documents) as f64 / elapsed.as_secs_f64();
+        self.peak.fetch_max(rate as u64, Ordering::Relaxed);
+        *window = Some((Instant::now(), current));
+    }
+
+    /// Records a number of documents as skipped.
+    pub(crate) fn skip(&self, amount: usize) {
+        self.skipped.increment(amount);
+    }
+
+    /// Records an error which caused a document to fail, if within the sample.
+    pub(crate) fn sample_error(&self, error: String) {
+        let mut errors = self.errors.lock().expect("poisoned lock");
+        if errors.len() < MAX_ERRORS {
+            errors.push(error);
+        }
+    }
+
+    /// Samples every metric of the tracker, as a set of named metrics.
+    ///
+    /// This allows the tracker to be published to any metrics system in a
+    /// consistent way, without each having to know about every metric.
+    pub fn metrics(&self) -> Vec<Metric> {
+        let mut metrics = vec![
+            Metric::counter(
+                "documents_processed",
+                "Documents processed, including those which failed",
+                self.documents() as f64,
+            ),
+            Metric::counter(
+                "documents_failed",
+                "Documents which failed to be processed",
+                self.failed() as f64,
+            ),
+            Metric::counter(
+                "documents_skipped",
+                "Documents skipped by filters or as duplicates",
+                self.skipped() as f64,
+            ),
+            Metric::counter(
+                "bytes_processed",
+                "Bytes written by an export, or read by an import",
+                self.bytes() as f64,
+            ),
+            Metric::gauge(
+                "requests_in_flight",
+                "Requests currently in flight to the cluster",
+                self.in_flight() as f64,
+            ),
+            Metric::gauge(
+                "elapsed_seconds",
+                "Time elapsed since the job started",
+                self.elapsed().as_secs_f64(),
+            ),
+        ];
+
+        // totals are only available when known up front
+        if let Some(total) = self.total_documents() {
+            metrics.push(Metric::gauge(
+                "documents_expected",
+                "Documents expected to be processed",
+                total as f64,
+            ));
+        }
+
+        // documents are broken down by index
+        for (index, count) in self.indices() {
+            let mut metric = Metric::counter(
+                "index_documents",
+                "Documents handled per index",
+                count as f64,
+            );
+            metric.labels.push(("index", index));
+            metrics.push(metric);
+        }
+
+        // request latency is measured as a histogram
+        metrics.push(Metric {
+            name: "request_duration_seconds",
+            help: "Latency of requests sent to the cluster",
+            labels: Vec::new(),
+            value: Measure::Histogram {
+                buckets: self.latency.buckets(),
+                count: self.latency.count(),
+                sum: self.latency.sum().as_secs_f64(),
+            },
+        });
+
+        // as is the time taken by the cluster to handle requests
+        metrics.push(Metric {
+            name: "request_took_seconds",
+            help: "Time taken by the cluster to handle requests",
+            labels: Vec::new(),
+            value: Measure::Histogram {
+                buckets: self.took.buckets(),
+                count: self.took.count(),
+                sum: self.took.sum().as_secs_f64(),
+            },
+        });
+
+        metrics
+    }
+
+    /// Marks the start of a request, returning the time it started.
+    pub(crate) fn begin_request(&self) -> Instant {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        Instant::now()
+    }
+
+    /// Marks the end of a request, recording the latency of the request.
+    pub(crate) fn end_request(&self, started: Instant) {
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+        self.latency.observe(started.elapsed());
+    }
+
+    /// Records the time taken by the cluster to handle a request.
+    pub(crate) fn observe_took(&self, took: Duration) {
+        self.took.observe(took);
+    }
+
+    /// Records a batch of documents handled by a worker.
+    pub(crate) fn record(&self, worker: usize, documents: usize, elapsed: Duration) {
+        let mut workers = self.workers.lock().expect("poisoned lock");
+        let worker = workers.entry(worker).or_default();
+        worker.batches += 1;
+        worker.documents += documents;
+        worker.busy += elapsed;
+    }
+
+    /// Records a number of documents as handled against an index.
+    pub(crate) fn tally(&self, index: &str, amount: usize) {
+        let mut indices = self.indices.lock().expect("poisoned lock");
+        match indices.get_mut(index) {
+            Some(count) => *count += amount,
+            None => {
+                indices.insert(index.to_owned(), amount);
+            }
+        }
+    }
+
+    /// Retrieves the counter of processed documents.
+    pub(crate) fn document_counter(&self) -> &Counter {
+        &self.documents
+    }
+
+    /// Retrieves the counter of failed documents.
+    pub(crate) fn failure_counter(&self) -> &Counter {
+        &self.failed
+    }
+
+    /// Adds to the number of bytes processed.
+    pub(crate) fn add_bytes(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Advances the position reached within an input.
+    ///
+    /// Just like `Progress::advance`, this position only moves forward.
+    pub(crate) fn advance(&self, position: u64) {
+        self.bytes.fetch_max(position, Ordering::Relaxed);
+    }
+
+    /// Sets the total number of documents expected to be processed.
+    pub(crate) fn set_total_documents(&self, total: u64) {
+        let _ = self.total_documents.set(total);
+    }
+
+    /// Sets the total number of bytes expected to be processed.
+    pub(crate) fn set_total_bytes(&self, total: u64) {
+        let _ = self.total_bytes.set(total);
+    }
+}
+
+/// Statistics of a single worker within a process.
+#[derive(Clone, Debug, Default)]
+pub struct Worker {
+    /// The number of batches handled by the worker.
+    pub batches: usize,
+    /// The number of documents handled by the worker.
+    pub documents: usize,
+    /// The time spent by the worker handling batches.
+    pub busy: Duration,
+}
+
+/// Histogram of durations, measured against `LATENCY_BUCKETS`.
+///
+/// Buckets are cumulative, in that each observation is counted against
+/// every bucket with an upper bound at least as large as the observation.
+#[derive(Debug, Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    /// Retrieves the cumulative count of each bucket, with the upper bound.
+    pub fn buckets(&self) -> Vec<(f64, u64)> {
+        LATENCY_BUCKETS
+            .iter()
+            .zip(self.buckets.iter())
+            .map(|(bound, count)| (*bound, count.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Retrieves the number of durations observed.
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Retrieves the sum of all durations observed.
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum.load(Ordering::Relaxed))
+    }
+
+    /// Observes a duration, counting it against each matching bucket.
+    pub(crate) fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(self.buckets.iter()) {
+            if seconds <= *bound {
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Named metric sampled from a `Tracker`.
+#[derive(Clone, Debug)]
+pub struct Metric {
+    /// The name of the metric, such as `documents_processed`.
+    pub name: &'static str,
+    /// A short description of the metric.
+    pub help: &'static str,
+    /// Any labels attached to the metric, such as the index.
+    pub labels: Vec<(&'static str, String)>,
+    /// The measured value of the metric.
+    pub value: Measure,
+}
+
+impl Metric {
+    /// Constructs a counter, which only ever increases.
+    fn counter(name: &'static str, help: &'static str, value: f64) -> Self {
+        Self {
+            name,
+            help,
+            labels: Vec::new(),
+            value: Measure::Counter(value),
+        }
+    }
+
+    /// Constructs a gauge, which can move in either direction.
+    fn gauge(name: &'static str, help: &'static str, value: f64) -> Self {
+        Self {
+            name,
+            help,
+            labels: Vec::new(),
+            value: Measure::Gauge(value),
+        }
+    }
+}
+
+/// Measured value of a `Metric`.
+#[derive(Clone, Debug)]
+pub enum Measure {
+    /// A total which only ever increases.
+    Counter(f64),
+    /// A value which can move in either direction.
+    Gauge(f64),
+    /// A distribution of observed values (in seconds).
+    Histogram {
+        /// The cumulative count of each bucket, with the upper bound.
+        buckets: Vec<(f64, u64)>,
+        /// The number of values observed.
+        count: u64,
+        /// The sum of all values observed.
+        sum: f64,
+    },
 }
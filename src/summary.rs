@@ -0,0 +1,257 @@
+//! End of run summaries for the Limber CLI.
+//!
+//! Once an import or export completes, a summary of the work carried out is
+//! reported. Summaries are written as a block of text to be read (and more
+//! than likely copied elsewhere) by people, or as a single event when the
+//! logs are written as JSON. Statistics can also be written to a file, to
+//! be consumed by other tooling, sent to a URL to notify of completion, or
+//! recorded against the run of a named job.
+use anyhow::{anyhow, Result};
+use clap::ArgMatches;
+use indicatif::HumanCount;
+use limber::error::ErrorKind;
+use limber::state::Run;
+use limber::stats::LATENCY_BUCKETS;
+use limber::{units, Tracker};
+use serde_json::{json, Value};
+use tracing::{debug, info, warn, Level};
+
+use std::time::Duration;
+
+/// The maximum time to wait when sending a notification.
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Reports a summary of a completed import or export.
+///
+/// The action describes what happened to each document, such as whether
+/// it was `imported` or `exported`. Nothing is reported when logging above
+/// the `info` level, such as when running with `--quiet`.
+pub fn report(action: &str, tracker: &Tracker, args: &ArgMatches) {
+    // summaries are informational, so respect the log level
+    if !tracing::enabled!(Level::INFO) {
+        return;
+    }
+
+    // failed documents are included in those processed
+    let succeeded = tracker.documents().saturating_sub(tracker.failed());
+
+    // structured logs get the summary as a single event
+    if args.value_of("log-format") == Some("json") {
+        info!(
+            action,
+            documents = succeeded,
+            skipped = tracker.skipped(),
+            failed = tracker.failed(),
+            bytes = tracker.bytes(),
+            elapsed_ms = tracker.elapsed().as_millis() as u64,
+            rate = tracker.rate().round() as u64,
+            peak_rate = tracker.peak_rate().round() as u64,
+            indices = %json!(tracker.indices()),
+            "Summary"
+        );
+        if tracing::enabled!(Level::DEBUG) {
+            let buckets = latency(tracker)
+                .into_iter()
+                .map(|(bucket, took, request)| json!([bucket, took, request]))
+                .collect::<Vec<_>>();
+            debug!(buckets = %json!(buckets), "Latency");
+        }
+        return;
+    }
+
+    // describe the totals of the run
+    let mut summary = format!(
+        "Summary: {} {} documents in {}\n",
+        action,
+        HumanCount(succeeded as u64),
+        units::format_duration(tracker.elapsed())
+    );
+    summary.push_str(&format!(
+        "  skipped: {}\n  failed: {}\n  bytes: {}\n",
+        HumanCount(tracker.skipped() as u64),
+        HumanCount(tracker.failed() as u64),
+        units::format_bytes(tracker.bytes())
+    ));
+    summary.push_str(&format!(
+        "  rate: {}/s average, {}/s peak\n",
+        HumanCount(tracker.rate().round() as u64),
+        HumanCount(tracker.peak_rate().round() as u64)
+    ));
+
+    // break down the documents per index
+    let indices = tracker.indices();
+    if !indices.is_empty() {
+        summary.push_str("  indices:\n");
+        for (index, count) in indices {
+            summary.push_str(&format!("    {}: {}\n", index, HumanCount(count as u64)));
+        }
+    }
+
+    // verbose runs break down where the time of each request went
+    let buckets = latency(tracker);
+    if tracing::enabled!(Level::DEBUG) && !buckets.is_empty() {
+        summary.push_str("  latency (cluster / request):\n");
+        for (bucket, took, request) in buckets {
+            summary.push_str(&format!(
+                "    {}: {} / {}\n",
+                bucket,
+                HumanCount(took),
+                HumanCount(request)
+            ));
+        }
+    }
+
+    eprint!("{}", summary);
+}
+
+/// Breaks down the latency of requests into non-empty buckets.
+///
+/// Each bucket holds the number of requests which the cluster reported as
+/// taking that long, alongside those which took that long to come back. A
+/// gap between the two shows time spent in transfer, rather than in the
+/// cluster itself.
+fn latency(tracker: &Tracker) -> Vec<(String, u64, u64)> {
+    let took = tracker.took();
+    let request = tracker.latency();
+
+    // convert the cumulative buckets into counts within each bucket
+    let mut buckets = Vec::new();
+    let mut previous = (0, 0);
+    for ((bound, took), (_, request)) in took.buckets().into_iter().zip(request.buckets()) {
+        let label = if bound < 1.0 {
+            format!("<= {}ms", (bound * 1000.0).round())
+        } else {
+            format!("<= {}s", bound)
+        };
+        buckets.push((
+            label,
+            took.saturating_sub(previous.0),
+            request.saturating_sub(previous.1),
+        ));
+        previous = (took, request);
+    }
+
+    // anything beyond the last bucket is counted separately
+    if let Some(bound) = LATENCY_BUCKETS.last() {
+        buckets.push((
+            format!("> {}s", bound),
+            took.count().saturating_sub(previous.0),
+            request.count().saturating_sub(previous.1),
+        ));
+    }
+
+    buckets.retain(|(_, took, request)| *took > 0 || *request > 0);
+    buckets
+}
+
+/// Determines whether a run was interrupted before completing.
+///
+/// Interrupted runs still warrant a summary, as it describes the work which
+/// was carried out before the interruption.
+pub fn interrupted(result: &Result<()>) -> bool {
+    result.as_ref().err().and_then(ErrorKind::of) == Some(ErrorKind::Interrupted)
+}
+
+/// Writes the statistics of a run to the file provided by `--stats-file`.
+///
+/// Statistics are written regardless of whether the run succeeded, with the
+/// error of any failed run included, so that failures can be alerted on.
+pub fn write(
+    command: &str,
+    tracker: &Tracker,
+    args: &ArgMatches,
+    result: &Result<()>,
+) -> Result<()> {
+    // only write statistics when requested
+    let path = match args.value_of("stats-file") {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+
+    // write the statistics out in a readable form
+    let contents = serde_json::to_string_pretty(&statistics(command, tracker, result))?;
+    std::fs::write(path, contents + "\n")
+        .map_err(|err| anyhow!("Unable to write stats file {}: {}", path, err))
+}
+
+/// Records the statistics of a run against the job it belongs to, if any.
+///
+/// As with the statistics file, this happens regardless of whether the run
+/// succeeded, so the history of a job always includes its failures.
+pub fn record(
+    run: Option<Run>,
+    command: &str,
+    tracker: &Tracker,
+    result: &Result<()>,
+) -> Result<()> {
+    match run {
+        Some(mut run) => run.finish(statistics(command, tracker, result)),
+        None => Ok(()),
+    }
+}
+
+/// Sends the statistics of a run to the URL provided by `--notify-url`.
+///
+/// Statistics are sent as JSON in a `POST` request, regardless of whether
+/// the run succeeded. Failing to notify is logged rather than returned, so
+/// that the outcome of the run itself is never hidden by the notification.
+pub async fn notify(command: &str, tracker: &Tracker, args: &ArgMatches, result: &Result<()>) {
+    // only notify when requested
+    let url = match args.value_of("notify-url") {
+        Some(url) => url,
+        None => return,
+    };
+
+    // send the statistics, waiting a reasonable time for the receiver
+    let response = reqwest::Client::new()
+        .post(url)
+        .timeout(NOTIFY_TIMEOUT)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(statistics(command, tracker, result).to_string())
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status);
+
+    match response {
+        Ok(_) => debug!(%url, "Sent notification"),
+        Err(err) => warn!(%url, error = %err, "Unable to send notification"),
+    }
+}
+
+/// Constructs the statistics of a run, as JSON.
+fn statistics(command: &str, tracker: &Tracker, result: &Result<()>) -> Value {
+    // break down the documents handled by each worker
+    let workers = tracker
+        .workers()
+        .into_iter()
+        .map(|(id, worker)| {
+            json!({
+                "id": id,
+                "batches": worker.batches,
+                "documents": worker.documents,
+                "busy_ms": worker.busy.as_millis() as u64,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    // construct the statistics of the entire run
+    json!({
+        "command": command,
+        "status": match result {
+            Ok(()) => "succeeded",
+            Err(_) if interrupted(result) => "interrupted",
+            Err(_) => "failed",
+        },
+        "error": result.as_ref().err().map(|err| err.to_string()),
+        "errors": tracker.errors(),
+        "documents": tracker.documents().saturating_sub(tracker.failed()),
+        "skipped": tracker.skipped(),
+        "failed": tracker.failed(),
+        "bytes": tracker.bytes(),
+        "elapsed_ms": tracker.elapsed().as_millis() as u64,
+        "rate": tracker.rate(),
+        "peak_rate": tracker.peak_rate(),
+        "indices": tracker.indices(),
+        "workers": workers,
+    })
+}
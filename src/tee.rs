@@ -0,0 +1,109 @@
+//! Writer duplicating output across multiple destinations.
+//!
+//! This allows a single export to be written to several places at once (such
+//! as fast local disk alongside more durable storage), without having to pass
+//! over the source cluster more than once.
+use tokio::io::AsyncWrite;
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Writer duplicating every write across a set of writers.
+///
+/// Each write only completes once every writer has accepted all of it, so
+/// output proceeds at the pace of the slowest writer. Writes which return
+/// `Poll::Pending` must be retried with the same buffer (as is the case for
+/// `write_all`), as writers which already accepted the buffer are skipped.
+pub struct Tee<W> {
+    writers: Vec<W>,
+    written: Vec<usize>,
+}
+
+impl<W> Tee<W> {
+    /// Constructs a new `Tee` from a set of writers.
+    pub fn new(writers: Vec<W>) -> Self {
+        let written = vec![0; writers.len()];
+        Self { writers, written }
+    }
+
+    /// Returns the writers held by this `Tee`.
+    pub fn into_inner(self) -> Vec<W> {
+        self.writers
+    }
+}
+
+impl<W> AsyncWrite for Tee<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let mut pending = false;
+
+        // hand the rest of the buffer to each writer which hasn't taken it all
+        for (writer, written) in this.writers.iter_mut().zip(this.written.iter_mut()) {
+            while *written < buf.len() {
+                match Pin::new(&mut *writer).poll_write(cx, &buf[*written..]) {
+                    Poll::Ready(Ok(0)) => {
+                        this.written.iter_mut().for_each(|written| *written = 0);
+                        return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                    }
+                    Poll::Ready(Ok(count)) => *written += count,
+                    Poll::Ready(Err(err)) => {
+                        this.written.iter_mut().for_each(|written| *written = 0);
+                        return Poll::Ready(Err(err));
+                    }
+                    Poll::Pending => {
+                        pending = true;
+                        break;
+                    }
+                }
+            }
+        }
+
+        if pending {
+            return Poll::Pending;
+        }
+
+        // every writer has the full buffer, so reset for the next one
+        this.written.iter_mut().for_each(|written| *written = 0);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        poll_all(&mut self.get_mut().writers, |writer| writer.poll_flush(cx))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        poll_all(&mut self.get_mut().writers, |writer| {
+            writer.poll_shutdown(cx)
+        })
+    }
+}
+
+/// Polls an operation against every writer, completing once all have.
+fn poll_all<W, F>(writers: &mut [W], mut poll: F) -> Poll<io::Result<()>>
+where
+    W: AsyncWrite + Unpin,
+    F: FnMut(Pin<&mut W>) -> Poll<io::Result<()>>,
+{
+    let mut pending = false;
+    for writer in writers {
+        match poll(Pin::new(writer)) {
+            Poll::Ready(Ok(())) => (),
+            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+            Poll::Pending => pending = true,
+        }
+    }
+
+    if pending {
+        Poll::Pending
+    } else {
+        Poll::Ready(Ok(()))
+    }
+}
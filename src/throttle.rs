@@ -0,0 +1,45 @@
+//! Bandwidth throttling for data sent to (and received from) a cluster.
+//!
+//! Jobs run over shared links (such as between datacenters) can easily
+//! saturate them, so a `Throttle` can be used to cap the aggregate rate of
+//! transfer across every concurrent request of a job.
+use tokio::time::Instant;
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Throttle limiting the number of bytes transferred per second.
+///
+/// Each transfer reserves a slot of time proportional to its size, starting
+/// once every earlier reservation has finished. Transfers then wait for the
+/// start of their slot, so the aggregate rate never exceeds the limit (even
+/// though each individual transfer still happens at full speed).
+#[derive(Debug)]
+pub(crate) struct Throttle {
+    rate: f64,
+    next: Mutex<Instant>,
+}
+
+impl Throttle {
+    /// Constructs a new throttle, allowing a number of bytes per second.
+    pub(crate) fn new(rate: usize) -> Self {
+        Self {
+            rate: rate.max(1) as f64,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Waits for room to transfer a number of bytes.
+    pub(crate) async fn consume(&self, bytes: usize) {
+        let start = {
+            let mut next = self.next.lock().expect("poisoned lock");
+
+            // idle time can't be saved up, so reserve from now at the earliest
+            let start = (*next).max(Instant::now());
+            *next = start + Duration::from_secs_f64(bytes as f64 / self.rate);
+            start
+        };
+
+        tokio::time::sleep_until(start).await
+    }
+}
@@ -0,0 +1,460 @@
+//! Field transforms applied to document sources during import.
+//!
+//! Transforms are provided via the CLI as simple expressions, such as setting
+//! a field via `environment=staging`, removing a field via `user.email`,
+//! renaming a field via `user=account`, shifting a date field via
+//! `@timestamp=+30d`, or coercing a field to another type via
+//! `status:long->string`. Nested fields use dot notation. Anything more
+//! complex can be carried out by a `Jq` expression, or by a WebAssembly
+//! `Plugin`. Fields can also be redacted or hashed to sanitize documents,
+//! as listed within a YAML rules file.
+use anyhow::{anyhow, Result};
+use chrono::Duration;
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+
+use crate::jq::Jq;
+use crate::plugin::Plugin;
+use crate::{document, mapping, units};
+
+/// Transform applied to the source of each document.
+pub enum Transform {
+    /// Sets a field to a value, replacing any existing value.
+    Set(String, Value),
+    /// Removes a field, if it exists.
+    Remove(String),
+    /// Renames a field, if it exists.
+    Rename(String, String),
+    /// Shifts a date field by an offset, if it exists.
+    Shift(String, Duration),
+    /// Coerces a field to a type, optionally only from another type.
+    Coerce(String, Option<Kind>, Kind),
+    /// Replaces a field with a placeholder value, if it exists.
+    Redact(String, Value),
+    /// Replaces a field with a salted SHA-256 hash, if it exists.
+    Hash(String, String),
+    /// Evaluates a jq expression against the source.
+    Jq(Box<Jq>),
+    /// Passes the source through a WebAssembly plugin.
+    Plugin(Box<Plugin>),
+}
+
+impl Transform {
+    /// Parses a transform setting a field from a `field=value` expression.
+    ///
+    /// Values are parsed as JSON when possible, otherwise as a plain string.
+    pub fn set(input: &str) -> Result<Self> {
+        let (field, value) = split(input)?;
+        let value = serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_owned()));
+        Ok(Transform::Set(field.to_owned(), value))
+    }
+
+    /// Parses a transform removing a field.
+    pub fn remove(input: &str) -> Result<Self> {
+        let field = input.trim();
+        if field.is_empty() {
+            return Err(anyhow!("Invalid field transform provided: {}", input));
+        }
+        Ok(Transform::Remove(field.to_owned()))
+    }
+
+    /// Parses a transform renaming a field from an `old=new` expression.
+    pub fn rename(input: &str) -> Result<Self> {
+        let (old, new) = split(input)?;
+        if new.is_empty() {
+            return Err(anyhow!("Invalid field transform provided: {}", input));
+        }
+        Ok(Transform::Rename(old.to_owned(), new.to_owned()))
+    }
+
+    /// Parses a transform shifting a date field from a `field=offset` expression.
+    ///
+    /// Offsets are provided as a duration with an optional sign, such as
+    /// `+30d` or `-12h`. A missing sign will shift the date forward.
+    pub fn shift(input: &str) -> Result<Self> {
+        let (field, offset) = split(input)?;
+
+        // split the sign from the duration
+        let (negative, duration) = match offset.as_bytes().first() {
+            Some(b'-') => (true, &offset[1..]),
+            Some(b'+') => (false, &offset[1..]),
+            _ => (false, offset),
+        };
+
+        // parse the duration into a signed offset
+        let duration = Duration::from_std(units::parse_duration(duration)?)
+            .map_err(|_| anyhow!("Invalid field transform provided: {}", input))?;
+        let offset = if negative { -duration } else { duration };
+
+        Ok(Transform::Shift(field.to_owned(), offset))
+    }
+
+    /// Parses a transform coercing a field from a `field:from->to` expression.
+    ///
+    /// The `from->` portion is optional, and restricts the coercion to values
+    /// of that type only. Values which can't be coerced are left as they are.
+    pub fn coerce(input: &str) -> Result<Self> {
+        let invalid = || anyhow!("Invalid coercion provided: {}", input);
+
+        // split the field from the types
+        let (field, types) = match input.rsplit_once(':') {
+            Some((field, types)) if !field.trim().is_empty() => (field.trim(), types),
+            _ => return Err(invalid()),
+        };
+
+        // parse the types, with an optional source type
+        let (from, to) = match types.split_once("->") {
+            Some((from, to)) => (Some(Kind::parse(from).ok_or_else(invalid)?), to),
+            None => (None, types),
+        };
+        let to = Kind::parse(to)
+            .filter(|kind| *kind != Kind::Object)
+            .ok_or_else(invalid)?;
+
+        Ok(Transform::Coerce(field.to_owned(), from, to))
+    }
+
+    /// Constructs transforms coercing each field to its type within mappings.
+    ///
+    /// Only fields mapped as one of the supported types are coerced. When
+    /// several mappings are provided (such as every index behind an alias),
+    /// fields which are mapped to different types are left as they are.
+    pub fn coerce_mappings<'a, I>(mappings: I) -> Vec<Self>
+    where
+        I: IntoIterator<Item = &'a Value>,
+    {
+        let mut kinds = BTreeMap::new();
+        for mapping in mappings {
+            for (field, kind) in mapping::types(mapping) {
+                let kind = Kind::parse(&kind).filter(|kind| *kind != Kind::Object);
+                kinds
+                    .entry(field)
+                    .and_modify(|existing| {
+                        if *existing != kind {
+                            *existing = None;
+                        }
+                    })
+                    .or_insert(kind);
+            }
+        }
+        kinds
+            .into_iter()
+            .filter_map(|(field, kind)| Some(Transform::Coerce(field, None, kind?)))
+            .collect()
+    }
+
+    /// Loads the transforms listed within a YAML rules file.
+    ///
+    /// Rules list the fields to `remove`, `redact` and `hash`, which are
+    /// applied in that order. Redacted fields are replaced with `"[REDACTED]"`
+    /// unless a `replacement` is provided, and hashes are salted with the
+    /// `salt` (if any) so that values can't be recovered by hashing guesses.
+    pub fn rules(path: &str) -> Result<Vec<Self>> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| anyhow!("Unable to read rules {}: {}", path, err))?;
+        let rules: Rules = serde_yaml::from_str(&contents)
+            .map_err(|err| anyhow!("Invalid rules provided in {}: {}", path, err))?;
+
+        // every rule applies to a single field, in the order of each action
+        let replacement = rules.replacement.unwrap_or_else(|| Value::from(REDACTED));
+        let salt = rules.salt.unwrap_or_default();
+        let removals = rules.remove.into_iter().map(Transform::Remove);
+        let redactions = rules
+            .redact
+            .into_iter()
+            .map(|field| Transform::Redact(field, replacement.clone()));
+        let hashes = rules
+            .hash
+            .into_iter()
+            .map(|field| Transform::Hash(field, salt.clone()));
+
+        let transforms = removals.chain(redactions).chain(hashes).collect::<Vec<_>>();
+        if transforms.is_empty() {
+            return Err(anyhow!("No rules provided in {}", path));
+        }
+        Ok(transforms)
+    }
+
+    /// Compiles a transform evaluating a jq expression.
+    pub fn jq(expression: &str) -> Result<Self> {
+        Ok(Transform::Jq(Box::new(Jq::compile(expression)?)))
+    }
+
+    /// Loads a transform passing sources through a WebAssembly plugin.
+    pub fn plugin(path: &str) -> Result<Self> {
+        Ok(Transform::Plugin(Box::new(Plugin::load(path)?)))
+    }
+
+    /// Applies this transform to a document source.
+    ///
+    /// Field transforms never fail, as missing fields are left untouched, so
+    /// only failures within an expression or plugin are returned as errors.
+    pub fn apply(&self, source: &mut Value) -> Result<()> {
+        match self {
+            Transform::Set(field, value) => document::insert(source, field, value.clone()),
+            Transform::Remove(field) => {
+                document::remove(source, field);
+            }
+            Transform::Rename(old, new) => {
+                if let Some(value) = document::remove(source, old) {
+                    document::insert(source, new, value);
+                }
+            }
+            Transform::Shift(field, offset) => {
+                // shift every date within the field, leaving invalid values
+                let shifted = match document::lookup(source, field) {
+                    Some(Value::Array(values)) => Value::Array(
+                        values
+                            .iter()
+                            .map(|value| {
+                                document::shift_date(value, *offset)
+                                    .unwrap_or_else(|| value.clone())
+                            })
+                            .collect(),
+                    ),
+                    Some(value) => match document::shift_date(value, *offset) {
+                        Some(shifted) => shifted,
+                        None => return Ok(()),
+                    },
+                    None => return Ok(()),
+                };
+                document::insert(source, field, shifted);
+            }
+            Transform::Coerce(field, from, to) => {
+                // coerce every value within the field, leaving the rest
+                let coerced = match document::lookup(source, field) {
+                    Some(Value::Array(values)) => Value::Array(
+                        values
+                            .iter()
+                            .map(|value| coerce(value, *from, *to))
+                            .collect(),
+                    ),
+                    Some(value) => coerce(value, *from, *to),
+                    None => return Ok(()),
+                };
+                document::insert(source, field, coerced);
+            }
+            Transform::Redact(field, replacement) => {
+                if document::lookup(source, field).is_some() {
+                    document::insert(source, field, replacement.clone());
+                }
+            }
+            Transform::Hash(field, salt) => {
+                // hash every value within the field, leaving any nulls
+                let hashed = match document::lookup(source, field) {
+                    Some(Value::Array(values)) => {
+                        Value::Array(values.iter().map(|value| hash(value, salt)).collect())
+                    }
+                    Some(value) => hash(value, salt),
+                    None => return Ok(()),
+                };
+                document::insert(source, field, hashed);
+            }
+            Transform::Jq(jq) => return jq.apply(source),
+            Transform::Plugin(plugin) => return plugin.apply(source),
+        }
+        Ok(())
+    }
+}
+
+/// The value redacted fields are replaced with by default.
+const REDACTED: &str = "[REDACTED]";
+
+/// Rules used to sanitize documents, as written in a rules file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rules {
+    #[serde(default)]
+    remove: Vec<String>,
+    #[serde(default)]
+    redact: Vec<String>,
+    #[serde(default)]
+    hash: Vec<String>,
+    replacement: Option<Value>,
+    salt: Option<String>,
+}
+
+/// Types which a field can be coerced between, named after their mappings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// Strings, mapped as `keyword` or `text`.
+    String,
+    /// Whole numbers, mapped as `long` (or any smaller integer type).
+    Long,
+    /// Decimal numbers, mapped as `double` (or any smaller float type).
+    Double,
+    /// Booleans, mapped as `boolean`.
+    Boolean,
+    /// Objects, which can only be coerced from (into a JSON string).
+    Object,
+}
+
+impl Kind {
+    /// Parses a type from its name, accepting the names of similar mappings.
+    fn parse(input: &str) -> Option<Self> {
+        match input.trim() {
+            "string" | "keyword" | "text" => Some(Kind::String),
+            "long" | "integer" | "short" | "byte" => Some(Kind::Long),
+            "double" | "float" | "half_float" => Some(Kind::Double),
+            "boolean" => Some(Kind::Boolean),
+            "object" => Some(Kind::Object),
+            _ => None,
+        }
+    }
+
+    /// Determines the type of a JSON value, if it has one.
+    fn of(value: &Value) -> Option<Self> {
+        match value {
+            Value::String(_) => Some(Kind::String),
+            Value::Number(number) if number.is_f64() => Some(Kind::Double),
+            Value::Number(_) => Some(Kind::Long),
+            Value::Bool(_) => Some(Kind::Boolean),
+            Value::Object(_) => Some(Kind::Object),
+            Value::Null | Value::Array(_) => None,
+        }
+    }
+}
+
+/// Coerces a single value to a type, if it's of the expected source type.
+///
+/// Conversions follow the rules Elasticsearch uses when coercing values, so
+/// decimals are truncated when converted to whole numbers. Anything which
+/// can't be converted is returned unchanged, for the cluster to report.
+fn coerce(value: &Value, from: Option<Kind>, to: Kind) -> Value {
+    // only coerce values matching the source type, if any
+    let kind = Kind::of(value);
+    if kind.is_none() || (from.is_some() && kind != from) || kind == Some(to) {
+        return value.clone();
+    }
+
+    let coerced = match (to, value) {
+        (Kind::String, Value::Number(number)) => Some(Value::from(number.to_string())),
+        (Kind::String, Value::Bool(boolean)) => Some(Value::from(boolean.to_string())),
+        (Kind::String, Value::Object(_)) => Some(Value::from(value.to_string())),
+        (Kind::Long, Value::Number(number)) => number.as_f64().and_then(truncate),
+        (Kind::Long, Value::String(string)) => match string.trim().parse::<i64>() {
+            Ok(long) => Some(Value::from(long)),
+            Err(_) => string.trim().parse::<f64>().ok().and_then(truncate),
+        },
+        (Kind::Double, Value::Number(number)) => number.as_f64().map(Value::from),
+        (Kind::Double, Value::String(string)) => string
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|double| double.is_finite())
+            .map(Value::from),
+        (Kind::Boolean, Value::String(string)) => match string.trim() {
+            "true" => Some(Value::Bool(true)),
+            "false" | "" => Some(Value::Bool(false)),
+            _ => None,
+        },
+        (Kind::Boolean, Value::Number(number)) => match number.as_i64() {
+            Some(0) => Some(Value::Bool(false)),
+            Some(1) => Some(Value::Bool(true)),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    coerced.unwrap_or_else(|| value.clone())
+}
+
+/// Truncates a decimal into a whole number, if it fits within a `long`.
+fn truncate(double: f64) -> Option<Value> {
+    let truncated = double.trunc();
+    if truncated.is_finite() && truncated >= i64::MIN as f64 && truncated < i64::MAX as f64 {
+        Some(Value::from(truncated as i64))
+    } else {
+        None
+    }
+}
+
+/// Splits an expression into a field and value on the first `=`.
+fn split(input: &str) -> Result<(&str, &str)> {
+    match input.split_once('=') {
+        Some((field, value)) if !field.trim().is_empty() => Ok((field.trim(), value.trim())),
+        _ => Err(anyhow!("Invalid field transform provided: {}", input)),
+    }
+}
+
+/// Hashes a single value with a salt, into a lowercase hex string.
+///
+/// Strings are hashed as they are, whereas any other value is hashed as its
+/// JSON representation. Nulls are returned unchanged, as there's nothing to
+/// hide within them.
+fn hash(value: &Value, salt: &str) -> Value {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    match value {
+        Value::Null => return Value::Null,
+        Value::String(string) => hasher.update(string.as_bytes()),
+        value => hasher.update(value.to_string().as_bytes()),
+    }
+
+    let mut hex = String::with_capacity(64);
+    for byte in hasher.finalize() {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    Value::from(hex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn coerce_mappings_skips_multi_fields_and_conflicts() {
+        let first = json!({
+            "properties": {
+                "name": { "type": "text", "fields": { "raw": { "type": "keyword" } } },
+                "status": { "type": "keyword" },
+                "user": { "properties": { "age": { "type": "integer" } } },
+                "when": { "type": "date" },
+                "score": { "type": "float" }
+            }
+        });
+        let second = json!({
+            "_doc": {
+                "properties": {
+                    "score": { "type": "long" },
+                    "active": { "type": "boolean" }
+                }
+            }
+        });
+
+        let coercions = Transform::coerce_mappings([&first, &second])
+            .into_iter()
+            .map(|transform| match transform {
+                Transform::Coerce(field, None, kind) => (field, kind),
+                _ => panic!("expected a coercion"),
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            coercions,
+            vec![
+                ("active".to_owned(), Kind::Boolean),
+                ("name".to_owned(), Kind::String),
+                ("status".to_owned(), Kind::String),
+                ("user.age".to_owned(), Kind::Long),
+            ]
+        );
+    }
+
+    #[test]
+    fn coerce_applies_mapping_types() {
+        let mapping = json!({ "properties": { "status": { "type": "keyword" } } });
+        let mut source = json!({ "status": 404, "other": 1 });
+
+        for transform in Transform::coerce_mappings([&mapping]) {
+            transform.apply(&mut source).unwrap();
+        }
+        assert_eq!(source, json!({ "status": "404", "other": 1 }));
+    }
+}
@@ -0,0 +1,126 @@
+//! Utility functions for parsing and formatting human readable units.
+//!
+//! This module offers parsers for values provided via the CLI, such as
+//! byte sizes in the form of `8mb` or durations in the form of `30s`, to
+//! allow for friendlier arguments. Values can also be formatted back into
+//! the same units, for friendlier output.
+use anyhow::{anyhow, Result};
+
+use std::time::Duration;
+
+/// Parses a human readable byte size into a number of bytes.
+///
+/// Sizes are provided as a number followed by an optional unit, such as
+/// `512`, `64kb` or `8mb`. Units are case insensitive and use powers of
+/// 1024, matching the units accepted by Elasticsearch itself.
+pub fn parse_bytes(input: &str) -> Result<usize> {
+    let input = input.trim().to_lowercase();
+
+    // split the numeric value from the unit suffix
+    let offset = input
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(input.len());
+    let (value, unit) = input.split_at(offset);
+
+    // determine the multiplier of the unit
+    let multiplier = match unit.trim() {
+        "" | "b" => 1,
+        "k" | "kb" => 1 << 10,
+        "m" | "mb" => 1 << 20,
+        "g" | "gb" => 1 << 30,
+        "t" | "tb" => 1u64 << 40,
+        _ => return Err(anyhow!("Invalid byte size provided: {}", input)),
+    };
+
+    // parse the value, allowing for fractional values
+    let value = value
+        .parse::<f64>()
+        .map_err(|_| anyhow!("Invalid byte size provided: {}", input))?;
+
+    Ok((value * multiplier as f64) as usize)
+}
+
+/// Parses a human readable duration into a `Duration`.
+///
+/// Durations are provided as a number followed by an optional unit, such
+/// as `500ms`, `30s`, `5m`, `1h`, `7d` or `2w`. Values without a unit are
+/// treated as a number of seconds.
+pub fn parse_duration(input: &str) -> Result<Duration> {
+    let input = input.trim().to_lowercase();
+
+    // split the numeric value from the unit suffix
+    let offset = input
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(input.len());
+    let (value, unit) = input.split_at(offset);
+
+    // determine the multiplier of the unit, in seconds
+    let multiplier = match unit.trim() {
+        "ms" => 0.001,
+        "" | "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        "d" => 86400.0,
+        "w" => 604800.0,
+        _ => return Err(anyhow!("Invalid duration provided: {}", input)),
+    };
+
+    // parse the value, allowing for fractional values
+    let value = value
+        .parse::<f64>()
+        .map_err(|_| anyhow!("Invalid duration provided: {}", input))?;
+
+    Duration::try_from_secs_f64(value * multiplier)
+        .map_err(|_| anyhow!("Invalid duration provided: {}", input))
+}
+
+/// Formats a number of bytes into a human readable byte size.
+///
+/// The largest unit which keeps the value above one is used, with a single
+/// decimal place, such as `512b`, `1.5kb` or `8.0mb`.
+pub fn format_bytes(bytes: u64) -> String {
+    let units = [("gb", 1 << 30), ("mb", 1 << 20), ("kb", 1 << 10)];
+
+    // find the largest unit which fits
+    for (unit, size) in units {
+        if bytes >= size {
+            return format!("{:.1}{}", bytes as f64 / size as f64, unit);
+        }
+    }
+
+    format!("{}b", bytes)
+}
+
+/// Formats a duration into a human readable duration.
+///
+/// Durations are rounded to the second and split into the hour, minute
+/// and second components, such as `45s`, `3m05s` or `1h02m03s`.
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration
+        .saturating_add(Duration::from_millis(500))
+        .as_secs();
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+
+    match (hours, minutes) {
+        (0, 0) => format!("{}s", seconds),
+        (0, _) => format!("{}m{:02}s", minutes, seconds),
+        (_, _) => format!("{}h{:02}m{:02}s", hours, minutes, seconds),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_are_rounded_to_the_second() {
+        assert_eq!(format_duration(Duration::from_millis(499)), "0s");
+        assert_eq!(format_duration(Duration::from_millis(1500)), "2s");
+        assert_eq!(format_duration(Duration::from_millis(184_600)), "3m05s");
+        assert_eq!(
+            format_duration(Duration::from_millis(3_722_999)),
+            "1h02m03s"
+        );
+        assert_eq!(format_duration(Duration::MAX), "5124095576030431h00m15s");
+    }
+}